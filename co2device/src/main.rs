@@ -1,10 +1,16 @@
 extern crate core;
 
 use std::str::FromStr;
-use std::thread;
 use std::time::{Duration, SystemTime};
 use amqpiothubv2;
-use amqpiothubv2::amqp::transfer::{create_message_from_str, TransferExceptions};
+use amqpiothubv2::async_std;
+use amqpiothubv2::amqp::transfer::{create_message_from_str, Settlement, TransferExceptions};
+use amqpiothubv2::amqp::client::Client;
+use amqpiothubv2::provisioning::ProvisioningClient;
+use amqpiothubv2::amqp::methods::MethodResponse;
+use amqpiothubv2::util::queue::QueueConfig;
+use amqpiothubv2::util::reconnect::ReconnectPolicy;
+use amqpiothubv2::util::batch::BatchConfig;
 use cs811lib;
 use amqpiothubv2::ntex;
 use amqpiothubv2::ntex_amqp;
@@ -22,43 +28,77 @@ struct DataEntry{
     value: f64
 }
 
+// New devices register through DPS when IOTHUB_DPS_ID_SCOPE is set: the group enrollment key
+// comes from IOTHUB_DPS_GROUP_KEY, the registration id from IOTHUB_DPS_REGISTRATION_ID (default airquality).
+// Otherwise the client is created from IOTHUB_DEVICE_CONNECTION_STRING.
+async fn create_client(cert_location: &str) -> Client {
+    let id_scope = match std::env::var("IOTHUB_DPS_ID_SCOPE"){
+        Ok(id_scope) => {
+            id_scope
+        }
+        Err(_) => {
+            // Device Params: HostName=...;DeviceId=airquality;SharedAccessKey=...
+            let connection_string = std::env::var("IOTHUB_DEVICE_CONNECTION_STRING")
+                .expect("IOTHUB_DEVICE_CONNECTION_STRING is not set");
+            // The SAS token is derived from the connection string
+            return match Client::from_connection_string(&connection_string, cert_location).await{
+                Ok(client) => {
+                    client
+                }
+                Err(fail) => {
+                    // Invalid connection string or certificate --> Exit
+                    panic!("Failed to create the client: {}", fail);
+                }
+            };
+        }
+    };
+    let group_key = std::env::var("IOTHUB_DPS_GROUP_KEY")
+        .expect("IOTHUB_DPS_GROUP_KEY is not set");
+    let registration_id = std::env::var("IOTHUB_DPS_REGISTRATION_ID")
+        .unwrap_or_else(|_| String::from("airquality"));
+    let provisioning = match ProvisioningClient::group_symmetric_key(&id_scope, &registration_id, &group_key, cert_location).await{
+        Ok(provisioning) => {
+            provisioning
+        }
+        Err(fail) => {
+            panic!("Failed to create the provisioning client: {}", fail);
+        }
+    };
+    let device = match provisioning.register(None).await{
+        Ok(device) => {
+            device
+        }
+        Err(fail) => {
+            panic!("Registration failed: {}", fail);
+        }
+    };
+    println!("Provisioned {} on {}", device.device_id, device.assigned_hub);
+    match device.client(cert_location).await{
+        Ok(client) => {
+            client
+        }
+        Err(fail) => {
+            panic!("Failed to create the client: {}", fail);
+        }
+    }
+}
+
 #[ntex::main]
 async fn main() {
     // Main thread --> Apply ntex instance
     // Enable trace logs
     amqpiothubv2::amqp::util::enable_logging_traces(None);
-    // Device Params
-    let device_id =  "airquality";
-    let primary_key = "";
-    let hub_name = "researchprojecthub";
     let cert_location = "src/root.pem";
-    // Sas token
-    let mut sas_token = amqpiothubv2::util::token::SasToken::new(
-      &primary_key, 1, &hub_name, &device_id
-    );
-    // Check if token is valid.
-    let mut sas_token_opt = match sas_token{
-        Ok(ok) => {
-            // Continue
-            println!("Token: {}", ok);
-            Some(ok)
-        }
-        Err(fail) => {
-            // Failed to create the token --> Exit
-            panic!("Failed SAS: {}",fail);
-            None
-        }
-    };
-    // Unwrap the token
-    let mut token = sas_token_opt.unwrap();
-    // Create client
-    let mut client = amqpiothubv2::amqp::client::Client::new(
-        &device_id,
-        &hub_name,
-        &cert_location,
-        &primary_key,
-        &token.sas)
-        .await;
+    let mut client = create_client(cert_location).await;
+    let device_id = client.device_id().to_string();
+    // Reconnect with backoff when a send or receive fails, the device runs unattended.
+    client.set_reconnect_policy(ReconnectPolicy::automatic());
+    // Keep telemetry on disk while the hub is unreachable, it is sent once the client reconnects.
+    if let Err(err) = client.enable_offline_queue("telemetry-queue.jsonl", QueueConfig::default()){
+        println!("Offline queue disabled: {}", err);
+    }
+    // Readings are sent together, one transfer per batch instead of one per reading.
+    client.enable_batching(BatchConfig::default());
     // Check client state.
     let connectors = client.connect().await;
     let connectors_result = match connectors{
@@ -72,7 +112,7 @@ async fn main() {
     };
     let create_result = client.attach_sender(
         "sender_link_global",
-        "/devices/airquality/messages/events",
+        &format!("/devices/{}/messages/events", device_id),
         5
     ).await;
     /*
@@ -89,7 +129,7 @@ async fn main() {
     */
     let mut recv_response = client.attach_receiver(
         "recv_link_global",
-        "devices/airquality/messages/devicebound",
+        &format!("devices/{}/messages/devicebound", device_id),
         5)
         .await;
 
@@ -103,6 +143,34 @@ async fn main() {
     cs811_sensor.enter_application_mode(&mut driver);
     cs811_sensor.set_measurement_mode(&mut driver, MeasurementModes::TenSeconds);
 
+    // Actions triggered from the web app as direct methods.
+    client.register_method("test", |_payload| async move {
+        match pulse_pin(20).await{
+            Ok(_) => {
+                MethodResponse::ok(serde_json::json!({"action": "test"}))
+            }
+            Err(err) => {
+                println!("GPIO failure: {}", err);
+                MethodResponse::new(500, serde_json::json!({"message": err.to_string()}))
+            }
+        }
+    });
+
+    // Configuration pushed through the device twin (desired properties).
+    let mut measurement_interval = DEFAULT_MEASUREMENT_INTERVAL;
+    match client.get_twin(10).await{
+        Ok(twin) => {
+            measurement_interval = read_measurement_interval(&twin.desired, measurement_interval);
+        }
+        Err(err) => {
+            println!("Failed to read the twin: {}", err);
+        }
+    }
+    report_measurement_interval(&mut client, measurement_interval).await;
+    if let Err(err) = client.subscribe_desired_properties(10).await{
+        println!("Failed to subscribe to desired properties: {}", err);
+    }
+
     let mut loop_time = SystemTime::now();
 
     loop{
@@ -110,7 +178,7 @@ async fn main() {
         if sensor_value != 0{
             println!("Current value: {}", sensor_value);
             let prepare_payload = prepare_payload("airquality", sensor_value as f64);
-            let send_msg_result = client.send_message(
+            let send_msg_result = client.send_batched(
                 "sender_link_global",
                 prepare_payload,
                 10).await;
@@ -119,16 +187,29 @@ async fn main() {
                     println!("Ok")
                 }
                 Err(e) => {
+                    // Failed to transfer a message, the client already tried
+                    // to reconnect following its reconnect policy.
                     println!("Failed: {}", e);
-                    // Failed to transfer a message
-                    // Create new token:
-                    client.recover().await;
-                    client.reattach_sender_links().await;
-                    client.reattach_receiver_links().await;
                 }
             }
         }
-        while loop_time.elapsed().unwrap().as_secs() < 20{
+        while loop_time.elapsed().unwrap().as_secs() < measurement_interval{
+            match client.handle_method_request(1).await{
+                Ok(Some(method)) => {
+                    println!("Handled direct method {}", method);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    println!("Direct method failure: {}", err);
+                }
+            }
+            if let Ok(update) = client.next_desired_update(1).await{
+                measurement_interval = read_measurement_interval(&update.patch, measurement_interval);
+                report_measurement_interval(&mut client, measurement_interval).await;
+            }
+            if let Err(e) = client.flush_due_batches(10).await{
+                println!("Failed to send the batch: {}", e);
+            }
             let mut incoming_data = client.receive_message_listener(
                 0, 2)
                 .await;
@@ -149,40 +230,50 @@ async fn main() {
                 }
             };
             if has_data.is_some(){
-                let content = message_handler(has_data.unwrap());
-                if content.is_none(){
+                let transfer = has_data.unwrap();
+                let content = message_handler(transfer.clone());
+                let settlement = if content.is_none(){
                     // Failed to read
                     println!("Failed to read the message contents");
+                    Settlement::Reject(Some("Unreadable message".to_string()))
                 }
                 else{
                     // There is some content
                     let read_content = content.unwrap();
-                    let json: Value = serde_json::from_str(&read_content).unwrap();
+                    let json: Value = serde_json::from_str(&read_content).unwrap_or(Value::Null);
                     // Attempt to get the action value
-                    let action = json.get("action");
+                    let action = json.get("action").and_then(|action| action.as_str());
                     match action{
-                        Some(action)  => {
-                            println!("Found action: {}", action);
-                            let action_val = action.as_str().unwrap();
+                        Some(action_val)  => {
+                            println!("Found action: {}", action_val);
                             match action_val{
                                 "test" => {
                                     println!("Buzzer action");
-                                    let mut pin = Gpio::new().unwrap().get(20).unwrap().into_output();
-                                    pin.set_high();
-                                    let mut time = SystemTime::now();
-                                    thread::sleep(Duration::from_secs(2));
-                                    pin.set_low();
+                                    match pulse_pin(20).await{
+                                        Ok(_) => {
+                                            Settlement::Complete
+                                        }
+                                        Err(err) => {
+                                            // Let the hub deliver it again, the pin may be free by then.
+                                            println!("GPIO failure: {}", err);
+                                            Settlement::Abandon
+                                        }
+                                    }
                                 }
                                 _ => {
                                     println!("Did not find action.");
+                                    Settlement::Reject(Some("Unknown action".to_string()))
                                 }
                             }
                         }
                         None => {
                             println!("Failed to get the action");
+                            Settlement::Reject(Some("Missing action".to_string()))
                         }
                     }
-
+                };
+                if let Err(err) = client.settle_message(&transfer, settlement){
+                    println!("Failed to settle the message: {}", err);
                 }
             }
         }
@@ -190,6 +281,37 @@ async fn main() {
     }
 }
 
+const DEFAULT_MEASUREMENT_INTERVAL: u64 = 20;
+
+// Buzzer action: the pin goes high for two seconds.
+// Sleeps asynchronously, the client keeps serving its links meanwhile.
+async fn pulse_pin(pin: u8) -> Result<(), rppal::gpio::Error> {
+    let mut pin = Gpio::new()?.get(pin)?.into_output();
+    pin.set_high();
+    async_std::task::sleep(Duration::from_secs(2)).await;
+    pin.set_low();
+    Ok(())
+}
+
+// Seconds between two readings, from a desired properties document or patch.
+fn read_measurement_interval(desired: &Value, current: u64) -> u64 {
+    match desired.get("measurementInterval").and_then(|interval| interval.as_u64()){
+        Some(interval) if interval > 0 => {
+            interval
+        }
+        _ => {
+            current
+        }
+    }
+}
+
+async fn report_measurement_interval(client: &mut Client, interval: u64){
+    let reported = serde_json::json!({"measurementInterval": interval});
+    if let Err(err) = client.update_reported_properties(&reported, 10).await{
+        println!("Failed to report the measurement interval: {}", err);
+    }
+}
+
 fn prepare_payload(sensor_name: &str, value: f64) -> TransferBody {
     let data_entry = DataEntry{
         sensor: sensor_name.to_string(),
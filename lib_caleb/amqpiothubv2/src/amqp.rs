@@ -1,32 +1,59 @@
 pub mod client{
+    use std::collections::HashMap;
     use std::fmt::{Display, Formatter};
+    use std::rc::Rc;
     use std::result::Result::{Err, Ok};
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use ntex_amqp::client::{ConnectError, SaslAuth};
-    use ntex_amqp::{Connection, ReceiverLink, SenderLink, Session};
+    use ntex_amqp::{Connection, ControlFrame, ControlFrameKind, ReceiverLink, SenderLink, Session};
     use rustls::ClientConfig;
     use async_std::future;
     use async_std::future::TimeoutError;
     use async_std::prelude::*;
     use ntex::connect::rustls::RustlsConnector;
+    use crate::transport::websocket::WebSocketConnector;
+    use crate::transport::sasl::SaslAnonymousConnector;
+    use ntex::codec::{AsyncRead, AsyncWrite};
     use ntex::util::ByteString;
-    use ntex_amqp::codec::protocol::{Handle, Transfer, TransferBody};
-    use ntex_amqp::error::{AmqpProtocolError, DispatcherError};
+    use ntex_amqp::codec::protocol::{Disposition, Error, Handle, Transfer, TransferBody};
+    use ntex_amqp::error::{AmqpCodecError, AmqpProtocolError, DispatcherError};
+    use chrono::{DateTime, Utc};
     use tokio::task::JoinHandle;
     use crate::amqp::client::AmqpFailure::AlreadyActive;
-    use crate::amqp::client::ClientRedirectRecovery::{AMQPProtocolFailure, Disconnected, NoThreadAvailable, ServiceDisconnect, ThreadJoinError, Timeout};
-    use crate::amqp::config::{create_address, create_hostname, create_sas_login, create_tls_config, create_username, read_certificate, TlsConfigFailure};
+    use crate::amqp::client::ClientRedirectRecovery::NoThreadAvailable;
+    use crate::amqp::config::{AMQPS_PORT, Authentication, create_sas_login, create_tls_config, create_username, create_x509_tls_config, Endpoint, read_certificate, read_client_identity, TlsConfigFailure, Transport};
     use crate::amqp::transfer::TransferExceptions;
     use crate::amqp::transfer::TransferExceptions::{LinkAlreadyActive, LinkAmqpProtocolError, LinkDetachedOrDoesNotExist, MessageAmqpProtocolError, MessageTimeOut, NoSession};
-    use crate::util::token::{SasToken, SasTokenCreateException};
+    use crate::amqp::cbs::{CbsFailure, CbsLink, device_audience};
+    use crate::amqp::twin::{DesiredUpdate, TwinFailure, TwinLink, TwinProperties};
+    use crate::amqp::methods::{invoke_path, MethodFailure, MethodFuture, MethodHandler, MethodInvocation, MethodLink, MethodResponse, METHOD_NOT_FOUND};
+    use crate::amqp::feedback::{AckLevel, FeedbackFailure, FeedbackLink, FeedbackRecord, request_ack};
+    use crate::amqp::eventhub::{EventHubConsumer, EventHubFailure, EventHubLocation, partition_ids, PartitionReceiver};
+    use crate::registry::RegistryClient;
+    use crate::util::checkpoint::{CheckpointStore, EventPosition};
+    use crate::transport::https::{encode_request, https_request, HTTPS_PORT};
+    use futures::Stream;
+    use std::future::Future;
+    use crate::util::connection_string::{ConnectionString, ConnectionStringException};
+    use crate::util::token::{Clock, SasToken, SasTokenCreateException, SystemClock};
+    use crate::error::IotHubError;
+    use crate::util::reconnect::{CircuitBreaker, CircuitState, ReconnectEvent, ReconnectPolicy};
+    use crate::util::events::{ClientEvent, EventBus, RedirectTarget};
+    use futures::channel::mpsc::UnboundedReceiver;
+    use futures::channel::{mpsc, oneshot};
+    use std::path::Path;
+    use ntex::util::Bytes;
+    use crate::amqp::transfer::{create_batch_message, encode_transfer_body, MAX_MESSAGE_BYTES, redirect_target, settlement_disposition, Settlement, with_creation_time};
+    use crate::util::queue::{OfflineQueue, QueueConfig, QueueException};
+    use crate::util::batch::{BATCH_ENVELOPE, BatchConfig, MAX_BATCH_BYTES, MessageBatch};
 
     pub struct Client{
-        address: String,
+        endpoint: Endpoint,
+        authentication: Authentication,
         auth: SaslAuth,
         tls_config: ClientConfig,
         session: Option<Session>,
-        hostname: String,
         device_id: String,
         hub_name: String,
         primary_key: String,
@@ -34,61 +61,122 @@ pub mod client{
         stream: Option<Connection>,
         recover_links: Option<Vec<(String,String)>>,
         pub recv_handles: Option<Vec<Handle>>,
-        recv_recover_links: Option<Vec<(String, String)>>
+        recv_recover_links: Option<Vec<(String, String)>>,
+        // Handle of every attached receiver link by name
+        recv_link_names: HashMap<String, Handle>,
+        // Credit per receiver link, links without an entry get receiver_credit
+        receiver_credits: HashMap<String, u32>,
+        twin: Option<TwinLink>,
+        // Resubscribe to desired updates when the twin links are reopened
+        twin_subscribed: bool,
+        methods: Option<MethodLink>,
+        method_handlers: HashMap<String, MethodHandler>,
+        token_expiry: Option<DateTime<Utc>>,
+        token_lifetime: chrono::Duration,
+        renewal_margin: chrono::Duration,
+        // Time source for new tokens and the renewal margin
+        clock: Box<dyn Clock>,
+        cbs: Option<CbsLink>,
+        gateway_devices: HashMap<String, GatewayDevice>,
+        reconnect_policy: ReconnectPolicy,
+        circuit: CircuitBreaker,
+        reconnect_listener: Option<Box<dyn FnMut(&ReconnectEvent)>>,
+        events: EventBus,
+        offline_queue: Option<OfflineQueue>,
+        batch_config: Option<BatchConfig>,
+        // Open batch per sender link
+        batches: HashMap<String, MessageBatch<Bytes>>,
+        receiver_credit: u32,
+    }
+
+    // Credit granted to every receiver link, unless changed with set_receiver_credit.
+    pub const DEFAULT_RECEIVER_CREDIT: u32 = 360;
+
+    // A downstream device sharing the connection of a gateway client.
+    struct GatewayDevice{
+        primary_key: String,
+        sender_link: String,
+        receiver_link: Option<String>,
     }
 
     pub struct ServiceClient{
-        address: String,
+        endpoint: Endpoint,
         auth: SaslAuth,
         tls_config: ClientConfig,
         session: Option<Session>,
-        hostname: String,
         device_id: String,
         hub_name: String,
         primary_key: String,
+        policy: String,
         spawner: Option<JoinHandle<Result<(), DispatcherError>>>,
         stream: Option<Connection>,
         recover_links: Option<Vec<(String,String)>>,
+        // Sender on /messages/devicebound, reused for every cloud-to-device message
+        devicebound: Option<SenderLink>,
+        feedback: Option<FeedbackLink>,
+        ack: AckLevel,
+        // Connection to the Event Hubs namespace after the hub redirected the event readers
+        event_hub: Option<EventHubConnection>,
+    }
+
+    struct EventHubConnection{
+        location: EventHubLocation,
+        session: Session,
+        // Kept so the connection stays open
+        _stream: Connection,
+        _spawner: JoinHandle<Result<(), DispatcherError>>,
     }
+
     impl ServiceClient{
         // Variant of client.
-        pub async fn new(hub_name: &str, cert_location: &str, primairy_key: &str, sas_token: &str, policy: &str) -> ServiceClient {
-            // Create a service client
-            let certificate = read_certificate(cert_location).await;
-            if certificate.is_none(){
-                panic!("Failed to find the certificate. End of program.");
-            }
-            let tls_config_create = create_tls_config(certificate.unwrap());
-            let tls_config_opt = match tls_config_create{
-                Ok(config) => {
-                    Some(config)
-                }
-                Err(exception) => {
-                    panic!("Failed to create the TLS config: {}", exception);
-                    None
+        pub async fn new(hub_name: &str, cert_location: &str, primairy_key: &str, sas_token: &str, policy: &str) -> Result<ServiceClient, IotHubError> {
+            ServiceClient::with_endpoint(Endpoint::azure(hub_name), cert_location, primairy_key, sas_token, policy).await
+        }
 
-                }
-            };
-            let tls_config = tls_config_opt.unwrap();
+        // Service client for any endpoint (other clouds, custom DNS, local broker).
+        pub async fn with_endpoint(endpoint: Endpoint, cert_location: &str, primairy_key: &str, sas_token: &str, policy: &str) -> Result<ServiceClient, IotHubError> {
+            // Create a service client
+            let tls_config = load_tls_config(cert_location).await?;
+            let hub_name = endpoint.hub_name();
             let username = &*format!("{}@sas.root.{}", policy, hub_name);
             let credentials = create_sas_login(&username, &sas_token);
-            let hostname =  create_hostname(&hub_name);
-            let address = create_address(&hub_name);
 
-            ServiceClient{
-                address,
+            Ok(ServiceClient{
+                endpoint,
                 auth: credentials,
                 tls_config,
                 session: None,
-                hostname,
                 device_id: "".to_string(),
-                hub_name: hub_name.to_string(),
+                hub_name,
                 primary_key: primairy_key.to_string(),
+                policy: policy.to_string(),
                 spawner: None,
                 stream: None,
-                recover_links: None
-            }
+                recover_links: None,
+                devicebound: None,
+                feedback: None,
+                ack: AckLevel::Full,
+                event_hub: None
+            })
+
+        }
 
+        // Creates the service client from a shared access policy connection string
+        // (HostName=...;SharedAccessKeyName=...;SharedAccessKey=...).
+        pub async fn from_connection_string(connection_string: &str, cert_location: &str) -> Result<ServiceClient, IotHubError> {
+            let parsed = ConnectionString::parse(connection_string)?;
+            let policy = match parsed.shared_access_key_name.as_ref(){
+                None => {
+                    return Err(ConnectionStringException::NotAServiceConnectionString.into());
+                }
+                Some(policy) => {
+                    policy.clone()
+                }
+            };
+            let endpoint = Endpoint::custom(&parsed.host_name, AMQPS_PORT, Transport::Tls);
+            let token = SasToken::service_token_for_host(&parsed.shared_access_key, chrono::Duration::days(1), &endpoint.host_name, &policy, &SystemClock)
+                .map_err(|_| ConnectionStringException::InvalidKey)?;
+            ServiceClient::with_endpoint(endpoint, cert_location, &parsed.shared_access_key, &token.sas, &policy).await
         }
 
         pub async fn connect(&mut self) -> Result<(), AmqpFailure> {
@@ -96,68 +184,126 @@ pub mod client{
                 // Connection already exists
                 return Err(AlreadyActive);
             }
-            let mut driver = ntex_amqp::client::Connector::new()
-                .connector(RustlsConnector::new(Arc::new(self.tls_config.clone())));
-            driver.hostname(&*self.hostname);
-            let username = self.auth.authn_id.clone();
-            let password_sasl = self.auth.password.clone();
-            let address = self.address.clone();
-            println!("Login:\n{}\n{}", username, password_sasl);
-            let sasl_result = driver.connect_sasl(address.clone(), SaslAuth{
-                authz_id: ByteString::from_static(""),
-                authn_id: username,
-                password: password_sasl,
-            }).await;
-            if sasl_result.is_err(){
-                // Failed to connect to something
-                match sasl_result{
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("{:?}", err);
-                    }
-                }
-                return Err(AmqpFailure::FailedSasl);
-            }
-            let ssl_connected_client = sasl_result.unwrap();
-            let main_amqp_stream = ssl_connected_client.sink();
-            let spawner = ntex::rt::spawn(ssl_connected_client.start_default());
-            let mut session = main_amqp_stream.open_session().await.unwrap();
+            let (stream, spawner, session) = open_connection(
+                Some(&self.auth),
+                &self.endpoint,
+                &self.tls_config,
+                None
+            ).await?;
             self.session = Some(session);
             self.spawner = Some(spawner);
-            self.stream = Some(main_amqp_stream);
+            self.stream = Some(stream);
             Ok(())
         }
-        pub async fn send_simple_message(&mut self, message: TransferBody, device: &str, timeout: u64) -> Result<(), TransferExceptions>{
-            // Send one simple message
-            let timeout = Duration::from_secs(timeout);
-            // Attach the sender
-            let local_session = self.session.as_mut();
-            if local_session.is_none(){
-                return Err(NoSession);
+
+        // Point the service client to another AMQP endpoint (e.g. a local broker).
+        // Takes effect on the next connect, the credentials are kept.
+        pub fn set_endpoint(&mut self, endpoint: Endpoint){
+            self.endpoint = endpoint;
+        }
+
+        pub fn endpoint(&self) -> &Endpoint {
+            &self.endpoint
+        }
+
+        // Invoke a direct method on a device through the REST endpoint of the hub and
+        // return the status and payload the device answered with.
+        // response_timeout is how long the hub waits for the device (5 to 300 seconds).
+        pub async fn invoke_method(&self, device_id: &str, method_name: &str, payload: serde_json::Value, response_timeout: u64) -> Result<MethodResponse, MethodFailure> {
+            self.method_invoker().invoke_method(device_id, method_name, payload, response_timeout).await
+        }
+
+        // What invoke_method needs, without the AMQP connection.
+        fn method_invoker(&self) -> MethodInvoker {
+            MethodInvoker{
+                host_name: self.endpoint.host_name.clone(),
+                tls_config: Arc::new(self.tls_config.clone()),
+                primary_key: self.primary_key.clone(),
+                policy: self.policy.clone()
             }
-            // There is a session
-            let mut local_session = local_session.unwrap();
-            let mut sender = local_session.build_sender_link(
-                "devicebound_link",
-                "/messages/devicebound"
-            ).max_message_size(65535);
-            let task = future::timeout(
-                timeout,
-                async{
-                    sender.open().await
+        }
+
+        // Registry client with the credentials of this service client.
+        pub fn registry(&self) -> RegistryClient {
+            RegistryClient::new(&self.endpoint.host_name, Arc::new(self.tls_config.clone()), &self.primary_key, &self.policy)
+        }
+
+        // Acknowledgement the hub is asked for on every cloud-to-device message (default full).
+        pub fn set_ack_level(&mut self, ack: AckLevel){
+            self.ack = ack;
+        }
+
+        // Send a cloud-to-device message, the To property addresses the device.
+        // Returns the message id, feedback on the message carries it as original_message_id.
+        // The devicebound link stays attached between sends. When it is gone it is attached
+        // again (reconnecting if the session is gone too) and the message is sent once more.
+        pub async fn send_simple_message(&mut self, message: TransferBody, device: &str, timeout: u64) -> Result<String, TransferExceptions>{
+            let (message, message_id) = request_ack(message, self.ack);
+            let result = match self.send_devicebound(message.clone(), timeout).await{
+                Err(err) if ServiceClient::needs_reattach(&err) => {
+                    println!("Send failed ({}), reattaching the devicebound link...", err);
+                    if self.devicebound_link(timeout).await.is_err(){
+                        if let Err(err) = self.reconnect().await{
+                            println!("Reconnect failed: {}", err);
+                            return Err(NoSession);
+                        }
+                    }
+                    self.send_devicebound(message, timeout).await
+                }
+                result => {
+                    result
+                }
+            };
+            let disposition = result?;
+            println!("Message {} for {} disposition: {:?}", message_id, device, disposition.state);
+            Ok(message_id)
+        }
+
+        // Close the session (if any) and connect again with a new service token.
+        // The devicebound and feedback links are attached again on their next use.
+        pub async fn reconnect(&mut self) -> Result<(), IotHubError> {
+            if let Some(session) = self.session.take(){
+                let close_task = future::timeout(
+                    Duration::from_secs(5), async{
+                        session.close().await
+                    }
+                ).await;
+                if close_task.is_err(){
+                    println!("Failed to disconnect: Server did not react!");
                 }
-            );
-            let task_result = task.await;
-            if task_result.is_err(){
-                return Err(TransferExceptions::GeneralTimeout);
-            }
-            let task_unwrap = task_result.unwrap();
-            if task_unwrap.is_err(){
-                return Err(TransferExceptions::LinkAmqpProtocolError);
             }
-            let sender = task_unwrap.unwrap();
+            self.spawner = None;
+            self.stream = None;
+            self.devicebound = None;
+            self.feedback = None;
+            self.event_hub = None;
+            let token = SasToken::service_token_for_host(
+                &self.primary_key,
+                chrono::Duration::days(1),
+                &self.endpoint.host_name,
+                &self.policy,
+                &SystemClock)?;
+            let username = format!("{}@sas.root.{}", self.policy, self.hub_name);
+            self.auth = create_sas_login(&username, &token.sas);
+            self.connect().await?;
+            Ok(())
+        }
+
+        // Failures after which the message did not reach the hub and a new link can help.
+        // A timed out send may have arrived, it is not repeated.
+        fn needs_reattach(error: &TransferExceptions) -> bool {
+            matches!(error,
+                TransferExceptions::NoSession |
+                TransferExceptions::GeneralTimeout |
+                TransferExceptions::LinkDetachedOrDoesNotExist |
+                TransferExceptions::MessageAmqpProtocolError(_) |
+                TransferExceptions::LinkAmqpProtocolError(_))
+        }
+
+        async fn send_devicebound(&mut self, message: TransferBody, timeout: u64) -> Result<Disposition, TransferExceptions> {
+            let sender = self.devicebound_link(timeout).await?;
             let sender_task = future::timeout(
-                timeout,
+                Duration::from_secs(timeout),
                 async{
                     sender.send(message).await
                 }
@@ -166,30 +312,596 @@ pub mod client{
             if sender_task.is_err(){
                 return Err(TransferExceptions::MessageTimeOut);
             }
-            let sender_task_unwrap =  sender_task.unwrap();
-            if sender_task_unwrap.is_err(){
-                // Failed
-                return Err(TransferExceptions::MessageAmqpProtocolError);
+            match sender_task.unwrap(){
+                Ok(disposition) => {
+                    Ok(disposition)
+                }
+                Err(err) => {
+                    // The link may be gone, open a new one on the next send.
+                    self.devicebound = None;
+                    Err(TransferExceptions::MessageAmqpProtocolError(err))
+                }
+            }
+        }
+
+        // Next delivery feedback record, for any message.
+        pub async fn next_feedback(&mut self, timeout: u64) -> Result<FeedbackRecord, FeedbackFailure> {
+            let result = self.feedback_link(timeout).await?.next(timeout).await;
+            self.keep_feedback_link(result)
+        }
+
+        // Wait for the feedback on one message sent with send_simple_message.
+        // The hub batches feedback, it can take a minute before it arrives.
+        pub async fn wait_for_feedback(&mut self, message_id: &str, timeout: u64) -> Result<FeedbackRecord, FeedbackFailure> {
+            let result = self.feedback_link(timeout).await?.next_for(message_id, timeout).await;
+            self.keep_feedback_link(result)
+        }
+
+        // Every feedback record as it arrives, ends when the feedback link closes.
+        pub async fn feedback_stream(&mut self, timeout: u64) -> Result<impl Stream<Item = Result<FeedbackRecord, FeedbackFailure>> + '_, FeedbackFailure> {
+            Ok(self.feedback_link(timeout).await?.stream())
+        }
+
+        // Partition ids of the built-in Event Hub-compatible endpoint (named after the hub).
+        pub async fn partition_ids(&mut self, timeout: u64) -> Result<Vec<String>, EventHubFailure> {
+            if self.event_hub.is_none(){
+                let hub_name = self.hub_name.clone();
+                let session = self.session.as_mut().ok_or(EventHubFailure::NoSession)?;
+                match partition_ids(session, &hub_name, timeout).await{
+                    Err(EventHubFailure::Redirect(target)) => {
+                        self.follow_event_hub_redirect(&target).await?;
+                    }
+                    result => {
+                        return result;
+                    }
+                }
+            }
+            let event_hub = self.event_hub.as_mut().unwrap();
+            let entity = event_hub.location.entity.clone();
+            partition_ids(&mut event_hub.session, &entity, timeout).await
+        }
+
+        // Read one partition of the Event Hub-compatible endpoint from the given position.
+        // When the hub hands the endpoint off to its Event Hubs namespace the attach fails
+        // with an amqp:link:redirect error, the receiver is then opened on that namespace.
+        pub async fn partition_receiver(&mut self, consumer_group: &str, partition_id: &str, position: &EventPosition, timeout: u64) -> Result<PartitionReceiver, EventHubFailure> {
+            if self.event_hub.is_none(){
+                let session = self.session.as_mut().ok_or(EventHubFailure::NoSession)?;
+                match PartitionReceiver::open(session, consumer_group, partition_id, position, timeout).await{
+                    Err(EventHubFailure::Redirect(target)) => {
+                        self.follow_event_hub_redirect(&target).await?;
+                    }
+                    result => {
+                        return result;
+                    }
+                }
+            }
+            let event_hub = self.event_hub.as_mut().unwrap();
+            let address = event_hub.location.partition_address(consumer_group, partition_id);
+            PartitionReceiver::open_at(&mut event_hub.session, &address, consumer_group, partition_id, position, timeout).await
+        }
+
+        // Read all partitions of a consumer group, continuing after the checkpoints in the store.
+        pub async fn event_consumer(&mut self, consumer_group: &str, initial: EventPosition, checkpoints: Option<CheckpointStore>, timeout: u64) -> Result<EventHubConsumer, EventHubFailure> {
+            let partition_ids = self.partition_ids(timeout).await?;
+            let mut partitions = Vec::new();
+            for partition_id in partition_ids{
+                let position = match checkpoints.as_ref(){
+                    None => {
+                        initial.clone()
+                    }
+                    Some(checkpoints) => {
+                        checkpoints.position(consumer_group, &partition_id, &initial)
+                    }
+                };
+                partitions.push(self.partition_receiver(consumer_group, &partition_id, &position, timeout).await?);
             }
-            println!("Message disposition: {:?}", sender_task_unwrap.unwrap());
+            Ok(EventHubConsumer::new(consumer_group, partitions, checkpoints))
+        }
+
+        // Connect to the Event Hubs namespace the hub redirected to, with a service token
+        // for the event hub. The event hub calls use this connection until the next reconnect.
+        async fn follow_event_hub_redirect(&mut self, target: &RedirectTarget) -> Result<(), EventHubFailure> {
+            let location = match EventHubLocation::from_redirect(target){
+                None => {
+                    return Err(EventHubFailure::InvalidRedirect(target.address.clone().unwrap_or_default()));
+                }
+                Some(location) => {
+                    location
+                }
+            };
+            println!("Event hub redirected to {}", location.endpoint.address());
+            let token = SasToken::service_token_for_host(
+                &self.primary_key,
+                chrono::Duration::days(1),
+                &location.audience(),
+                &self.policy,
+                &SystemClock).map_err(EventHubFailure::Token)?;
+            let username = format!("{}@sas.root.{}", self.policy, self.hub_name);
+            let auth = create_sas_login(&username, &token.sas);
+            let (stream, spawner, session) = open_connection(
+                Some(&auth),
+                &location.endpoint,
+                &self.tls_config,
+                None
+            ).await.map_err(EventHubFailure::RedirectFailure)?;
+            self.event_hub = Some(EventHubConnection{
+                location,
+                session,
+                _stream: stream,
+                _spawner: spawner
+            });
             Ok(())
         }
+
+        async fn devicebound_link(&mut self, timeout: u64) -> Result<&SenderLink, TransferExceptions> {
+            if self.devicebound.is_none(){
+                let local_session = match self.session.as_mut(){
+                    None => {
+                        return Err(NoSession);
+                    }
+                    Some(session) => {
+                        session
+                    }
+                };
+                let sender = local_session.build_sender_link(
+                    "devicebound_link",
+                    "/messages/devicebound"
+                ).max_message_size(65535);
+                let task_result = future::timeout(
+                    Duration::from_secs(timeout),
+                    async{
+                        sender.open().await
+                    }
+                ).await;
+                let sender = match task_result{
+                    Err(_) => {
+                        return Err(TransferExceptions::GeneralTimeout);
+                    }
+                    Ok(Err(err)) => {
+                        return Err(TransferExceptions::LinkAmqpProtocolError(err));
+                    }
+                    Ok(Ok(sender)) => {
+                        sender
+                    }
+                };
+                self.devicebound = Some(sender);
+            }
+            Ok(self.devicebound.as_ref().unwrap())
+        }
+
+        // Opens the feedback link on first use.
+        async fn feedback_link(&mut self, timeout: u64) -> Result<&mut FeedbackLink, FeedbackFailure> {
+            if self.feedback.is_none(){
+                let session = match self.session.as_mut(){
+                    None => {
+                        return Err(FeedbackFailure::NoSession);
+                    }
+                    Some(session) => {
+                        session
+                    }
+                };
+                self.feedback = Some(FeedbackLink::open(session, timeout).await?);
+            }
+            Ok(self.feedback.as_mut().unwrap())
+        }
+
+        // Hands the feedback link over (opened if needed), for ServiceClientHandle.
+        async fn take_feedback_link(&mut self, timeout: u64) -> Result<FeedbackLink, FeedbackFailure> {
+            self.feedback_link(timeout).await?;
+            Ok(self.feedback.take().unwrap())
+        }
+
+        // Drop the feedback link when it failed, it is reopened on the next call.
+        fn keep_feedback_link(&mut self, result: Result<FeedbackRecord, FeedbackFailure>) -> Result<FeedbackRecord, FeedbackFailure> {
+            if matches!(result, Err(FeedbackFailure::LinkClosed) | Err(FeedbackFailure::ReceiveFailure(_))){
+                self.feedback = None;
+            }
+            result
+        }
+    }
+
+    // Direct method calls go over HTTPS, they do not need the AMQP connection and run
+    // on the runtime of the caller.
+    #[derive(Clone)]
+    struct MethodInvoker{
+        host_name: String,
+        tls_config: Arc<ClientConfig>,
+        primary_key: String,
+        policy: String,
+    }
+
+    impl MethodInvoker{
+        async fn invoke_method(&self, device_id: &str, method_name: &str, payload: serde_json::Value, response_timeout: u64) -> Result<MethodResponse, MethodFailure> {
+            let invocation = MethodInvocation{
+                method_name: method_name.to_string(),
+                payload,
+                response_timeout_in_seconds: response_timeout,
+                connect_timeout_in_seconds: 0,
+            };
+            let token = SasToken::service_token_for_host(
+                &self.primary_key,
+                chrono::Duration::hours(1),
+                &self.host_name,
+                &self.policy,
+                &SystemClock)
+                .map_err(MethodFailure::Token)?;
+            let body = serde_json::to_vec(&invocation).map_err(MethodFailure::InvalidResponse)?;
+            let request = encode_request(
+                "POST",
+                &self.host_name,
+                &invoke_path(device_id),
+                &[("Authorization", &token.sas), ("Content-Type", "application/json; charset=utf-8")],
+                Some(&body));
+            // The hub answers at the latest when the device timed out, plus some slack for the network.
+            let timeout = Duration::from_secs(response_timeout + 10);
+            let response = https_request(self.tls_config.clone(), &self.host_name, HTTPS_PORT, request, timeout).await?;
+            if !response.is_success(){
+                return Err(MethodFailure::Rejected(response.status, String::from_utf8_lossy(&response.body).to_string()));
+            }
+            serde_json::from_slice(&response.body).map_err(MethodFailure::InvalidResponse)
+        }
+    }
+
+    // ServiceClient on its own thread, for multi-threaded hosts such as Rocket.
+    // The AMQP types are bound to the thread of their ntex runtime, so the handle only
+    // passes commands to that thread. Clone it freely; the thread stops once every
+    // handle is dropped. Feedback waits run next to the other commands and direct
+    // methods skip the thread entirely, so neither holds up a send.
+    #[derive(Clone)]
+    pub struct ServiceClientHandle{
+        commands: mpsc::UnboundedSender<ServiceCommand>,
+        methods: MethodInvoker,
+    }
+
+    enum ServiceCommand{
+        Send{
+            message: TransferBody,
+            device_id: String,
+            timeout: u64,
+            reply: oneshot::Sender<Result<String, TransferExceptions>>,
+        },
+        WaitForFeedback{
+            message_id: String,
+            timeout: u64,
+            reply: oneshot::Sender<Result<FeedbackRecord, FeedbackFailure>>,
+        },
+    }
+
+    impl ServiceClientHandle{
+        // Connects with a shared access policy connection string.
+        pub async fn start(connection_string: &str, cert_location: &str) -> Result<ServiceClientHandle, IotHubError> {
+            ServiceClientHandle::start_with_endpoint(connection_string, cert_location, None).await
+        }
+
+        // Like start, but connects to the given endpoint instead of the host name
+        // of the connection string (e.g. a local broker).
+        pub async fn start_with_endpoint(connection_string: &str, cert_location: &str, endpoint: Option<Endpoint>) -> Result<ServiceClientHandle, IotHubError> {
+            let (commands, receiver) = mpsc::unbounded();
+            let (started, start_result) = oneshot::channel();
+            let connection_string = connection_string.to_string();
+            let cert_location = cert_location.to_string();
+            std::thread::Builder::new()
+                .name("service-client".to_string())
+                .spawn(move || {
+                    ntex::rt::System::new("service-client").block_on(async move {
+                        let mut client = match ServiceClient::from_connection_string(&connection_string, &cert_location).await{
+                            Ok(client) => {
+                                client
+                            }
+                            Err(err) => {
+                                started.send(Err(err)).ok();
+                                return;
+                            }
+                        };
+                        if let Some(endpoint) = endpoint{
+                            client.set_endpoint(endpoint);
+                        }
+                        if let Err(err) = client.connect().await{
+                            started.send(Err(err.into())).ok();
+                            return;
+                        }
+                        started.send(Ok(client.method_invoker())).ok();
+                        run_service_client(client, receiver).await;
+                    })
+                })
+                .map_err(|_| IotHubError::Recovery(NoThreadAvailable))?;
+            match start_result.await{
+                Ok(Ok(methods)) => {
+                    Ok(ServiceClientHandle{
+                        commands,
+                        methods
+                    })
+                }
+                Ok(Err(err)) => {
+                    Err(err)
+                }
+                Err(_) => {
+                    // The thread ended without an answer
+                    Err(IotHubError::Recovery(ClientRedirectRecovery::ThreadJoinError))
+                }
+            }
+        }
+
+        // See ServiceClient::send_simple_message.
+        pub async fn send_message(&self, message: TransferBody, device_id: &str, timeout: u64) -> Result<String, TransferExceptions> {
+            let (reply, result) = oneshot::channel();
+            let command = ServiceCommand::Send{
+                message,
+                device_id: device_id.to_string(),
+                timeout,
+                reply
+            };
+            if self.commands.unbounded_send(command).is_err(){
+                return Err(NoSession);
+            }
+            result.await.unwrap_or(Err(NoSession))
+        }
+
+        // See ServiceClient::wait_for_feedback.
+        pub async fn wait_for_feedback(&self, message_id: &str, timeout: u64) -> Result<FeedbackRecord, FeedbackFailure> {
+            let (reply, result) = oneshot::channel();
+            let command = ServiceCommand::WaitForFeedback{
+                message_id: message_id.to_string(),
+                timeout,
+                reply
+            };
+            if self.commands.unbounded_send(command).is_err(){
+                return Err(FeedbackFailure::NoSession);
+            }
+            result.await.unwrap_or(Err(FeedbackFailure::NoSession))
+        }
+
+        // See ServiceClient::invoke_method. Runs on the runtime of the caller.
+        pub async fn invoke_method(&self, device_id: &str, method_name: &str, payload: serde_json::Value, response_timeout: u64) -> Result<MethodResponse, MethodFailure> {
+            self.methods.invoke_method(device_id, method_name, payload, response_timeout).await
+        }
+    }
+
+    type SharedFeedbackLink = Rc<futures::lock::Mutex<Option<FeedbackLink>>>;
+
+    // Runs the commands of the handles until all of them are dropped.
+    // Feedback waits are spawned and share one feedback link.
+    async fn run_service_client(mut client: ServiceClient, mut commands: mpsc::UnboundedReceiver<ServiceCommand>){
+        let feedback: SharedFeedbackLink = Rc::new(futures::lock::Mutex::new(None));
+        while let Some(command) = futures::StreamExt::next(&mut commands).await{
+            match command{
+                ServiceCommand::Send{ message, device_id, timeout, reply } => {
+                    reply.send(client.send_simple_message(message, &device_id, timeout).await).ok();
+                }
+                ServiceCommand::WaitForFeedback{ message_id, timeout, reply } => {
+                    let mut slot = feedback.lock().await;
+                    if slot.is_none(){
+                        match client.take_feedback_link(timeout).await{
+                            Ok(link) => {
+                                *slot = Some(link);
+                            }
+                            Err(err) => {
+                                reply.send(Err(err)).ok();
+                                continue;
+                            }
+                        }
+                    }
+                    drop(slot);
+                    ntex::rt::spawn(wait_for_shared_feedback(feedback.clone(), message_id, timeout, reply));
+                }
+            }
+        }
+        if let Some(session) = client.session.as_ref(){
+            future::timeout(Duration::from_secs(5), session.close()).await.ok();
+        }
+    }
+
+    // Waits in slices of a second, so concurrent waits take turns on the link and pick
+    // up the records received for them in the meantime.
+    async fn wait_for_shared_feedback(feedback: SharedFeedbackLink, message_id: String, timeout: u64,
+                                      reply: oneshot::Sender<Result<FeedbackRecord, FeedbackFailure>>){
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        let result = loop{
+            let mut slot = feedback.lock().await;
+            let link = match slot.as_mut(){
+                None => {
+                    break Err(FeedbackFailure::LinkClosed);
+                }
+                Some(link) => {
+                    link
+                }
+            };
+            match link.next_for(&message_id, 1).await{
+                Err(FeedbackFailure::Timeout) if Instant::now() < deadline => {}
+                Err(err) => {
+                    if matches!(err, FeedbackFailure::LinkClosed | FeedbackFailure::ReceiveFailure(_)){
+                        // Reopened by the next wait
+                        *slot = None;
+                    }
+                    break Err(err);
+                }
+                Ok(record) => {
+                    break Ok(record);
+                }
+            }
+        };
+        reply.send(result).ok();
+    }
+
+    // Reads the CA certificate and builds the TLS config from it.
+    pub(crate) async fn load_tls_config(cert_location: &str) -> Result<ClientConfig, TlsConfigFailure> {
+        match read_certificate(cert_location).await{
+            None => {
+                Err(TlsConfigFailure::CertificateNotFound)
+            }
+            Some(certificate) => {
+                create_tls_config(certificate)
+            }
+        }
+    }
+
+    // Opens the AMQP connection and the first session.
+    // Plain TCP is only meant for local brokers, IoT Hub itself always requires TLS
+    // (either directly on 5671 or inside a WebSocket on 443).
+    // Without credentials SASL ANONYMOUS is used, the TLS client certificate is the identity.
+    // The events get a Disconnected once the dispatcher of this connection stops.
+    async fn open_connection(auth: Option<&SaslAuth>, endpoint: &Endpoint, tls_config: &ClientConfig, events: Option<&EventBus>)
+        -> Result<(Connection, JoinHandle<Result<(), DispatcherError>>, Session), AmqpFailure> {
+        let address = endpoint.address();
+        let tls_config = Arc::new(tls_config.clone());
+        let (stream, spawner) = match (&endpoint.transport, auth) {
+            (Transport::Tls, Some(auth)) => {
+                let mut driver = ntex_amqp::client::Connector::new()
+                    .connector(RustlsConnector::new(tls_config));
+                driver.hostname(&endpoint.host_name);
+                start_client(driver.connect_sasl(address, copy_credentials(auth)).await, events)?
+            }
+            (Transport::Tls, None) => {
+                let mut driver = ntex_amqp::client::Connector::new()
+                    .connector(SaslAnonymousConnector::new(RustlsConnector::new(tls_config)));
+                driver.hostname(&endpoint.host_name);
+                start_client(driver.connect(address).await, events)?
+            }
+            (Transport::WebSocket, Some(auth)) => {
+                let mut driver = ntex_amqp::client::Connector::new()
+                    .connector(WebSocketConnector::new(tls_config));
+                driver.hostname(&endpoint.host_name);
+                start_client(driver.connect_sasl(address, copy_credentials(auth)).await, events)?
+            }
+            (Transport::WebSocket, None) => {
+                let mut driver = ntex_amqp::client::Connector::new()
+                    .connector(SaslAnonymousConnector::new(WebSocketConnector::new(tls_config)));
+                driver.hostname(&endpoint.host_name);
+                start_client(driver.connect(address).await, events)?
+            }
+            (Transport::Plain, Some(auth)) => {
+                let mut driver = ntex_amqp::client::Connector::new();
+                driver.hostname(&endpoint.host_name);
+                start_client(driver.connect_sasl(address, copy_credentials(auth)).await, events)?
+            }
+            (Transport::Plain, None) => {
+                // A client certificate needs TLS.
+                return Err(AmqpFailure::UnsupportedAuthentication);
+            }
+        };
+        let session = match stream.open_session().await{
+            Ok(session) => {
+                session
+            }
+            Err(err) => {
+                return Err(AmqpFailure::SessionFailure(err));
+            }
+        };
+        Ok((stream, spawner, session))
+    }
+
+    fn copy_credentials(auth: &SaslAuth) -> SaslAuth {
+        // Never print the password, it is a valid SAS token.
+        println!("Login: {}", auth.authn_id);
+        SaslAuth{
+            authz_id: ByteString::from_static(""),
+            authn_id: auth.authn_id.clone(),
+            password: auth.password.clone(),
+        }
+    }
+
+    // Spawns the dispatcher of a freshly connected client.
+    fn start_client<Io>(connect_result: Result<ntex_amqp::client::Client<Io>, ConnectError>, events: Option<&EventBus>)
+        -> Result<(Connection, JoinHandle<Result<(), DispatcherError>>), AmqpFailure>
+        where Io: AsyncRead + AsyncWrite + Unpin + 'static {
+        let connected_client = match connect_result{
+            Ok(client) => {
+                client
+            }
+            Err(err) => {
+                // Failed to connect to something
+                println!("{:?}", err);
+                return Err(AmqpFailure::FailedSasl(err));
+            }
+        };
+        let main_amqp_stream = connected_client.sink();
+        let detach_events = events.cloned();
+        let dispatcher = connected_client
+            .control(move |frame: ControlFrame| {
+                if let Some(events) = detach_events.as_ref(){
+                    report_detach(&frame, events);
+                }
+                async{
+                    Ok::<_, ()>(())
+                }
+            })
+            .start_default();
+        let spawner = match events{
+            None => {
+                ntex::rt::spawn(dispatcher)
+            }
+            Some(events) => {
+                let events = events.clone();
+                ntex::rt::spawn(async move {
+                    let result = dispatcher.await;
+                    let error = result.as_ref().err().map(|err| format!("{:?}", err));
+                    events.emit(ClientEvent::Disconnected{ error });
+                    result
+                })
+            }
+        };
+        Ok((main_amqp_stream, spawner))
+    }
+
+    // Links the hub detached, reported as soon as the dispatcher sees the detach.
+    fn report_detach(frame: &ControlFrame, events: &EventBus){
+        let (name, error) = match frame.kind(){
+            ControlFrameKind::DetachSender(detach, link) => {
+                (link.name().to_string(), detach.error.as_ref())
+            }
+            ControlFrameKind::DetachReceiver(detach, link) => {
+                (link.name().to_string(), detach.error.as_ref())
+            }
+            _ => {
+                return;
+            }
+        };
+        events.emit(detach_event(name, error));
+    }
+
+    // Redirect when the error points to another host, LinkDetached otherwise.
+    fn detach_event(name: String, error: Option<&Error>) -> ClientEvent {
+        let description = error.map(|err| format!("{:?}", err));
+        match error.and_then(redirect_target){
+            Some(target) => {
+                ClientEvent::Redirect{ name, error: description.unwrap_or_default(), target }
+            }
+            None => {
+                ClientEvent::LinkDetached{ name, error: description }
+            }
+        }
     }
 
+    #[derive(Debug)]
     pub enum AmqpFailure
     {
         AlreadyActive,
-        FailedSasl
+        FailedSasl(ConnectError),
+        UnsupportedAuthentication,
+        SessionFailure(AmqpProtocolError),
     }
     impl Display for AmqpFailure{
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            match *self{
+            match self{
                 AmqpFailure::AlreadyActive => write!(f, "AlreadyActive"),
-                AmqpFailure::FailedSasl => write!(f, "FailedSasl")
+                AmqpFailure::FailedSasl(err) => write!(f, "FailedSasl: {}", err),
+                AmqpFailure::UnsupportedAuthentication => write!(f, "UnsupportedAuthentication"),
+                AmqpFailure::SessionFailure(err) => write!(f, "SessionFailure: {}", err)
+            }
+        }
+    }
+    impl std::error::Error for AmqpFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                AmqpFailure::FailedSasl(err) => Some(err),
+                AmqpFailure::SessionFailure(err) => Some(err),
+                _ => None
             }
         }
     }
 
+    #[derive(Debug)]
     pub enum ClientRedirectRecovery
     {
         GeneralFailure,
@@ -197,24 +909,72 @@ pub mod client{
         Timeout,
         ThreadJoinError,
         ServiceDisconnect,
-        AMQPCodecFailure,
-        AMQPIOFailure,
-        AMQPProtocolFailure,
+        AMQPCodecFailure(AmqpCodecError),
+        AMQPIOFailure(std::io::Error),
+        AMQPProtocolFailure(AmqpProtocolError),
         Disconnected,
+        TokenRenewalFailure(SasTokenCreateException),
+        ReconnectFailure(AmqpFailure),
+        CircuitOpen,
     }
 
     impl Display for ClientRedirectRecovery{
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            match *self{
+            match self{
                 ClientRedirectRecovery::GeneralFailure => write!(f, "GeneralFailure"),
                 ClientRedirectRecovery::NoThreadAvailable => write!(f, "NoThreadAvailable"),
                 ClientRedirectRecovery::Timeout => write!(f, "Timeout"),
                 ClientRedirectRecovery::ThreadJoinError => write!(f, "ThreadJoinError"),
                 ClientRedirectRecovery::ServiceDisconnect => write!(f, "ServiceDisconnect"),
-                ClientRedirectRecovery::AMQPCodecFailure => write!(f, "AMQPCodedFailure"),
-                ClientRedirectRecovery::AMQPIOFailure => write!(f, "AMQPIOFailure"),
-                ClientRedirectRecovery::AMQPProtocolFailure => write!(f, "AMQPProtocolFailure"),
+                ClientRedirectRecovery::AMQPCodecFailure(err) => write!(f, "AMQPCodedFailure: {}", err),
+                ClientRedirectRecovery::AMQPIOFailure(err) => write!(f, "AMQPIOFailure: {}", err),
+                ClientRedirectRecovery::AMQPProtocolFailure(err) => write!(f, "AMQPProtocolFailure: {}", err),
                 ClientRedirectRecovery::Disconnected => write!(f, "Disconnected"),
+                ClientRedirectRecovery::TokenRenewalFailure(err) => write!(f, "TokenRenewalFailure: {}", err),
+                ClientRedirectRecovery::ReconnectFailure(err) => write!(f, "ReconnectFailure: {}", err),
+                ClientRedirectRecovery::CircuitOpen => write!(f, "CircuitOpen"),
+            }
+        }
+    }
+    impl std::error::Error for ClientRedirectRecovery{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                ClientRedirectRecovery::AMQPCodecFailure(err) => Some(err),
+                ClientRedirectRecovery::AMQPIOFailure(err) => Some(err),
+                ClientRedirectRecovery::AMQPProtocolFailure(err) => Some(err),
+                ClientRedirectRecovery::TokenRenewalFailure(err) => Some(err),
+                ClientRedirectRecovery::ReconnectFailure(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum GatewayFailure
+    {
+        UnknownDevice,
+        Token(SasTokenCreateException),
+        Cbs(CbsFailure),
+        Link(TransferExceptions),
+    }
+
+    impl Display for GatewayFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                GatewayFailure::UnknownDevice => write!(f, "UnknownDevice"),
+                GatewayFailure::Token(err) => write!(f, "Token: {}", err),
+                GatewayFailure::Cbs(err) => write!(f, "CBS: {}", err),
+                GatewayFailure::Link(err) => write!(f, "Link: {}", err),
+            }
+        }
+    }
+    impl std::error::Error for GatewayFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                GatewayFailure::UnknownDevice => None,
+                GatewayFailure::Token(err) => Some(err),
+                GatewayFailure::Cbs(err) => Some(err),
+                GatewayFailure::Link(err) => Some(err),
             }
         }
     }
@@ -222,92 +982,292 @@ pub mod client{
 
     impl Client{
         // Creates the client, but does not connect it yet
-        pub async fn new(device_id: &str, hub_name: &str, cert_location: &str, primary_key: &str, sas_token: &str) -> Client {
-            let certificate = read_certificate(cert_location).await;
-            if certificate.is_none(){
-                panic!("Failed to find the certificate. End of program.");
-            }
-            let tls_config_create = create_tls_config(certificate.unwrap());
-            let tls_config_opt = match tls_config_create{
-                Ok(config) => {
-                    Some(config)
-                }
-                Err(exception) => {
-                    panic!("Failed to create the TLS config: {}", exception);
-                    None
+        pub async fn new(device_id: &str, hub_name: &str, cert_location: &str, primary_key: &str, sas_token: &str) -> Result<Client, IotHubError> {
+            Client::with_endpoint(device_id, Endpoint::azure(hub_name), cert_location, primary_key, sas_token).await
+        }
 
-                }
-            };
-            let tls_config = tls_config_opt.unwrap();
+        // Client for any endpoint (other clouds, custom DNS, local broker).
+        pub async fn with_endpoint(device_id: &str, endpoint: Endpoint, cert_location: &str, primary_key: &str, sas_token: &str) -> Result<Client, IotHubError> {
+            let tls_config = load_tls_config(cert_location).await?;
             // Create the other params:
+            let hub_name = endpoint.hub_name();
             let username = create_username(&device_id, &hub_name);
             let credentials = create_sas_login(&username, &sas_token);
-            let hostname = create_hostname(&hub_name);
-            let address = create_address(&hub_name);
 
-            Client{
+            Ok(Client{
+                authentication: Authentication::Sas,
                 auth: credentials,
                 tls_config,
-                hostname: hostname.to_string(),
                 device_id: device_id.to_string(),
-                hub_name: hub_name.to_string(),
+                hub_name,
                 primary_key: primary_key.to_string(),
                 session: None,
-                address,
+                endpoint,
                 spawner: None,
                 stream: None,
                 recover_links: None,
                 recv_handles: None,
-                recv_recover_links: None
-            }
+                recv_recover_links: None,
+                recv_link_names: HashMap::new(),
+                receiver_credits: HashMap::new(),
+                twin: None,
+                twin_subscribed: false,
+                methods: None,
+                method_handlers: HashMap::new(),
+                token_expiry: SasToken::expiry_from_sas(sas_token),
+                token_lifetime: chrono::Duration::days(1),
+                renewal_margin: chrono::Duration::minutes(10),
+                clock: Box::new(SystemClock),
+                cbs: None,
+                gateway_devices: HashMap::new(),
+                reconnect_policy: ReconnectPolicy::default(),
+                circuit: CircuitBreaker::default(),
+                reconnect_listener: None,
+                events: EventBus::new(),
+                offline_queue: None,
+                batch_config: None,
+                batches: HashMap::new(),
+                receiver_credit: DEFAULT_RECEIVER_CREDIT,
+            })
         }
 
-        pub async fn recover(&mut self) -> Result<(), ClientRedirectRecovery>{
-            // When an error occurs, try to recover the program.
+        // Creates the client from a device connection string
+        // (HostName=...;DeviceId=...;SharedAccessKey=...), the SAS token is derived from the key.
+        pub async fn from_connection_string(connection_string: &str, cert_location: &str) -> Result<Client, IotHubError> {
+            let parsed = ConnectionString::parse(connection_string)?;
+            let device_id = match parsed.device_id.as_ref(){
+                None => {
+                    return Err(ConnectionStringException::NotADeviceConnectionString.into());
+                }
+                Some(device_id) => {
+                    device_id.clone()
+                }
+            };
+            let endpoint = Endpoint::custom(&parsed.host_name, AMQPS_PORT, Transport::Tls);
+            let token = SasToken::for_host(&parsed.shared_access_key, chrono::Duration::days(1), &endpoint.host_name, &device_id, &SystemClock)
+                .map_err(|_| ConnectionStringException::InvalidKey)?;
+            Client::with_endpoint(&device_id, endpoint, cert_location, &parsed.shared_access_key, &token.sas).await
+        }
+
+        // X.509 device: the hub identifies the device by its client certificate,
+        // there is no SAS token to renew.
+        pub async fn with_x509(device_id: &str, endpoint: Endpoint, cert_location: &str, client_cert_location: &str, client_key_location: &str) -> Result<Client, IotHubError> {
+            let certificate = match read_certificate(cert_location).await{
+                None => {
+                    return Err(TlsConfigFailure::CertificateNotFound.into());
+                }
+                Some(certificate) => {
+                    certificate
+                }
+            };
+            let (client_chain, client_key) = read_client_identity(client_cert_location, client_key_location).await?;
+            let tls_config = create_x509_tls_config(certificate, client_chain, client_key)?;
+            let hub_name = endpoint.hub_name();
+            Ok(Client{
+                authentication: Authentication::X509,
+                auth: create_sas_login("", ""),
+                tls_config,
+                device_id: device_id.to_string(),
+                hub_name,
+                primary_key: "".to_string(),
+                session: None,
+                endpoint,
+                spawner: None,
+                stream: None,
+                recover_links: None,
+                recv_handles: None,
+                recv_recover_links: None,
+                recv_link_names: HashMap::new(),
+                receiver_credits: HashMap::new(),
+                twin: None,
+                twin_subscribed: false,
+                methods: None,
+                method_handlers: HashMap::new(),
+                token_expiry: None,
+                token_lifetime: chrono::Duration::days(1),
+                renewal_margin: chrono::Duration::minutes(10),
+                clock: Box::new(SystemClock),
+                cbs: None,
+                gateway_devices: HashMap::new(),
+                reconnect_policy: ReconnectPolicy::default(),
+                circuit: CircuitBreaker::default(),
+                reconnect_listener: None,
+                events: EventBus::new(),
+                offline_queue: None,
+                batch_config: None,
+                batches: HashMap::new(),
+                receiver_credit: DEFAULT_RECEIVER_CREDIT,
+            })
+        }
+
+        pub fn authentication(&self) -> &Authentication {
+            &self.authentication
+        }
+
+        pub fn device_id(&self) -> &str {
+            &self.device_id
+        }
+
+        pub fn hub_name(&self) -> &str {
+            &self.hub_name
+        }
+
+        pub async fn recover(&mut self) -> Result<(), ClientRedirectRecovery>{
+            // When an error occurs, try to recover the program.
             println!("Attempt recovery");
             println!("Clearing drivers....");
-            let mut disconnection = self.disconnect(5).await;
-            if disconnection.is_err(){
-                // Failed to disconnect
-                panic!("Failed to disconnect: Server did not react!");
-            }
-            let mut disconnection_unwrap = disconnection.unwrap();
-            if disconnection_unwrap.is_err(){
-                panic!("Failed to disconnect: Server returned AMQP protocol error");
+            // The old session is dropped either way, a failed close only gets logged.
+            match self.disconnect(5).await{
+                Err(_) => {
+                    println!("Failed to disconnect: Server did not react!");
+                }
+                Ok(Err(err)) => {
+                    println!("Failed to disconnect: Server returned AMQP protocol error: {}", err);
+                }
+                Ok(Ok(_)) => {}
             }
             // Detach everything
             self.session = None;
             self.spawner = None;
             self.stream = None;
+            self.cbs = None;
+            self.twin = None;
+            self.methods = None;
 
-            let mut new_sas = match SasToken::new(
-                &self.primary_key,
-                1,
-                &self.hub_name,
-                &self.device_id) {
-                Ok(token) => {
-                    Some(token)
-                }
-                Err(_) => {
-                    None
-                }
-            };
+            if self.authentication == Authentication::Sas{
+                let new_sas = match SasToken::for_host(
+                    &self.primary_key,
+                    self.token_lifetime,
+                    &self.endpoint.host_name,
+                    &self.device_id,
+                    self.clock.as_ref()) {
+                    Ok(token) => {
+                        token
+                    }
+                    Err(err) => {
+                        return Err(ClientRedirectRecovery::TokenRenewalFailure(err));
+                    }
+                };
 
-            // Generate new AUTH credentials.
-            self.update_sas_token(&new_sas.unwrap().sas);
+                // Generate new AUTH credentials.
+                self.update_sas_token(&new_sas.sas);
+                self.token_expiry = Some(new_sas.expiry);
+                self.events.emit(ClientEvent::TokenRefreshed{ expiry: new_sas.expiry });
+            }
             println!("Attempting to reconnect...");
             let mut reconnect_result = self.connect().await;
             match reconnect_result{
                 Ok(ok) => {
-                    println!("Reconnected: Ok")
+                    println!("Reconnected: Ok");
+                    if !self.gateway_devices.is_empty(){
+                        // The gateway devices need their tokens before the links are reattached.
+                        if let Err(err) = self.attach_cbs(5).await{
+                            println!("Failed to reopen the $cbs links: {}", err);
+                        }
+                        else if let Err(err) = self.put_gateway_device_tokens(5).await{
+                            println!("Failed to authorize the gateway devices: {}", err);
+                        }
+                    }
                 }
                 Err(err) => {
                     println!("Fail reconnect: {}", err);
+                    return Err(ClientRedirectRecovery::ReconnectFailure(err));
                 }
             }
             Ok(())
         }
 
+        // Backoff, attempt limit and circuit breaker used by reconnect().
+        pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy){
+            self.reconnect_policy = policy;
+        }
+
+        pub fn reconnect_policy(&self) -> &ReconnectPolicy {
+            &self.reconnect_policy
+        }
+
+        pub fn circuit_state(&self) -> CircuitState {
+            self.circuit.state()
+        }
+
+        // Called on every reconnect attempt, success, give up and circuit change.
+        pub fn set_reconnect_listener<F>(&mut self, listener: F)
+            where F: FnMut(&ReconnectEvent) + 'static {
+            self.reconnect_listener = Some(Box::new(listener));
+        }
+
+        fn notify_reconnect(&mut self, event: ReconnectEvent){
+            println!("Reconnect: {:?}", event);
+            if let Some(listener) = self.reconnect_listener.as_mut(){
+                listener(&event);
+            }
+        }
+
+        // Recover the session following the reconnect policy and restore all recorded
+        // sender and receiver links. Gives up after max_attempts or when the circuit opens.
+        pub async fn reconnect(&mut self) -> Result<(), ClientRedirectRecovery>{
+            if !self.circuit.allow(&self.reconnect_policy, Instant::now()){
+                return Err(ClientRedirectRecovery::CircuitOpen);
+            }
+            if self.circuit.state() == CircuitState::HalfOpen{
+                self.notify_reconnect(ReconnectEvent::CircuitChanged(CircuitState::HalfOpen));
+            }
+            let mut attempt = 0;
+            loop{
+                attempt += 1;
+                let delay = if attempt == 1{
+                    // First attempt right away
+                    Duration::from_secs(0)
+                }
+                else {
+                    self.reconnect_policy.next_delay(attempt - 1)
+                };
+                self.notify_reconnect(ReconnectEvent::Reconnecting{ attempt, delay });
+                if delay > Duration::from_secs(0){
+                    async_std::task::sleep(delay).await;
+                }
+                match self.recover().await{
+                    Ok(_) => {
+                        self.reattach_sender_links().await;
+                        self.reattach_receiver_links().await;
+                        if let Some(state) = self.circuit.record_success(){
+                            self.notify_reconnect(ReconnectEvent::CircuitChanged(state));
+                        }
+                        self.notify_reconnect(ReconnectEvent::Reconnected{ attempts: attempt });
+                        if self.queued_messages() > 0{
+                            if let Err(err) = self.flush_offline_queue(10).await{
+                                println!("Replay of the offline queue stopped: {}", err);
+                            }
+                        }
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        self.notify_reconnect(ReconnectEvent::AttemptFailed{ attempt, error: err.to_string() });
+                        if let Some(state) = self.circuit.record_failure(&self.reconnect_policy, Instant::now()){
+                            self.notify_reconnect(ReconnectEvent::CircuitChanged(state));
+                            return Err(ClientRedirectRecovery::CircuitOpen);
+                        }
+                        if self.reconnect_policy.attempts_exhausted(attempt){
+                            self.notify_reconnect(ReconnectEvent::GaveUp{ attempts: attempt });
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Failures that a new session can fix, as long as the link gets restored.
+        // A timed out send may have arrived, it is not repeated.
+        fn needs_reconnect(error: &TransferExceptions) -> bool {
+            matches!(error,
+                TransferExceptions::NoSession |
+                TransferExceptions::LinkDetachedOrDoesNotExist |
+                TransferExceptions::MessageAmqpProtocolError(_) |
+                TransferExceptions::LinkAmqpProtocolError(_))
+        }
+
+        // Waits on the dispatcher for the reason the connection ended.
+        // subscribe_events reports the same as a Disconnected event without blocking.
         pub async fn attempt_get_runtime_exception(&mut self, timeout: u64) -> Result<(), ClientRedirectRecovery>{
             if self.spawner.is_none(){
                 // Unsafe to abort
@@ -341,11 +1301,11 @@ pub mod client{
                         }
                         DispatcherError::Codec(codec) => {
                             println!("Test: Codec error: {:?}", codec);
-                            Err(ClientRedirectRecovery::AMQPCodecFailure)
+                            Err(ClientRedirectRecovery::AMQPCodecFailure(codec))
                         }
                         DispatcherError::Protocol(proto) => {
                             println!("Test: Proto error: {:?}", proto);
-                            Err(ClientRedirectRecovery::AMQPProtocolFailure)
+                            Err(ClientRedirectRecovery::AMQPProtocolFailure(proto))
                         }
                         DispatcherError::Disconnected => {
                             println!("Test: Disconnected");
@@ -353,7 +1313,7 @@ pub mod client{
                         }
                         DispatcherError::Io(io) => {
                             println!("Test: IO error: {:?}", io);
-                            Err(ClientRedirectRecovery::AMQPIOFailure)
+                            Err(ClientRedirectRecovery::AMQPIOFailure(io))
                         }
                     }
                 }
@@ -365,146 +1325,736 @@ pub mod client{
             let mut credentials = create_sas_login(&username, new_sas_token);
             self.auth = credentials;
         }
-        pub async fn disconnect(&mut self, max_timeout: i32) -> Result<Result<(), AmqpProtocolError>, TimeoutError> {
-            if self.session.is_none(){
-                // No session to unwrap!
-                panic!("There is no session to unwrap");
-            }
-            let disconnect_result = future::timeout(
-                Duration::from_secs(max_timeout as u64), async {
-                    self.session.as_ref().unwrap().close().await
+
+        // Lifetime of the generated tokens and how long before expiry they get replaced.
+        pub fn set_token_renewal(&mut self, lifetime: chrono::Duration, renewal_margin: chrono::Duration){
+            self.token_lifetime = lifetime;
+            self.renewal_margin = renewal_margin;
+        }
+
+        // Replace the time source used for tokens, e.g. a FixedClock in tests.
+        pub fn set_clock(&mut self, clock: Box<dyn Clock>){
+            self.clock = clock;
+        }
+
+        pub fn token_expiry(&self) -> Option<DateTime<Utc>> {
+            self.token_expiry
+        }
+
+        pub fn token_needs_renewal(&self) -> bool {
+            match self.token_expiry{
+                None => {
+                    // Unknown expiry: never renew on our own.
+                    false
                 }
-            ).await;
-            return disconnect_result;
+                Some(expiry) => {
+                    self.clock.now() + self.renewal_margin >= expiry
+                }
+            }
         }
 
-        pub async fn send_message(&mut self, sender_link_name: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
-            if self.session.is_none(){
-                // No session available
-                return Err(NoSession);
+        // Renews the token when the renewal margin is reached.
+        // Returns true when a new token was put in place.
+        pub async fn renew_token_if_due(&mut self) -> Result<bool, ClientRedirectRecovery> {
+            if !self.token_needs_renewal(){
+                return Ok(false);
             }
-            // Session available
-            // Check for sender link existence
-            let mut local_session = self.session.as_mut().unwrap();
-            let mut sender_link_local = local_session.get_sender_link(sender_link_name);
-            if sender_link_local.is_none(){
-                return Err(LinkDetachedOrDoesNotExist);
+            self.renew_token().await?;
+            Ok(true)
+        }
+
+        // Generate a fresh token and reconnect the session with it.
+        // Every recorded sender and receiver link is restored afterwards.
+        pub async fn renew_token(&mut self) -> Result<(), ClientRedirectRecovery> {
+            if self.authentication == Authentication::X509{
+                // The certificate does not expire with the session.
+                return Ok(());
             }
-            // Link exists!
-            // Attempt to send a message
-            let timeout = Duration::from_secs(timeout);
-            let timed_send = future::timeout(
-                timeout, async {
-                    sender_link_local.unwrap().send(message).await
+            let new_sas = match SasToken::for_host(
+                &self.primary_key,
+                self.token_lifetime,
+                &self.endpoint.host_name,
+                &self.device_id,
+                self.clock.as_ref()) {
+                Ok(token) => {
+                    token
                 }
-            ).await;
-            if timed_send.is_err(){
-                return Err(MessageTimeOut);
+                Err(err) => {
+                    println!("Failed to renew the SAS token: {}", err);
+                    return Err(ClientRedirectRecovery::TokenRenewalFailure(err));
+                }
+            };
+            self.update_sas_token(&new_sas.sas);
+            self.token_expiry = Some(new_sas.expiry);
+            self.events.emit(ClientEvent::TokenRefreshed{ expiry: new_sas.expiry });
+            if self.session.is_none(){
+                // Not connected: the new credentials are used on the next connect.
+                return Ok(());
             }
-            // No timeout
-            let msg_result = timed_send.unwrap();
-            if msg_result.is_err(){
-                println!("Error on send: {:?}", msg_result);
-                return Err(MessageAmqpProtocolError);
+            if self.cbs.is_some(){
+                // Refresh in place, the session stays up.
+                let audience = device_audience(&self.endpoint.host_name, &self.device_id);
+                let refreshed = match self.put_token(&audience, &new_sas.sas, 10).await{
+                    Ok(_) => {
+                        self.put_gateway_device_tokens(10).await
+                    }
+                    Err(err) => {
+                        Err(GatewayFailure::Cbs(err))
+                    }
+                };
+                match refreshed{
+                    Ok(_) => {
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        println!("CBS token refresh failed, falling back to reconnect: {}", err);
+                    }
+                }
+            }
+            println!("SAS token expires soon, reconnecting with a new token...");
+            let disconnection = self.disconnect(5).await;
+            if disconnection.is_err(){
+                println!("Server did not react on disconnect, dropping the session.");
             }
+            let had_cbs = self.cbs.is_some();
+            self.session = None;
+            self.spawner = None;
+            self.stream = None;
+            self.cbs = None;
+            self.twin = None;
+            self.methods = None;
+            if let Err(err) = self.connect().await{
+                println!("Fail reconnect: {}", err);
+                return Err(ClientRedirectRecovery::ReconnectFailure(err));
+            }
+            if had_cbs || !self.gateway_devices.is_empty(){
+                if let Err(err) = self.attach_cbs(5).await{
+                    println!("Failed to reopen the $cbs links: {}", err);
+                }
+                else if let Err(err) = self.put_gateway_device_tokens(5).await{
+                    println!("Failed to authorize the gateway devices: {}", err);
+                }
+            }
+            self.reattach_sender_links().await;
+            self.reattach_receiver_links().await;
             Ok(())
         }
-        pub fn retrieve_receiver_link(&mut self, handle: Handle) -> Option<&ReceiverLink> {
-            self.session.as_mut().unwrap().get_receiver_link_by_handle(handle)
+
+        // Open the $cbs link pair so tokens can be put without reconnecting.
+        pub async fn attach_cbs(&mut self, timeout: u64) -> Result<(), CbsFailure> {
+            if self.cbs.is_some(){
+                return Ok(());
+            }
+            let local_session = match self.session.as_mut(){
+                None => {
+                    return Err(CbsFailure::NoSession);
+                }
+                Some(session) => {
+                    session
+                }
+            };
+            let cbs = CbsLink::open(local_session, timeout).await?;
+            self.cbs = Some(cbs);
+            Ok(())
         }
 
+        // Authorize an audience on the current connection through the $cbs node.
+        pub async fn put_token(&mut self, audience: &str, token: &str, timeout: u64) -> Result<(), CbsFailure> {
+            match self.cbs.as_mut(){
+                None => {
+                    Err(CbsFailure::NoSession)
+                }
+                Some(cbs) => {
+                    cbs.put_token(audience, token, timeout).await
+                }
+            }
+        }
 
-        pub async fn attach_receiver(&mut self, name: &str, address: &str, timeout: u64) -> Result<(), TransferExceptions>{
-            // Create a receiver link.
-            if self.session.is_none(){
-                return Err(NoSession);
+        // The twin links are opened on first use (and again after a reconnect).
+        async fn twin_link(&mut self, timeout: u64) -> Result<&mut TwinLink, TwinFailure> {
+            if self.twin.is_none(){
+                let local_session = match self.session.as_mut(){
+                    None => {
+                        return Err(TwinFailure::NoSession);
+                    }
+                    Some(session) => {
+                        session
+                    }
+                };
+                let mut twin = TwinLink::open(local_session, &self.device_id, timeout).await?;
+                if self.twin_subscribed{
+                    twin.subscribe_desired(timeout).await?;
+                }
+                self.twin = Some(twin);
             }
-            let mut local_session = self.session.as_mut().unwrap();
+            Ok(self.twin.as_mut().unwrap())
+        }
 
-            // Dont check if the receiver already exists.
-            if self.recv_handles.is_none(){
-                // The handles are empty --> Start creation.
+        // The full twin of the device: desired and reported properties.
+        pub async fn get_twin(&mut self, timeout: u64) -> Result<TwinProperties, TwinFailure> {
+            self.twin_link(timeout).await?.get(timeout).await
+        }
+
+        // Merge the patch into the reported properties, returns the new reported version.
+        pub async fn update_reported_properties(&mut self, patch: &serde_json::Value, timeout: u64) -> Result<Option<i64>, TwinFailure> {
+            self.twin_link(timeout).await?.patch_reported(patch, timeout).await
+        }
+
+        // Ask the hub to push desired property changes, read them with next_desired_update.
+        // The subscription is renewed after a reconnect.
+        pub async fn subscribe_desired_properties(&mut self, timeout: u64) -> Result<(), TwinFailure> {
+            if self.twin_subscribed{
+                // Renewed by twin_link if the links had to be reopened
+                self.twin_link(timeout).await?;
+                return Ok(());
             }
-            else {
-                let handles = self.recv_handles.as_mut().unwrap();
-                let mut handle_index = 65535;
-                for (index, handle) in handles.iter().enumerate() {
-                    if local_session.get_receiver_link_by_handle(handle.to_owned()).is_some() {
-                        handle_index = index;
-                        break;
-                    }
+            self.twin_link(timeout).await?.subscribe_desired(timeout).await?;
+            self.twin_subscribed = true;
+            Ok(())
+        }
+
+        pub async fn unsubscribe_desired_properties(&mut self, timeout: u64) -> Result<(), TwinFailure> {
+            self.twin_subscribed = false;
+            match self.twin.as_mut(){
+                None => {
+                    Ok(())
                 }
-                if handle_index != 65535 {
-                    // Good, no link already created with the same handle.
-                    return Err(TransferExceptions::LinkAlreadyActive);
+                Some(twin) => {
+                    twin.unsubscribe_desired(timeout).await
                 }
             }
+        }
 
-            // Create the handle
-            let mut new_recv_link = local_session.build_receiver_link(
-                name,
-                address,
-            ).max_message_size(65535);
+        // Wait for the next desired property patch, Timeout when none arrived in time.
+        pub async fn next_desired_update(&mut self, timeout: u64) -> Result<DesiredUpdate, TwinFailure> {
+            if !self.twin_subscribed{
+                self.subscribe_desired_properties(timeout).await?;
+            }
+            self.twin_link(timeout).await?.next_desired(timeout).await
+        }
 
-            let timeout = Duration::from_secs(timeout);
-            let create_task = future::timeout(
-              timeout, async
-                    {
-                        new_recv_link.open().await
+        // Register the handler of a direct method, it replaces an earlier one with the same name.
+        // Requests are dispatched by handle_method_request.
+        pub fn register_method<F, Fut>(&mut self, name: &str, mut handler: F)
+            where F: FnMut(serde_json::Value) -> Fut + 'static,
+                  Fut: Future<Output = MethodResponse> + 'static {
+            self.method_handlers.insert(name.to_string(), Box::new(move |payload| -> MethodFuture {
+                Box::pin(handler(payload))
+            }));
+        }
+
+        pub fn unregister_method(&mut self, name: &str){
+            self.method_handlers.remove(name);
+        }
+
+        // Wait for one direct method request and answer it with the registered handler
+        // (404 for unknown methods). Returns the name of the method, None when no request came in.
+        pub async fn handle_method_request(&mut self, timeout: u64) -> Result<Option<String>, MethodFailure> {
+            if self.methods.is_none(){
+                let local_session = match self.session.as_mut(){
+                    None => {
+                        return Err(MethodFailure::NoSession);
                     }
-            ).await;
-            if create_task.is_err(){
-                // Task timed out
-                return Err(TransferExceptions::GeneralTimeout);
+                    Some(session) => {
+                        session
+                    }
+                };
+                self.methods = Some(MethodLink::open(local_session, &self.device_id, timeout).await?);
             }
-            let unwrap_create_task = create_task.unwrap();
-            if unwrap_create_task.is_err(){
-                // The request to create the recv link failed.
-                return Err(TransferExceptions::LinkCreateFailure);
+            let request = match self.methods.as_mut().unwrap().next_request(timeout).await{
+                Err(MethodFailure::Timeout) => {
+                    return Ok(None);
+                }
+                Err(err) => {
+                    // Reopened on the next call
+                    self.methods = None;
+                    return Err(err);
+                }
+                Ok(request) => {
+                    request
+                }
+            };
+            let response = match self.method_handlers.get_mut(&request.name){
+                None => {
+                    println!("No handler for direct method {}", request.name);
+                    MethodResponse::new(METHOD_NOT_FOUND, serde_json::json!({"message": format!("Method '{}' is not registered", request.name)}))
+                }
+                Some(handler) => {
+                    handler(request.payload.clone()).await
+                }
+            };
+            self.methods.as_mut().unwrap().respond(&request, &response, timeout).await?;
+            Ok(Some(request.name))
+        }
+
+        // Gateway mode: authorize another device on this connection through CBS
+        // and attach its telemetry sender link (and optionally its devicebound receiver).
+        // The connection itself stays authenticated as the gateway device.
+        pub async fn add_gateway_device(&mut self, device_id: &str, primary_key: &str, receive: bool, timeout: u64) -> Result<(), GatewayFailure> {
+            if self.gateway_devices.contains_key(device_id){
+                return Err(GatewayFailure::Link(LinkAlreadyActive));
             }
-            let mut get_link = unwrap_create_task.unwrap();
-            get_link.set_link_credit(360);
-            // Add the handle to handle vec.
-            if self.recv_handles.is_none(){
-                // Create the instance
-                self.recv_handles = Some(vec![get_link.handle()])
+            self.attach_cbs(timeout).await.map_err(GatewayFailure::Cbs)?;
+            self.put_device_token(device_id, primary_key, timeout).await?;
+            let sender_link = format!("events_{}", device_id);
+            self.attach_sender(
+                &sender_link,
+                &format!("/devices/{}/messages/events", device_id),
+                timeout
+            ).await.map_err(GatewayFailure::Link)?;
+            let receiver_link = if receive {
+                let receiver_link = format!("devicebound_{}", device_id);
+                self.attach_receiver(
+                    &receiver_link,
+                    &format!("/devices/{}/messages/devicebound", device_id),
+                    timeout
+                ).await.map_err(GatewayFailure::Link)?;
+                Some(receiver_link)
             }
-            else
-            {
-                // Local
-                let mut local_handle_vec = self.recv_handles.as_mut().unwrap();
-                local_handle_vec.push(get_link.handle());
+            else {
+                None
+            };
+            self.gateway_devices.insert(device_id.to_string(), GatewayDevice{
+                primary_key: primary_key.to_string(),
+                sender_link,
+                receiver_link
+            });
+            Ok(())
+        }
+
+        // Stop routing for a device. Its links are closed with the next reconnect at the latest.
+        pub async fn remove_gateway_device(&mut self, device_id: &str) -> Result<(), GatewayFailure> {
+            let device = match self.gateway_devices.remove(device_id){
+                None => {
+                    return Err(GatewayFailure::UnknownDevice);
+                }
+                Some(device) => {
+                    device
+                }
+            };
+            if let Some(links) = self.recover_links.as_mut(){
+                links.retain(|link| link.0 != device.sender_link);
             }
-            // Add the properties of the handle to the array.
-            if self.recv_recover_links.is_none()
-            {
-                self.recv_recover_links = Some(vec![(name.to_string(), address.to_string())]);
+            if let Some(receiver_link) = device.receiver_link{
+                if let Some(links) = self.recv_recover_links.as_mut(){
+                    links.retain(|link| link.0 != receiver_link);
+                }
+                if let Some(handle) = self.recv_link_names.remove(&receiver_link){
+                    if let Some(handles) = self.recv_handles.as_mut(){
+                        handles.retain(|known| *known != handle);
+                    }
+                }
             }
-            else
-            {
-                let local_handle_vec = self.recv_recover_links.as_mut().unwrap();
-                local_handle_vec.push((name.to_string(), address.to_string()))
+            if let Some(session) = self.session.as_mut(){
+                if let Some(link) = session.get_sender_link(&device.sender_link){
+                    let _ = link.close().await;
+                }
             }
+            Ok(())
+        }
+
+        pub fn gateway_device_ids(&self) -> Vec<String> {
+            self.gateway_devices.keys().cloned().collect()
+        }
 
+        // Send telemetry on behalf of a device added with add_gateway_device.
+        pub async fn send_device_message(&mut self, device_id: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
+            let sender_link = match self.gateway_devices.get(device_id){
+                None => {
+                    return Err(LinkDetachedOrDoesNotExist);
+                }
+                Some(device) => {
+                    device.sender_link.clone()
+                }
+            };
+            self.send_message(&sender_link, message, timeout).await
+        }
+
+        async fn put_device_token(&mut self, device_id: &str, primary_key: &str, timeout: u64) -> Result<(), GatewayFailure> {
+            let token = SasToken::for_host(
+                primary_key,
+                self.token_lifetime,
+                &self.endpoint.host_name,
+                device_id,
+                self.clock.as_ref()
+            ).map_err(GatewayFailure::Token)?;
+            let audience = device_audience(&self.endpoint.host_name, device_id);
+            self.put_token(&audience, &token.sas, timeout).await.map_err(GatewayFailure::Cbs)
+        }
+
+        async fn put_gateway_device_tokens(&mut self, timeout: u64) -> Result<(), GatewayFailure> {
+            let devices: Vec<(String, String)> = self.gateway_devices.iter()
+                .map(|(device_id, device)| (device_id.clone(), device.primary_key.clone()))
+                .collect();
+            for (device_id, primary_key) in devices{
+                self.put_device_token(&device_id, &primary_key, timeout).await?;
+            }
             Ok(())
         }
 
-        pub async fn attach_sender(&mut self, name: &str, address: &str, timeout: u64) -> Result<(), TransferExceptions>{
+        pub async fn disconnect(&mut self, max_timeout: i32) -> Result<Result<(), AmqpProtocolError>, TimeoutError> {
             if self.session.is_none(){
-                return Err(NoSession);
+                // Nothing to close
+                return Ok(Ok(()));
             }
-            let mut local_session = self.session.as_mut().unwrap();
-            // Check if the link already exists
-            let mut local_sender_link = local_session.get_sender_link(&name);
-            if local_sender_link.is_some(){
+            let disconnect_result = future::timeout(
+                Duration::from_secs(max_timeout as u64), async {
+                    self.session.as_ref().unwrap().close().await
+                }
+            ).await;
+            return disconnect_result;
+        }
+
+        // With an offline queue enabled, messages that cannot be delivered are stored and
+        // Ok is returned; they are replayed before anything newer once the link is back.
+        pub async fn send_message(&mut self, sender_link_name: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
+            // Stamped before any attempt, a queued message is replayed with the time it was taken.
+            let created_at = Utc::now();
+            let message = if self.offline_queue.is_some(){
+                with_creation_time(message, created_at)
+            }
+            else {
+                message
+            };
+            // Replace the token before the hub starts refusing it.
+            if let Err(err) = self.renew_token_if_due().await{
+                println!("Token renewal failed: {}", err);
+            }
+            if self.queued_messages() > 0{
+                // The new message has to stay behind the queued ones.
+                if let Err(err) = self.flush_offline_queue(timeout).await{
+                    println!("Replay of the offline queue stopped: {}", err);
+                }
+                if self.queued_messages() > 0{
+                    return self.enqueue_message(sender_link_name, message, created_at);
+                }
+            }
+            let result = self.send_with_reconnect(sender_link_name, message.clone(), timeout).await;
+            match result{
+                Err(err) if self.offline_queue.is_some() && Client::needs_reconnect(&err) => {
+                    println!("Send failed ({}), storing the message offline.", err);
+                    self.enqueue_message(sender_link_name, message, created_at)
+                }
+                other => other
+            }
+        }
+
+        async fn send_with_reconnect(&mut self, sender_link_name: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
+            let result = self.send_once(sender_link_name, message.clone(), timeout).await;
+            match result{
+                Err(err) if self.reconnect_policy.automatic && Client::needs_reconnect(&err) && self.is_recorded_sender(sender_link_name) => {
+                    println!("Send failed ({}), reconnecting...", err);
+                    if let Err(recovery) = self.reconnect().await{
+                        println!("Reconnect failed: {}", recovery);
+                        return Err(err);
+                    }
+                    // One more try on the restored link
+                    self.send_once(sender_link_name, message, timeout).await
+                }
+                other => other
+            }
+        }
+
+        // Persist undeliverable telemetry in the given file, it survives restarts.
+        pub fn enable_offline_queue<P: AsRef<Path>>(&mut self, path: P, config: QueueConfig) -> Result<(), QueueException> {
+            let queue = OfflineQueue::open(path, config)?;
+            if !queue.is_empty(){
+                println!("Offline queue holds {} messages from an earlier run.", queue.len());
+            }
+            self.offline_queue = Some(queue);
+            Ok(())
+        }
+
+        pub fn queued_messages(&self) -> usize {
+            self.offline_queue.as_ref().map_or(0, |queue| queue.len())
+        }
+
+        // Stores the message with the time it was created, the hub gets that time
+        // as iothub-creation-time-utc when the message is replayed later.
+        // A message stamped already keeps its time.
+        fn enqueue_message(&mut self, sender_link_name: &str, message: TransferBody, created_at: DateTime<Utc>) -> Result<(), TransferExceptions> {
+            let queue = match self.offline_queue.as_mut(){
+                None => {
+                    return Err(NoSession);
+                }
+                Some(queue) => {
+                    queue
+                }
+            };
+            let message = with_creation_time(message, created_at);
+            match queue.push(sender_link_name, encode_transfer_body(&message), created_at){
+                Ok(evicted) => {
+                    if evicted > 0{
+                        println!("Offline queue full: dropped {} message(s).", evicted);
+                    }
+                    Ok(())
+                }
+                Err(err) => {
+                    Err(TransferExceptions::OfflineQueue(err))
+                }
+            }
+        }
+
+        // Send the queued messages oldest first, stops at the first failure.
+        // Returns how many messages were delivered.
+        pub async fn flush_offline_queue(&mut self, timeout: u64) -> Result<usize, TransferExceptions> {
+            let mut delivered = 0;
+            loop{
+                let next = match self.offline_queue.as_ref().and_then(|queue| queue.peek()){
+                    None => {
+                        return Ok(delivered);
+                    }
+                    Some(next) => {
+                        next.clone()
+                    }
+                };
+                let message = TransferBody::Data(Bytes::from(next.payload));
+                self.send_once(&next.link, message, timeout).await?;
+                delivered += 1;
+                if let Err(err) = self.offline_queue.as_mut().unwrap().pop(){
+                    // Delivered but still in the file: it will be sent again after a restart.
+                    println!("Failed to remove a delivered message from the offline queue: {}", err);
+                    return Err(TransferExceptions::OfflineQueue(err));
+                }
+            }
+        }
+
+        // Collect messages passed to send_batched and send them as batched transfers.
+        // max_bytes is capped so a full batch still fits the limit of the sender links.
+        pub fn enable_batching(&mut self, mut config: BatchConfig){
+            config.max_bytes = config.max_bytes.min(MAX_BATCH_BYTES - BATCH_ENVELOPE);
+            self.batch_config = Some(config);
+        }
+
+        // Adds the message to the batch of the link; the batch is sent once it is full or
+        // its window has passed. The window is only checked on calls to the client, so
+        // call flush_due_batches (or flush_batches) when no more messages are coming.
+        // Without batching enabled the message is sent right away.
+        pub async fn send_batched(&mut self, sender_link_name: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
+            let config = match self.batch_config.as_ref(){
+                None => {
+                    return self.send_message(sender_link_name, message, timeout).await;
+                }
+                Some(config) => {
+                    config.clone()
+                }
+            };
+            // Every message in a batch keeps the time it was taken.
+            let message = with_creation_time(message, Utc::now());
+            let encoded = Bytes::from(encode_transfer_body(&message));
+            let size = encoded.len();
+            let fits = self.batches.get(sender_link_name).map_or(true, |batch| batch.fits(size));
+            if !fits{
+                self.flush_batch(sender_link_name, timeout).await?;
+            }
+            let batch = self.batches
+                .entry(sender_link_name.to_string())
+                .or_insert_with(|| MessageBatch::new(config));
+            if !batch.fits(size){
+                // Too big for any batch, it goes out on its own.
+                return self.send_message(sender_link_name, message, timeout).await;
+            }
+            batch.push(encoded, size, Instant::now());
+            self.flush_due_batches(timeout).await
+        }
+
+        // Sends the open batch of the link, if any.
+        pub async fn flush_batch(&mut self, sender_link_name: &str, timeout: u64) -> Result<(), TransferExceptions> {
+            let messages = match self.batches.get_mut(sender_link_name){
+                None => {
+                    return Ok(());
+                }
+                Some(batch) => {
+                    batch.take()
+                }
+            };
+            if messages.is_empty(){
+                return Ok(());
+            }
+            if self.queued_messages() > 0{
+                // Older messages are waiting, the batch has to stay behind them.
+                if let Err(err) = self.flush_offline_queue(timeout).await{
+                    println!("Replay of the offline queue stopped: {}", err);
+                }
+                if self.queued_messages() > 0{
+                    return self.enqueue_batch(sender_link_name, messages);
+                }
+            }
+            let result = self.send_with_reconnect(sender_link_name, create_batch_message(messages.clone()), timeout).await;
+            match result{
+                Err(err) if self.offline_queue.is_some() && Client::needs_reconnect(&err) => {
+                    println!("Batch send failed ({}), storing {} messages offline.", err, messages.len());
+                    self.enqueue_batch(sender_link_name, messages)
+                }
+                other => other
+            }
+        }
+
+        // Sends the batches that are full or have waited their window.
+        pub async fn flush_due_batches(&mut self, timeout: u64) -> Result<(), TransferExceptions> {
+            let now = Instant::now();
+            let due: Vec<String> = self.batches.iter()
+                .filter(|(_, batch)| batch.is_due(now))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in due{
+                self.flush_batch(&name, timeout).await?;
+            }
+            Ok(())
+        }
+
+        // Sends every open batch, e.g. before shutting down.
+        pub async fn flush_batches(&mut self, timeout: u64) -> Result<(), TransferExceptions> {
+            let names: Vec<String> = self.batches.keys().cloned().collect();
+            for name in names{
+                self.flush_batch(&name, timeout).await?;
+            }
+            Ok(())
+        }
+
+        // The messages of a batch are queued one by one, the queue replays them as single transfers.
+        // They were stamped when they joined the batch.
+        fn enqueue_batch(&mut self, sender_link_name: &str, messages: Vec<Bytes>) -> Result<(), TransferExceptions> {
+            let enqueued_at = Utc::now();
+            for message in messages{
+                self.enqueue_message(sender_link_name, TransferBody::Data(message), enqueued_at)?;
+            }
+            Ok(())
+        }
+
+        fn is_recorded_sender(&self, sender_link_name: &str) -> bool {
+            match self.recover_links.as_ref(){
+                None => false,
+                Some(links) => links.iter().any(|(name, _)| name == sender_link_name)
+            }
+        }
+
+        async fn send_once(&mut self, sender_link_name: &str, message: TransferBody, timeout: u64) -> Result<(), TransferExceptions> {
+            if self.session.is_none(){
+                // No session available
+                return Err(NoSession);
+            }
+            // Session available
+            // Check for sender link existence
+            let mut local_session = self.session.as_mut().unwrap();
+            let mut sender_link_local = local_session.get_sender_link(sender_link_name);
+            if sender_link_local.is_none(){
+                return Err(LinkDetachedOrDoesNotExist);
+            }
+            // Link exists!
+            // Attempt to send a message
+            let timeout = Duration::from_secs(timeout);
+            let timed_send = future::timeout(
+                timeout, async {
+                    sender_link_local.unwrap().send(message).await
+                }
+            ).await;
+            if timed_send.is_err(){
+                return Err(MessageTimeOut);
+            }
+            // No timeout
+            if let Err(err) = timed_send.unwrap(){
+                println!("Error on send: {:?}", err);
+                return Err(MessageAmqpProtocolError(err));
+            }
+            Ok(())
+        }
+
+
+        // Lifecycle events (connect, links, disconnect, redirect, token refresh) from now on.
+        pub fn subscribe_events(&self) -> UnboundedReceiver<ClientEvent> {
+            self.events.subscribe()
+        }
+        pub fn retrieve_receiver_link(&mut self, handle: Handle) -> Option<&ReceiverLink> {
+            self.session.as_mut().unwrap().get_receiver_link_by_handle(handle)
+        }
+
+
+        pub async fn attach_receiver(&mut self, name: &str, address: &str, timeout: u64) -> Result<(), TransferExceptions>{
+            // Create a receiver link.
+            if self.session.is_none(){
+                return Err(NoSession);
+            }
+            let mut local_session = self.session.as_mut().unwrap();
+
+            // Check if a receiver with this name is still attached.
+            if let Some(handle) = self.recv_link_names.get(name){
+                if local_session.get_receiver_link_by_handle(handle.to_owned()).is_some() {
+                    return Err(TransferExceptions::LinkAlreadyActive);
+                }
+            }
+
+            // Create the handle
+            let mut new_recv_link = local_session.build_receiver_link(
+                name,
+                address,
+            ).max_message_size(65535);
+
+            let timeout = Duration::from_secs(timeout);
+            let create_task = future::timeout(
+              timeout, async
+                    {
+                        new_recv_link.open().await
+                    }
+            ).await;
+            if create_task.is_err(){
+                // Task timed out
+                return Err(TransferExceptions::GeneralTimeout);
+            }
+            let mut get_link = match create_task.unwrap(){
+                Ok(link) => {
+                    link
+                }
+                Err(err) => {
+                    // The request to create the recv link failed.
+                    return Err(TransferExceptions::LinkCreateFailure(err));
+                }
+            };
+            get_link.set_link_credit(self.credit_for(name));
+            self.recv_link_names.insert(name.to_string(), get_link.handle());
+            self.events.emit(ClientEvent::LinkAttached{ name: name.to_string(), address: address.to_string() });
+            // Add the handle to handle vec.
+            if self.recv_handles.is_none(){
+                // Create the instance
+                self.recv_handles = Some(vec![get_link.handle()])
+            }
+            else
+            {
+                // Local
+                let mut local_handle_vec = self.recv_handles.as_mut().unwrap();
+                local_handle_vec.push(get_link.handle());
+            }
+            // Add the properties of the handle to the array.
+            if self.recv_recover_links.is_none()
+            {
+                self.recv_recover_links = Some(vec![(name.to_string(), address.to_string())]);
+            }
+            else
+            {
+                let local_handle_vec = self.recv_recover_links.as_mut().unwrap();
+                local_handle_vec.push((name.to_string(), address.to_string()))
+            }
+
+            Ok(())
+        }
+
+        pub async fn attach_sender(&mut self, name: &str, address: &str, timeout: u64) -> Result<(), TransferExceptions>{
+            if self.session.is_none(){
+                return Err(NoSession);
+            }
+            let mut local_session = self.session.as_mut().unwrap();
+            // Check if the link already exists
+            let mut local_sender_link = local_session.get_sender_link(&name);
+            if local_sender_link.is_some(){
                 return Err(LinkAlreadyActive);
             }
             // Passed all checks --> Lets try creating the sender link
             let timeout = Duration::from_secs(timeout);
+            // Room for the largest message (or batch) IoT Hub accepts.
             let sender_link_build = local_session.build_sender_link(
                 name,
                 address
-            ).max_message_size(65535);
+            ).max_message_size(MAX_MESSAGE_BYTES as u64);
 
             let create_link_task = future::timeout(
                 timeout, async{
@@ -517,11 +2067,11 @@ pub mod client{
                 return Err(TransferExceptions::GeneralTimeout)
             }
             // No error
-            let create_result = create_link_task.unwrap();
-            if create_result.is_err(){
-                return Err(LinkAmqpProtocolError);
+            if let Err(err) = create_link_task.unwrap(){
+                return Err(LinkAmqpProtocolError(err));
             }
             // Creation success.
+            self.events.emit(ClientEvent::LinkAttached{ name: name.to_string(), address: address.to_string() });
             if self.recover_links.is_none(){
                 self.recover_links = Some(vec![(name.to_string(), address.to_string())]);
             }
@@ -557,8 +2107,14 @@ pub mod client{
         pub async fn reattach_receiver_links(&mut self){
             // Reset the handles
             self.recv_handles = None;
+            self.recv_link_names.clear();
             // Loop the recv if possible
             let mut links = self.recv_recover_links.as_mut().cloned();
+            if links.is_none(){
+                // No links
+                println!("No receiver links to recover!");
+                return;
+            }
             self.recv_recover_links = None;
             for link in links.unwrap(){
                 self.attach_receiver(
@@ -570,112 +2126,283 @@ pub mod client{
 
         }
 
-        pub async fn receive_message_listener(&mut self, link_index: u32, msg_timeout: u64) -> Result<Transfer, TransferExceptions> {
-            let recv_handles = self.recv_handles.as_ref();
-            let handle= match recv_handles{
-                None => {
-                    // No handles applied
-                    return Err(TransferExceptions::LinkDetachedOrDoesNotExist);
+        // How many unsettled cloud-to-device messages the hub may deliver ahead.
+        // Applies to receiver links attached from now on, and to the others once their
+        // credit is topped up after the next delivery.
+        pub fn set_receiver_credit(&mut self, credit: u32){
+            self.receiver_credit = credit;
+        }
+
+        // Settle a message returned by receive_message_listener. Until it is settled the hub
+        // keeps the message locked and delivers it again once the lock expires.
+        pub fn settle_message(&mut self, transfer: &Transfer, settlement: Settlement) -> Result<(), TransferExceptions> {
+            if self.session.is_none(){
+                return Err(NoSession);
+            }
+            let delivery_id = match transfer.delivery_id{
+                Some(delivery_id) if transfer.settled != Some(true) => {
+                    delivery_id
                 }
-                Some(recv_handles) => {
-                    // Handles found
-                    let mut handle = recv_handles.get(0);
-                    if handle.is_none(){
-                        return Err(TransferExceptions::LinkDetachedOrDoesNotExist);
-                    }
-                    else
-                    {
-                        handle.unwrap()
-                    }
+                _ => {
+                    return Err(TransferExceptions::NotSettleable);
                 }
             };
-
-            let mut link = match self.retrieve_receiver_link(*handle)
-            {
+            let link = match self.retrieve_receiver_link(transfer.handle){
                 None => {
+                    // Detached since: the hub has dropped the lock already and redelivers.
                     return Err(TransferExceptions::LinkDetachedOrDoesNotExist);
                 }
                 Some(link) => {
                     link
                 }
             };
-            let mut timeout = Duration::from_secs(msg_timeout);
-            let mut recv_task = future::timeout(
-                timeout,
-                async{
-                    use futures::StreamExt;
-                    let mut streams = futures::stream::select_all(vec![link.clone()]);
-                    let item = futures::StreamExt::next(&mut streams).await;
-                    return item;
+            link.send_disposition(settlement_disposition(delivery_id, &settlement));
+            Ok(())
+        }
+
+        pub fn complete_message(&mut self, transfer: &Transfer) -> Result<(), TransferExceptions> {
+            self.settle_message(transfer, Settlement::Complete)
+        }
+
+        pub fn abandon_message(&mut self, transfer: &Transfer) -> Result<(), TransferExceptions> {
+            self.settle_message(transfer, Settlement::Abandon)
+        }
+
+        pub fn reject_message(&mut self, transfer: &Transfer, reason: Option<&str>) -> Result<(), TransferExceptions> {
+            self.settle_message(transfer, Settlement::Reject(reason.map(|reason| reason.to_string())))
+        }
+
+        // Credit for one receiver link, kept for reattaches. Applied right away when attached.
+        pub fn set_link_credit(&mut self, name: &str, credit: u32) -> Result<(), TransferExceptions> {
+            self.receiver_credits.insert(name.to_string(), credit);
+            let handle = match self.recv_link_names.get(name){
+                None => {
+                    // Not attached yet, attach_receiver picks it up.
+                    return Ok(());
                 }
-            ).await;
-            return if recv_task.is_err() {
-                Err(TransferExceptions::NoMessage)
+                Some(handle) => {
+                    *handle
+                }
+            };
+            if self.session.is_none(){
+                return Err(NoSession);
             }
-            else
-            {
-                let transfer_option = recv_task.unwrap();
-                if transfer_option.is_some() {
-                    match transfer_option.unwrap() {
-                        Ok(transfer) => {
-                            println!("Received RTransfer content. Reading...");
-                            Ok(transfer)
-                        }
-                        Err(failure) => {
-                            println!("Received an exception while attempting to get RTransfer.");
-                            Err(TransferExceptions::LinkAmqpProtocolError)
-                        }
-                    }
-                } else {
-                    println!("Message contains nothing.");
-                    Err(TransferExceptions::NoMessage)
+            match self.retrieve_receiver_link(handle).cloned(){
+                None => {
+                    Err(TransferExceptions::LinkDetachedOrDoesNotExist)
+                }
+                Some(mut link) => {
+                    link.set_link_credit(credit);
+                    Ok(())
                 }
             }
         }
 
+        // Credit set with set_link_credit, otherwise the client wide receiver credit.
+        fn credit_for(&self, name: &str) -> u32 {
+            self.receiver_credits.get(name).copied().unwrap_or(self.receiver_credit)
+        }
 
-        pub async fn connect(&mut self) -> Result<(), AmqpFailure>{
-            // Start the connection
-            if self.session.is_some() {
-                // Connection already exists
-                return Err(AlreadyActive);
-            }
-            // Create connector
-            let mut driver = ntex_amqp::client::Connector::new()
-                .connector(RustlsConnector::new(Arc::new(self.tls_config.clone())));
-            driver.hostname(&*self.hostname);
-            let username = self.auth.authn_id.clone();
-            let password_sasl = self.auth.password.clone();
-            let address = self.address.clone();
-            println!("Login:\n{}\n{}", username, password_sasl);
-            let sasl_result = driver.connect_sasl(address.clone(), SaslAuth{
-                authz_id: ByteString::from_static(""),
-                authn_id: username,
-                password: password_sasl,
-            }).await;
-            if sasl_result.is_err(){
-                // Failed to connect to something
-                match sasl_result{
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("{:?}", err);
+        // Names of the attached receiver links, in attach order.
+        pub fn receiver_link_names(&self) -> Vec<String> {
+            let handles = match self.recv_handles.as_ref(){
+                None => {
+                    return Vec::new();
+                }
+                Some(handles) => {
+                    handles
+                }
+            };
+            handles.iter()
+                .filter_map(|handle| self.receiver_name(*handle))
+                .collect()
+        }
+
+        fn receiver_name(&self, handle: Handle) -> Option<String> {
+            self.recv_link_names.iter()
+                .find(|(_, known)| **known == handle)
+                .map(|(name, _)| name.clone())
+        }
+
+        // Receive on the receiver link at link_index (attach order).
+        pub async fn receive_message_listener(&mut self, link_index: u32, msg_timeout: u64) -> Result<Transfer, TransferExceptions> {
+            let result = self.receive_once(link_index, msg_timeout).await;
+            match result{
+                Err(err) if self.receive_needs_reconnect(&err) => {
+                    if !self.reconnect_receivers(&err).await{
+                        return Err(err);
                     }
+                    self.receive_once(link_index, msg_timeout).await
                 }
-                return Err(AmqpFailure::FailedSasl);
+                other => other
             }
-            let ssl_connected_client = sasl_result.unwrap();
-            let main_amqp_stream = ssl_connected_client.sink();
-            let spawner = ntex::rt::spawn(ssl_connected_client.start_default());
-            let mut session = main_amqp_stream.open_session().await.unwrap();
-            self.session = Some(session);
-            self.spawner = Some(spawner);
-            self.stream = Some(main_amqp_stream);
-            Ok(())
         }
-    }
-}
 
-pub mod util{
+        // Receive on the receiver link attached under the given name.
+        pub async fn receive_from(&mut self, name: &str, msg_timeout: u64) -> Result<Transfer, TransferExceptions> {
+            let result = self.receive_named(name, msg_timeout).await;
+            match result{
+                Err(err) if self.receive_needs_reconnect(&err) => {
+                    if !self.reconnect_receivers(&err).await{
+                        return Err(err);
+                    }
+                    self.receive_named(name, msg_timeout).await
+                }
+                other => other
+            }
+        }
+
+        // Receive the first message arriving on any attached receiver link,
+        // together with the name of the link it came in on.
+        pub async fn receive_any(&mut self, msg_timeout: u64) -> Result<(String, Transfer), TransferExceptions> {
+            let result = self.receive_all(msg_timeout).await;
+            match result{
+                Err(err) if self.receive_needs_reconnect(&err) => {
+                    if !self.reconnect_receivers(&err).await{
+                        return Err(err);
+                    }
+                    self.receive_all(msg_timeout).await
+                }
+                other => other
+            }
+        }
+
+        fn receive_needs_reconnect(&self, err: &TransferExceptions) -> bool {
+            let has_receivers = self.recv_recover_links.as_ref().map_or(false, |links| !links.is_empty());
+            self.reconnect_policy.automatic && Client::needs_reconnect(err) && has_receivers
+        }
+
+        // Reconnect after a failed receive, true when the receivers are back.
+        async fn reconnect_receivers(&mut self, err: &TransferExceptions) -> bool {
+            println!("Receive failed ({}), reconnecting...", err);
+            if let Err(recovery) = self.reconnect().await{
+                println!("Reconnect failed: {}", recovery);
+                return false;
+            }
+            true
+        }
+
+        async fn receive_once(&mut self, link_index: u32, msg_timeout: u64) -> Result<Transfer, TransferExceptions> {
+            let handle = match self.recv_handles.as_ref().and_then(|handles| handles.get(link_index as usize)){
+                None => {
+                    // No link at this index, a new session will not change that.
+                    return Err(TransferExceptions::UnknownLink);
+                }
+                Some(handle) => {
+                    *handle
+                }
+            };
+            let (_, transfer) = self.receive_on(vec![handle], msg_timeout).await?;
+            Ok(transfer)
+        }
+
+        async fn receive_named(&mut self, name: &str, msg_timeout: u64) -> Result<Transfer, TransferExceptions> {
+            let handle = match self.recv_link_names.get(name){
+                None => {
+                    return Err(TransferExceptions::UnknownLink);
+                }
+                Some(handle) => {
+                    *handle
+                }
+            };
+            let (_, transfer) = self.receive_on(vec![handle], msg_timeout).await?;
+            Ok(transfer)
+        }
+
+        async fn receive_all(&mut self, msg_timeout: u64) -> Result<(String, Transfer), TransferExceptions> {
+            let handles = self.recv_handles.clone().unwrap_or_default();
+            self.receive_on(handles, msg_timeout).await
+        }
+
+        // Wait for the next transfer on any of the given receiver links.
+        async fn receive_on(&mut self, handles: Vec<Handle>, msg_timeout: u64) -> Result<(String, Transfer), TransferExceptions> {
+            if self.session.is_none(){
+                return Err(NoSession);
+            }
+            let mut links = Vec::new();
+            for handle in handles{
+                let name = self.receiver_name(handle).unwrap_or_default();
+                if let Some(link) = self.retrieve_receiver_link(handle).cloned(){
+                    links.push(futures::StreamExt::map(link, move |item| (name.clone(), item)));
+                }
+            }
+            if links.is_empty(){
+                return Err(TransferExceptions::LinkDetachedOrDoesNotExist);
+            }
+            let timeout = Duration::from_secs(msg_timeout);
+            let recv_task = future::timeout(
+                timeout,
+                async{
+                    let mut streams = futures::stream::select_all(links);
+                    futures::StreamExt::next(&mut streams).await
+                }
+            ).await;
+            let received = match recv_task{
+                Err(_) => {
+                    return Err(TransferExceptions::NoMessage);
+                }
+                Ok(received) => {
+                    received
+                }
+            };
+            match received{
+                None => {
+                    println!("Message contains nothing.");
+                    Err(TransferExceptions::NoMessage)
+                }
+                Some((name, Ok(transfer))) => {
+                    println!("Received RTransfer content on {}. Reading...", name);
+                    // Top the credit up again, the hub stops sending once it is used up.
+                    let credit = self.credit_for(&name);
+                    if let Some(mut link) = self.retrieve_receiver_link(transfer.handle).cloned(){
+                        link.set_link_credit(credit);
+                    }
+                    Ok((name, transfer))
+                }
+                Some((name, Err(failure))) => {
+                    println!("Received an exception on {} while attempting to get RTransfer.", name);
+                    Err(TransferExceptions::LinkAmqpProtocolError(failure))
+                }
+            }
+        }
+
+
+        pub async fn connect(&mut self) -> Result<(), AmqpFailure>{
+            // Start the connection
+            if self.session.is_some() {
+                // Connection already exists
+                return Err(AlreadyActive);
+            }
+            let credentials = match self.authentication{
+                Authentication::Sas => Some(&self.auth),
+                Authentication::X509 => None
+            };
+            let (stream, spawner, session) = open_connection(
+                credentials,
+                &self.endpoint,
+                &self.tls_config,
+                Some(&self.events)
+            ).await?;
+            self.session = Some(session);
+            self.spawner = Some(spawner);
+            self.stream = Some(stream);
+            self.events.emit(ClientEvent::Connected{ host_name: self.endpoint.host_name.clone() });
+            Ok(())
+        }
+
+        // Point the client to another AMQP endpoint (e.g. a local broker).
+        // Takes effect on the next connect, new tokens are generated for the new host.
+        pub fn set_endpoint(&mut self, endpoint: Endpoint){
+            self.endpoint = endpoint;
+        }
+
+        pub fn endpoint(&self) -> &Endpoint {
+            &self.endpoint
+        }
+    }
+}
+
+pub mod util{
     pub fn enable_logging_traces(trace: Option<&str>){
         // Allow for optional logging params
         if let Some(trace) = trace{
@@ -692,8 +2419,16 @@ pub mod util{
 
 pub mod transfer{
     use std::fmt::{Display, Formatter};
-    use ntex::util::{Bytes, ByteString};
-    use ntex_amqp::codec::protocol::{Address, Properties};
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use ntex::util::{Bytes, BytesMut, ByteString};
+    use ntex_amqp::codec::{Decode, Encode, Message};
+    use ntex_amqp::codec::protocol::{Accepted, Address, ConnectionError, DeliveryNumber, DeliveryState, Disposition, Error, ErrorCondition, LinkError, MessageId, Properties, Rejected, Released, Role, Transfer, TransferBody};
+    use ntex_amqp::codec::types::{Symbol, Variant};
+    use ntex_amqp::error::AmqpProtocolError;
+    use serde::Serialize;
+    use crate::util::events::RedirectTarget;
+    use crate::util::batch::MAX_BATCH_BYTES;
+    use crate::util::queue::QueueException;
 
     // Create a transfer body from a str
     pub fn create_message_from_str(body: &str) -> ntex_amqp::codec::protocol::TransferBody{
@@ -728,42 +2463,453 @@ pub mod transfer{
         ntex_amqp::codec::protocol::TransferBody::Message(Box::new(content))
     }
 
+    // Properties with every field unset, to fill in where needed.
+    pub fn empty_properties() -> Properties {
+        Properties{
+            message_id: None,
+            user_id: None,
+            to: None,
+            subject: None,
+            reply_to: None,
+            correlation_id: None,
+            content_type: None,
+            content_encoding: None,
+            absolute_expiry_time: None,
+            creation_time: None,
+            group_id: None,
+            group_sequence: None,
+            reply_to_group_id: None
+        }
+    }
+
+    // Read the AMQP message carried by a received transfer.
+    // The hub hands over the encoded message as Data, so it is decoded here.
+    pub fn message_from_transfer(transfer: &Transfer) -> Option<Message> {
+        match transfer.body.as_ref()?{
+            TransferBody::Data(data) => {
+                match Message::decode(data){
+                    Ok((_, message)) => {
+                        Some(message)
+                    }
+                    Err(_) => {
+                        None
+                    }
+                }
+            }
+            TransferBody::Message(message) => {
+                Some(*message.clone())
+            }
+        }
+    }
+
+    // IoT Hub system property for the time the device created the message.
+    pub const CREATION_TIME_ANNOTATION: &str = "iothub-creation-time-utc";
+
+    // Sets iothub-creation-time-utc unless the message already carries one.
+    pub fn stamp_creation_time(message: &mut Message, created_at: DateTime<Utc>){
+        if message.message_annotation(CREATION_TIME_ANNOTATION).is_none(){
+            message.add_message_annotation(
+                CREATION_TIME_ANNOTATION,
+                string_variant(&created_at.to_rfc3339_opts(SecondsFormat::Millis, true))
+            );
+        }
+    }
+
+    // Same for a transfer body; raw data is decoded first and kept as is when it is no message.
+    pub fn with_creation_time(body: TransferBody, created_at: DateTime<Utc>) -> TransferBody {
+        match body{
+            TransferBody::Message(mut message) => {
+                stamp_creation_time(&mut message, created_at);
+                TransferBody::Message(message)
+            }
+            TransferBody::Data(data) => {
+                match Message::decode(&data){
+                    Ok((_, mut message)) => {
+                        stamp_creation_time(&mut message, created_at);
+                        TransferBody::Message(Box::new(message))
+                    }
+                    Err(_) => {
+                        TransferBody::Data(data)
+                    }
+                }
+            }
+        }
+    }
+
+    // The encoded AMQP message of a transfer body, as it goes over the wire.
+    pub fn encode_transfer_body(body: &TransferBody) -> Vec<u8> {
+        match body{
+            TransferBody::Data(data) => {
+                data.to_vec()
+            }
+            TransferBody::Message(message) => {
+                let mut buffer = BytesMut::with_capacity(message.encoded_size());
+                message.encode(&mut buffer);
+                buffer.to_vec()
+            }
+        }
+    }
+
+    // Content type IoT Hub expects on a batch of telemetry messages.
+    pub const BATCH_CONTENT_TYPE: &str = "application/vnd.microsoft.iothub.json";
+    // Microsoft AMQP message format for batched messages, set on the transfer.
+    pub const BATCH_MESSAGE_FORMAT: u32 = 0x8001_3700;
+
+    // One message carrying several encoded messages, each in its own data section.
+    // The hub splits it up again, every message keeps its own properties.
+    pub fn create_batch_message(messages: Vec<Bytes>) -> TransferBody {
+        let mut batch = Message::default();
+        batch.message_format = Some(BATCH_MESSAGE_FORMAT);
+        let mut properties = empty_properties();
+        properties.content_type = Some(BATCH_CONTENT_TYPE.into());
+        batch.properties = Some(properties);
+        batch.body.data = messages;
+        TransferBody::Message(Box::new(batch))
+    }
+
+    // What the device tells the hub about a cloud-to-device message.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Settlement{
+        // Done with it, the hub removes the message (accepted).
+        Complete,
+        // Could not handle it now, the hub delivers it again (released).
+        Abandon,
+        // Will never handle it, the hub dead-letters the message (rejected).
+        Reject(Option<String>),
+    }
+
+    // Error condition sent along with a reject reason.
+    pub const REJECT_CONDITION: &str = "rejected-by-device";
+
+    // The disposition frame settling the delivery with the given outcome.
+    pub fn settlement_disposition(delivery_id: DeliveryNumber, settlement: &Settlement) -> Disposition {
+        let state = match settlement{
+            Settlement::Complete => {
+                DeliveryState::Accepted(Accepted{})
+            }
+            Settlement::Abandon => {
+                DeliveryState::Released(Released{})
+            }
+            Settlement::Reject(reason) => {
+                DeliveryState::Rejected(Rejected{
+                    error: reason.as_ref().map(|reason| Error{
+                        condition: ErrorCondition::Custom(Symbol::from(REJECT_CONDITION)),
+                        description: Some(ByteString::from(reason.as_str())),
+                        info: None
+                    })
+                })
+            }
+        };
+        Disposition{
+            role: Role::Receiver,
+            first: delivery_id,
+            last: None,
+            settled: true,
+            state: Some(state),
+            batchable: false
+        }
+    }
+
+    // IoT Hub rejects larger messages.
+    pub const MAX_MESSAGE_BYTES: usize = MAX_BATCH_BYTES;
+    // IoT Plug and Play component the telemetry belongs to.
+    pub const COMPONENT_NAME_ANNOTATION: &str = "dt-subject";
+    // Content type and encoding that let IoT Hub routing query the body.
+    pub const JSON_CONTENT_TYPE: &str = "application/json";
+    pub const UTF8_CONTENT_ENCODING: &str = "utf-8";
+
+    // Telemetry message with the properties IoT Hub understands.
+    // build() checks the result against the 256 KB limit.
+    pub struct MessageBuilder{
+        body: Vec<u8>,
+        message_id: Option<String>,
+        correlation_id: Option<String>,
+        content_type: Option<String>,
+        content_encoding: Option<String>,
+        to: Option<String>,
+        creation_time: Option<DateTime<Utc>>,
+        component: Option<String>,
+        properties: Vec<(String, String)>,
+    }
+
+    impl MessageBuilder{
+        pub fn new<B: Into<Vec<u8>>>(body: B) -> MessageBuilder {
+            MessageBuilder{
+                body: body.into(),
+                message_id: None,
+                correlation_id: None,
+                content_type: None,
+                content_encoding: None,
+                to: None,
+                creation_time: None,
+                component: None,
+                properties: Vec::new(),
+            }
+        }
+
+        // JSON body with content type and encoding already set for routing queries.
+        pub fn json<T: Serialize>(value: &T) -> Result<MessageBuilder, MessageBuildFailure> {
+            let body = serde_json::to_vec(value)?;
+            Ok(MessageBuilder::new(body)
+                .content_type(JSON_CONTENT_TYPE)
+                .content_encoding(UTF8_CONTENT_ENCODING))
+        }
+
+        pub fn message_id(mut self, message_id: &str) -> Self {
+            self.message_id = Some(message_id.to_string());
+            self
+        }
+
+        pub fn correlation_id(mut self, correlation_id: &str) -> Self {
+            self.correlation_id = Some(correlation_id.to_string());
+            self
+        }
+
+        pub fn content_type(mut self, content_type: &str) -> Self {
+            self.content_type = Some(content_type.to_string());
+            self
+        }
+
+        pub fn content_encoding(mut self, content_encoding: &str) -> Self {
+            self.content_encoding = Some(content_encoding.to_string());
+            self
+        }
+
+        // Redirect to another consumer queue, as create_directed_message does.
+        pub fn to(mut self, target: &str) -> Self {
+            self.to = Some(target.to_string());
+            self
+        }
+
+        // When the reading was taken, sent as creation time and iothub-creation-time-utc.
+        pub fn creation_time(mut self, creation_time: DateTime<Utc>) -> Self {
+            self.creation_time = Some(creation_time);
+            self
+        }
+
+        pub fn component(mut self, component: &str) -> Self {
+            self.component = Some(component.to_string());
+            self
+        }
+
+        // User application property, available to routing queries as $.<name>.
+        pub fn property(mut self, name: &str, value: &str) -> Self {
+            self.properties.push((name.to_string(), value.to_string()));
+            self
+        }
+
+        pub fn build(self) -> Result<TransferBody, MessageBuildFailure> {
+            let mut message = Message::with_body(Bytes::from(self.body));
+            let mut properties = empty_properties();
+            properties.message_id = self.message_id.map(|id| MessageId::String(ByteString::from(id)));
+            properties.correlation_id = self.correlation_id.map(|id| MessageId::String(ByteString::from(id)));
+            properties.content_type = self.content_type.map(Symbol::from);
+            properties.content_encoding = self.content_encoding.map(Symbol::from);
+            properties.to = self.to.map(ByteString::from);
+            properties.creation_time = self.creation_time;
+            message.properties = Some(properties);
+            for (name, value) in self.properties.iter(){
+                if name.is_empty() || name.starts_with("iothub-"){
+                    // iothub- names are reserved for system properties
+                    return Err(MessageBuildFailure::InvalidPropertyName(name.clone()));
+                }
+                message.set_app_property(ByteString::from(name.as_str()), string_variant(value));
+            }
+            if let Some(creation_time) = self.creation_time{
+                stamp_creation_time(&mut message, creation_time);
+            }
+            if let Some(component) = self.component.as_ref(){
+                message.add_message_annotation(COMPONENT_NAME_ANNOTATION, string_variant(component));
+            }
+            let size = message.encoded_size();
+            if size > MAX_MESSAGE_BYTES{
+                return Err(MessageBuildFailure::TooLarge(size));
+            }
+            Ok(TransferBody::Message(Box::new(message)))
+        }
+    }
+
+    // String application property / annotation value
+    pub fn string_variant(value: &str) -> Variant {
+        Variant::String(ByteString::from(value).into())
+    }
+
+    // Read a string application property of a message.
+    pub fn string_app_property(message: &Message, key: &str) -> Option<String> {
+        match message.app_property(key)?{
+            Variant::String(value) => {
+                Some(value.as_str().to_string())
+            }
+            Variant::Symbol(value) => {
+                Some(value.as_str().to_string())
+            }
+            _ => {
+                None
+            }
+        }
+    }
+
+    // Read a numeric application property of a message.
+    pub fn int_app_property(message: &Message, key: &str) -> Option<i64> {
+        int_variant(message.app_property(key)?)
+    }
+
+    // Read a string message annotation.
+    pub fn string_annotation(message: &Message, key: &str) -> Option<String> {
+        match message.message_annotation(key)?{
+            Variant::String(value) => Some(value.as_str().to_string()),
+            Variant::Symbol(value) => Some(value.as_str().to_string()),
+            _ => None
+        }
+    }
+
+    // Read a numeric message annotation (e.g. status and version of twin responses).
+    pub fn int_annotation(message: &Message, key: &str) -> Option<i64> {
+        int_variant(message.message_annotation(key)?)
+    }
+
+    fn int_variant(value: &Variant) -> Option<i64> {
+        match value{
+            Variant::Int(value) => Some(*value as i64),
+            Variant::Uint(value) => Some(*value as i64),
+            Variant::Long(value) => Some(*value),
+            Variant::Ulong(value) => Some(*value as i64),
+            Variant::Short(value) => Some(*value as i64),
+            Variant::Ushort(value) => Some(*value as i64),
+            _ => None
+        }
+    }
+
+    fn string_value(value: &Variant) -> Option<String> {
+        match value{
+            Variant::String(value) => Some(value.as_str().to_string()),
+            Variant::Symbol(value) => Some(value.as_str().to_string()),
+            _ => None
+        }
+    }
+
+    // The AMQP error the peer detached the link or closed the connection with.
+    pub fn remote_error(error: &AmqpProtocolError) -> Option<&Error> {
+        match error{
+            AmqpProtocolError::LinkDetached(Some(err)) => Some(err),
+            AmqpProtocolError::ConnectionClosed(Some(err)) => Some(err),
+            _ => None
+        }
+    }
+
+    // IoT Hub and Event Hubs signal redirects with amqp:link:redirect or amqp:connection:redirect.
+    pub fn is_redirect(condition: &ErrorCondition) -> bool {
+        matches!(condition,
+            ErrorCondition::LinkError(LinkError::Redirect) |
+            ErrorCondition::ConnectionError(ConnectionError::Redirect))
+    }
+
+    // Target of a redirect error, None for every other error.
+    // The info map carries hostname, network-host, port and address.
+    pub fn redirect_target(error: &Error) -> Option<RedirectTarget> {
+        if !is_redirect(&error.condition){
+            return None;
+        }
+        let field = |name: &str| error.info.as_ref()
+            .and_then(|info| info.iter().find(|(key, _)| key.as_str() == name))
+            .map(|(_, value)| value);
+        Some(RedirectTarget{
+            host_name: field("hostname").and_then(string_value),
+            network_host: field("network-host").and_then(string_value),
+            port: field("port").and_then(int_variant).and_then(|port| u16::try_from(port).ok()),
+            address: field("address").and_then(string_value),
+        })
+    }
+
+    #[derive(Debug)]
     pub enum TransferExceptions{
         NoSession,
         LinkDetachedOrDoesNotExist,
         MessageTimeOut,
-        MessageAmqpProtocolError,
+        MessageAmqpProtocolError(AmqpProtocolError),
         LinkAlreadyActive,
         GeneralTimeout,
-        LinkAmqpProtocolError,
-        LinkCreateFailure,
+        LinkAmqpProtocolError(AmqpProtocolError),
+        LinkCreateFailure(AmqpProtocolError),
         NoMessage,
+        // No receiver link at this index or with this name
+        UnknownLink,
+        OfflineQueue(QueueException),
+        // The transfer was settled by the hub already or carries no delivery id.
+        NotSettleable,
     }
     impl Display for TransferExceptions{
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            match *self{
+            match self{
                 TransferExceptions::NoSession => write!(f, "NoSession"),
                 TransferExceptions::LinkDetachedOrDoesNotExist => write!(f, "Link detached or non existent."),
                 TransferExceptions::MessageTimeOut => write!(f, "Message sent timed out."),
-                TransferExceptions::MessageAmqpProtocolError => write!(f, "Message encountered AMQP Exception."),
+                TransferExceptions::MessageAmqpProtocolError(err) => write!(f, "Message encountered AMQP Exception: {}", err),
                 TransferExceptions::LinkAlreadyActive => write!(f, "Link is already active"),
                 TransferExceptions::GeneralTimeout => write!(f,"General timeout on operation."),
-                TransferExceptions::LinkAmqpProtocolError => write!(f, "An AMQP error occurred on an link operation."),
-                TransferExceptions::LinkCreateFailure => write!(f, "Failed to create the link."),
+                TransferExceptions::LinkAmqpProtocolError(err) => write!(f, "An AMQP error occurred on an link operation: {}", err),
+                TransferExceptions::LinkCreateFailure(err) => write!(f, "Failed to create the link: {}", err),
                 TransferExceptions::NoMessage => write!(f, "No message"),
+                TransferExceptions::UnknownLink => write!(f, "No receiver link with this index or name."),
+                TransferExceptions::OfflineQueue(err) => write!(f, "Offline queue: {}", err),
+                TransferExceptions::NotSettleable => write!(f, "The message cannot be settled (pre-settled or without delivery id)."),
+            }
+        }
+    }
+    impl std::error::Error for TransferExceptions{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                TransferExceptions::MessageAmqpProtocolError(err) => Some(err),
+                TransferExceptions::LinkAmqpProtocolError(err) => Some(err),
+                TransferExceptions::LinkCreateFailure(err) => Some(err),
+                TransferExceptions::OfflineQueue(err) => Some(err),
+                _ => None
             }
         }
     }
+
+    #[derive(Debug)]
+    pub enum MessageBuildFailure{
+        // Encoded size in bytes
+        TooLarge(usize),
+        InvalidPropertyName(String),
+        Serialization(serde_json::Error),
+    }
+    impl Display for MessageBuildFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                MessageBuildFailure::TooLarge(size) => write!(f, "The message is {} bytes, IoT Hub accepts at most {}.", size, MAX_MESSAGE_BYTES),
+                MessageBuildFailure::InvalidPropertyName(name) => write!(f, "Invalid application property name: '{}'", name),
+                MessageBuildFailure::Serialization(err) => write!(f, "Failed to serialize the body: {}", err),
+            }
+        }
+    }
+    impl std::error::Error for MessageBuildFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                MessageBuildFailure::Serialization(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+    impl From<serde_json::Error> for MessageBuildFailure{
+        fn from(err: serde_json::Error) -> Self {
+            MessageBuildFailure::Serialization(err)
+        }
+    }
 }
 
 pub mod config{
     use std::fmt::{Display, Formatter};
+    use crate::util::token::DEFAULT_HOST_SUFFIX;
+    use crate::transport::websocket::WEBSOCKET_PORT;
     // Required imports
     use std::io::Cursor;
     use std::path::Path;
     use ntex::util::ByteString;
     use ntex_amqp::client::SaslAuth;
-    use rustls::ClientConfig;
+    use rustls::{Certificate, ClientConfig, PrivateKey, TLSError};
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 
     pub async fn read_certificate(certificate_path: &str) -> Option<Cursor<Vec<u8>>>{
         let path = Path::new(certificate_path);
@@ -799,16 +2945,71 @@ pub mod config{
         }
         return Ok(tls_config);
     }
+
+    // Client certificate chain and private key of an X.509 device (PEM).
+    // The key may be PKCS#8 or PKCS#1 (RSA).
+    pub async fn read_client_identity(certificate_path: &str, key_path: &str) -> Result<(Vec<Certificate>, PrivateKey), TlsConfigFailure>{
+        let certificate = read_certificate(certificate_path).await;
+        let key = read_certificate(key_path).await;
+        if certificate.is_none() || key.is_none(){
+            return Err(TlsConfigFailure::CertificateNotFound);
+        }
+        let chain = match certs(&mut certificate.unwrap()){
+            Ok(chain) if !chain.is_empty() => {
+                chain
+            }
+            _ => {
+                return Err(TlsConfigFailure::InvalidClientCertificate);
+            }
+        };
+        let key = key.unwrap();
+        let mut keys = pkcs8_private_keys(&mut key.clone()).unwrap_or_default();
+        if keys.is_empty(){
+            keys = rsa_private_keys(&mut key.clone()).unwrap_or_default();
+        }
+        if keys.is_empty(){
+            return Err(TlsConfigFailure::InvalidPrivateKey);
+        }
+        Ok((chain, keys.remove(0)))
+    }
+
+    // TLS config that also presents the device certificate to the hub.
+    pub fn create_x509_tls_config(certificate_vector: Cursor<Vec<u8>>, client_chain: Vec<Certificate>, client_key: PrivateKey) -> Result<ClientConfig, TlsConfigFailure> {
+        let mut tls_config = create_tls_config(certificate_vector)?;
+        if let Err(err) = tls_config.set_single_client_cert(client_chain, client_key){
+            // The key does not match the certificate or is not supported.
+            return Err(TlsConfigFailure::ClientCertRejected(err));
+        }
+        Ok(tls_config)
+    }
+
+    #[derive(Debug)]
     pub enum TlsConfigFailure
     {
         CertAddToRootStoreFailure,
-        NoValidCerts
+        NoValidCerts,
+        CertificateNotFound,
+        InvalidClientCertificate,
+        InvalidPrivateKey,
+        ClientCertRejected(TLSError),
     }
     impl Display for TlsConfigFailure{
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            match *self{
+            match self{
                 TlsConfigFailure::CertAddToRootStoreFailure => write!(f, "Failed to add the cert to the root store."),
-                TlsConfigFailure::NoValidCerts => write!(f, "The applied certificate is invalid.")
+                TlsConfigFailure::NoValidCerts => write!(f, "The applied certificate is invalid."),
+                TlsConfigFailure::CertificateNotFound => write!(f, "Did not find the certificate or key file."),
+                TlsConfigFailure::InvalidClientCertificate => write!(f, "The client certificate chain is invalid."),
+                TlsConfigFailure::InvalidPrivateKey => write!(f, "The client private key is invalid."),
+                TlsConfigFailure::ClientCertRejected(err) => write!(f, "The client certificate was rejected: {}", err)
+            }
+        }
+    }
+    impl std::error::Error for TlsConfigFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                TlsConfigFailure::ClientCertRejected(err) => Some(err),
+                _ => None
             }
         }
     }
@@ -817,10 +3018,1761 @@ pub mod config{
     }
 
     pub fn create_address(hub_name: &str) -> String{
-        format!("{}.azure-devices.net:5671", hub_name)
+        Endpoint::azure(hub_name).address()
     }
     pub fn create_hostname(hub_name: &str) -> String{
-        format!("{}.azure-devices.net", hub_name)
+        Endpoint::azure(hub_name).host_name
+    }
+
+    pub const AMQPS_PORT: u16 = 5671;
+    pub const AMQP_PORT: u16 = 5672;
+
+    // How the AMQP connection is carried.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Transport{
+        Tls,
+        // Unencrypted TCP, only for local test brokers.
+        Plain,
+        // AMQP tunneled through a TLS WebSocket, for networks that block 5671.
+        WebSocket,
+    }
+
+    // How a device proves its identity.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Authentication{
+        // SASL PLAIN with a SAS token as password.
+        Sas,
+        // TLS client certificate, SASL ANONYMOUS.
+        X509,
     }
 
-}
\ No newline at end of file
+    // Where a Client or ServiceClient connects to.
+    // The host name is also the one that ends up in the SAS token resource.
+    #[derive(Clone, Debug)]
+    pub struct Endpoint{
+        pub host_name: String,
+        pub port: u16,
+        pub transport: Transport,
+    }
+
+    impl Endpoint{
+        // {hub_name}.azure-devices.net:5671 over TLS
+        pub fn azure(hub_name: &str) -> Endpoint {
+            Endpoint::with_suffix(hub_name, DEFAULT_HOST_SUFFIX)
+        }
+
+        // Sovereign clouds, e.g. "azure-devices.cn" or "azure-devices.us"
+        pub fn with_suffix(hub_name: &str, host_suffix: &str) -> Endpoint {
+            Endpoint{
+                host_name: format!("{}.{}", hub_name, host_suffix.trim_start_matches('.')),
+                port: AMQPS_PORT,
+                transport: Transport::Tls
+            }
+        }
+
+        // wss://{hub_name}.azure-devices.net:443/$iothub/websocket
+        pub fn websocket(hub_name: &str) -> Endpoint {
+            Endpoint{
+                port: WEBSOCKET_PORT,
+                transport: Transport::WebSocket,
+                ..Endpoint::azure(hub_name)
+            }
+        }
+
+        // Any host, e.g. custom DNS in front of the hub or a local broker.
+        pub fn custom(host_name: &str, port: u16, transport: Transport) -> Endpoint {
+            Endpoint{
+                host_name: host_name.to_string(),
+                port,
+                transport
+            }
+        }
+
+        pub fn address(&self) -> String {
+            format!("{}:{}", self.host_name, self.port)
+        }
+
+        // First label of the host name, used in the SASL usernames.
+        pub fn hub_name(&self) -> String {
+            self.host_name.split('.').next().unwrap_or_default().to_string()
+        }
+    }
+
+}
+
+pub mod cbs{
+    // Claims based security: authorize a connection by putting tokens on the $cbs node.
+    // One connection can hold the tokens of several devices and refresh them in place.
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+    use async_std::future;
+    use ntex::util::ByteString;
+    use ntex_amqp::codec::Message;
+    use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+    use ntex_amqp::{ReceiverLink, SenderLink, Session};
+    use ntex_amqp::error::AmqpProtocolError;
+    use crate::amqp::transfer::{empty_properties, int_app_property, message_from_transfer, string_app_property, string_variant};
+
+    pub const CBS_ADDRESS: &str = "$cbs";
+    pub const CBS_REPLY_TO: &str = "cbs";
+    pub const SAS_TOKEN_TYPE: &str = "servicebus.windows.net:sastoken";
+
+    pub struct CbsLink{
+        sender: SenderLink,
+        receiver: ReceiverLink,
+        next_request_id: u64,
+    }
+
+    impl CbsLink{
+        // Open the $cbs sender/receiver link pair on an existing session.
+        pub async fn open(session: &mut Session, timeout: u64) -> Result<CbsLink, CbsFailure> {
+            let timeout = Duration::from_secs(timeout);
+            let sender_task = future::timeout(
+                timeout, async{
+                    session.build_sender_link("cbs_sender", CBS_ADDRESS).open().await
+                }
+            ).await;
+            let sender = match sender_task{
+                Err(_) => {
+                    return Err(CbsFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(CbsFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(sender)) => {
+                    sender
+                }
+            };
+            let receiver_task = future::timeout(
+                timeout, async{
+                    session.build_receiver_link("cbs_receiver", CBS_ADDRESS).open().await
+                }
+            ).await;
+            let receiver = match receiver_task{
+                Err(_) => {
+                    return Err(CbsFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(CbsFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(receiver)) => {
+                    receiver
+                }
+            };
+            receiver.set_link_credit(10);
+            Ok(CbsLink{
+                sender,
+                receiver,
+                next_request_id: 0
+            })
+        }
+
+        // Put a SAS token for the given audience (e.g. "{host}/devices/{device_id}")
+        // and wait for the matching status response.
+        pub async fn put_token(&mut self, audience: &str, token: &str, timeout: u64) -> Result<(), CbsFailure> {
+            self.next_request_id += 1;
+            let request_id = format!("cbs-put-token-{}", self.next_request_id);
+            let request = create_put_token_message(audience, token, &request_id);
+            let timeout = Duration::from_secs(timeout);
+            let send_task = future::timeout(
+                timeout, async{
+                    self.sender.send(request).await
+                }
+            ).await;
+            match send_task{
+                Err(_) => {
+                    return Err(CbsFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(CbsFailure::SendFailure(err));
+                }
+                Ok(Ok(_)) => {}
+            }
+            // Responses can belong to older requests that timed out: skip those.
+            let receiver = &mut self.receiver;
+            let response_task = future::timeout(
+                timeout, async{
+                    use futures::StreamExt;
+                    loop{
+                        let transfer = match receiver.next().await{
+                            None => {
+                                return Err(CbsFailure::InvalidResponse);
+                            }
+                            Some(Err(_)) => {
+                                return Err(CbsFailure::InvalidResponse);
+                            }
+                            Some(Ok(transfer)) => {
+                                transfer
+                            }
+                        };
+                        let message = match message_from_transfer(&transfer){
+                            None => {
+                                continue;
+                            }
+                            Some(message) => {
+                                message
+                            }
+                        };
+                        if let Some(status) = read_put_token_status(&message, &request_id){
+                            return Ok(status);
+                        }
+                    }
+                }
+            ).await;
+            let (status_code, description) = match response_task{
+                Err(_) => {
+                    return Err(CbsFailure::Timeout);
+                }
+                Ok(response) => {
+                    response?
+                }
+            };
+            self.receiver.set_link_credit(10);
+            if status_code == 200 || status_code == 202{
+                Ok(())
+            }
+            else {
+                Err(CbsFailure::Rejected(status_code, description))
+            }
+        }
+    }
+
+    // The audience a device token grants access to.
+    pub fn device_audience(hostname: &str, device_id: &str) -> String {
+        format!("{}/devices/{}", hostname, device_id)
+    }
+
+    pub fn create_put_token_message(audience: &str, token: &str, request_id: &str) -> TransferBody {
+        let mut content = Message::default();
+        content.body.value = Some(string_variant(token));
+        let mut props = empty_properties();
+        props.message_id = Some(MessageId::String(ByteString::from(request_id)));
+        props.to = Some(ByteString::from_static(CBS_ADDRESS));
+        props.reply_to = Some(ByteString::from_static(CBS_REPLY_TO));
+        content.properties = Some(props);
+        content.set_app_property(ByteString::from_static("operation"), string_variant("put-token"));
+        content.set_app_property(ByteString::from_static("type"), string_variant(SAS_TOKEN_TYPE));
+        content.set_app_property(ByteString::from_static("name"), string_variant(audience));
+        TransferBody::Message(Box::new(content))
+    }
+
+    // Status code and description of the response to request_id, None for other messages.
+    pub fn read_put_token_status(message: &Message, request_id: &str) -> Option<(i64, String)> {
+        let correlation_id = match message.properties.as_ref()?.correlation_id.as_ref()?{
+            MessageId::String(id) => {
+                id.to_string()
+            }
+            _ => {
+                return None;
+            }
+        };
+        if correlation_id != request_id{
+            return None;
+        }
+        let status_code = int_app_property(message, "status-code")?;
+        let description = string_app_property(message, "status-description").unwrap_or_default();
+        Some((status_code, description))
+    }
+
+    #[derive(Debug)]
+    pub enum CbsFailure{
+        NoSession,
+        Timeout,
+        LinkCreateFailure(AmqpProtocolError),
+        SendFailure(AmqpProtocolError),
+        InvalidResponse,
+        Rejected(i64, String),
+    }
+
+    impl Display for CbsFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                CbsFailure::NoSession => write!(f, "NoSession"),
+                CbsFailure::Timeout => write!(f, "CBS request timed out."),
+                CbsFailure::LinkCreateFailure(err) => write!(f, "Failed to create the $cbs links: {}", err),
+                CbsFailure::SendFailure(err) => write!(f, "Failed to send the put-token request: {}", err),
+                CbsFailure::InvalidResponse => write!(f, "Invalid response on the $cbs link."),
+                CbsFailure::Rejected(code, description) => write!(f, "Token rejected ({}): {}", code, description),
+            }
+        }
+    }
+    impl std::error::Error for CbsFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                CbsFailure::LinkCreateFailure(err) => Some(err),
+                CbsFailure::SendFailure(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+}
+pub mod twin{
+    // Device twin over AMQP: a sender/receiver link pair on /devices/{id}/twin.
+    // Requests carry an operation annotation and a correlation id, the hub answers on the
+    // receiver with a status annotation. Desired property updates arrive on the same
+    // receiver without correlation id once the device has subscribed to them.
+    use std::collections::VecDeque;
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+    use async_std::future;
+    use ntex::util::{Bytes, ByteString};
+    use ntex_amqp::codec::Message;
+    use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+    use ntex_amqp::codec::types::{Symbol, Variant};
+    use ntex_amqp::{ReceiverLink, SenderLink, Session};
+    use ntex_amqp::error::AmqpProtocolError;
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use crate::amqp::transfer::{empty_properties, int_annotation, message_from_transfer, string_variant};
+
+    pub const CHANNEL_CORRELATION_PROPERTY: &str = "com.microsoft:channel-correlation-id";
+    pub const API_VERSION_PROPERTY: &str = "com.microsoft:api-version";
+    pub const TWIN_API_VERSION: &str = "2020-09-30";
+    pub const OPERATION_ANNOTATION: &str = "operation";
+    pub const RESOURCE_ANNOTATION: &str = "resource";
+    pub const STATUS_ANNOTATION: &str = "status";
+    pub const VERSION_ANNOTATION: &str = "version";
+    pub const REPORTED_RESOURCE: &str = "/properties/reported";
+    pub const DESIRED_NOTIFICATIONS_RESOURCE: &str = "/notifications/twin/properties/desired";
+
+    pub fn twin_address(device_id: &str) -> String {
+        format!("/devices/{}/twin", device_id)
+    }
+
+    // The full twin as returned by a GET: desired and reported properties,
+    // both including their $version.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct TwinProperties{
+        #[serde(default)]
+        pub desired: Value,
+        #[serde(default)]
+        pub reported: Value,
+    }
+
+    impl TwinProperties{
+        pub fn desired_version(&self) -> Option<i64> {
+            properties_version(&self.desired)
+        }
+
+        pub fn reported_version(&self) -> Option<i64> {
+            properties_version(&self.reported)
+        }
+    }
+
+    // A desired property patch pushed by the hub.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct DesiredUpdate{
+        pub version: Option<i64>,
+        // Changed properties, null values mean the property was removed
+        pub patch: Value,
+    }
+
+    impl DesiredUpdate{
+        pub fn from_patch(patch: Value) -> DesiredUpdate {
+            DesiredUpdate{
+                version: properties_version(&patch),
+                patch
+            }
+        }
+    }
+
+    pub fn properties_version(properties: &Value) -> Option<i64> {
+        properties.get("$version")?.as_i64()
+    }
+
+    pub struct TwinLink{
+        sender: SenderLink,
+        receiver: ReceiverLink,
+        next_request_id: u64,
+        // Desired updates that came in while waiting for a response
+        pending_updates: VecDeque<DesiredUpdate>,
+    }
+
+    impl TwinLink{
+        // Open the twin link pair of the device on an existing session.
+        pub async fn open(session: &mut Session, device_id: &str, timeout: u64) -> Result<TwinLink, TwinFailure> {
+            let address = twin_address(device_id);
+            // Both links share one channel correlation id, that is how the hub pairs them.
+            let channel = format!("twin:{:016x}", rand::thread_rng().gen::<u64>());
+            let timeout = Duration::from_secs(timeout);
+            let sender_task = future::timeout(
+                timeout, async{
+                    session.build_sender_link(&format!("twin_sender_{}", device_id), &address)
+                        .with_frame(|frame| {
+                            frame.properties = Some(link_properties(&channel));
+                        })
+                        .open().await
+                }
+            ).await;
+            let sender = match sender_task{
+                Err(_) => {
+                    return Err(TwinFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(TwinFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(sender)) => {
+                    sender
+                }
+            };
+            let receiver_task = future::timeout(
+                timeout, async{
+                    session.build_receiver_link(&format!("twin_receiver_{}", device_id), &address)
+                        .with_frame(|frame| {
+                            frame.properties = Some(link_properties(&channel));
+                        })
+                        .open().await
+                }
+            ).await;
+            let receiver = match receiver_task{
+                Err(_) => {
+                    return Err(TwinFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(TwinFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(receiver)) => {
+                    receiver
+                }
+            };
+            receiver.set_link_credit(10);
+            Ok(TwinLink{
+                sender,
+                receiver,
+                next_request_id: 0,
+                pending_updates: VecDeque::new()
+            })
+        }
+
+        pub async fn get(&mut self, timeout: u64) -> Result<TwinProperties, TwinFailure> {
+            let response = self.request("GET", None, None, timeout).await?;
+            expect_status(&response, &[200])?;
+            let body = response_body(&response).unwrap_or_default();
+            serde_json::from_slice(&body).map_err(TwinFailure::InvalidBody)
+        }
+
+        // Patch the reported properties, returns the new reported version if the hub sends it.
+        pub async fn patch_reported(&mut self, patch: &Value, timeout: u64) -> Result<Option<i64>, TwinFailure> {
+            let body = serde_json::to_vec(patch).map_err(TwinFailure::InvalidBody)?;
+            let response = self.request("PATCH", Some(REPORTED_RESOURCE), Some(body), timeout).await?;
+            expect_status(&response, &[200, 202, 204])?;
+            Ok(int_annotation(&response, VERSION_ANNOTATION))
+        }
+
+        pub async fn subscribe_desired(&mut self, timeout: u64) -> Result<(), TwinFailure> {
+            let response = self.request("PUT", Some(DESIRED_NOTIFICATIONS_RESOURCE), None, timeout).await?;
+            expect_status(&response, &[200, 202, 204])
+        }
+
+        pub async fn unsubscribe_desired(&mut self, timeout: u64) -> Result<(), TwinFailure> {
+            let response = self.request("DELETE", Some(DESIRED_NOTIFICATIONS_RESOURCE), None, timeout).await?;
+            expect_status(&response, &[200, 202, 204])
+        }
+
+        // Wait for the next desired property update.
+        pub async fn next_desired(&mut self, timeout: u64) -> Result<DesiredUpdate, TwinFailure> {
+            if let Some(update) = self.pending_updates.pop_front(){
+                return Ok(update);
+            }
+            let message = self.next_message(None, timeout).await?;
+            desired_update(&message)
+        }
+
+        async fn request(&mut self, operation: &str, resource: Option<&str>, body: Option<Vec<u8>>, timeout: u64) -> Result<Message, TwinFailure> {
+            self.next_request_id += 1;
+            let request_id = format!("twin-{}", self.next_request_id);
+            let request = create_twin_request(operation, resource, body, &request_id);
+            let send_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    self.sender.send(request).await
+                }
+            ).await;
+            match send_task{
+                Err(_) => {
+                    return Err(TwinFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(TwinFailure::SendFailure(err));
+                }
+                Ok(Ok(_)) => {}
+            }
+            self.next_message(Some(&request_id), timeout).await
+        }
+
+        // Next response to request_id, or the next desired update when request_id is None.
+        // Desired updates seen while waiting for a response are kept for next_desired,
+        // responses to older (timed out) requests are skipped.
+        async fn next_message(&mut self, request_id: Option<&str>, timeout: u64) -> Result<Message, TwinFailure> {
+            let receiver = &mut self.receiver;
+            let pending_updates = &mut self.pending_updates;
+            let receive_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    use futures::StreamExt;
+                    loop{
+                        let transfer = match receiver.next().await{
+                            None => {
+                                return Err(TwinFailure::LinkClosed);
+                            }
+                            Some(Err(err)) => {
+                                return Err(TwinFailure::ReceiveFailure(err));
+                            }
+                            Some(Ok(transfer)) => {
+                                transfer
+                            }
+                        };
+                        let message = match message_from_transfer(&transfer){
+                            None => {
+                                continue;
+                            }
+                            Some(message) => {
+                                message
+                            }
+                        };
+                        match (request_id, response_correlation_id(&message)){
+                            (None, None) => {
+                                return Ok(message);
+                            }
+                            (Some(request_id), Some(correlation_id)) if request_id == correlation_id => {
+                                return Ok(message);
+                            }
+                            (Some(_), None) => {
+                                if let Ok(update) = desired_update(&message){
+                                    pending_updates.push_back(update);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            ).await;
+            self.receiver.set_link_credit(10);
+            match receive_task{
+                Err(_) => {
+                    Err(TwinFailure::Timeout)
+                }
+                Ok(result) => {
+                    result
+                }
+            }
+        }
+    }
+
+    fn link_properties(channel: &str) -> ntex_amqp::codec::types::Fields {
+        let mut properties = ntex_amqp::codec::types::Fields::default();
+        properties.insert(Symbol::from(CHANNEL_CORRELATION_PROPERTY), string_variant(channel));
+        properties.insert(Symbol::from(API_VERSION_PROPERTY), string_variant(TWIN_API_VERSION));
+        properties
+    }
+
+    pub fn create_twin_request(operation: &str, resource: Option<&str>, body: Option<Vec<u8>>, request_id: &str) -> TransferBody {
+        // The hub expects a body section even on GET.
+        let mut content = Message::with_body(Bytes::from(body.unwrap_or_else(|| b" ".to_vec())));
+        let mut props = empty_properties();
+        props.correlation_id = Some(MessageId::String(ByteString::from(request_id)));
+        content.properties = Some(props);
+        content.add_message_annotation(OPERATION_ANNOTATION, string_variant(operation));
+        if let Some(resource) = resource{
+            content.add_message_annotation(RESOURCE_ANNOTATION, string_variant(resource));
+        }
+        TransferBody::Message(Box::new(content))
+    }
+
+    pub fn response_correlation_id(message: &Message) -> Option<String> {
+        match message.properties.as_ref()?.correlation_id.as_ref()?{
+            MessageId::String(id) => Some(id.to_string()),
+            _ => None
+        }
+    }
+
+    pub fn response_body(message: &Message) -> Option<Bytes> {
+        if let Some(data) = message.body.data(){
+            return Some(data.clone());
+        }
+        match message.body.value.as_ref()?{
+            Variant::Binary(data) => Some(data.clone()),
+            Variant::String(text) => Some(Bytes::copy_from_slice(text.as_str().as_bytes())),
+            _ => None
+        }
+    }
+
+    pub fn desired_update(message: &Message) -> Result<DesiredUpdate, TwinFailure> {
+        let body = response_body(message).unwrap_or_default();
+        let patch: Value = serde_json::from_slice(&body).map_err(TwinFailure::InvalidBody)?;
+        Ok(DesiredUpdate::from_patch(patch))
+    }
+
+    fn expect_status(response: &Message, accepted: &[i64]) -> Result<(), TwinFailure> {
+        match int_annotation(response, STATUS_ANNOTATION){
+            Some(status) if accepted.contains(&status) => {
+                Ok(())
+            }
+            Some(status) => {
+                Err(TwinFailure::Rejected(status))
+            }
+            None => {
+                Err(TwinFailure::InvalidResponse)
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum TwinFailure{
+        NoSession,
+        Timeout,
+        LinkCreateFailure(AmqpProtocolError),
+        SendFailure(AmqpProtocolError),
+        ReceiveFailure(AmqpProtocolError),
+        LinkClosed,
+        InvalidResponse,
+        InvalidBody(serde_json::Error),
+        // Status code of the hub, e.g. 400 for a malformed patch
+        Rejected(i64),
+    }
+
+    impl Display for TwinFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                TwinFailure::NoSession => write!(f, "NoSession"),
+                TwinFailure::Timeout => write!(f, "Twin request timed out."),
+                TwinFailure::LinkCreateFailure(err) => write!(f, "Failed to create the twin links: {}", err),
+                TwinFailure::SendFailure(err) => write!(f, "Failed to send the twin request: {}", err),
+                TwinFailure::ReceiveFailure(err) => write!(f, "Failed to receive on the twin link: {}", err),
+                TwinFailure::LinkClosed => write!(f, "The twin link was closed."),
+                TwinFailure::InvalidResponse => write!(f, "Twin response without status."),
+                TwinFailure::InvalidBody(err) => write!(f, "Invalid twin document: {}", err),
+                TwinFailure::Rejected(status) => write!(f, "Twin request rejected with status {}.", status),
+            }
+        }
+    }
+    impl std::error::Error for TwinFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                TwinFailure::LinkCreateFailure(err) => Some(err),
+                TwinFailure::SendFailure(err) => Some(err),
+                TwinFailure::ReceiveFailure(err) => Some(err),
+                TwinFailure::InvalidBody(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+}
+pub mod methods{
+    // Direct methods. Devices get requests on a receiver link and answer on a sender link,
+    // both on /devices/{id}/methods/devicebound and paired by a channel correlation id.
+    // The method name comes in the IoThub-methodname property, the answer carries
+    // IoThub-status and the correlation id of the request.
+    // Services invoke methods through the REST endpoint of the hub.
+    use std::fmt::{Display, Formatter};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::time::Duration;
+    use async_std::future;
+    use ntex::util::{Bytes, ByteString};
+    use ntex_amqp::codec::Message;
+    use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+    use ntex_amqp::codec::types::{Symbol, Variant};
+    use ntex_amqp::{ReceiverLink, SenderLink, Session};
+    use ntex_amqp::error::AmqpProtocolError;
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use crate::amqp::transfer::{empty_properties, int_app_property, message_from_transfer, string_app_property, string_variant};
+    use crate::amqp::twin::{API_VERSION_PROPERTY, CHANNEL_CORRELATION_PROPERTY, response_body};
+    use crate::transport::https::HttpFailure;
+
+    pub const METHOD_NAME_PROPERTY: &str = "IoThub-methodname";
+    pub const METHOD_STATUS_PROPERTY: &str = "IoThub-status";
+    pub const METHODS_API_VERSION: &str = "2021-04-12";
+    // Status returned for methods without a registered handler
+    pub const METHOD_NOT_FOUND: i32 = 404;
+
+    pub fn methods_address(device_id: &str) -> String {
+        format!("/devices/{}/methods/devicebound", device_id)
+    }
+
+    // REST path a service posts invocations to.
+    pub fn invoke_path(device_id: &str) -> String {
+        format!("/twins/{}/methods?api-version={}", urlencoding::encode(device_id), METHODS_API_VERSION)
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct MethodRequest{
+        pub name: String,
+        pub payload: Value,
+        // Copied to the response so the hub can match it
+        pub correlation_id: MessageId,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct MethodResponse{
+        pub status: i32,
+        #[serde(default)]
+        pub payload: Value,
+    }
+
+    impl MethodResponse{
+        pub fn new(status: i32, payload: Value) -> MethodResponse {
+            MethodResponse{
+                status,
+                payload
+            }
+        }
+
+        pub fn ok(payload: Value) -> MethodResponse {
+            MethodResponse::new(200, payload)
+        }
+    }
+
+    // Body of a REST invocation.
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct MethodInvocation{
+        pub method_name: String,
+        pub payload: Value,
+        // How long the hub waits for the device to answer
+        pub response_timeout_in_seconds: u64,
+        // How long the hub waits for the device to come online
+        pub connect_timeout_in_seconds: u64,
+    }
+
+    pub type MethodFuture = Pin<Box<dyn Future<Output = MethodResponse>>>;
+    pub type MethodHandler = Box<dyn FnMut(Value) -> MethodFuture>;
+
+    pub fn method_request(message: &Message) -> Option<MethodRequest> {
+        let name = string_app_property(message, METHOD_NAME_PROPERTY)?;
+        let correlation_id = message.properties.as_ref()?.correlation_id.clone()?;
+        let body = response_body(message).unwrap_or_default();
+        // An empty or non JSON body is handed over as null
+        let payload = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        Some(MethodRequest{
+            name,
+            payload,
+            correlation_id
+        })
+    }
+
+    pub fn create_method_response(request: &MethodRequest, response: &MethodResponse) -> TransferBody {
+        let body = serde_json::to_vec(&response.payload).unwrap_or_else(|_| b"null".to_vec());
+        let mut content = Message::with_body(Bytes::from(body));
+        let mut props = empty_properties();
+        props.correlation_id = Some(request.correlation_id.clone());
+        content.properties = Some(props);
+        content.set_app_property(ByteString::from_static(METHOD_STATUS_PROPERTY), Variant::Int(response.status));
+        TransferBody::Message(Box::new(content))
+    }
+
+    // Status of a method response message (for the mock hub and tests).
+    pub fn response_status(message: &Message) -> Option<i64> {
+        int_app_property(message, METHOD_STATUS_PROPERTY)
+    }
+
+    pub struct MethodLink{
+        sender: SenderLink,
+        receiver: ReceiverLink,
+    }
+
+    impl MethodLink{
+        pub async fn open(session: &mut Session, device_id: &str, timeout: u64) -> Result<MethodLink, MethodFailure> {
+            let address = methods_address(device_id);
+            let channel = format!("methods:{:016x}", rand::thread_rng().gen::<u64>());
+            let timeout = Duration::from_secs(timeout);
+            let sender_task = future::timeout(
+                timeout, async{
+                    session.build_sender_link(&format!("methods_sender_{}", device_id), &address)
+                        .with_frame(|frame| {
+                            frame.properties = Some(link_properties(&channel));
+                        })
+                        .open().await
+                }
+            ).await;
+            let sender = match sender_task{
+                Err(_) => {
+                    return Err(MethodFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(MethodFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(sender)) => {
+                    sender
+                }
+            };
+            let receiver_task = future::timeout(
+                timeout, async{
+                    session.build_receiver_link(&format!("methods_receiver_{}", device_id), &address)
+                        .with_frame(|frame| {
+                            frame.properties = Some(link_properties(&channel));
+                        })
+                        .open().await
+                }
+            ).await;
+            let receiver = match receiver_task{
+                Err(_) => {
+                    return Err(MethodFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(MethodFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(receiver)) => {
+                    receiver
+                }
+            };
+            receiver.set_link_credit(10);
+            Ok(MethodLink{
+                sender,
+                receiver
+            })
+        }
+
+        // Next method request, Timeout when none arrived in time.
+        pub async fn next_request(&mut self, timeout: u64) -> Result<MethodRequest, MethodFailure> {
+            let receiver = &mut self.receiver;
+            let receive_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    use futures::StreamExt;
+                    loop{
+                        let transfer = match receiver.next().await{
+                            None => {
+                                return Err(MethodFailure::LinkClosed);
+                            }
+                            Some(Err(err)) => {
+                                return Err(MethodFailure::ReceiveFailure(err));
+                            }
+                            Some(Ok(transfer)) => {
+                                transfer
+                            }
+                        };
+                        // Anything that is not a method request is skipped.
+                        if let Some(request) = message_from_transfer(&transfer).as_ref().and_then(method_request){
+                            return Ok(request);
+                        }
+                    }
+                }
+            ).await;
+            self.receiver.set_link_credit(10);
+            match receive_task{
+                Err(_) => {
+                    Err(MethodFailure::Timeout)
+                }
+                Ok(result) => {
+                    result
+                }
+            }
+        }
+
+        pub async fn respond(&mut self, request: &MethodRequest, response: &MethodResponse, timeout: u64) -> Result<(), MethodFailure> {
+            let message = create_method_response(request, response);
+            let send_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    self.sender.send(message).await
+                }
+            ).await;
+            match send_task{
+                Err(_) => {
+                    Err(MethodFailure::Timeout)
+                }
+                Ok(Err(err)) => {
+                    Err(MethodFailure::SendFailure(err))
+                }
+                Ok(Ok(_)) => {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn link_properties(channel: &str) -> ntex_amqp::codec::types::Fields {
+        let mut properties = ntex_amqp::codec::types::Fields::default();
+        properties.insert(Symbol::from(CHANNEL_CORRELATION_PROPERTY), string_variant(channel));
+        properties.insert(Symbol::from(API_VERSION_PROPERTY), string_variant(METHODS_API_VERSION));
+        properties
+    }
+
+    #[derive(Debug)]
+    pub enum MethodFailure{
+        NoSession,
+        Timeout,
+        LinkCreateFailure(AmqpProtocolError),
+        SendFailure(AmqpProtocolError),
+        ReceiveFailure(AmqpProtocolError),
+        LinkClosed,
+        Token(crate::util::token::SasTokenCreateException),
+        Http(HttpFailure),
+        InvalidResponse(serde_json::Error),
+        // HTTP status and body of a failed invocation (404: device offline or unknown,
+        // 504: the device did not answer in time)
+        Rejected(u16, String),
+    }
+
+    impl Display for MethodFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                MethodFailure::NoSession => write!(f, "NoSession"),
+                MethodFailure::Timeout => write!(f, "Direct method timed out."),
+                MethodFailure::LinkCreateFailure(err) => write!(f, "Failed to create the method links: {}", err),
+                MethodFailure::SendFailure(err) => write!(f, "Failed to send the method response: {}", err),
+                MethodFailure::ReceiveFailure(err) => write!(f, "Failed to receive on the method link: {}", err),
+                MethodFailure::LinkClosed => write!(f, "The method link was closed."),
+                MethodFailure::Token(err) => write!(f, "Failed to create the service token: {}", err),
+                MethodFailure::Http(err) => write!(f, "{}", err),
+                MethodFailure::InvalidResponse(err) => write!(f, "Invalid method response: {}", err),
+                MethodFailure::Rejected(status, body) => write!(f, "Method invocation failed ({}): {}", status, body),
+            }
+        }
+    }
+    impl std::error::Error for MethodFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                MethodFailure::LinkCreateFailure(err) => Some(err),
+                MethodFailure::SendFailure(err) => Some(err),
+                MethodFailure::ReceiveFailure(err) => Some(err),
+                MethodFailure::Token(err) => Some(err),
+                MethodFailure::Http(err) => Some(err),
+                MethodFailure::InvalidResponse(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+    impl From<HttpFailure> for MethodFailure{
+        fn from(err: HttpFailure) -> Self {
+            MethodFailure::Http(err)
+        }
+    }
+}
+pub mod feedback{
+    // Delivery feedback for cloud-to-device messages. The service asks for it with the
+    // iothub-ack property, the hub then reports what happened to each message on
+    // /messages/servicebound/feedback, a JSON array of records per feedback message.
+    use std::collections::VecDeque;
+    use std::fmt::{Display, Formatter};
+    use std::time::{Duration, Instant};
+    use async_std::future;
+    use chrono::{DateTime, Utc};
+    use futures::Stream;
+    use ntex::util::{Bytes, ByteString};
+    use ntex_amqp::codec::{Decode, Message};
+    use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+    use ntex_amqp::{ReceiverLink, Session};
+    use ntex_amqp::error::AmqpProtocolError;
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use crate::amqp::transfer::{empty_properties, message_from_transfer, settlement_disposition, string_variant, Settlement};
+    use crate::amqp::twin::response_body;
+
+    pub const FEEDBACK_ADDRESS: &str = "/messages/servicebound/feedback";
+    pub const ACK_PROPERTY: &str = "iothub-ack";
+    pub const FEEDBACK_CONTENT_TYPE: &str = "application/vnd.microsoft.iothub.feedback.json";
+    // How long feedback_stream waits per receive before trying again
+    const STREAM_POLL_SECONDS: u64 = 60;
+
+    // Which outcomes the hub reports back for a message.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum AckLevel{
+        None,
+        // Only Success
+        Positive,
+        // Everything except Success
+        Negative,
+        Full,
+    }
+
+    impl AckLevel{
+        pub fn as_str(&self) -> &'static str {
+            match self{
+                AckLevel::None => "none",
+                AckLevel::Positive => "positive",
+                AckLevel::Negative => "negative",
+                AckLevel::Full => "full",
+            }
+        }
+
+        pub fn reports(&self, status: &FeedbackStatus) -> bool {
+            match self{
+                AckLevel::None => false,
+                AckLevel::Positive => *status == FeedbackStatus::Success,
+                AckLevel::Negative => *status != FeedbackStatus::Success,
+                AckLevel::Full => true,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub enum FeedbackStatus{
+        // The device completed the message
+        Success,
+        // The message expired before the device took it
+        Expired,
+        // The device abandoned it too often
+        DeliveryCountExceeded,
+        // The device rejected it
+        Rejected,
+        // The queue of the device was purged
+        Purged,
+        #[serde(other)]
+        Unknown,
+    }
+
+    // Outcome of one cloud-to-device message.
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FeedbackRecord{
+        pub original_message_id: String,
+        pub device_id: String,
+        #[serde(default)]
+        pub device_generation_id: String,
+        #[serde(rename = "statusCode")]
+        pub status: FeedbackStatus,
+        #[serde(default)]
+        pub description: String,
+        pub enqueued_time_utc: DateTime<Utc>,
+    }
+
+    pub fn parse_feedback(body: &[u8]) -> Result<Vec<FeedbackRecord>, serde_json::Error> {
+        serde_json::from_slice(body)
+    }
+
+    // Ask for feedback on a cloud-to-device message. Feedback is matched on the message id,
+    // so a message without a (string or numeric) id gets a generated one.
+    // Returns the message to send and its id.
+    pub fn request_ack(body: TransferBody, ack: AckLevel) -> (TransferBody, String) {
+        let mut message = match body{
+            TransferBody::Message(message) => {
+                *message
+            }
+            TransferBody::Data(data) => {
+                match Message::decode(&data){
+                    Ok((_, message)) => {
+                        message
+                    }
+                    Err(_) => {
+                        // Not an encoded message, the bytes are the body.
+                        Message::with_body(data)
+                    }
+                }
+            }
+        };
+        let mut properties = message.properties.take().unwrap_or_else(empty_properties);
+        let message_id = match properties.message_id.as_ref().and_then(message_id_string){
+            Some(message_id) => {
+                message_id
+            }
+            None => {
+                let message_id = format!("c2d-{:016x}", rand::thread_rng().gen::<u64>());
+                properties.message_id = Some(MessageId::String(ByteString::from(message_id.as_str())));
+                message_id
+            }
+        };
+        message.properties = Some(properties);
+        if ack != AckLevel::None{
+            message.set_app_property(ByteString::from_static(ACK_PROPERTY), string_variant(ack.as_str()));
+        }
+        (TransferBody::Message(Box::new(message)), message_id)
+    }
+
+    pub fn message_id_string(message_id: &MessageId) -> Option<String> {
+        match message_id{
+            MessageId::String(message_id) => Some(message_id.to_string()),
+            MessageId::Ulong(message_id) => Some(message_id.to_string()),
+            _ => None
+        }
+    }
+
+    // Receiver on the feedback endpoint. Records of a feedback message that were not
+    // asked for yet are kept until next or next_for picks them up.
+    pub struct FeedbackLink{
+        receiver: ReceiverLink,
+        pending: VecDeque<FeedbackRecord>,
+    }
+
+    impl FeedbackLink{
+        pub async fn open(session: &mut Session, timeout: u64) -> Result<FeedbackLink, FeedbackFailure> {
+            let receiver_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    session.build_receiver_link("feedback_receiver", FEEDBACK_ADDRESS).open().await
+                }
+            ).await;
+            let receiver = match receiver_task{
+                Err(_) => {
+                    return Err(FeedbackFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(FeedbackFailure::LinkCreateFailure(err));
+                }
+                Ok(Ok(receiver)) => {
+                    receiver
+                }
+            };
+            receiver.set_link_credit(10);
+            Ok(FeedbackLink{
+                receiver,
+                pending: VecDeque::new()
+            })
+        }
+
+        // Next feedback record, whichever message it is about.
+        pub async fn next(&mut self, timeout: u64) -> Result<FeedbackRecord, FeedbackFailure> {
+            let deadline = Instant::now() + Duration::from_secs(timeout);
+            loop{
+                if let Some(record) = self.pending.pop_front(){
+                    return Ok(record);
+                }
+                self.receive_batch(deadline).await?;
+            }
+        }
+
+        // Feedback on one message, records about other messages stay pending.
+        pub async fn next_for(&mut self, message_id: &str, timeout: u64) -> Result<FeedbackRecord, FeedbackFailure> {
+            let deadline = Instant::now() + Duration::from_secs(timeout);
+            loop{
+                let position = self.pending.iter().position(|record| record.original_message_id == message_id);
+                if let Some(record) = position.and_then(|position| self.pending.remove(position)){
+                    return Ok(record);
+                }
+                self.receive_batch(deadline).await?;
+            }
+        }
+
+        // Records until the link closes. Waiting is unbounded, wrap it in a timeout if needed.
+        pub fn stream(&mut self) -> impl Stream<Item = Result<FeedbackRecord, FeedbackFailure>> + '_ {
+            futures::stream::unfold(Some(self), |link| async move {
+                let link = link?;
+                loop{
+                    match link.next(STREAM_POLL_SECONDS).await{
+                        Ok(record) => {
+                            return Some((Ok(record), Some(link)));
+                        }
+                        Err(FeedbackFailure::Timeout) => {}
+                        Err(err) => {
+                            // The link is gone, end the stream after reporting why.
+                            return Some((Err(err), None));
+                        }
+                    }
+                }
+            })
+        }
+
+        // Receive one feedback message and queue its records. The message is completed
+        // once read, an unreadable one is rejected so the hub does not send it again.
+        async fn receive_batch(&mut self, deadline: Instant) -> Result<(), FeedbackFailure> {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let receiver = &mut self.receiver;
+            let receive_task = future::timeout(
+                remaining, async{
+                    use futures::StreamExt;
+                    match receiver.next().await{
+                        None => {
+                            Err(FeedbackFailure::LinkClosed)
+                        }
+                        Some(Err(err)) => {
+                            Err(FeedbackFailure::ReceiveFailure(err))
+                        }
+                        Some(Ok(transfer)) => {
+                            Ok(transfer)
+                        }
+                    }
+                }
+            ).await;
+            self.receiver.set_link_credit(10);
+            let transfer = match receive_task{
+                Err(_) => {
+                    return Err(FeedbackFailure::Timeout);
+                }
+                Ok(result) => {
+                    result?
+                }
+            };
+            let body = message_from_transfer(&transfer)
+                .and_then(|message| response_body(&message))
+                .unwrap_or_else(Bytes::new);
+            let records = parse_feedback(&body);
+            if let (Some(delivery_id), false) = (transfer.delivery_id, transfer.settled == Some(true)){
+                let settlement = match records{
+                    Ok(_) => Settlement::Complete,
+                    Err(_) => Settlement::Reject(Some("Invalid feedback body".to_string()))
+                };
+                self.receiver.send_disposition(settlement_disposition(delivery_id, &settlement));
+            }
+            self.pending.extend(records.map_err(FeedbackFailure::InvalidBody)?);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum FeedbackFailure{
+        NoSession,
+        Timeout,
+        LinkCreateFailure(AmqpProtocolError),
+        ReceiveFailure(AmqpProtocolError),
+        LinkClosed,
+        InvalidBody(serde_json::Error),
+    }
+
+    impl Display for FeedbackFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                FeedbackFailure::NoSession => write!(f, "NoSession"),
+                FeedbackFailure::Timeout => write!(f, "No feedback received in time."),
+                FeedbackFailure::LinkCreateFailure(err) => write!(f, "Failed to create the feedback link: {}", err),
+                FeedbackFailure::ReceiveFailure(err) => write!(f, "Failed to receive on the feedback link: {}", err),
+                FeedbackFailure::LinkClosed => write!(f, "The feedback link was closed."),
+                FeedbackFailure::InvalidBody(err) => write!(f, "Invalid feedback message: {}", err),
+            }
+        }
+    }
+    impl std::error::Error for FeedbackFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                FeedbackFailure::LinkCreateFailure(err) => Some(err),
+                FeedbackFailure::ReceiveFailure(err) => Some(err),
+                FeedbackFailure::InvalidBody(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+}
+pub mod eventhub{
+    // Reading device telemetry from the built-in Event Hub-compatible endpoint.
+    // Every partition is read through its own receiver on
+    // /messages/events/ConsumerGroups/{consumer group}/Partitions/{partition id}, the start
+    // position is a selector filter on the source. The partition ids come from a READ
+    // request on the $management node.
+    use std::collections::HashMap;
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+    use async_std::future;
+    use chrono::{DateTime, TimeZone, Utc};
+    use ntex::util::{Bytes, ByteString};
+    use ntex_amqp::codec::Message;
+    use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+    use ntex_amqp::codec::types::{Descriptor, Symbol, Variant, VariantMap};
+    use ntex_amqp::{ReceiverLink, Session};
+    use ntex_amqp::error::AmqpProtocolError;
+    use serde_json::Value;
+    use crate::amqp::client::AmqpFailure;
+    use crate::amqp::config::{AMQP_PORT, AMQPS_PORT, Endpoint, Transport};
+    use crate::amqp::feedback::message_id_string;
+    use crate::amqp::transfer::{empty_properties, int_annotation, int_app_property, message_from_transfer, redirect_target, remote_error, settlement_disposition, string_annotation, string_app_property, string_variant, Settlement};
+    use crate::amqp::twin::response_body;
+    use crate::util::checkpoint::{Checkpoint, CheckpointException, CheckpointStore, EventPosition};
+    use crate::util::events::RedirectTarget;
+    use crate::util::token::SasTokenCreateException;
+
+    pub const DEFAULT_CONSUMER_GROUP: &str = "$Default";
+    pub const MANAGEMENT_ADDRESS: &str = "$management";
+    pub const MANAGEMENT_REPLY_TO: &str = "eventhub-management";
+    pub const EVENTHUB_TYPE: &str = "com.microsoft:eventhub";
+    pub const PARTITION_IDS_KEY: &str = "partition_ids";
+    pub const SELECTOR_FILTER: &str = "apache.org:selector-filter:string";
+    // System properties the hub adds to every event
+    pub const OFFSET_ANNOTATION: &str = "x-opt-offset";
+    pub const SEQUENCE_NUMBER_ANNOTATION: &str = "x-opt-sequence-number";
+    pub const ENQUEUED_TIME_ANNOTATION: &str = "x-opt-enqueued-time";
+    pub const DEVICE_ID_ANNOTATION: &str = "iothub-connection-device-id";
+    pub const MODULE_ID_ANNOTATION: &str = "iothub-connection-module-id";
+
+    pub fn partition_address(consumer_group: &str, partition_id: &str) -> String {
+        format!("/messages/events/ConsumerGroups/{}/Partitions/{}", consumer_group, partition_id)
+    }
+
+    // The Event Hubs namespace IoT Hub sends its event readers to with amqp:link:redirect.
+    #[derive(Clone, Debug)]
+    pub struct EventHubLocation{
+        pub endpoint: Endpoint,
+        // Name of the event hub in the namespace, e.g. iothub-ehub-{hub}-{id}
+        pub entity: String,
+    }
+
+    impl EventHubLocation{
+        // Reads amqps://{host}:{port}/{event hub}[/ConsumerGroups/..] from the redirect.
+        // The hostname and port fields, when present, win over the ones in the address.
+        pub fn from_redirect(target: &RedirectTarget) -> Option<EventHubLocation> {
+            let address = target.address.as_ref()?;
+            let (transport, default_port, rest) = if let Some(rest) = address.strip_prefix("amqps://"){
+                (Transport::Tls, AMQPS_PORT, rest)
+            }
+            else if let Some(rest) = address.strip_prefix("amqp://"){
+                (Transport::Plain, AMQP_PORT, rest)
+            }
+            else {
+                return None;
+            };
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let (host, port) = match authority.rsplit_once(':'){
+                Some((host, port)) => (host, port.parse::<u16>().ok()?),
+                None => (authority, default_port)
+            };
+            let entity = path.split("/ConsumerGroups/").next().unwrap_or_default().trim_matches('/');
+            if entity.is_empty(){
+                return None;
+            }
+            let host_name = target.host_name.clone()
+                .or_else(|| target.network_host.clone())
+                .unwrap_or_else(|| host.to_string());
+            Some(EventHubLocation{
+                endpoint: Endpoint::custom(&host_name, target.port.unwrap_or(port), transport),
+                entity: entity.to_string()
+            })
+        }
+
+        // Resource of the service token for the event hub.
+        pub fn audience(&self) -> String {
+            format!("{}/{}", self.endpoint.host_name, self.entity)
+        }
+
+        pub fn partition_address(&self, consumer_group: &str, partition_id: &str) -> String {
+            format!("{}/ConsumerGroups/{}/Partitions/{}", self.entity, consumer_group, partition_id)
+        }
+    }
+
+    // A redirect turns into EventHubFailure::Redirect, anything else into the given failure.
+    fn link_failure(err: AmqpProtocolError, failure: fn(AmqpProtocolError) -> EventHubFailure) -> EventHubFailure {
+        match remote_error(&err).and_then(redirect_target){
+            Some(target) => {
+                EventHubFailure::Redirect(target)
+            }
+            None => {
+                failure(err)
+            }
+        }
+    }
+
+    // One telemetry message as read from a partition.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct EventData{
+        pub partition_id: String,
+        pub offset: String,
+        pub sequence_number: i64,
+        pub enqueued_time: Option<DateTime<Utc>>,
+        pub device_id: Option<String>,
+        pub module_id: Option<String>,
+        pub message_id: Option<String>,
+        pub content_type: Option<String>,
+        // Application properties with a string or numeric value
+        pub properties: HashMap<String, String>,
+        pub body: Bytes,
+    }
+
+    impl EventData{
+        // The body as JSON, the way the devices send their measurements.
+        pub fn json(&self) -> Result<Value, serde_json::Error> {
+            serde_json::from_slice(&self.body)
+        }
+
+        pub fn checkpoint(&self) -> Checkpoint {
+            Checkpoint{
+                offset: self.offset.clone(),
+                sequence_number: self.sequence_number,
+                enqueued_time: self.enqueued_time
+            }
+        }
+    }
+
+    // The event carried by a message, None when the offset annotation is missing.
+    pub fn event_from_message(partition_id: &str, message: &Message) -> Option<EventData> {
+        let offset = string_annotation(message, OFFSET_ANNOTATION)?;
+        let properties = message.app_properties()
+            .map(|properties| properties.iter()
+                .filter_map(|(key, _)| {
+                    let value = string_app_property(message, key.as_str())
+                        .or_else(|| int_app_property(message, key.as_str()).map(|value| value.to_string()))?;
+                    Some((key.to_string(), value))
+                })
+                .collect())
+            .unwrap_or_default();
+        let message_properties = message.properties.as_ref();
+        Some(EventData{
+            partition_id: partition_id.to_string(),
+            offset,
+            sequence_number: int_annotation(message, SEQUENCE_NUMBER_ANNOTATION).unwrap_or_default(),
+            enqueued_time: timestamp_annotation(message, ENQUEUED_TIME_ANNOTATION),
+            device_id: string_annotation(message, DEVICE_ID_ANNOTATION),
+            module_id: string_annotation(message, MODULE_ID_ANNOTATION),
+            message_id: message_properties.and_then(|props| props.message_id.as_ref()).and_then(message_id_string),
+            content_type: message_properties.and_then(|props| props.content_type.as_ref()).map(|content_type| content_type.to_string()),
+            properties,
+            body: response_body(message).unwrap_or_default()
+        })
+    }
+
+    // Timestamps arrive as AMQP timestamps, or as milliseconds since the epoch.
+    fn timestamp_annotation(message: &Message, key: &str) -> Option<DateTime<Utc>> {
+        match message.message_annotation(key)?{
+            Variant::Timestamp(time) => Some(*time),
+            _ => int_annotation(message, key).map(|millis| Utc.timestamp_millis(millis))
+        }
+    }
+
+    // Source filter that starts the receiver at the position.
+    pub fn selector_filter(position: &EventPosition) -> ntex_amqp::codec::types::FilterSet {
+        let mut filter = ntex_amqp::codec::types::FilterSet::default();
+        filter.insert(
+            Symbol::from(SELECTOR_FILTER),
+            Variant::Described((Descriptor::Symbol(Symbol::from(SELECTOR_FILTER)), Box::new(string_variant(&position.selector()))))
+        );
+        filter
+    }
+
+    // Reads the position back from a source filter (for the mock hub).
+    pub fn filter_position(filter: &ntex_amqp::codec::types::FilterSet) -> Option<EventPosition> {
+        match filter.get(&Symbol::from(SELECTOR_FILTER))?{
+            Variant::Described((_, selector)) => {
+                match selector.as_ref(){
+                    Variant::String(selector) => EventPosition::from_selector(selector.as_str()),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    }
+
+    pub fn create_partitions_request(event_hub: &str, request_id: &str) -> TransferBody {
+        let mut content = Message::default();
+        let mut props = empty_properties();
+        props.message_id = Some(MessageId::String(ByteString::from(request_id)));
+        props.reply_to = Some(ByteString::from_static(MANAGEMENT_REPLY_TO));
+        content.properties = Some(props);
+        content.set_app_property(ByteString::from_static("operation"), string_variant("READ"));
+        content.set_app_property(ByteString::from_static("type"), string_variant(EVENTHUB_TYPE));
+        content.set_app_property(ByteString::from_static("name"), string_variant(event_hub));
+        TransferBody::Message(Box::new(content))
+    }
+
+    // The partition_ids entry of the map in a READ response.
+    pub fn read_partition_ids(message: &Message) -> Option<Vec<String>> {
+        let entries = match message.body.value.as_ref()?{
+            Variant::Map(entries) => entries,
+            _ => return None
+        };
+        let partition_ids = entries.map.iter()
+            .find(|(key, _)| variant_str(key) == Some(PARTITION_IDS_KEY))
+            .map(|(_, value)| value)?;
+        match partition_ids{
+            Variant::List(partition_ids) => {
+                partition_ids.iter().map(|id| variant_str(id).map(|id| id.to_string())).collect()
+            }
+            _ => None
+        }
+    }
+
+    // Body of a READ response listing the partitions (for the mock hub).
+    pub fn partition_ids_value(partition_ids: &[String]) -> Variant {
+        let mut entries = HashMap::new();
+        entries.insert(
+            string_variant(PARTITION_IDS_KEY),
+            Variant::List(partition_ids.iter().map(|id| string_variant(id)).collect())
+        );
+        Variant::Map(VariantMap::new(entries))
+    }
+
+    fn variant_str(value: &Variant) -> Option<&str> {
+        match value{
+            Variant::String(value) => Some(value.as_str()),
+            Variant::Symbol(value) => Some(value.as_str()),
+            _ => None
+        }
+    }
+
+    // Partition ids of the Event Hub-compatible endpoint, through a one-off $management link pair.
+    pub async fn partition_ids(session: &mut Session, event_hub: &str, timeout: u64) -> Result<Vec<String>, EventHubFailure> {
+        let timeout = Duration::from_secs(timeout);
+        let sender_task = future::timeout(
+            timeout, async{
+                session.build_sender_link("management_sender", MANAGEMENT_ADDRESS).open().await
+            }
+        ).await;
+        let sender = match sender_task{
+            Err(_) => {
+                return Err(EventHubFailure::Timeout);
+            }
+            Ok(Err(err)) => {
+                return Err(link_failure(err, EventHubFailure::LinkCreateFailure));
+            }
+            Ok(Ok(sender)) => {
+                sender
+            }
+        };
+        let receiver_task = future::timeout(
+            timeout, async{
+                session.build_receiver_link("management_receiver", MANAGEMENT_ADDRESS).open().await
+            }
+        ).await;
+        let mut receiver = match receiver_task{
+            Err(_) => {
+                return Err(EventHubFailure::Timeout);
+            }
+            Ok(Err(err)) => {
+                return Err(link_failure(err, EventHubFailure::LinkCreateFailure));
+            }
+            Ok(Ok(receiver)) => {
+                receiver
+            }
+        };
+        receiver.set_link_credit(1);
+        let request_id = "eventhub-read-1";
+        let response_task = future::timeout(
+            timeout, async{
+                use futures::StreamExt;
+                sender.send(create_partitions_request(event_hub, request_id)).await
+                    .map_err(|err| link_failure(err, EventHubFailure::SendFailure))?;
+                match receiver.next().await{
+                    None => {
+                        Err(EventHubFailure::LinkClosed)
+                    }
+                    Some(Err(err)) => {
+                        Err(link_failure(err, EventHubFailure::ReceiveFailure))
+                    }
+                    Some(Ok(transfer)) => {
+                        message_from_transfer(&transfer).ok_or(EventHubFailure::InvalidResponse)
+                    }
+                }
+            }
+        ).await;
+        sender.close().await.ok();
+        receiver.close().await.ok();
+        let response = match response_task{
+            Err(_) => {
+                return Err(EventHubFailure::Timeout);
+            }
+            Ok(response) => {
+                response?
+            }
+        };
+        match int_app_property(&response, "status-code"){
+            Some(200) | None => {}
+            Some(status) => {
+                return Err(EventHubFailure::Rejected(status, string_app_property(&response, "status-description").unwrap_or_default()));
+            }
+        }
+        read_partition_ids(&response).ok_or(EventHubFailure::InvalidResponse)
+    }
+
+    // Receiver on one partition.
+    pub struct PartitionReceiver{
+        partition_id: String,
+        receiver: ReceiverLink,
+    }
+
+    impl PartitionReceiver{
+        // Fails with EventHubFailure::Redirect when the hub sends the reader elsewhere.
+        pub async fn open(session: &mut Session, consumer_group: &str, partition_id: &str, position: &EventPosition, timeout: u64) -> Result<PartitionReceiver, EventHubFailure> {
+            let address = partition_address(consumer_group, partition_id);
+            PartitionReceiver::open_at(session, &address, consumer_group, partition_id, position, timeout).await
+        }
+
+        // Receiver on the partition at any address, e.g. on the Event Hubs namespace.
+        pub async fn open_at(session: &mut Session, address: &str, consumer_group: &str, partition_id: &str, position: &EventPosition, timeout: u64) -> Result<PartitionReceiver, EventHubFailure> {
+            let filter = selector_filter(position);
+            let receiver_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    session.build_receiver_link(&format!("eventhub_{}_{}", consumer_group, partition_id), address)
+                        .with_frame(|frame| {
+                            if let Some(source) = frame.source.as_mut(){
+                                source.filter = Some(filter);
+                            }
+                        })
+                        .open().await
+                }
+            ).await;
+            let receiver = match receiver_task{
+                Err(_) => {
+                    return Err(EventHubFailure::Timeout);
+                }
+                Ok(Err(err)) => {
+                    return Err(link_failure(err, EventHubFailure::LinkCreateFailure));
+                }
+                Ok(Ok(receiver)) => {
+                    receiver
+                }
+            };
+            receiver.set_link_credit(100);
+            Ok(PartitionReceiver{
+                partition_id: partition_id.to_string(),
+                receiver
+            })
+        }
+
+        pub fn partition_id(&self) -> &str {
+            &self.partition_id
+        }
+
+        pub async fn close(self){
+            if let Err(err) = self.receiver.close().await{
+                println!("Failed to close the receiver of partition {}: {}", self.partition_id, err);
+            }
+        }
+
+        // Next event of the partition. Events are read-only, so every transfer is accepted
+        // once read, the checkpoint is what remembers the progress.
+        pub async fn next_event(&mut self, timeout: u64) -> Result<EventData, EventHubFailure> {
+            let partition_id = self.partition_id.clone();
+            let receiver = &mut self.receiver;
+            let receive_task = future::timeout(
+                Duration::from_secs(timeout), async{
+                    use futures::StreamExt;
+                    loop{
+                        let transfer = match receiver.next().await{
+                            None => {
+                                return Err(EventHubFailure::LinkClosed);
+                            }
+                            Some(Err(err)) => {
+                                return Err(link_failure(err, EventHubFailure::ReceiveFailure));
+                            }
+                            Some(Ok(transfer)) => {
+                                transfer
+                            }
+                        };
+                        if let (Some(delivery_id), false) = (transfer.delivery_id, transfer.settled == Some(true)){
+                            receiver.send_disposition(settlement_disposition(delivery_id, &Settlement::Complete));
+                        }
+                        // Anything without the event annotations is skipped.
+                        if let Some(event) = message_from_transfer(&transfer).and_then(|message| event_from_message(&partition_id, &message)){
+                            return Ok(event);
+                        }
+                    }
+                }
+            ).await;
+            self.receiver.set_link_credit(100);
+            match receive_task{
+                Err(_) => {
+                    Err(EventHubFailure::Timeout)
+                }
+                Ok(result) => {
+                    result
+                }
+            }
+        }
+    }
+
+    // Reads every partition of a consumer group. With a checkpoint store each partition
+    // continues after its last checkpoint, partitions without one start at the initial position.
+    pub struct EventHubConsumer{
+        consumer_group: String,
+        partitions: Vec<PartitionReceiver>,
+        checkpoints: Option<CheckpointStore>,
+    }
+
+    impl EventHubConsumer{
+        pub async fn open(session: &mut Session, event_hub: &str, consumer_group: &str, initial: EventPosition, checkpoints: Option<CheckpointStore>, timeout: u64) -> Result<EventHubConsumer, EventHubFailure> {
+            let partition_ids = partition_ids(session, event_hub, timeout).await?;
+            let mut partitions = Vec::new();
+            for partition_id in partition_ids{
+                let position = match checkpoints.as_ref(){
+                    None => {
+                        initial.clone()
+                    }
+                    Some(checkpoints) => {
+                        checkpoints.position(consumer_group, &partition_id, &initial)
+                    }
+                };
+                partitions.push(PartitionReceiver::open(session, consumer_group, &partition_id, &position, timeout).await?);
+            }
+            Ok(EventHubConsumer::new(consumer_group, partitions, checkpoints))
+        }
+
+        // Consumer over receivers opened elsewhere (e.g. after a redirect).
+        pub fn new(consumer_group: &str, partitions: Vec<PartitionReceiver>, checkpoints: Option<CheckpointStore>) -> EventHubConsumer {
+            EventHubConsumer{
+                consumer_group: consumer_group.to_string(),
+                partitions,
+                checkpoints
+            }
+        }
+
+        pub fn consumer_group(&self) -> &str {
+            &self.consumer_group
+        }
+
+        pub fn partition_ids(&self) -> Vec<String> {
+            self.partitions.iter().map(|partition| partition.partition_id().to_string()).collect()
+        }
+
+        // Next event from whichever partition has one first.
+        pub async fn next_event(&mut self, timeout: u64) -> Result<EventData, EventHubFailure> {
+            if self.partitions.is_empty(){
+                return Err(EventHubFailure::NoPartitions);
+            }
+            let receivers = self.partitions.iter_mut()
+                .map(|partition| Box::pin(partition.next_event(timeout)));
+            let (result, _, _) = futures::future::select_all(receivers).await;
+            result
+        }
+
+        // Detach the partition receivers, the checkpoints stay in their file.
+        pub async fn close(self){
+            for partition in self.partitions{
+                partition.close().await;
+            }
+        }
+
+        // Remember the event as processed, a restart continues after it.
+        pub fn checkpoint(&mut self, event: &EventData) -> Result<(), EventHubFailure> {
+            match self.checkpoints.as_mut(){
+                None => {
+                    Err(EventHubFailure::NoCheckpointStore)
+                }
+                Some(checkpoints) => {
+                    checkpoints.update(&self.consumer_group, &event.partition_id, event.checkpoint())
+                        .map_err(EventHubFailure::Checkpoint)
+                }
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum EventHubFailure{
+        NoSession,
+        Timeout,
+        LinkCreateFailure(AmqpProtocolError),
+        SendFailure(AmqpProtocolError),
+        ReceiveFailure(AmqpProtocolError),
+        LinkClosed,
+        InvalidResponse,
+        // Status code and description of a failed $management request
+        Rejected(i64, String),
+        NoPartitions,
+        NoCheckpointStore,
+        Checkpoint(CheckpointException),
+        // amqp:link:redirect, the event hub is read on another host
+        Redirect(RedirectTarget),
+        // A redirect without a usable address
+        InvalidRedirect(String),
+        Token(SasTokenCreateException),
+        // Could not connect to the host of the redirect
+        RedirectFailure(AmqpFailure),
+    }
+
+    impl Display for EventHubFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                EventHubFailure::NoSession => write!(f, "NoSession"),
+                EventHubFailure::Timeout => write!(f, "No event received in time."),
+                EventHubFailure::LinkCreateFailure(err) => write!(f, "Failed to create the event hub link: {}", err),
+                EventHubFailure::SendFailure(err) => write!(f, "Failed to send the management request: {}", err),
+                EventHubFailure::ReceiveFailure(err) => write!(f, "Failed to receive on the event hub link: {}", err),
+                EventHubFailure::LinkClosed => write!(f, "The event hub link was closed."),
+                EventHubFailure::InvalidResponse => write!(f, "Invalid response of the $management node."),
+                EventHubFailure::Rejected(status, description) => write!(f, "Management request rejected ({}): {}", status, description),
+                EventHubFailure::NoPartitions => write!(f, "The event hub has no partitions."),
+                EventHubFailure::NoCheckpointStore => write!(f, "No checkpoint store configured."),
+                EventHubFailure::Checkpoint(err) => write!(f, "{}", err),
+                EventHubFailure::Redirect(target) => write!(f, "The event hub link was redirected to {}.", target.address.as_deref().unwrap_or_default()),
+                EventHubFailure::InvalidRedirect(address) => write!(f, "Unusable event hub redirect: {}", address),
+                EventHubFailure::Token(err) => write!(f, "Failed to create the event hub token: {}", err),
+                EventHubFailure::RedirectFailure(err) => write!(f, "Failed to connect to the redirected event hub: {}", err),
+            }
+        }
+    }
+    impl std::error::Error for EventHubFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                EventHubFailure::LinkCreateFailure(err) => Some(err),
+                EventHubFailure::SendFailure(err) => Some(err),
+                EventHubFailure::ReceiveFailure(err) => Some(err),
+                EventHubFailure::Checkpoint(err) => Some(err),
+                EventHubFailure::Token(err) => Some(err),
+                EventHubFailure::RedirectFailure(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// One error type for everything the crate can fail on.
+// The specific failures stay available through the variants and source(),
+// the AMQP errors (conditions and descriptions) are kept inside them.
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use ntex_amqp::error::AmqpProtocolError;
+use crate::amqp::cbs::CbsFailure;
+use crate::amqp::client::{AmqpFailure, ClientRedirectRecovery, GatewayFailure};
+use crate::amqp::config::TlsConfigFailure;
+use crate::amqp::transfer::{MessageBuildFailure, TransferExceptions};
+use crate::amqp::twin::TwinFailure;
+use crate::amqp::methods::MethodFailure;
+use crate::amqp::feedback::FeedbackFailure;
+use crate::amqp::eventhub::EventHubFailure;
+use crate::registry::RegistryFailure;
+use crate::provisioning::ProvisioningFailure;
+use crate::util::connection_string::ConnectionStringException;
+use crate::util::token::{SasTokenCreateException, SasTokenParseException};
+
+#[derive(Debug)]
+pub enum IotHubError
+{
+    Tls(TlsConfigFailure),
+    ConnectionString(ConnectionStringException),
+    TokenCreate(SasTokenCreateException),
+    TokenParse(SasTokenParseException),
+    Connection(AmqpFailure),
+    Transfer(TransferExceptions),
+    Recovery(ClientRedirectRecovery),
+    Cbs(CbsFailure),
+    Gateway(GatewayFailure),
+    Message(MessageBuildFailure),
+    Twin(TwinFailure),
+    Method(MethodFailure),
+    Feedback(FeedbackFailure),
+    EventHub(EventHubFailure),
+    Registry(RegistryFailure),
+    Provisioning(ProvisioningFailure),
+}
+
+impl IotHubError{
+    // The AMQP error reported by the peer or the protocol layer, if any.
+    pub fn amqp_error(&self) -> Option<&AmqpProtocolError> {
+        match self{
+            IotHubError::Connection(AmqpFailure::SessionFailure(err)) => Some(err),
+            IotHubError::Transfer(err) => transfer_amqp_error(err),
+            IotHubError::Recovery(ClientRedirectRecovery::AMQPProtocolFailure(err)) => Some(err),
+            IotHubError::Cbs(err) => cbs_amqp_error(err),
+            IotHubError::Gateway(GatewayFailure::Link(err)) => transfer_amqp_error(err),
+            IotHubError::Gateway(GatewayFailure::Cbs(err)) => cbs_amqp_error(err),
+            IotHubError::Twin(TwinFailure::LinkCreateFailure(err)) => Some(err),
+            IotHubError::Twin(TwinFailure::SendFailure(err)) => Some(err),
+            IotHubError::Twin(TwinFailure::ReceiveFailure(err)) => Some(err),
+            IotHubError::Method(MethodFailure::LinkCreateFailure(err)) => Some(err),
+            IotHubError::Method(MethodFailure::SendFailure(err)) => Some(err),
+            IotHubError::Method(MethodFailure::ReceiveFailure(err)) => Some(err),
+            IotHubError::Feedback(FeedbackFailure::LinkCreateFailure(err)) => Some(err),
+            IotHubError::Feedback(FeedbackFailure::ReceiveFailure(err)) => Some(err),
+            IotHubError::EventHub(EventHubFailure::LinkCreateFailure(err)) => Some(err),
+            IotHubError::EventHub(EventHubFailure::SendFailure(err)) => Some(err),
+            IotHubError::EventHub(EventHubFailure::ReceiveFailure(err)) => Some(err),
+            _ => None
+        }
+    }
+
+    // Whether the same call can succeed later (network, timeouts, a dropped session)
+    // or will keep failing until the configuration or credentials change.
+    pub fn is_retryable(&self) -> bool {
+        match self{
+            IotHubError::Tls(_) | IotHubError::ConnectionString(_) | IotHubError::TokenCreate(_) | IotHubError::TokenParse(_) => false,
+            IotHubError::Message(_) => false,
+            IotHubError::Twin(err) => match err{
+                // 4xx: the request itself is wrong (429 throttling excepted)
+                TwinFailure::Rejected(status) => *status == 429 || *status >= 500,
+                TwinFailure::InvalidBody(_) => false,
+                _ => true
+            },
+            IotHubError::Method(err) => match err{
+                // 404: device offline or unknown, 504: device did not answer in time
+                MethodFailure::Rejected(status, _) => matches!(*status, 404 | 429) || *status >= 500,
+                MethodFailure::Token(_) | MethodFailure::InvalidResponse(_) => false,
+                _ => true
+            },
+            IotHubError::Feedback(err) => !matches!(err, FeedbackFailure::InvalidBody(_)),
+            IotHubError::EventHub(err) => match err{
+                EventHubFailure::Rejected(status, _) => *status == 429 || *status >= 500,
+                EventHubFailure::NoPartitions | EventHubFailure::NoCheckpointStore | EventHubFailure::Checkpoint(_) => false,
+                EventHubFailure::Token(_) | EventHubFailure::InvalidRedirect(_) => false,
+                _ => true
+            },
+            IotHubError::Registry(err) => match err{
+                // 404/409/412: unknown device, taken id or a stale etag
+                RegistryFailure::Rejected(status, _) => *status == 429 || *status >= 500,
+                RegistryFailure::Token(_) | RegistryFailure::InvalidBody(_) | RegistryFailure::NotSymmetricKey => false,
+                RegistryFailure::Http(_) => true
+            },
+            IotHubError::Provisioning(err) => match err{
+                // 401: wrong key or unknown registration id
+                ProvisioningFailure::Rejected(status, _) => *status == 429 || *status >= 500,
+                ProvisioningFailure::Http(_) | ProvisioningFailure::Timeout => true,
+                _ => false
+            },
+            IotHubError::Connection(err) => connection_retryable(err),
+            IotHubError::Transfer(err) => transfer_retryable(err),
+            IotHubError::Recovery(err) => match err{
+                ClientRedirectRecovery::TokenRenewalFailure(_) => false,
+                ClientRedirectRecovery::ReconnectFailure(err) => connection_retryable(err),
+                _ => true
+            },
+            IotHubError::Cbs(err) => cbs_retryable(err),
+            IotHubError::Gateway(err) => match err{
+                GatewayFailure::UnknownDevice | GatewayFailure::Token(_) => false,
+                GatewayFailure::Cbs(err) => cbs_retryable(err),
+                GatewayFailure::Link(err) => transfer_retryable(err),
+            },
+        }
+    }
+}
+
+fn transfer_amqp_error(err: &TransferExceptions) -> Option<&AmqpProtocolError> {
+    match err{
+        TransferExceptions::MessageAmqpProtocolError(err) => Some(err),
+        TransferExceptions::LinkAmqpProtocolError(err) => Some(err),
+        TransferExceptions::LinkCreateFailure(err) => Some(err),
+        _ => None
+    }
+}
+
+fn cbs_amqp_error(err: &CbsFailure) -> Option<&AmqpProtocolError> {
+    match err{
+        CbsFailure::LinkCreateFailure(err) => Some(err),
+        CbsFailure::SendFailure(err) => Some(err),
+        _ => None
+    }
+}
+
+fn connection_retryable(err: &AmqpFailure) -> bool {
+    match err{
+        AmqpFailure::AlreadyActive | AmqpFailure::UnsupportedAuthentication => false,
+        AmqpFailure::FailedSasl(_) | AmqpFailure::SessionFailure(_) => true,
+    }
+}
+
+fn transfer_retryable(err: &TransferExceptions) -> bool {
+    // Already attached links, empty receives, unknown receiver links, a broken offline
+    // queue and settling a pre-settled message are not failures worth repeating.
+    !matches!(err, TransferExceptions::LinkAlreadyActive | TransferExceptions::NoMessage | TransferExceptions::UnknownLink | TransferExceptions::OfflineQueue(_) | TransferExceptions::NotSettleable)
+}
+
+fn cbs_retryable(err: &CbsFailure) -> bool {
+    match err{
+        // 401/403/404: the token or the audience is wrong.
+        CbsFailure::Rejected(code, _) => !matches!(code, 401 | 403 | 404),
+        _ => true
+    }
+}
+
+impl Display for IotHubError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self{
+            IotHubError::Tls(err) => write!(f, "TLS configuration: {}", err),
+            IotHubError::ConnectionString(err) => write!(f, "Connection string: {}", err),
+            IotHubError::TokenCreate(err) => write!(f, "SAS token creation: {}", err),
+            IotHubError::TokenParse(err) => write!(f, "SAS token parsing: {}", err),
+            IotHubError::Connection(err) => write!(f, "Connection: {}", err),
+            IotHubError::Transfer(err) => write!(f, "Transfer: {}", err),
+            IotHubError::Recovery(err) => write!(f, "Recovery: {}", err),
+            IotHubError::Cbs(err) => write!(f, "CBS: {}", err),
+            IotHubError::Gateway(err) => write!(f, "Gateway: {}", err),
+            IotHubError::Message(err) => write!(f, "Message: {}", err),
+            IotHubError::Twin(err) => write!(f, "Twin: {}", err),
+            IotHubError::Method(err) => write!(f, "Direct method: {}", err),
+            IotHubError::Feedback(err) => write!(f, "Feedback: {}", err),
+            IotHubError::EventHub(err) => write!(f, "Event hub: {}", err),
+            IotHubError::Registry(err) => write!(f, "Registry: {}", err),
+            IotHubError::Provisioning(err) => write!(f, "Provisioning: {}", err),
+        }
+    }
+}
+
+impl Error for IotHubError{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self{
+            IotHubError::Tls(err) => Some(err),
+            IotHubError::ConnectionString(err) => Some(err),
+            IotHubError::TokenCreate(err) => Some(err),
+            IotHubError::TokenParse(err) => Some(err),
+            IotHubError::Connection(err) => Some(err),
+            IotHubError::Transfer(err) => Some(err),
+            IotHubError::Recovery(err) => Some(err),
+            IotHubError::Cbs(err) => Some(err),
+            IotHubError::Gateway(err) => Some(err),
+            IotHubError::Message(err) => Some(err),
+            IotHubError::Twin(err) => Some(err),
+            IotHubError::Method(err) => Some(err),
+            IotHubError::Feedback(err) => Some(err),
+            IotHubError::EventHub(err) => Some(err),
+            IotHubError::Registry(err) => Some(err),
+            IotHubError::Provisioning(err) => Some(err),
+        }
+    }
+}
+
+impl From<TlsConfigFailure> for IotHubError{
+    fn from(err: TlsConfigFailure) -> Self {
+        IotHubError::Tls(err)
+    }
+}
+
+impl From<ConnectionStringException> for IotHubError{
+    fn from(err: ConnectionStringException) -> Self {
+        IotHubError::ConnectionString(err)
+    }
+}
+
+impl From<SasTokenCreateException> for IotHubError{
+    fn from(err: SasTokenCreateException) -> Self {
+        IotHubError::TokenCreate(err)
+    }
+}
+
+impl From<SasTokenParseException> for IotHubError{
+    fn from(err: SasTokenParseException) -> Self {
+        IotHubError::TokenParse(err)
+    }
+}
+
+impl From<AmqpFailure> for IotHubError{
+    fn from(err: AmqpFailure) -> Self {
+        IotHubError::Connection(err)
+    }
+}
+
+impl From<TransferExceptions> for IotHubError{
+    fn from(err: TransferExceptions) -> Self {
+        IotHubError::Transfer(err)
+    }
+}
+
+impl From<ClientRedirectRecovery> for IotHubError{
+    fn from(err: ClientRedirectRecovery) -> Self {
+        IotHubError::Recovery(err)
+    }
+}
+
+impl From<CbsFailure> for IotHubError{
+    fn from(err: CbsFailure) -> Self {
+        IotHubError::Cbs(err)
+    }
+}
+
+impl From<GatewayFailure> for IotHubError{
+    fn from(err: GatewayFailure) -> Self {
+        IotHubError::Gateway(err)
+    }
+}
+
+impl From<MessageBuildFailure> for IotHubError{
+    fn from(err: MessageBuildFailure) -> Self {
+        IotHubError::Message(err)
+    }
+}
+
+impl From<TwinFailure> for IotHubError{
+    fn from(err: TwinFailure) -> Self {
+        IotHubError::Twin(err)
+    }
+}
+
+impl From<MethodFailure> for IotHubError{
+    fn from(err: MethodFailure) -> Self {
+        IotHubError::Method(err)
+    }
+}
+
+impl From<FeedbackFailure> for IotHubError{
+    fn from(err: FeedbackFailure) -> Self {
+        IotHubError::Feedback(err)
+    }
+}
+
+impl From<EventHubFailure> for IotHubError{
+    fn from(err: EventHubFailure) -> Self {
+        IotHubError::EventHub(err)
+    }
+}
+
+impl From<RegistryFailure> for IotHubError{
+    fn from(err: RegistryFailure) -> Self {
+        IotHubError::Registry(err)
+    }
+}
+
+impl From<ProvisioningFailure> for IotHubError{
+    fn from(err: ProvisioningFailure) -> Self {
+        IotHubError::Provisioning(err)
+    }
+}
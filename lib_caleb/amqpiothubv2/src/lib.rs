@@ -1,10 +1,1635 @@
 pub mod util;
 pub mod amqp;
+pub mod transport;
+pub mod error;
+pub mod registry;
+pub mod provisioning;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub use ntex_amqp;
 pub use ntex;
 pub use async_std;
 
 #[cfg(test)]
 mod tests {
+    mod token {
+        use chrono::{Duration, TimeZone, Utc};
+        use crate::util::token::{FixedClock, SasToken, SasTokenCreateException, SasTokenParseException};
 
+        // base64("0123456789abcdef0123456789abcdef")
+        const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+        fn clock() -> FixedClock {
+            // 2022-01-01T00:00:00Z
+            FixedClock(Utc.timestamp(1640995200, 0))
+        }
+
+        #[test]
+        fn device_token_known_answer(){
+            // sig = base64(HMAC-SHA256(base64decode(key), "{sr}\n{se}"))
+            let token = SasToken::with_validity(KEY, Duration::days(1), "researchprojecthub", "airquality", &clock())
+                .ok().unwrap();
+            assert_eq!(token.expiry, Utc.timestamp(1641081600, 0));
+            assert_eq!(token.sig, "sig=01ITxhgPXgbaSztqdxdqKACxiOqc8x%2BPCDjqb%2BWLovU%3D");
+            assert_eq!(token.sas, "SharedAccessSignature sr=researchprojecthub.azure-devices.net%2Fdevices%2Fairquality\
+                                   &sig=01ITxhgPXgbaSztqdxdqKACxiOqc8x%2BPCDjqb%2BWLovU%3D&se=1641081600&skn=airquality");
+        }
+
+        #[test]
+        fn service_token_known_answer(){
+            let token = SasToken::service_token_with_validity(KEY, Duration::days(1), "researchprojecthub", "iothubowner", &clock())
+                .ok().unwrap();
+            assert_eq!(token.expiry, Utc.timestamp(1641081600, 0));
+            assert_eq!(token.sas, "SharedAccessSignature sr=researchprojecthub.azure-devices.net\
+                                   &sig=tBlkQmC92Wf4S6k%2Bo2btlDiX8X6EBOB5EgWP%2BumtQbU%3D&se=1641081600&skn=iothubowner");
+        }
+
+        #[test]
+        fn provisioning_token_known_answer(){
+            // Group enrollment: the device key is HMAC-SHA256 of the registration id with the group key.
+            let device_key = SasToken::derive_device_key(KEY, "airquality").ok().unwrap();
+            assert_eq!(device_key, "MHPUdHDe14+3V7Cq0DT43NFb0TH5ojSCPJfuXNLF2U4=");
+            let token = SasToken::provisioning_token(&device_key, Duration::hours(1), "0ne00000001", "airquality", Some("registration"), &clock())
+                .ok().unwrap();
+            assert_eq!(token.sas, "SharedAccessSignature sr=0ne00000001%2Fregistrations%2Fairquality\
+                                   &sig=U3CVxTMSlVGqjS%2Fy5gA4Sd1B8qaAZMTSFVjERXlbtN0%3D&se=1640998800&skn=registration");
+            assert!(SasToken::parse(&token.sas).unwrap().verify(&device_key));
+            assert!(matches!(SasToken::derive_device_key("not base64!", "airquality"), Err(SasTokenCreateException::InvalidPrimaryTokenEncoding)));
+        }
+
+        #[test]
+        fn expiry_follows_validity(){
+            let token = SasToken::with_validity(KEY, Duration::hours(6), "hub", "device", &clock()).ok().unwrap();
+            assert_eq!(token.expiry, Utc.timestamp(1640995200 + 6 * 3600, 0));
+            assert_eq!(SasToken::expiry_from_sas(&token.sas), Some(token.expiry));
+        }
+
+        #[test]
+        fn invalid_input_is_rejected(){
+            assert!(matches!(SasToken::with_validity("not base64!", Duration::days(1), "hub", "device", &clock()),
+                             Err(SasTokenCreateException::InvalidPrimaryTokenEncoding)));
+            assert!(matches!(SasToken::with_validity(KEY, Duration::zero(), "hub", "device", &clock()),
+                             Err(SasTokenCreateException::InvalidValidity)));
+            assert!(matches!(SasToken::sign("not-base64", "x"), Err(SasTokenCreateException::InvalidPrimaryTokenEncoding)));
+            assert!(SasToken::sign(KEY, "x").ok().unwrap().starts_with("sig="));
+        }
+
+        #[test]
+        fn device_token_round_trip(){
+            let token = SasToken::with_validity(KEY, Duration::days(1), "researchprojecthub", "airquality", &clock())
+                .ok().unwrap();
+            let parsed = SasToken::parse(&token.sas).ok().unwrap();
+            assert_eq!(parsed.resource, "researchprojecthub.azure-devices.net%2Fdevices%2Fairquality");
+            assert_eq!(parsed.resource_uri(), "researchprojecthub.azure-devices.net/devices/airquality");
+            assert_eq!(parsed.device_id(), Some("airquality".to_string()));
+            assert_eq!(parsed.key_name, Some("airquality".to_string()));
+            assert_eq!(parsed.expiry, token.expiry);
+            assert!(parsed.verify(KEY));
+            assert!(!parsed.verify("b3RoZXJrZXlvdGhlcmtleW90aGVya2V5b3RoZXJrZXk="));
+            assert!(!parsed.is_expired(&clock()));
+            assert!(parsed.is_expired(&FixedClock(token.expiry)));
+        }
+
+        #[test]
+        fn service_token_round_trip(){
+            let token = SasToken::service_token_with_validity(KEY, Duration::days(1), "researchprojecthub", "iothubowner", &clock())
+                .ok().unwrap();
+            let parsed = SasToken::parse(&token.sas).ok().unwrap();
+            assert_eq!(parsed.resource_uri(), "researchprojecthub.azure-devices.net");
+            assert_eq!(parsed.device_id(), None);
+            assert_eq!(parsed.key_name, Some("iothubowner".to_string()));
+            assert!(parsed.verify(KEY));
+        }
+
+        #[test]
+        fn sovereign_cloud_token(){
+            let token = SasToken::for_host(KEY, Duration::days(1), "researchprojecthub.azure-devices.us", "airquality", &clock())
+                .ok().unwrap();
+            let parsed = SasToken::parse(&token.sas).ok().unwrap();
+            assert_eq!(parsed.resource_uri(), "researchprojecthub.azure-devices.us/devices/airquality");
+            assert!(parsed.verify(KEY));
+        }
+
+        #[test]
+        fn malformed_tokens_are_rejected(){
+            assert!(matches!(SasToken::parse("sr=hub&sig=abc&se=1"), Err(SasTokenParseException::MissingPrefix)));
+            assert!(matches!(SasToken::parse("SharedAccessSignature sr=hub&se=1"), Err(SasTokenParseException::MissingField("sig"))));
+            assert!(matches!(SasToken::parse("SharedAccessSignature sr=hub&sig=YWJj&se=soon"), Err(SasTokenParseException::InvalidExpiry)));
+        }
+    }
+
+    mod endpoint {
+        use crate::amqp::config::{create_address, Endpoint, Transport};
+
+        #[test]
+        fn azure_endpoints(){
+            assert_eq!(create_address("researchprojecthub"), "researchprojecthub.azure-devices.net:5671");
+            let china = Endpoint::with_suffix("researchprojecthub", "azure-devices.cn");
+            assert_eq!(china.address(), "researchprojecthub.azure-devices.cn:5671");
+            assert_eq!(china.hub_name(), "researchprojecthub");
+            let local = Endpoint::custom("broker.local", 5672, Transport::Plain);
+            assert_eq!(local.address(), "broker.local:5672");
+            let websocket = Endpoint::websocket("researchprojecthub");
+            assert_eq!(websocket.address(), "researchprojecthub.azure-devices.net:443");
+            assert_eq!(websocket.transport, Transport::WebSocket);
+        }
+    }
+
+    mod websocket {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+        use tokio_tungstenite::tungstenite::http::HeaderValue;
+        use crate::transport::websocket::{websocket_handshake, websocket_url, WEBSOCKET_PATH, WEBSOCKET_SUBPROTOCOL};
+
+        #[test]
+        fn websocket_urls(){
+            assert_eq!(websocket_url("researchprojecthub.azure-devices.net", 443), "wss://researchprojecthub.azure-devices.net/$iothub/websocket");
+            assert_eq!(websocket_url("localhost", 8443), "wss://localhost:8443/$iothub/websocket");
+        }
+
+        #[tokio::test]
+        async fn bytes_round_trip_through_binary_frames(){
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn(async move {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let callback = |request: &Request, mut response: Response| {
+                    assert_eq!(request.uri().path(), WEBSOCKET_PATH);
+                    assert_eq!(request.headers().get("Sec-WebSocket-Protocol").unwrap(), WEBSOCKET_SUBPROTOCOL);
+                    response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(WEBSOCKET_SUBPROTOCOL));
+                    Ok(response)
+                };
+                let socket = tokio_tungstenite::accept_hdr_async(tcp, callback).await.unwrap();
+                // Echo every frame back
+                let mut stream = crate::transport::websocket::WsStream::new(socket);
+                let mut buffer = [0u8; 64];
+                let read = stream.read(&mut buffer).await.unwrap();
+                stream.write_all(&buffer[..read]).await.unwrap();
+                stream.flush().await.unwrap();
+            });
+            let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let url = format!("ws://127.0.0.1:{}{}", port, WEBSOCKET_PATH);
+            let mut client = websocket_handshake(tcp, &url).await.unwrap();
+            // AMQP protocol header
+            client.write_all(b"AMQP\x00\x01\x00\x00").await.unwrap();
+            client.flush().await.unwrap();
+            let mut header = [0u8; 8];
+            client.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header, b"AMQP\x00\x01\x00\x00");
+            server.await.unwrap();
+        }
+    }
+
+    mod sasl {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use crate::transport::sasl::{decode_sasl_frame, encode_sasl_init, sasl_anonymous_handshake, SaslFailure, SASL_PROTOCOL_HEADER};
+
+        // sasl-mechanisms offering EXTERNAL and ANONYMOUS, as sent by IoT Hub
+        const MECHANISMS: &[u8] = b"\x00\x00\x00\x25\x02\x01\x00\x00\x00\x53\x40\xc0\x18\x01\xe0\x15\x02\xa3\x08EXTERNAL\x09ANONYMOUS";
+
+        fn outcome(code: u8) -> Vec<u8> {
+            vec![0x00, 0x00, 0x00, 0x10, 0x02, 0x01, 0x00, 0x00, 0x00, 0x53, 0x44, 0xc0, 0x03, 0x01, 0x50, code]
+        }
+
+        async fn fake_broker(mut server: tokio::io::DuplexStream, code: u8) -> Vec<u8> {
+            let mut header = [0u8; 8];
+            server.read_exact(&mut header).await.unwrap();
+            assert_eq!(header, SASL_PROTOCOL_HEADER);
+            server.write_all(&SASL_PROTOCOL_HEADER).await.unwrap();
+            server.write_all(MECHANISMS).await.unwrap();
+            let mut init = vec![0u8; encode_sasl_init("ANONYMOUS").len()];
+            server.read_exact(&mut init).await.unwrap();
+            server.write_all(&outcome(code)).await.unwrap();
+            init
+        }
+
+        #[test]
+        fn sasl_init_encoding(){
+            let init = encode_sasl_init("ANONYMOUS");
+            assert_eq!(init.len(), 25);
+            assert_eq!(&init[..8], b"\x00\x00\x00\x19\x02\x01\x00\x00");
+            let (descriptor, fields) = decode_sasl_frame(&init).unwrap();
+            assert_eq!(descriptor, 0x41);
+            assert_eq!(fields, b"\xc0\x0c\x01\xa3\x09ANONYMOUS");
+        }
+
+        #[tokio::test]
+        async fn anonymous_handshake_succeeds(){
+            let (mut client, server) = tokio::io::duplex(256);
+            let broker = tokio::spawn(fake_broker(server, 0));
+            sasl_anonymous_handshake(&mut client).await.unwrap();
+            assert_eq!(broker.await.unwrap(), encode_sasl_init("ANONYMOUS"));
+        }
+
+        #[tokio::test]
+        async fn rejected_handshake_fails(){
+            let (mut client, server) = tokio::io::duplex(256);
+            tokio::spawn(fake_broker(server, 1));
+            let error = sasl_anonymous_handshake(&mut client).await.unwrap_err();
+            let failure = error.get_ref().unwrap().downcast_ref::<SaslFailure>().unwrap();
+            assert_eq!(failure, &SaslFailure::Rejected(1));
+        }
+    }
+
+    mod https {
+        use crate::transport::https::{encode_request, exchange, parse_response, HttpFailure};
+
+        #[test]
+        fn requests_are_encoded(){
+            let request = encode_request("POST", "hub.azure-devices.net", "/twins/airquality/methods?api-version=2021-04-12",
+                                         &[("Authorization", "SharedAccessSignature sr=x")], Some(b"{}"));
+            assert_eq!(String::from_utf8(request).unwrap(),
+                       "POST /twins/airquality/methods?api-version=2021-04-12 HTTP/1.1\r\nHost: hub.azure-devices.net\r\nConnection: close\r\n\
+                        Authorization: SharedAccessSignature sr=x\r\nContent-Length: 2\r\n\r\n{}");
+        }
+
+        #[test]
+        fn responses_are_parsed(){
+            let sized = parse_response(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\ncontent-length: 12\r\n\r\n{\"status\":1}trailing").unwrap();
+            assert_eq!(sized.status, 200);
+            assert_eq!(sized.header("Content-Length"), Some("12"));
+            assert_eq!(sized.body, b"{\"status\":1}".to_vec());
+            assert!(sized.is_success());
+            let chunked = parse_response(b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n").unwrap();
+            assert_eq!(chunked.status, 404);
+            assert_eq!(chunked.body, b"Wikipedia".to_vec());
+            let until_close = parse_response(b"HTTP/1.0 204 No Content\r\n\r\n").unwrap();
+            assert!(until_close.body.is_empty());
+            assert!(matches!(parse_response(b"garbage\r\n\r\n"), Err(HttpFailure::InvalidResponse)));
+            assert!(matches!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort"), Err(HttpFailure::InvalidResponse)));
+        }
+
+        #[tokio::test]
+        async fn exchange_reads_until_close(){
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let server_task = tokio::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                let mut request = vec![0u8; 5];
+                server.read_exact(&mut request).await.unwrap();
+                server.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+                request
+            });
+            let response = exchange(&mut client, b"GET /").await.unwrap();
+            assert_eq!(response.body, b"ok".to_vec());
+            assert_eq!(server_task.await.unwrap(), b"GET /".to_vec());
+        }
+    }
+
+    mod reconnect {
+        use std::time::{Duration, Instant};
+        use crate::util::reconnect::{CircuitBreaker, CircuitState, ReconnectPolicy};
+
+        fn policy() -> ReconnectPolicy {
+            ReconnectPolicy{
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(10),
+                multiplier: 2.0,
+                jitter: 0.5,
+                max_attempts: Some(3),
+                failure_threshold: 3,
+                cooldown: Duration::from_secs(60),
+                ..ReconnectPolicy::default()
+            }
+        }
+
+        #[test]
+        fn exponential_backoff_is_capped(){
+            let policy = policy();
+            assert_eq!(policy.base_delay(1), Duration::from_secs(1));
+            assert_eq!(policy.base_delay(2), Duration::from_secs(2));
+            assert_eq!(policy.base_delay(4), Duration::from_secs(8));
+            assert_eq!(policy.base_delay(5), Duration::from_secs(10));
+            assert_eq!(policy.base_delay(50), Duration::from_secs(10));
+        }
+
+        #[test]
+        fn jitter_stays_within_the_window(){
+            let policy = policy();
+            assert_eq!(policy.delay_with_sample(3, 0.0), Duration::from_secs(2));
+            assert_eq!(policy.delay_with_sample(3, 0.5), Duration::from_secs(4));
+            assert_eq!(policy.delay_with_sample(3, 1.0), Duration::from_secs(6));
+            // Never above the cap
+            assert_eq!(policy.delay_with_sample(4, 1.0), Duration::from_secs(10));
+            for retry in 1..10{
+                let delay = policy.next_delay(retry);
+                assert!(delay >= policy.base_delay(retry) / 2 && delay <= policy.max_delay);
+            }
+            assert!(!policy.attempts_exhausted(2));
+            assert!(policy.attempts_exhausted(3));
+        }
+
+        #[test]
+        fn circuit_opens_and_recovers(){
+            let policy = policy();
+            let start = Instant::now();
+            let mut circuit = CircuitBreaker::default();
+            assert!(circuit.allow(&policy, start));
+            assert_eq!(circuit.record_failure(&policy, start), None);
+            assert_eq!(circuit.record_failure(&policy, start), None);
+            assert_eq!(circuit.record_failure(&policy, start), Some(CircuitState::Open));
+            assert!(!circuit.allow(&policy, start + Duration::from_secs(30)));
+            // After the cooldown one trial attempt is allowed
+            assert!(circuit.allow(&policy, start + Duration::from_secs(60)));
+            assert_eq!(circuit.state(), CircuitState::HalfOpen);
+            // A failed trial reopens immediately
+            assert_eq!(circuit.record_failure(&policy, start + Duration::from_secs(61)), Some(CircuitState::Open));
+            assert!(circuit.allow(&policy, start + Duration::from_secs(121)));
+            assert_eq!(circuit.record_success(), Some(CircuitState::Closed));
+            assert_eq!(circuit.consecutive_failures(), 0);
+            assert_eq!(circuit.record_success(), None);
+        }
+    }
+
+    mod events {
+        use futures::StreamExt;
+        use ntex::util::ByteString;
+        use ntex_amqp::codec::protocol::{AmqpError, Error, ErrorCondition, Fields, LinkError};
+        use ntex_amqp::codec::types::{Symbol, Variant};
+        use ntex_amqp::error::AmqpProtocolError;
+        use crate::amqp::config::Transport;
+        use crate::amqp::eventhub::EventHubLocation;
+        use crate::amqp::transfer::{redirect_target, remote_error, string_variant};
+        use crate::util::events::{ClientEvent, EventBus, RedirectTarget};
+
+        #[test]
+        fn events_reach_every_subscriber(){
+            let events = EventBus::new();
+            let mut first = events.subscribe();
+            let second = events.subscribe();
+            events.emit(ClientEvent::Connected{ host_name: "researchprojecthub.azure-devices.net".to_string() });
+            // Dropped subscribers are forgotten on the next event
+            drop(second);
+            events.emit(ClientEvent::Disconnected{ error: None });
+            assert_eq!(events.subscriber_count(), 1);
+            futures::executor::block_on(async {
+                assert_eq!(first.next().await, Some(ClientEvent::Connected{ host_name: "researchprojecthub.azure-devices.net".to_string() }));
+                assert_eq!(first.next().await, Some(ClientEvent::Disconnected{ error: None }));
+            });
+        }
+
+        #[test]
+        fn redirect_target_is_read_from_the_error_info(){
+            let mut info = Fields::default();
+            info.insert(Symbol::from("hostname"), string_variant("ihsuprodamres.servicebus.windows.net"));
+            info.insert(Symbol::from("network-host"), string_variant("ihsuprodamres.servicebus.windows.net"));
+            info.insert(Symbol::from("port"), Variant::Int(5671));
+            info.insert(Symbol::from("address"), string_variant("amqps://ihsuprodamres.servicebus.windows.net:5671/iothub-ehub-research-1234-abcd/ConsumerGroups/$Default/Partitions/0"));
+            let detached = AmqpProtocolError::LinkDetached(Some(Error{
+                condition: ErrorCondition::LinkError(LinkError::Redirect),
+                description: None,
+                info: Some(info)
+            }));
+            let target = remote_error(&detached).and_then(redirect_target).unwrap();
+            assert_eq!(target, RedirectTarget{
+                host_name: Some("ihsuprodamres.servicebus.windows.net".to_string()),
+                network_host: Some("ihsuprodamres.servicebus.windows.net".to_string()),
+                port: Some(5671),
+                address: Some("amqps://ihsuprodamres.servicebus.windows.net:5671/iothub-ehub-research-1234-abcd/ConsumerGroups/$Default/Partitions/0".to_string())
+            });
+
+            // Any other condition is no redirect, even if the description mentions one.
+            let unauthorized = Error{
+                condition: ErrorCondition::AmqpError(AmqpError::UnauthorizedAccess),
+                description: Some(ByteString::from_static("redirect refused")),
+                info: None
+            };
+            assert_eq!(redirect_target(&unauthorized), None);
+            assert!(remote_error(&AmqpProtocolError::Disconnected).is_none());
+        }
+
+        #[test]
+        fn event_hub_location_is_read_from_the_redirect(){
+            let target = RedirectTarget{
+                host_name: Some("ihsuprodamres.servicebus.windows.net".to_string()),
+                network_host: None,
+                port: Some(5671),
+                address: Some("amqps://ihsuprodamres.servicebus.windows.net:5671/iothub-ehub-research-1234-abcd/ConsumerGroups/$Default/Partitions/0".to_string())
+            };
+            let location = EventHubLocation::from_redirect(&target).unwrap();
+            assert_eq!(location.endpoint.host_name, "ihsuprodamres.servicebus.windows.net");
+            assert_eq!(location.endpoint.port, 5671);
+            assert!(matches!(location.endpoint.transport, Transport::Tls));
+            assert_eq!(location.entity, "iothub-ehub-research-1234-abcd");
+            assert_eq!(location.audience(), "ihsuprodamres.servicebus.windows.net/iothub-ehub-research-1234-abcd");
+            assert_eq!(location.partition_address("$Default", "1"), "iothub-ehub-research-1234-abcd/ConsumerGroups/$Default/Partitions/1");
+
+            // Without the info fields the host and port come from the address.
+            let target = RedirectTarget{
+                address: Some("amqp://127.0.0.1:5673/messages/events".to_string()),
+                ..RedirectTarget::default()
+            };
+            let location = EventHubLocation::from_redirect(&target).unwrap();
+            assert_eq!(location.endpoint.address(), "127.0.0.1:5673");
+            assert!(matches!(location.endpoint.transport, Transport::Plain));
+            assert_eq!(location.entity, "messages/events");
+
+            // No address or no event hub in it, nothing to connect to.
+            assert!(EventHubLocation::from_redirect(&RedirectTarget::default()).is_none());
+            let target = RedirectTarget{
+                address: Some("amqps://ihsuprodamres.servicebus.windows.net/".to_string()),
+                ..RedirectTarget::default()
+            };
+            assert!(EventHubLocation::from_redirect(&target).is_none());
+        }
+    }
+
+    mod queue {
+        use std::io::Write;
+        use std::path::{Path, PathBuf};
+        use chrono::Utc;
+        use crate::util::queue::{EvictionPolicy, OfflineQueue, QueueConfig, QueueException};
+
+        fn queue_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!("amqpiothubv2-{}-{}.jsonl", name, std::process::id()));
+            remove_queue(&path);
+            path
+        }
+
+        fn remove_queue(path: &Path){
+            let _ = std::fs::remove_file(path);
+            let _ = std::fs::remove_file(path.with_extension("jsonl.ack"));
+        }
+
+        fn line_count(path: &Path) -> usize {
+            std::fs::read_to_string(path).unwrap().lines().count()
+        }
+
+        fn config(max_messages: usize, eviction: EvictionPolicy) -> QueueConfig {
+            QueueConfig{ max_messages, max_bytes: 1024, eviction, eviction_batch: 1, compact_after: 1000 }
+        }
+
+        #[test]
+        fn messages_survive_a_restart_in_order(){
+            let path = queue_path("restart");
+            {
+                let mut queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+                queue.push("sender", b"first".to_vec(), Utc::now()).unwrap();
+                queue.push("sender", b"second".to_vec(), Utc::now()).unwrap();
+                assert_eq!(queue.bytes(), 11);
+            }
+            let mut queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+            assert_eq!(queue.len(), 2);
+            let first = queue.pop().unwrap().unwrap();
+            assert_eq!((first.sequence, first.link.as_str(), first.payload.as_slice()), (0, "sender", &b"first"[..]));
+            // Sequence numbers continue after the reopen
+            queue.push("sender", b"third".to_vec(), Utc::now()).unwrap();
+            let mut queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+            assert_eq!(queue.pop().unwrap().unwrap().payload, b"second".to_vec());
+            let third = queue.pop().unwrap().unwrap();
+            assert_eq!(third.sequence, 2);
+            assert!(queue.pop().unwrap().is_none());
+            remove_queue(&path);
+        }
+
+        #[test]
+        fn failed_writes_leave_the_queue_unchanged(){
+            let path = queue_path("failed-writes");
+            let ack_path = path.with_extension("jsonl.ack");
+            let mut queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+            queue.push("sender", b"first".to_vec(), Utc::now()).unwrap();
+            queue.push("sender", b"second".to_vec(), Utc::now()).unwrap();
+            // A directory in place of the ack file makes the ack write fail.
+            std::fs::create_dir(&ack_path).unwrap();
+            assert!(queue.pop().is_err());
+            assert_eq!((queue.len(), queue.bytes()), (2, 11));
+            std::fs::remove_dir(&ack_path).unwrap();
+            assert_eq!(queue.pop().unwrap().unwrap().payload, b"first".to_vec());
+
+            // Same for the queue file itself when appending.
+            std::fs::remove_file(&path).unwrap();
+            std::fs::create_dir(&path).unwrap();
+            assert!(queue.push("sender", b"third".to_vec(), Utc::now()).is_err());
+            assert_eq!((queue.len(), queue.bytes()), (1, 6));
+            std::fs::remove_dir(&path).unwrap();
+            remove_queue(&path);
+        }
+
+        #[test]
+        fn eviction_policies(){
+            let path = queue_path("drop-oldest");
+            let mut queue = OfflineQueue::open(&path, config(2, EvictionPolicy::DropOldest)).unwrap();
+            queue.push("sender", b"1".to_vec(), Utc::now()).unwrap();
+            queue.push("sender", b"2".to_vec(), Utc::now()).unwrap();
+            assert_eq!(queue.push("sender", b"3".to_vec(), Utc::now()).unwrap(), 1);
+            assert_eq!(queue.peek().unwrap().payload, b"2".to_vec());
+            remove_queue(&path);
+
+            let path = queue_path("drop-newest");
+            let mut queue = OfflineQueue::open(&path, config(1, EvictionPolicy::DropNewest)).unwrap();
+            queue.push("sender", b"1".to_vec(), Utc::now()).unwrap();
+            assert_eq!(queue.push("sender", b"2".to_vec(), Utc::now()).unwrap(), 1);
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue.peek().unwrap().payload, b"1".to_vec());
+            remove_queue(&path);
+
+            let path = queue_path("reject");
+            let mut queue = OfflineQueue::open(&path, config(1, EvictionPolicy::Reject)).unwrap();
+            queue.push("sender", b"1".to_vec(), Utc::now()).unwrap();
+            assert!(matches!(queue.push("sender", b"2".to_vec(), Utc::now()), Err(QueueException::QueueFull)));
+            assert!(matches!(queue.push("sender", vec![0; 2048], Utc::now()), Err(QueueException::MessageTooLarge)));
+            remove_queue(&path);
+        }
+
+        #[test]
+        fn corrupt_lines_are_skipped(){
+            let path = queue_path("corrupt");
+            {
+                let mut queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+                queue.push("sender", b"kept".to_vec(), Utc::now()).unwrap();
+            }
+            let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"{\"sequence\":1,\"enq").unwrap();
+            drop(file);
+            let queue = OfflineQueue::open(&path, QueueConfig::default()).unwrap();
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue.peek().unwrap().payload, b"kept".to_vec());
+            remove_queue(&path);
+        }
+
+        #[test]
+        fn delivered_messages_only_move_the_watermark(){
+            let path = queue_path("watermark");
+            let config = QueueConfig{ compact_after: 3, ..QueueConfig::default() };
+            let mut queue = OfflineQueue::open(&path, config.clone()).unwrap();
+            for value in 0..5u8{
+                queue.push("sender", vec![value], Utc::now()).unwrap();
+            }
+            queue.pop().unwrap();
+            queue.pop().unwrap();
+            // The delivered lines are still in the file, the reopen skips them.
+            assert_eq!(line_count(&path), 5);
+            let mut queue = OfflineQueue::open(&path, config.clone()).unwrap();
+            assert_eq!((queue.len(), queue.peek().unwrap().sequence), (3, 2));
+            // The third acked line triggers the compaction.
+            queue.pop().unwrap();
+            assert_eq!(line_count(&path), 2);
+            queue.pop().unwrap();
+            queue.pop().unwrap();
+            assert_eq!(line_count(&path), 0);
+            // Sequences continue after the emptied file.
+            queue.push("sender", b"next".to_vec(), Utc::now()).unwrap();
+            let queue = OfflineQueue::open(&path, config).unwrap();
+            assert_eq!((queue.len(), queue.peek().unwrap().sequence), (1, 5));
+            remove_queue(&path);
+        }
+
+        #[test]
+        fn drop_oldest_evicts_in_batches(){
+            let path = queue_path("evict-batch");
+            let config = QueueConfig{ max_messages: 10, eviction_batch: 4, ..QueueConfig::default() };
+            let mut queue = OfflineQueue::open(&path, config.clone()).unwrap();
+            for value in 0..10u8{
+                queue.push("sender", vec![value], Utc::now()).unwrap();
+            }
+            assert_eq!(queue.push("sender", vec![10], Utc::now()).unwrap(), 4);
+            assert_eq!((queue.len(), queue.peek().unwrap().payload.clone()), (7, vec![4]));
+            // Room for three more before the next eviction
+            for value in 11..14u8{
+                assert_eq!(queue.push("sender", vec![value], Utc::now()).unwrap(), 0);
+            }
+            assert_eq!(queue.push("sender", vec![14], Utc::now()).unwrap(), 4);
+            let queue = OfflineQueue::open(&path, config).unwrap();
+            assert_eq!((queue.len(), queue.peek().unwrap().payload.clone()), (7, vec![8]));
+            remove_queue(&path);
+        }
+    }
+
+    mod checkpoint {
+        use std::path::PathBuf;
+        use chrono::{TimeZone, Utc};
+        use crate::util::checkpoint::{Checkpoint, CheckpointException, CheckpointStore, EventPosition};
+
+        fn store_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!("amqpiothubv2-{}-{}.json", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        #[test]
+        fn positions_become_selectors(){
+            let enqueued = Utc.timestamp_millis(1640995200123);
+            let positions = vec![
+                (EventPosition::Start, "amqp.annotation.x-opt-offset > '-1'"),
+                (EventPosition::End, "amqp.annotation.x-opt-offset > '@latest'"),
+                (EventPosition::Offset("4096".to_string()), "amqp.annotation.x-opt-offset > '4096'"),
+                (EventPosition::EnqueuedAfter(enqueued), "amqp.annotation.x-opt-enqueued-time > '1640995200123'"),
+            ];
+            for (position, selector) in positions{
+                assert_eq!(position.selector(), selector);
+                assert_eq!(EventPosition::from_selector(selector), Some(position));
+            }
+            assert_eq!(EventPosition::from_selector("amqp.annotation.x-opt-sequence-number > 5"), None);
+        }
+
+        #[test]
+        fn checkpoints_survive_a_restart(){
+            let path = store_path("checkpoints");
+            {
+                let mut store = CheckpointStore::open(&path).ok().unwrap();
+                assert_eq!(store.position("$Default", "0", &EventPosition::End), EventPosition::End);
+                let checkpoint = Checkpoint{ offset: "512".to_string(), sequence_number: 3, enqueued_time: None };
+                assert!(store.update("$Default", "0", checkpoint).is_ok());
+            }
+            let store = CheckpointStore::open(&path).ok().unwrap();
+            assert_eq!(store.get("$Default", "0").unwrap().sequence_number, 3);
+            assert_eq!(store.position("$Default", "0", &EventPosition::Start), EventPosition::Offset("512".to_string()));
+            // Checkpoints are per consumer group
+            assert_eq!(store.position("dashboard", "0", &EventPosition::Start), EventPosition::Start);
+            std::fs::write(&path, "{not json").unwrap();
+            assert!(matches!(CheckpointStore::open(&path), Err(CheckpointException::Serialization(_))));
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    mod message_builder {
+        use chrono::{TimeZone, Utc};
+        use ntex_amqp::codec::protocol::TransferBody;
+        use std::time::Instant;
+        use ntex::util::Bytes;
+        use ntex_amqp::codec::{Decode, Message};
+        use ntex_amqp::codec::protocol::MessageId;
+        use crate::amqp::transfer::{create_batch_message, encode_transfer_body, MessageBuilder, MessageBuildFailure, BATCH_CONTENT_TYPE, COMPONENT_NAME_ANNOTATION, CREATION_TIME_ANNOTATION, JSON_CONTENT_TYPE, MAX_MESSAGE_BYTES, string_variant};
+        use crate::util::batch::{BatchConfig, MessageBatch};
+
+        #[test]
+        fn builder_sets_properties_and_annotations(){
+            let created = Utc.ymd(2022, 3, 1).and_hms(12, 0, 0);
+            let body = MessageBuilder::json(&serde_json::json!({"sensor": "airquality", "value": 412.0})).unwrap()
+                .message_id("reading-1")
+                .correlation_id("batch-7")
+                .creation_time(created)
+                .component("co2")
+                .property("alert", "false")
+                .build()
+                .unwrap();
+            let message = match body{
+                TransferBody::Message(message) => message,
+                TransferBody::Data(_) => panic!("Expected a message")
+            };
+            let properties = message.properties().unwrap();
+            assert_eq!(properties.content_type.as_ref().unwrap().as_str(), JSON_CONTENT_TYPE);
+            assert_eq!(properties.content_encoding.as_ref().unwrap().as_str(), "utf-8");
+            assert_eq!(properties.creation_time, Some(created));
+            assert_eq!(message.app_property("alert"), Some(&string_variant("false")));
+            assert_eq!(message.message_annotation(COMPONENT_NAME_ANNOTATION), Some(&string_variant("co2")));
+            assert_eq!(message.message_annotation(CREATION_TIME_ANNOTATION), Some(&string_variant("2022-03-01T12:00:00.000Z")));
+        }
+
+        #[test]
+        fn builder_validates(){
+            assert!(matches!(MessageBuilder::new(vec![0; 300 * 1024]).build(), Err(MessageBuildFailure::TooLarge(_))));
+            assert!(matches!(MessageBuilder::new("{}").property("iothub-connection-device-id", "x").build(), Err(MessageBuildFailure::InvalidPropertyName(_))));
+            assert!(MessageBuilder::new(vec![0; 200 * 1024]).build().is_ok());
+        }
+
+        #[test]
+        fn batch_message_wraps_every_message(){
+            let messages: Vec<Bytes> = ["21.5", "22.0"].iter()
+                .map(|value| Bytes::from(encode_transfer_body(&MessageBuilder::new(*value).message_id(value).build().unwrap())))
+                .collect();
+            let batch = match create_batch_message(messages){
+                TransferBody::Message(message) => message,
+                TransferBody::Data(_) => panic!("Expected a message")
+            };
+            assert_eq!(batch.message_format, Some(0x8001_3700));
+            assert_eq!(batch.properties().unwrap().content_type.as_ref().unwrap().as_str(), BATCH_CONTENT_TYPE);
+            // Every data section is a complete message with its own properties.
+            let sections: Vec<(String, Bytes)> = batch.body.data.iter()
+                .map(|section| {
+                    let (_, message) = Message::decode(section).unwrap();
+                    let message_id = match message.properties().unwrap().message_id.as_ref(){
+                        Some(MessageId::String(id)) => id.to_string(),
+                        other => panic!("Unexpected message id: {:?}", other)
+                    };
+                    (message_id, message.body.data().cloned().unwrap())
+                })
+                .collect();
+            assert_eq!(sections, vec![
+                ("21.5".to_string(), Bytes::from_static(b"21.5")),
+                ("22.0".to_string(), Bytes::from_static(b"22.0"))
+            ]);
+        }
+
+        #[test]
+        fn full_batch_fits_the_message_limit(){
+            let config = BatchConfig::default();
+            let mut batch = MessageBatch::new(config.clone());
+            let message = Bytes::from(encode_transfer_body(&MessageBuilder::new(vec![b'x'; 4000]).build().unwrap()));
+            let now = Instant::now();
+            while batch.fits(message.len()) && batch.len() < config.max_messages{
+                batch.push(message.clone(), message.len(), now);
+            }
+            let encoded = encode_transfer_body(&create_batch_message(batch.take()));
+            assert!(encoded.len() > MAX_MESSAGE_BYTES - 8 * 1024);
+            assert!(encoded.len() <= MAX_MESSAGE_BYTES);
+        }
+    }
+
+    mod settlement {
+        use ntex_amqp::codec::protocol::{DeliveryState, Role};
+        use crate::amqp::transfer::{settlement_disposition, Settlement};
+
+        #[test]
+        fn settlements_map_to_dispositions(){
+            let complete = settlement_disposition(7, &Settlement::Complete);
+            assert_eq!((complete.role, complete.first, complete.settled), (Role::Receiver, 7, true));
+            assert!(matches!(complete.state, Some(DeliveryState::Accepted(_))));
+            assert!(matches!(settlement_disposition(7, &Settlement::Abandon).state, Some(DeliveryState::Released(_))));
+            match settlement_disposition(7, &Settlement::Reject(Some("Unknown action".to_string()))).state{
+                Some(DeliveryState::Rejected(rejected)) => {
+                    assert_eq!(rejected.error.unwrap().description.unwrap().as_ref() as &str, "Unknown action");
+                }
+                other => panic!("Unexpected state: {:?}", other)
+            }
+        }
+    }
+
+    mod twin {
+        use serde_json::json;
+        use crate::amqp::twin::{DesiredUpdate, TwinProperties, twin_address};
+
+        #[test]
+        fn twin_documents(){
+            assert_eq!(twin_address("airquality"), "/devices/airquality/twin");
+            let twin: TwinProperties = serde_json::from_value(json!({
+                "desired": {"measurementInterval": 10, "$version": 4},
+                "reported": {"$version": 2}
+            })).unwrap();
+            assert_eq!(twin.desired_version(), Some(4));
+            assert_eq!(twin.reported_version(), Some(2));
+            assert_eq!(twin.desired["measurementInterval"], 10);
+            let update = DesiredUpdate::from_patch(json!({"alarmThreshold": 1200, "$version": 5}));
+            assert_eq!(update.version, Some(5));
+            assert_eq!(DesiredUpdate::from_patch(json!({})).version, None);
+        }
+    }
+
+    mod methods {
+        use serde_json::json;
+        use crate::amqp::methods::{invoke_path, methods_address, MethodInvocation, MethodResponse};
+
+        #[test]
+        fn method_invocations(){
+            assert_eq!(methods_address("airquality"), "/devices/airquality/methods/devicebound");
+            assert_eq!(invoke_path("air quality"), "/twins/air%20quality/methods?api-version=2021-04-12");
+            let invocation = MethodInvocation{
+                method_name: "buzz".to_string(),
+                payload: json!({"seconds": 2}),
+                response_timeout_in_seconds: 30,
+                connect_timeout_in_seconds: 0
+            };
+            assert_eq!(serde_json::to_value(&invocation).unwrap(), json!({
+                "methodName": "buzz",
+                "payload": {"seconds": 2},
+                "responseTimeoutInSeconds": 30,
+                "connectTimeoutInSeconds": 0
+            }));
+            let response: MethodResponse = serde_json::from_str("{\"status\":200,\"payload\":{\"done\":true}}").unwrap();
+            assert_eq!(response, MethodResponse::ok(json!({"done": true})));
+        }
+    }
+
+    mod feedback {
+        use ntex_amqp::codec::protocol::{MessageId, TransferBody};
+        use crate::amqp::feedback::{ACK_PROPERTY, AckLevel, FeedbackStatus, parse_feedback, request_ack};
+        use crate::amqp::transfer::{MessageBuilder, create_message_from_str, string_app_property};
+
+        #[test]
+        fn feedback_records_are_parsed(){
+            let records = parse_feedback(b"[{\"originalMessageId\":\"c2d-1\",\"description\":\"Success\",\
+                \"deviceGenerationId\":\"6376\",\"deviceId\":\"airquality\",\
+                \"enqueuedTimeUtc\":\"2022-01-01T00:00:00.123Z\",\"statusCode\":\"Success\"},\
+                {\"originalMessageId\":\"c2d-2\",\"deviceId\":\"temperature\",\
+                \"enqueuedTimeUtc\":\"2022-01-01T00:00:01Z\",\"statusCode\":\"DeliveryCountExceeded\"},\
+                {\"originalMessageId\":\"c2d-3\",\"deviceId\":\"temperature\",\
+                \"enqueuedTimeUtc\":\"2022-01-01T00:00:02Z\",\"statusCode\":\"SomethingNew\"}]").unwrap();
+            assert_eq!(records.len(), 3);
+            assert_eq!(records[0].original_message_id, "c2d-1");
+            assert_eq!(records[0].device_id, "airquality");
+            assert_eq!(records[0].status, FeedbackStatus::Success);
+            assert_eq!(records[1].status, FeedbackStatus::DeliveryCountExceeded);
+            assert_eq!(records[2].status, FeedbackStatus::Unknown);
+            assert!(parse_feedback(b"{}").is_err());
+            assert!(AckLevel::Full.reports(&FeedbackStatus::Expired));
+            assert!(AckLevel::Positive.reports(&FeedbackStatus::Success));
+            assert!(!AckLevel::Positive.reports(&FeedbackStatus::Rejected));
+            assert!(!AckLevel::Negative.reports(&FeedbackStatus::Success));
+            assert!(!AckLevel::None.reports(&FeedbackStatus::Purged));
+        }
+
+        #[test]
+        fn acknowledgement_is_requested(){
+            let (body, message_id) = request_ack(create_message_from_str("{\"action\":\"test\"}"), AckLevel::Full);
+            let message = match body{
+                TransferBody::Message(message) => message,
+                other => panic!("Unexpected body: {:?}", other)
+            };
+            assert!(message_id.starts_with("c2d-"));
+            assert_eq!(message.properties.as_ref().unwrap().message_id, Some(MessageId::String(message_id.as_str().into())));
+            assert_eq!(string_app_property(&message, ACK_PROPERTY), Some("full".to_string()));
+            // An id set by the caller is kept.
+            let own = MessageBuilder::new("{}").message_id("buzz-1").build().ok().unwrap();
+            assert_eq!(request_ack(own, AckLevel::Negative).1, "buzz-1");
+        }
+    }
+
+    mod registry {
+        use serde_json::json;
+        use crate::registry::{AuthenticationType, Device, DeviceStatus, device_path, generate_key, list_path};
+
+        #[test]
+        fn device_identities(){
+            assert_eq!(device_path("air quality"), "/devices/air%20quality?api-version=2021-04-12");
+            assert_eq!(list_path(5000), "/devices?top=1000&api-version=2021-04-12");
+            // Keys are left to the hub when none are given.
+            assert_eq!(serde_json::to_value(Device::new("airquality")).unwrap(), json!({
+                "deviceId": "airquality",
+                "status": "enabled",
+                "authentication": {"type": "sas", "symmetricKey": {}},
+                "capabilities": {"iotEdge": false}
+            }));
+            let device: Device = serde_json::from_str("{\"deviceId\":\"temperature\",\"generationId\":\"6376\",\"etag\":\"MQ==\",\
+                \"connectionState\":\"Connected\",\"status\":\"disabled\",\"statusReason\":\"replaced\",\
+                \"cloudToDeviceMessageCount\":0,\"authentication\":{\"symmetricKey\":{\"primaryKey\":\"cHJpbWFyeQ==\",\
+                \"secondaryKey\":\"c2Vjb25kYXJ5\"},\"x509Thumbprint\":{\"primaryThumbprint\":null,\"secondaryThumbprint\":null},\
+                \"type\":\"sas\"},\"capabilities\":{\"iotEdge\":false}}").unwrap();
+            assert_eq!(device.status, DeviceStatus::Disabled);
+            assert_eq!(device.etag.as_deref(), Some("MQ=="));
+            assert_eq!(device.authentication.kind, AuthenticationType::Sas);
+            assert_eq!(device.primary_key(), Some("cHJpbWFyeQ=="));
+            assert_eq!(device.connection_string("hub.azure-devices.net").unwrap(),
+                       "HostName=hub.azure-devices.net;DeviceId=temperature;SharedAccessKey=cHJpbWFyeQ==");
+            assert!(Device::self_signed("camera", "AB12", "CD34").connection_string("hub.azure-devices.net").is_none());
+            assert_eq!(base64::decode(generate_key()).unwrap().len(), 32);
+        }
+    }
+
+    mod provisioning {
+        use crate::provisioning::{operation_path, OperationStatus, register_path, RegistrationStatus};
+
+        #[test]
+        fn registration_operations(){
+            assert_eq!(register_path("0ne00000001", "air quality"), "/0ne00000001/registrations/air%20quality/register?api-version=2021-06-01");
+            assert_eq!(operation_path("0ne00000001", "airquality", "4.abc.1"),
+                       "/0ne00000001/registrations/airquality/operations/4.abc.1?api-version=2021-06-01");
+            let assigning: OperationStatus = serde_json::from_str("{\"operationId\":\"4.abc.1\",\"status\":\"assigning\"}").unwrap();
+            assert_eq!(assigning.status, RegistrationStatus::Assigning);
+            assert!(assigning.registration_state.is_none());
+            let assigned: OperationStatus = serde_json::from_str("{\"operationId\":\"4.abc.1\",\"status\":\"assigned\",\
+                \"registrationState\":{\"registrationId\":\"airquality\",\"createdDateTimeUtc\":\"2022-01-01T00:00:00.1234567Z\",\
+                \"assignedHub\":\"researchprojecthub.azure-devices.net\",\"deviceId\":\"airquality\",\"status\":\"assigned\",\
+                \"substatus\":\"initialAssignment\",\"lastUpdatedDateTimeUtc\":\"2022-01-01T00:00:01Z\",\"etag\":\"IjAwMDAi\"}}").unwrap();
+            let state = assigned.registration_state.unwrap();
+            assert_eq!(state.assigned_hub.as_deref(), Some("researchprojecthub.azure-devices.net"));
+            assert_eq!(state.device_id.as_deref(), Some("airquality"));
+            assert_eq!(state.substatus.as_deref(), Some("initialAssignment"));
+        }
+    }
+
+    mod batch {
+        use std::time::{Duration, Instant};
+        use crate::util::batch::{BATCH_ENVELOPE, BatchConfig, MessageBatch, MAX_BATCH_BYTES, SECTION_OVERHEAD};
+
+        #[test]
+        fn batch_respects_size_and_count(){
+            let mut batch = MessageBatch::new(BatchConfig{ max_bytes: 100, max_messages: 3, window: Duration::from_secs(60) });
+            let now = Instant::now();
+            assert!(batch.fits(100 - SECTION_OVERHEAD));
+            assert!(!batch.fits(101 - SECTION_OVERHEAD));
+            batch.push("first", 40, now);
+            assert_eq!(batch.bytes(), 40 + SECTION_OVERHEAD);
+            assert!(!batch.fits(60));
+            batch.push("second", 10, now);
+            batch.push("third", 10, now);
+            // Count limit reached
+            assert!(!batch.fits(1));
+            assert!(batch.is_due(now));
+            assert_eq!(batch.take(), vec!["first", "second", "third"]);
+            assert!(batch.is_empty());
+            assert_eq!(batch.bytes(), 0);
+        }
+
+        #[test]
+        fn batch_is_due_after_the_window(){
+            let mut batch = MessageBatch::new(BatchConfig{ window: Duration::from_millis(500), ..BatchConfig::default() });
+            let start = Instant::now();
+            assert!(!batch.is_due(start + Duration::from_secs(10)));
+            batch.push(1, 10, start);
+            batch.push(2, 10, start + Duration::from_millis(400));
+            assert!(!batch.is_due(start + Duration::from_millis(499)));
+            // The window starts with the first message
+            assert!(batch.is_due(start + Duration::from_millis(500)));
+            batch.take();
+            batch.push(3, 10, start + Duration::from_secs(1));
+            assert!(!batch.is_due(start + Duration::from_millis(1200)));
+            assert_eq!(BatchConfig::default().max_bytes, MAX_BATCH_BYTES - BATCH_ENVELOPE);
+        }
+    }
+
+    mod error {
+        use std::error::Error;
+        use crate::amqp::client::Client;
+        use crate::amqp::config::TlsConfigFailure;
+        use crate::error::IotHubError;
+        use crate::util::connection_string::ConnectionStringException;
+
+        #[ntex::test]
+        async fn missing_certificate_is_an_error(){
+            let result = Client::new("airquality", "researchprojecthub", "does/not/exist.pem", "", "").await;
+            let error = match result{
+                Ok(_) => panic!("Created a client without a certificate"),
+                Err(error) => error
+            };
+            assert!(matches!(error, IotHubError::Tls(TlsConfigFailure::CertificateNotFound)));
+            assert!(!error.is_retryable());
+            assert!(error.source().is_some());
+        }
+
+        #[ntex::test]
+        async fn invalid_connection_string_is_an_error(){
+            let result = Client::from_connection_string(
+                "HostName=researchprojecthub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=MDEy", "src/root.pem"
+            ).await;
+            assert!(matches!(result, Err(IotHubError::ConnectionString(ConnectionStringException::NotADeviceConnectionString))));
+        }
+    }
+
+    mod connection_string {
+        use crate::util::connection_string::{ConnectionString, ConnectionStringException};
+
+        #[test]
+        fn device_connection_string(){
+            let parsed = ConnectionString::parse(
+                "HostName=researchprojecthub.azure-devices.net;DeviceId=airquality;SharedAccessKey=MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+            ).ok().unwrap();
+            assert_eq!(parsed.host_name, "researchprojecthub.azure-devices.net");
+            assert_eq!(parsed.hub_name(), "researchprojecthub");
+            assert_eq!(parsed.device_id, Some("airquality".to_string()));
+            assert_eq!(parsed.shared_access_key, "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=");
+            assert!(parsed.is_device());
+            assert!(!parsed.to_string().contains("MDEy"));
+        }
+
+        #[test]
+        fn policy_connection_string(){
+            let parsed = ConnectionString::parse(
+                "HostName=researchprojecthub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=;"
+            ).ok().unwrap();
+            assert_eq!(parsed.shared_access_key_name, Some("iothubowner".to_string()));
+            assert!(!parsed.is_device());
+        }
+
+        #[test]
+        fn invalid_connection_strings(){
+            assert!(matches!(ConnectionString::parse("DeviceId=airquality;SharedAccessKey=YWJj"),
+                             Err(ConnectionStringException::MissingField("HostName"))));
+            assert!(matches!(ConnectionString::parse("HostName=hub.azure-devices.net;DeviceId=airquality;SharedAccessKey=not base64!"),
+                             Err(ConnectionStringException::InvalidKey)));
+            assert!(matches!(ConnectionString::parse("HostName=hub.azure-devices.net;SharedAccessKey=YWJj"),
+                             Err(ConnectionStringException::AmbiguousIdentity)));
+            assert!(matches!(ConnectionString::parse("HostName=hub.azure-devices.net;Endpoint=sb://x;SharedAccessKey=YWJj"),
+                             Err(ConnectionStringException::InvalidField(_))));
+            // Module connection strings are refused, not treated as device strings.
+            assert!(matches!(ConnectionString::parse("HostName=hub.azure-devices.net;DeviceId=airquality;ModuleId=sensor;SharedAccessKey=YWJj"),
+                             Err(ConnectionStringException::Unsupported("ModuleId"))));
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    mod mock_hub {
+        use ntex::util::Bytes;
+        use ntex_amqp::codec::{Decode, Message};
+        use ntex_amqp::codec::protocol::TransferBody;
+        use crate::amqp::client::{Client, ServiceClient};
+        use crate::amqp::transfer::{create_directed_message, create_message_from_str, TransferExceptions};
+        use crate::amqp::twin::TwinFailure;
+        use crate::testing::broker::{mock_partition, MockIotHub, MockSettlement};
+        use crate::testing::registry::MockRegistry;
+        use crate::testing::provisioning::MockProvisioning;
+        use crate::provisioning::ProvisioningFailure;
+        use crate::registry::{Device, DeviceStatus, KeyKind, RegistryFailure};
+        use crate::util::token::SasToken;
+
+        // Any valid base64 key of a sensible length will do for the mock broker.
+        const TEST_KEY: &str = "dGVzdGluZ2tleXRlc3RpbmdrZXl0ZXN0aW5na2V5MTI=";
+
+        async fn connected_device(hub: &MockIotHub, device_id: &str) -> Client {
+            let token = match SasToken::new(TEST_KEY, 1, hub.hub_name(), device_id){
+                Ok(token) => token,
+                Err(err) => panic!("Failed SAS: {}", err)
+            };
+            let mut client = Client::new(device_id, hub.hub_name(), "src/root.pem", TEST_KEY, &token.sas).await.unwrap();
+            client.set_endpoint(hub.endpoint());
+            assert!(client.connect().await.is_ok());
+            client
+        }
+
+        async fn connected_service(hub: &MockIotHub) -> ServiceClient {
+            let token = match SasToken::service_token(TEST_KEY, 1, hub.hub_name(), "iothubowner"){
+                Ok(token) => token,
+                Err(err) => panic!("Failed SAS: {}", err)
+            };
+            let mut service = ServiceClient::new(hub.hub_name(), "src/root.pem", TEST_KEY, &token.sas, "iothubowner").await.unwrap();
+            service.set_endpoint(hub.endpoint());
+            assert!(service.connect().await.is_ok());
+            service
+        }
+
+        #[ntex::test]
+        async fn device_telemetry_reaches_the_hub(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert!(client.send_message("sender_link_global", create_message_from_str("{\"sensor\":\"airquality\",\"value\":400.0}"), 5).await.is_ok());
+
+            let telemetry = hub.telemetry();
+            assert_eq!(telemetry.len(), 1);
+            assert_eq!(telemetry[0].device_id, "airquality");
+            assert_eq!(telemetry[0].body, Bytes::from_static(b"{\"sensor\":\"airquality\",\"value\":400.0}"));
+            assert_eq!(hub.logins(), vec!["airquality@sas.mockhub".to_string()]);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn service_message_reaches_the_device(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("recv_link_global", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+
+            let mut service = connected_service(&hub).await;
+            let message = create_directed_message(
+                "{\"action\":\"test\"}".to_string(),
+                "/devices/airquality/messages/devicebound".to_string());
+            assert!(service.send_simple_message(message, "airquality", 5).await.is_ok());
+
+            let transfer = match device.receive_message_listener(0, 5).await{
+                Ok(transfer) => transfer,
+                Err(err) => panic!("No message received: {}", err)
+            };
+            match transfer.body.as_ref(){
+                Some(TransferBody::Data(data)) => {
+                    // The hub hands over the encoded message.
+                    let (_, message) = Message::decode(data).unwrap();
+                    assert_eq!(message.body.data(), Some(&Bytes::from_static(b"{\"action\":\"test\"}")));
+                }
+                other => panic!("Unexpected body: {:?}", other)
+            }
+            assert_eq!(hub.devicebound().len(), 1);
+            assert!(device.complete_message(&transfer).is_ok());
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            assert_eq!(hub.settlements(), vec![("airquality".to_string(), MockSettlement::Completed)]);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn abandoned_messages_are_reported(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("recv_link_global", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+            let mut service = connected_service(&hub).await;
+            for action in ["first", "second"]{
+                let message = create_directed_message(
+                    format!("{{\"action\":\"{}\"}}", action),
+                    "/devices/airquality/messages/devicebound".to_string());
+                assert!(service.send_simple_message(message, "airquality", 5).await.is_ok());
+            }
+            let first = device.receive_message_listener(0, 5).await.ok().unwrap();
+            let second = device.receive_message_listener(0, 5).await.ok().unwrap();
+            assert!(device.abandon_message(&first).is_ok());
+            assert!(device.reject_message(&second, Some("Unknown action")).is_ok());
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            let settlements: Vec<MockSettlement> = hub.settlements().into_iter().map(|(_, settlement)| settlement).collect();
+            assert_eq!(settlements, vec![MockSettlement::Abandoned, MockSettlement::Rejected]);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn receiver_credit_is_topped_up_after_each_delivery(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.set_link_credit("commands", 1).is_ok());
+            assert!(device.attach_receiver("commands", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+
+            let mut service = connected_service(&hub).await;
+            for action in ["first", "second", "third"]{
+                let message = create_directed_message(
+                    format!("{{\"action\":\"{}\"}}", action),
+                    "/devices/airquality/messages/devicebound".to_string());
+                assert!(service.send_simple_message(message, "airquality", 5).await.is_ok());
+            }
+            // One credit at a time, every message still arrives.
+            for _ in 0..3{
+                let transfer = device.receive_from("commands", 5).await.ok().unwrap();
+                assert!(device.complete_message(&transfer).is_ok());
+            }
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn receive_by_name_and_from_all_links(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("commands", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+            assert!(device.attach_receiver("buzzer", "/devices/buzzer/messages/devicebound", 5).await.is_ok());
+            assert!(matches!(device.attach_receiver("buzzer", "/devices/buzzer/messages/devicebound", 5).await, Err(TransferExceptions::LinkAlreadyActive)));
+            assert_eq!(device.receiver_link_names(), vec!["commands".to_string(), "buzzer".to_string()]);
+            assert!(device.set_link_credit("buzzer", 10).is_ok());
+
+            let mut service = connected_service(&hub).await;
+            let message = create_directed_message("{\"action\":\"test\"}".to_string(), "/devices/buzzer/messages/devicebound".to_string());
+            assert!(service.send_simple_message(message, "buzzer", 5).await.is_ok());
+
+            // Nothing for the first link, the message waits on the second one.
+            assert!(matches!(device.receive_message_listener(0, 1).await, Err(TransferExceptions::NoMessage)));
+            assert!(device.receive_from("buzzer", 5).await.is_ok());
+
+            let message = create_directed_message("{\"action\":\"test\"}".to_string(), "/devices/airquality/messages/devicebound".to_string());
+            assert!(service.send_simple_message(message, "airquality", 5).await.is_ok());
+            let (name, _) = device.receive_any(5).await.ok().unwrap();
+            assert_eq!(name, "commands");
+            assert!(matches!(device.receive_from("unknown", 1).await, Err(TransferExceptions::UnknownLink)));
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn twin_get_patch_and_desired_updates(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            let twin = device.get_twin(5).await.ok().unwrap();
+            assert_eq!(twin.desired_version(), Some(1));
+
+            let version = device.update_reported_properties(&serde_json::json!({"measurementInterval": 10}), 5).await.ok().unwrap();
+            assert_eq!(version, Some(2));
+            assert_eq!(hub.twin("airquality").unwrap().reported["measurementInterval"], 10);
+            assert!(matches!(device.update_reported_properties(&serde_json::json!("not an object"), 5).await, Err(TwinFailure::Rejected(400))));
+
+            hub.update_desired("airquality", serde_json::json!({"alarmThreshold": 1200}));
+            assert!(device.subscribe_desired_properties(5).await.is_ok());
+            let update = device.next_desired_update(5).await.ok().unwrap();
+            assert_eq!(update.version, Some(2));
+            assert_eq!(update.patch["alarmThreshold"], 1200);
+            let twin = device.get_twin(5).await.ok().unwrap();
+            assert_eq!(twin.desired["alarmThreshold"], 1200);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn direct_methods_are_answered(){
+            use crate::amqp::methods::MethodResponse;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            device.register_method("buzz", |payload| async move {
+                MethodResponse::ok(serde_json::json!({"buzzed": payload["seconds"]}))
+            });
+            let buzz = hub.queue_method("airquality", "buzz", serde_json::json!({"seconds": 2}));
+            let unknown = hub.queue_method("airquality", "reboot", serde_json::Value::Null);
+            assert_eq!(device.handle_method_request(5).await.ok().unwrap(), Some("buzz".to_string()));
+            assert_eq!(device.handle_method_request(5).await.ok().unwrap(), Some("reboot".to_string()));
+            assert_eq!(device.handle_method_request(1).await.ok().unwrap(), None);
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            let responses = hub.method_responses();
+            assert_eq!(responses.len(), 2);
+            assert_eq!((responses[0].correlation_id.as_str(), responses[0].status), (buzz.as_str(), 200));
+            assert_eq!(responses[0].payload, serde_json::json!({"buzzed": 2}));
+            assert_eq!((responses[1].correlation_id.as_str(), responses[1].status), (unknown.as_str(), 404));
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn delivery_feedback_is_reported(){
+            use crate::amqp::feedback::{FeedbackFailure, FeedbackStatus};
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("recv_link_global", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+            let mut service = connected_service(&hub).await;
+            let mut sent = Vec::new();
+            for action in ["buzz", "unknown"]{
+                let message = create_directed_message(
+                    format!("{{\"action\":\"{}\"}}", action),
+                    "/devices/airquality/messages/devicebound".to_string());
+                sent.push(service.send_simple_message(message, "airquality", 5).await.ok().unwrap());
+            }
+            let buzz = device.receive_message_listener(0, 5).await.ok().unwrap();
+            let unknown = device.receive_message_listener(0, 5).await.ok().unwrap();
+            assert!(device.complete_message(&buzz).is_ok());
+            assert!(device.reject_message(&unknown, Some("Unknown action")).is_ok());
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+
+            // Asked out of order: the record of the first message waits until it is wanted.
+            let rejected = service.wait_for_feedback(&sent[1], 5).await.ok().unwrap();
+            assert_eq!(rejected.status, FeedbackStatus::Rejected);
+            assert_eq!(rejected.device_id, "airquality");
+            let success = service.next_feedback(5).await.ok().unwrap();
+            assert_eq!((success.original_message_id.as_str(), success.status), (sent[0].as_str(), FeedbackStatus::Success));
+            assert!(matches!(service.next_feedback(1).await, Err(FeedbackFailure::Timeout)));
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn service_handle_is_shared_between_threads(){
+            use crate::amqp::client::ServiceClientHandle;
+            use crate::amqp::feedback::{FeedbackFailure, FeedbackStatus};
+            fn shareable<T: Send + Sync + Clone + 'static>(_: &T){}
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("recv_link_global", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+            let connection_string = format!("HostName=mockhub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey={}", TEST_KEY);
+            let service = ServiceClientHandle::start_with_endpoint(&connection_string, "src/root.pem", Some(hub.endpoint())).await.ok().unwrap();
+            shareable(&service);
+
+            let mut sent = Vec::new();
+            for _ in 0..2{
+                let message = create_directed_message("{\"action\":\"test\"}".to_string(), "/devices/airquality/messages/devicebound".to_string());
+                sent.push(service.clone().send_message(message, "airquality", 5).await.ok().unwrap());
+            }
+            for _ in 0..2{
+                let transfer = device.receive_message_listener(0, 5).await.ok().unwrap();
+                assert!(device.complete_message(&transfer).is_ok());
+            }
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            assert_eq!(hub.devicebound().len(), 2);
+            let record = service.wait_for_feedback(&sent[1], 5).await.ok().unwrap();
+            assert_eq!(record.status, FeedbackStatus::Success);
+
+            // A wait for feedback that never comes does not hold up a send.
+            let waiting = service.wait_for_feedback("unknown", 3);
+            let sending = async {
+                let started = std::time::Instant::now();
+                let message = create_directed_message("{\"action\":\"test\"}".to_string(), "/devices/airquality/messages/devicebound".to_string());
+                let sent = service.send_message(message, "airquality", 5).await;
+                (sent, started.elapsed())
+            };
+            let (waited, (sent, elapsed)) = futures::join!(waiting, sending);
+            assert!(matches!(waited, Err(FeedbackFailure::Timeout)));
+            assert!(sent.is_ok());
+            assert!(elapsed < std::time::Duration::from_secs(2));
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn telemetry_is_read_from_the_event_hub_endpoint(){
+            use crate::amqp::eventhub::{DEFAULT_CONSUMER_GROUP, EventHubFailure};
+            use crate::util::checkpoint::{CheckpointStore, EventPosition};
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut airquality = connected_device(&hub, "airquality").await;
+            let mut temperature = connected_device(&hub, "temperature").await;
+            assert!(airquality.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert!(temperature.attach_sender("sender_link_global", "/devices/temperature/messages/events", 5).await.is_ok());
+            for value in [400, 410]{
+                let body = format!("{{\"sensor\":\"airquality\",\"value\":{}}}", value);
+                assert!(airquality.send_message("sender_link_global", create_message_from_str(&body), 5).await.is_ok());
+            }
+            assert!(temperature.send_message("sender_link_global", create_message_from_str("{\"sensor\":\"temperature\",\"value\":21}"), 5).await.is_ok());
+
+            let mut service = connected_service(&hub).await;
+            assert_eq!(service.partition_ids(5).await.ok().unwrap(), vec!["0".to_string(), "1".to_string()]);
+
+            let path = std::env::temp_dir().join(format!("amqpiothubv2-eventhub-{}.json", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let store = CheckpointStore::open(&path).ok().unwrap();
+            let mut consumer = service.event_consumer(DEFAULT_CONSUMER_GROUP, EventPosition::Start, Some(store), 5).await.ok().unwrap();
+            let mut values = Vec::new();
+            for _ in 0..3{
+                let event = consumer.next_event(5).await.ok().unwrap();
+                assert!(event.enqueued_time.is_some());
+                let json = event.json().unwrap();
+                assert_eq!(event.device_id.as_deref(), json["sensor"].as_str());
+                values.push(json["value"].as_i64().unwrap());
+                assert!(consumer.checkpoint(&event).is_ok());
+            }
+            values.sort();
+            assert_eq!(values, vec![21, 400, 410]);
+            assert!(matches!(consumer.next_event(1).await, Err(EventHubFailure::Timeout)));
+            consumer.close().await;
+
+            // A new consumer continues after the checkpoints.
+            assert!(airquality.send_message("sender_link_global", create_message_from_str("{\"sensor\":\"airquality\",\"value\":420}"), 5).await.is_ok());
+            let store = CheckpointStore::open(&path).ok().unwrap();
+            let mut consumer = service.event_consumer(DEFAULT_CONSUMER_GROUP, EventPosition::Start, Some(store), 5).await.ok().unwrap();
+            let event = consumer.next_event(5).await.ok().unwrap();
+            assert_eq!(event.json().unwrap()["value"], 420);
+            assert_eq!(event.sequence_number, 2);
+            assert!(matches!(consumer.next_event(1).await, Err(EventHubFailure::Timeout)));
+            let _ = std::fs::remove_file(&path);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn event_readers_follow_the_hub_redirect(){
+            use crate::amqp::eventhub::{DEFAULT_CONSUMER_GROUP, EventHubFailure};
+            use crate::util::checkpoint::EventPosition;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            // Stand-in for the Event Hubs namespace, the telemetry is stored there.
+            let event_hub = MockIotHub::start("ihsuprodamres").await.unwrap();
+            hub.redirect_event_hub(&event_hub, "messages/events");
+            let mut airquality = connected_device(&event_hub, "airquality").await;
+            assert!(airquality.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert!(airquality.send_message("sender_link_global", create_message_from_str("{\"sensor\":\"airquality\",\"value\":400}"), 5).await.is_ok());
+
+            let mut service = connected_service(&hub).await;
+            let mut consumer = service.event_consumer(DEFAULT_CONSUMER_GROUP, EventPosition::Start, None, 5).await.ok().unwrap();
+            let event = consumer.next_event(5).await.ok().unwrap();
+            assert_eq!(event.device_id.as_deref(), Some("airquality"));
+            assert_eq!(event.json().unwrap()["value"], 400);
+            assert!(matches!(consumer.next_event(1).await, Err(EventHubFailure::Timeout)));
+            consumer.close().await;
+            // The service logged in on the namespace with its own policy.
+            assert!(event_hub.logins().contains(&"iothubowner@sas.root.mockhub".to_string()));
+
+            // After a reconnect the redirect is followed again.
+            assert!(service.reconnect().await.is_ok());
+            assert_eq!(service.partition_ids(5).await.ok().unwrap(), vec!["0".to_string(), "1".to_string()]);
+            let mut receiver = service.partition_receiver(DEFAULT_CONSUMER_GROUP, mock_partition("airquality"), &EventPosition::Start, 5).await.ok().unwrap();
+            assert_eq!(receiver.next_event(5).await.ok().unwrap().json().unwrap()["value"], 400);
+            receiver.close().await;
+            event_hub.stop().await;
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn lifecycle_events_are_reported(){
+            use futures::StreamExt;
+            use crate::util::events::ClientEvent;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let token = SasToken::new(TEST_KEY, 1, hub.hub_name(), "airquality").ok().unwrap();
+            let mut client = Client::new("airquality", hub.hub_name(), "src/root.pem", TEST_KEY, &token.sas).await.unwrap();
+            client.set_endpoint(hub.endpoint());
+            let mut events = client.subscribe_events();
+            assert!(client.connect().await.is_ok());
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert_eq!(events.next().await, Some(ClientEvent::Connected{ host_name: "127.0.0.1".to_string() }));
+            assert_eq!(events.next().await, Some(ClientEvent::LinkAttached{
+                name: "sender_link_global".to_string(),
+                address: "/devices/airquality/messages/events".to_string()
+            }));
+            hub.stop().await;
+            assert!(matches!(events.next().await, Some(ClientEvent::Disconnected{ .. })));
+        }
+
+        #[ntex::test]
+        async fn detach_by_the_hub_is_reported(){
+            use futures::StreamExt;
+            use ntex::util::ByteString;
+            use ntex_amqp::codec::protocol::{Error, ErrorCondition, LinkError};
+            use crate::util::events::ClientEvent;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut device = connected_device(&hub, "airquality").await;
+            assert!(device.attach_receiver("recv_link_global", "/devices/airquality/messages/devicebound", 5).await.is_ok());
+            let mut events = device.subscribe_events();
+
+            // No send or receive needed, the detach alone is reported.
+            hub.detach_link("/devices/airquality/messages/devicebound", Some(Error{
+                condition: ErrorCondition::LinkError(LinkError::DetachForced),
+                description: Some(ByteString::from_static("Link idle")),
+                info: None
+            }));
+            match events.next().await{
+                Some(ClientEvent::LinkDetached{ name, error }) => {
+                    assert_eq!(name, "recv_link_global");
+                    assert!(error.unwrap().contains("DetachForced"));
+                }
+                other => panic!("Unexpected event: {:?}", other)
+            }
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn cbs_put_token_is_accepted(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_cbs(5).await.is_ok());
+            let token = SasToken::new(TEST_KEY, 1, hub.hub_name(), "temperature").ok().unwrap();
+            assert!(client.put_token("localhost/devices/temperature", &token.sas, 5).await.is_ok());
+            assert_eq!(hub.cbs_audiences(), vec!["localhost/devices/temperature".to_string()]);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn token_renewal_refreshes_over_cbs_inside_the_margin(){
+            use futures::StreamExt;
+            use crate::util::events::ClientEvent;
+            use crate::util::token::FixedClock;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_cbs(5).await.is_ok());
+            client.set_token_renewal(chrono::Duration::minutes(2), chrono::Duration::minutes(1));
+            let expiry = client.token_expiry().unwrap();
+
+            // Outside the margin nothing happens.
+            client.set_clock(Box::new(FixedClock(expiry - chrono::Duration::minutes(2))));
+            assert!(!client.token_needs_renewal());
+            assert_eq!(client.renew_token_if_due().await.ok(), Some(false));
+            assert!(hub.cbs_audiences().is_empty());
+
+            // Inside the margin the token is put on $cbs, the session stays up.
+            let now = expiry - chrono::Duration::seconds(30);
+            client.set_clock(Box::new(FixedClock(now)));
+            assert!(client.token_needs_renewal());
+            let mut events = client.subscribe_events();
+            assert_eq!(client.renew_token_if_due().await.ok(), Some(true));
+            let new_expiry = now + chrono::Duration::minutes(2);
+            assert_eq!(client.token_expiry(), Some(new_expiry));
+            assert_eq!(events.next().await, Some(ClientEvent::TokenRefreshed{ expiry: new_expiry }));
+            assert_eq!(hub.cbs_audiences(), vec!["127.0.0.1/devices/airquality".to_string()]);
+            assert_eq!(hub.logins().len(), 1);
+            assert!(!client.token_needs_renewal());
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn token_renewal_without_cbs_reconnects(){
+            use futures::StreamExt;
+            use crate::util::events::ClientEvent;
+            use crate::util::token::FixedClock;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            client.set_token_renewal(chrono::Duration::minutes(2), chrono::Duration::minutes(1));
+            let now = client.token_expiry().unwrap() - chrono::Duration::seconds(30);
+            client.set_clock(Box::new(FixedClock(now)));
+            let mut events = client.subscribe_events();
+            assert_eq!(client.renew_token_if_due().await.ok(), Some(true));
+            assert_eq!(events.next().await, Some(ClientEvent::TokenRefreshed{ expiry: now + chrono::Duration::minutes(2) }));
+
+            // A second login with the new token, the sender link is usable again.
+            assert_eq!(hub.logins().len(), 2);
+            assert!(hub.cbs_audiences().is_empty());
+            assert!(client.send_message("sender_link_global", create_message_from_str("400"), 5).await.is_ok());
+            assert_eq!(hub.telemetry().len(), 1);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn batched_messages_arrive_one_by_one(){
+            use crate::util::batch::BatchConfig;
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            client.enable_batching(BatchConfig{ max_messages: 2, window: std::time::Duration::from_secs(60), ..BatchConfig::default() });
+            assert!(client.send_batched("sender_link_global", create_message_from_str("400"), 5).await.is_ok());
+            assert!(hub.telemetry().is_empty());
+            // The second message fills the batch, both go out in one transfer.
+            assert!(client.send_batched("sender_link_global", create_message_from_str("410"), 5).await.is_ok());
+            let bodies: Vec<Bytes> = hub.telemetry().into_iter().map(|telemetry| telemetry.body).collect();
+            assert_eq!(bodies, vec![Bytes::from_static(b"400"), Bytes::from_static(b"410")]);
+
+            // Up to the size the sender link accepts
+            assert!(client.send_message("sender_link_global", create_message_from_str(&"x".repeat(100 * 1024)), 5).await.is_ok());
+            assert_eq!(hub.telemetry().len(), 3);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn reconnect_restores_the_session_and_links(){
+            let mut hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert!(client.send_message("sender_link_global", create_message_from_str("400"), 5).await.is_ok());
+
+            // The hub goes away and comes back on the same port.
+            assert!(hub.restart().await.is_ok());
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            assert!(client.reconnect().await.is_ok());
+            assert_eq!(hub.logins().len(), 2);
+
+            // Same link name as before the restart.
+            assert!(client.send_message("sender_link_global", create_message_from_str("410"), 5).await.is_ok());
+            let telemetry = hub.telemetry();
+            assert_eq!(telemetry.len(), 2);
+            assert_eq!(telemetry[1].body, Bytes::from_static(b"410"));
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn offline_messages_are_replayed_in_order_after_a_reconnect(){
+            use chrono::{DateTime, Utc};
+            use crate::util::queue::QueueConfig;
+            let path = std::env::temp_dir().join(format!("amqpiothubv2-store-forward-{}.jsonl", std::process::id()));
+            let ack_path = path.with_extension("jsonl.ack");
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(&ack_path);
+            let mut hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut client = connected_device(&hub, "airquality").await;
+            assert!(client.enable_offline_queue(&path, QueueConfig::default()).is_ok());
+            assert!(client.attach_sender("sender_link_global", "/devices/airquality/messages/events", 5).await.is_ok());
+            assert!(client.send_message("sender_link_global", create_message_from_str("400"), 5).await.is_ok());
+
+            // The hub drops the connection, both messages end up in the queue.
+            assert!(hub.restart().await.is_ok());
+            async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+            let taken_at = Utc::now();
+            assert!(client.send_message("sender_link_global", create_message_from_str("410"), 5).await.is_ok());
+            assert!(client.send_message("sender_link_global", create_message_from_str("420"), 5).await.is_ok());
+            assert_eq!(client.queued_messages(), 2);
+
+            async_std::task::sleep(std::time::Duration::from_millis(1100)).await;
+            assert!(client.reconnect().await.is_ok());
+            let reconnected_at = Utc::now();
+            // The next send replays the queue first.
+            assert!(client.send_message("sender_link_global", create_message_from_str("430"), 5).await.is_ok());
+            assert_eq!(client.queued_messages(), 0);
+
+            let telemetry = hub.telemetry();
+            let bodies: Vec<Bytes> = telemetry.iter().map(|telemetry| telemetry.body.clone()).collect();
+            assert_eq!(bodies, vec![Bytes::from_static(b"400"), Bytes::from_static(b"410"), Bytes::from_static(b"420"), Bytes::from_static(b"430")]);
+            let creation_times: Vec<DateTime<Utc>> = telemetry.iter()
+                .map(|telemetry| DateTime::parse_from_rfc3339(telemetry.creation_time.as_deref().unwrap()).unwrap().with_timezone(&Utc))
+                .collect();
+            // Replayed messages keep the time they were taken, not the time of the replay.
+            assert!(creation_times[1] >= taken_at - chrono::Duration::milliseconds(1) && creation_times[1] < reconnected_at);
+            assert!(creation_times[2] < reconnected_at);
+            assert!(creation_times[3] >= reconnected_at - chrono::Duration::milliseconds(1));
+            hub.stop().await;
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(&ack_path);
+        }
+
+        #[ntex::test]
+        async fn gateway_routes_messages_per_device(){
+            let hub = MockIotHub::start("mockhub").await.unwrap();
+            let mut gateway = connected_device(&hub, "gateway").await;
+            assert!(gateway.add_gateway_device("airquality", TEST_KEY, false, 5).await.is_ok());
+            assert!(gateway.add_gateway_device("temperature", TEST_KEY, false, 5).await.is_ok());
+            assert!(gateway.send_device_message("temperature", create_message_from_str("21.5"), 5).await.is_ok());
+            assert!(gateway.send_device_message("airquality", create_message_from_str("400"), 5).await.is_ok());
+            assert!(gateway.send_device_message("unknown", create_message_from_str("0"), 5).await.is_err());
+
+            let telemetry = hub.telemetry();
+            assert_eq!(telemetry.len(), 2);
+            assert_eq!(telemetry[0].device_id, "temperature");
+            assert_eq!(telemetry[1].device_id, "airquality");
+            // A single connection carried both devices.
+            assert_eq!(hub.logins().len(), 1);
+            hub.stop().await;
+        }
+
+        #[ntex::test]
+        async fn registry_manages_device_identities(){
+            let registry = MockRegistry::start().unwrap();
+            let client = registry.client(TEST_KEY, "registryReadWrite");
+            let created = client.create_device(&Device::new("airquality")).await.unwrap();
+            assert!(created.is_enabled());
+            assert!(created.primary_key().is_some());
+            assert!(client.create_device(&Device::with_keys("temperature", TEST_KEY, TEST_KEY)).await.is_ok());
+            // The id is taken now.
+            match client.create_device(&Device::new("airquality")).await{
+                Err(RegistryFailure::Rejected(409, _)) => {}
+                other => panic!("Unexpected result: {:?}", other)
+            }
+            let devices = client.list_devices(10).await.unwrap();
+            assert_eq!(devices.iter().map(|device| device.device_id.as_str()).collect::<Vec<_>>(), vec!["airquality", "temperature"]);
+
+            let disabled = client.disable_device("airquality", Some("maintenance")).await.unwrap();
+            assert_eq!(disabled.status, DeviceStatus::Disabled);
+            assert_eq!(registry.device("airquality").unwrap()["status"], "disabled");
+            // An update based on the identity as it was before is refused.
+            match client.update_device(&created).await{
+                Err(RegistryFailure::Rejected(412, _)) => {}
+                other => panic!("Unexpected result: {:?}", other)
+            }
+
+            let regenerated = client.regenerate_key("temperature", KeyKind::Primary).await.unwrap();
+            assert_ne!(regenerated.primary_key(), Some(TEST_KEY));
+            assert_eq!(regenerated.secondary_key(), Some(TEST_KEY));
+
+            assert!(client.delete_device("airquality", disabled.etag.as_deref()).await.is_ok());
+            assert!(client.get_device("airquality").await.unwrap_err().is_not_found());
+            assert!(registry.requests().iter().all(|request| request.contains("api-version=")));
+            registry.stop();
+        }
+
+        #[ntex::test]
+        async fn devices_are_provisioned_through_dps(){
+            let dps = MockProvisioning::start("0ne00000001", "researchprojecthub.azure-devices.net", TEST_KEY).unwrap();
+            dps.set_polls_before_assignment(2);
+            let device_key = SasToken::derive_device_key(TEST_KEY, "airquality").ok().unwrap();
+            let mut client = dps.client("airquality", &device_key);
+            client.set_poll_interval(std::time::Duration::from_millis(20));
+            let device = client.register(None).await.unwrap();
+            assert_eq!(device.device_id, "airquality");
+            assert_eq!(device.hub_name(), "researchprojecthub");
+            assert_eq!(device.endpoint().host_name, "researchprojecthub.azure-devices.net");
+            assert_eq!(device.connection_string().unwrap(),
+                       format!("HostName=researchprojecthub.azure-devices.net;DeviceId=airquality;SharedAccessKey={}", device_key));
+            assert_eq!(dps.assigned(), vec!["airquality".to_string()]);
+
+            // The group key itself is not the key of the device.
+            match dps.client("temperature", TEST_KEY).register(None).await{
+                Err(ProvisioningFailure::Rejected(401, _)) => {}
+                other => panic!("Unexpected result: {:?}", other.map(|device| device.device_id))
+            }
+            dps.disable_enrollment("temperature");
+            let temperature_key = SasToken::derive_device_key(TEST_KEY, "temperature").ok().unwrap();
+            assert!(matches!(dps.client("temperature", &temperature_key).register(None).await, Err(ProvisioningFailure::Disabled)));
+
+            // A registration that never completes runs into the timeout.
+            dps.set_polls_before_assignment(1000);
+            let mut slow = dps.client("airquality", &device_key);
+            slow.set_poll_interval(std::time::Duration::from_millis(20));
+            slow.set_timeout(std::time::Duration::from_millis(200));
+            assert!(matches!(slow.register(None).await, Err(ProvisioningFailure::Timeout)));
+            dps.stop();
+        }
+    }
 }
@@ -0,0 +1,393 @@
+// Device Provisioning Service (DPS) over its REST endpoint. A device registers with its
+// enrollment (symmetric key or X.509), DPS answers with an operation that is polled until
+// the device is assigned to a hub. The result feeds straight into Client.
+// Keys of group enrollments are derived from the group key and the registration id.
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::amqp::client::{Client, load_tls_config};
+use crate::amqp::config::{AMQPS_PORT, create_x509_tls_config, Endpoint, read_certificate, read_client_identity, TlsConfigFailure, Transport};
+use crate::error::IotHubError;
+use crate::transport::https::{encode_request, http_request, https_request, HttpFailure, HttpResponse, HTTPS_PORT};
+use crate::util::token::{SasToken, SasTokenCreateException, SystemClock};
+
+pub const GLOBAL_ENDPOINT: &str = "global.azure-devices-provisioning.net";
+pub const PROVISIONING_API_VERSION: &str = "2021-06-01";
+// Key name in the registration token
+pub const REGISTRATION_KEY_NAME: &str = "registration";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(3);
+const DEFAULT_REGISTRATION_TIMEOUT: Duration = Duration::from_secs(120);
+// Upper bound of a single HTTP request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn register_path(id_scope: &str, registration_id: &str) -> String {
+    format!("/{}/registrations/{}/register?api-version={}", id_scope, urlencoding::encode(registration_id), PROVISIONING_API_VERSION)
+}
+
+pub fn operation_path(id_scope: &str, registration_id: &str, operation_id: &str) -> String {
+    format!("/{}/registrations/{}/operations/{}?api-version={}", id_scope, urlencoding::encode(registration_id),
+            urlencoding::encode(operation_id), PROVISIONING_API_VERSION)
+}
+
+// How the device proves its identity to DPS.
+#[derive(Clone, Debug)]
+pub enum Attestation{
+    // Key of an individual enrollment, or the key derived from a group enrollment
+    SymmetricKey(String),
+    // Client certificate and key (PEM files), the registration id is the CN of the certificate
+    X509{certificate: String, key: String},
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationStatus{
+    Unassigned,
+    Assigning,
+    Assigned,
+    Failed,
+    Disabled,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationRequest{
+    pub registration_id: String,
+    // Custom data for allocation policies (Azure Functions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationState{
+    pub registration_id: String,
+    pub status: RegistrationStatus,
+    // Host name of the hub, e.g. myhub.azure-devices.net
+    #[serde(default)]
+    pub assigned_hub: Option<String>,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    // initialAssignment, deviceDataMigrated, deviceDataReset or reprovisionedToInitialAssignment
+    #[serde(default)]
+    pub substatus: Option<String>,
+    #[serde(default)]
+    pub error_code: Option<i64>,
+    #[serde(default)]
+    pub error_message: Option<String>,
+    #[serde(default)]
+    pub created_date_time_utc: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_updated_date_time_utc: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub etag: Option<String>,
+    // Returned by custom allocation policies
+    #[serde(default)]
+    pub payload: Option<Value>,
+}
+
+// Answer to the registration and to every poll of the operation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationStatus{
+    pub operation_id: String,
+    pub status: RegistrationStatus,
+    #[serde(default)]
+    pub registration_state: Option<RegistrationState>,
+}
+
+// A device DPS assigned to a hub.
+#[derive(Clone, Debug)]
+pub struct ProvisionedDevice{
+    pub assigned_hub: String,
+    pub device_id: String,
+    pub registration_state: RegistrationState,
+    attestation: Attestation,
+}
+
+impl ProvisionedDevice{
+    // First label of the assigned hub, the hub_name of Client::new.
+    pub fn hub_name(&self) -> String {
+        self.endpoint().hub_name()
+    }
+
+    // AMQPS endpoint of the assigned hub.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::custom(&self.assigned_hub, AMQPS_PORT, Transport::Tls)
+    }
+
+    // Symmetric key of the device, None for X.509 devices.
+    pub fn device_key(&self) -> Option<&str> {
+        match &self.attestation{
+            Attestation::SymmetricKey(key) => Some(key),
+            Attestation::X509{..} => None
+        }
+    }
+
+    // Device connection string, None for X.509 devices.
+    pub fn connection_string(&self) -> Option<String> {
+        Some(format!("HostName={};DeviceId={};SharedAccessKey={}", self.assigned_hub, self.device_id, self.device_key()?))
+    }
+
+    // Client for the assigned hub with the credentials used for the registration (not connected yet).
+    pub async fn client(&self, cert_location: &str) -> Result<Client, IotHubError> {
+        match &self.attestation{
+            Attestation::SymmetricKey(key) => {
+                let endpoint = self.endpoint();
+                let token = SasToken::for_host(key, chrono::Duration::days(1), &endpoint.host_name, &self.device_id, &SystemClock)?;
+                Client::with_endpoint(&self.device_id, endpoint, cert_location, key, &token.sas).await
+            }
+            Attestation::X509{certificate, key} => {
+                Client::with_x509(&self.device_id, self.endpoint(), cert_location, certificate, key).await
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProvisioningClient{
+    host_name: String,
+    port: u16,
+    // None: plain HTTP
+    tls_config: Option<Arc<ClientConfig>>,
+    id_scope: String,
+    registration_id: String,
+    attestation: Attestation,
+    poll_interval: Duration,
+    timeout: Duration,
+}
+
+impl ProvisioningClient{
+    // Individual enrollment with a symmetric key.
+    pub async fn symmetric_key(id_scope: &str, registration_id: &str, device_key: &str, cert_location: &str) -> Result<ProvisioningClient, IotHubError> {
+        let tls_config = load_tls_config(cert_location).await?;
+        Ok(ProvisioningClient::with_attestation(id_scope, registration_id, Attestation::SymmetricKey(device_key.to_string()), Some(Arc::new(tls_config))))
+    }
+
+    // Symmetric key group enrollment, the key of the device is derived from the group key.
+    pub async fn group_symmetric_key(id_scope: &str, registration_id: &str, group_key: &str, cert_location: &str) -> Result<ProvisioningClient, IotHubError> {
+        let device_key = SasToken::derive_device_key(group_key, registration_id)?;
+        ProvisioningClient::symmetric_key(id_scope, registration_id, &device_key, cert_location).await
+    }
+
+    // Individual or group X.509 enrollment, TLS client authentication with the device certificate.
+    pub async fn x509(id_scope: &str, registration_id: &str, cert_location: &str, client_cert_location: &str, client_key_location: &str) -> Result<ProvisioningClient, IotHubError> {
+        let certificate = match read_certificate(cert_location).await{
+            None => {
+                return Err(TlsConfigFailure::CertificateNotFound.into());
+            }
+            Some(certificate) => {
+                certificate
+            }
+        };
+        let (client_chain, client_key) = read_client_identity(client_cert_location, client_key_location).await?;
+        let tls_config = create_x509_tls_config(certificate, client_chain, client_key)?;
+        let attestation = Attestation::X509{
+            certificate: client_cert_location.to_string(),
+            key: client_key_location.to_string()
+        };
+        Ok(ProvisioningClient::with_attestation(id_scope, registration_id, attestation, Some(Arc::new(tls_config))))
+    }
+
+    // Symmetric key client without TLS, for a local stand-in of DPS.
+    pub fn plain(host_name: &str, port: u16, id_scope: &str, registration_id: &str, device_key: &str) -> ProvisioningClient {
+        let mut client = ProvisioningClient::with_attestation(id_scope, registration_id, Attestation::SymmetricKey(device_key.to_string()), None);
+        client.host_name = host_name.to_string();
+        client.port = port;
+        client
+    }
+
+    fn with_attestation(id_scope: &str, registration_id: &str, attestation: Attestation, tls_config: Option<Arc<ClientConfig>>) -> ProvisioningClient {
+        ProvisioningClient{
+            host_name: GLOBAL_ENDPOINT.to_string(),
+            port: HTTPS_PORT,
+            tls_config,
+            id_scope: id_scope.to_string(),
+            registration_id: registration_id.to_string(),
+            attestation,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            timeout: DEFAULT_REGISTRATION_TIMEOUT
+        }
+    }
+
+    // Other global endpoint (sovereign clouds, e.g. global.azure-devices-provisioning.cn).
+    pub fn set_global_endpoint(&mut self, host_name: &str){
+        self.host_name = host_name.to_string();
+    }
+
+    // Wait between polls when DPS does not send Retry-After (default 3 seconds).
+    pub fn set_poll_interval(&mut self, poll_interval: Duration){
+        self.poll_interval = poll_interval;
+    }
+
+    // How long a registration may take including the polls (default 2 minutes).
+    pub fn set_timeout(&mut self, timeout: Duration){
+        self.timeout = timeout;
+    }
+
+    pub fn registration_id(&self) -> &str {
+        &self.registration_id
+    }
+
+    // Register the device and wait until DPS assigned it to a hub.
+    pub async fn register(&self, payload: Option<Value>) -> Result<ProvisionedDevice, ProvisioningFailure> {
+        let deadline = Instant::now() + self.timeout;
+        let body = RegistrationRequest{
+            registration_id: self.registration_id.clone(),
+            payload
+        };
+        let body = serde_json::to_vec(&body).map_err(ProvisioningFailure::InvalidBody)?;
+        let mut response = self.request("PUT", &register_path(&self.id_scope, &self.registration_id), Some(&body)).await?;
+        loop{
+            let operation: OperationStatus = serde_json::from_slice(&response.body).map_err(ProvisioningFailure::InvalidBody)?;
+            match operation.status{
+                RegistrationStatus::Assigned => {
+                    return self.assigned(operation);
+                }
+                RegistrationStatus::Failed => {
+                    let state = operation.registration_state.unwrap_or_else(|| empty_state(&self.registration_id));
+                    return Err(ProvisioningFailure::Failed(state.error_code, state.error_message.unwrap_or_default()));
+                }
+                RegistrationStatus::Disabled => {
+                    return Err(ProvisioningFailure::Disabled);
+                }
+                RegistrationStatus::Unassigned | RegistrationStatus::Assigning => {
+                    // Still running, poll again below.
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline{
+                return Err(ProvisioningFailure::Timeout);
+            }
+            let wait = retry_after(&response).unwrap_or(self.poll_interval).min(deadline - now);
+            async_std::task::sleep(wait).await;
+            let path = operation_path(&self.id_scope, &self.registration_id, &operation.operation_id);
+            response = self.request("GET", &path, None).await?;
+        }
+    }
+
+    fn assigned(&self, operation: OperationStatus) -> Result<ProvisionedDevice, ProvisioningFailure> {
+        let state = operation.registration_state.ok_or(ProvisioningFailure::NotAssigned)?;
+        let (assigned_hub, device_id) = match (state.assigned_hub.clone(), state.device_id.clone()){
+            (Some(assigned_hub), Some(device_id)) => {
+                (assigned_hub, device_id)
+            }
+            _ => {
+                return Err(ProvisioningFailure::NotAssigned);
+            }
+        };
+        Ok(ProvisionedDevice{
+            assigned_hub,
+            device_id,
+            registration_state: state,
+            attestation: self.attestation.clone()
+        })
+    }
+
+    async fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<HttpResponse, ProvisioningFailure> {
+        // X.509 devices are authenticated by the TLS handshake
+        let token = match &self.attestation{
+            Attestation::SymmetricKey(key) => {
+                Some(SasToken::provisioning_token(
+                    key,
+                    chrono::Duration::hours(1),
+                    &self.id_scope,
+                    &self.registration_id,
+                    Some(REGISTRATION_KEY_NAME),
+                    &SystemClock)
+                    .map_err(ProvisioningFailure::Token)?)
+            }
+            Attestation::X509{..} => {
+                None
+            }
+        };
+        let mut headers = vec![("Accept", "application/json"), ("Content-Type", "application/json; charset=utf-8")];
+        if let Some(token) = token.as_ref(){
+            headers.push(("Authorization", token.sas.as_str()));
+        }
+        let request = encode_request(method, &self.host_name, path, &headers, body);
+        let response = match self.tls_config.as_ref(){
+            None => {
+                http_request(&self.host_name, self.port, request, REQUEST_TIMEOUT).await?
+            }
+            Some(tls_config) => {
+                https_request(tls_config.clone(), &self.host_name, self.port, request, REQUEST_TIMEOUT).await?
+            }
+        };
+        if !response.is_success(){
+            return Err(ProvisioningFailure::Rejected(response.status, String::from_utf8_lossy(&response.body).to_string()));
+        }
+        Ok(response)
+    }
+}
+
+fn empty_state(registration_id: &str) -> RegistrationState {
+    RegistrationState{
+        registration_id: registration_id.to_string(),
+        status: RegistrationStatus::Failed,
+        assigned_hub: None,
+        device_id: None,
+        substatus: None,
+        error_code: None,
+        error_message: None,
+        created_date_time_utc: None,
+        last_updated_date_time_utc: None,
+        etag: None,
+        payload: None
+    }
+}
+
+// Retry-After in seconds, as DPS sends it with operations that are still running.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    response.header("Retry-After")
+        .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+#[derive(Debug)]
+pub enum ProvisioningFailure{
+    Token(SasTokenCreateException),
+    Http(HttpFailure),
+    InvalidBody(serde_json::Error),
+    // HTTP status and body (401: wrong key or unknown registration, 429: throttled)
+    Rejected(u16, String),
+    // Error code and message DPS gave for a failed registration
+    Failed(Option<i64>, String),
+    Disabled,
+    // Assigned without a hub or device id
+    NotAssigned,
+    Timeout,
+}
+
+impl Display for ProvisioningFailure{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self{
+            ProvisioningFailure::Token(err) => write!(f, "Failed to create the registration token: {}", err),
+            ProvisioningFailure::Http(err) => write!(f, "{}", err),
+            ProvisioningFailure::InvalidBody(err) => write!(f, "Invalid registration response: {}", err),
+            ProvisioningFailure::Rejected(status, body) => write!(f, "Registration request failed ({}): {}", status, body),
+            ProvisioningFailure::Failed(code, message) => write!(f, "Registration failed ({}): {}", code.unwrap_or_default(), message),
+            ProvisioningFailure::Disabled => write!(f, "The enrollment is disabled."),
+            ProvisioningFailure::NotAssigned => write!(f, "The device was not assigned to a hub."),
+            ProvisioningFailure::Timeout => write!(f, "The registration did not complete in time."),
+        }
+    }
+}
+impl std::error::Error for ProvisioningFailure{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            ProvisioningFailure::Token(err) => Some(err),
+            ProvisioningFailure::Http(err) => Some(err),
+            ProvisioningFailure::InvalidBody(err) => Some(err),
+            _ => None
+        }
+    }
+}
+impl From<HttpFailure> for ProvisioningFailure{
+    fn from(err: HttpFailure) -> Self {
+        ProvisioningFailure::Http(err)
+    }
+}
@@ -0,0 +1,406 @@
+// Device identities in the IoT Hub registry, managed through the REST endpoint of the hub
+// with a service token (the policy needs the registry read/write permissions).
+// Updates and deletes are conditional on the etag of the identity when one is given.
+// Without a TLS configuration the client talks plain HTTP, for local stand-ins in tests.
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use rand::RngCore;
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use crate::amqp::client::load_tls_config;
+use crate::error::IotHubError;
+use crate::transport::https::{encode_request, http_request, https_request, HttpFailure, HttpResponse, HTTPS_PORT};
+use crate::util::connection_string::{ConnectionString, ConnectionStringException};
+use crate::util::token::{SasToken, SasTokenCreateException, SystemClock};
+
+pub const REGISTRY_API_VERSION: &str = "2021-04-12";
+// The hub returns at most 1000 identities per listing
+pub const MAX_LIST_SIZE: usize = 1000;
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Size of generated symmetric keys, same as the keys the hub generates
+const KEY_SIZE: usize = 32;
+
+pub fn device_path(device_id: &str) -> String {
+    format!("/devices/{}?api-version={}", urlencoding::encode(device_id), REGISTRY_API_VERSION)
+}
+
+pub fn list_path(max: usize) -> String {
+    format!("/devices?top={}&api-version={}", max.min(MAX_LIST_SIZE), REGISTRY_API_VERSION)
+}
+
+// Random base64 symmetric key for a device.
+pub fn generate_key() -> String {
+    let mut key = [0u8; KEY_SIZE];
+    rand::thread_rng().fill_bytes(&mut key);
+    base64::encode(key)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus{
+    #[default]
+    Enabled,
+    Disabled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuthenticationType{
+    Sas,
+    SelfSigned,
+    CertificateAuthority,
+    None,
+}
+
+// Keys left out are generated by the hub when the identity is created.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymmetricKey{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secondary_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct X509Thumbprint{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_thumbprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secondary_thumbprint: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceAuthentication{
+    #[serde(rename = "type")]
+    pub kind: AuthenticationType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symmetric_key: Option<SymmetricKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x509_thumbprint: Option<X509Thumbprint>,
+}
+
+impl Default for DeviceAuthentication{
+    fn default() -> Self {
+        DeviceAuthentication{
+            kind: AuthenticationType::Sas,
+            symmetric_key: Some(SymmetricKey::default()),
+            x509_thumbprint: None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCapabilities{
+    #[serde(default)]
+    pub iot_edge: bool,
+}
+
+// A device identity as the registry stores it. Fields the hub maintains
+// (etag, generation id, connection state) are only filled in on identities read from it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device{
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub status: DeviceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<String>,
+    // Connected or Disconnected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_state: Option<String>,
+    #[serde(default)]
+    pub authentication: DeviceAuthentication,
+    #[serde(default)]
+    pub capabilities: DeviceCapabilities,
+}
+
+impl Device{
+    // Enabled identity with symmetric keys generated by the hub.
+    pub fn new(device_id: &str) -> Device {
+        Device{
+            device_id: device_id.to_string(),
+            generation_id: None,
+            etag: None,
+            status: DeviceStatus::Enabled,
+            status_reason: None,
+            connection_state: None,
+            authentication: DeviceAuthentication::default(),
+            capabilities: DeviceCapabilities::default()
+        }
+    }
+
+    // Enabled identity with the given symmetric keys.
+    pub fn with_keys(device_id: &str, primary_key: &str, secondary_key: &str) -> Device {
+        let mut device = Device::new(device_id);
+        device.authentication.symmetric_key = Some(SymmetricKey{
+            primary_key: Some(primary_key.to_string()),
+            secondary_key: Some(secondary_key.to_string())
+        });
+        device
+    }
+
+    // Enabled identity authenticated with self-signed certificates (SHA thumbprints).
+    pub fn self_signed(device_id: &str, primary_thumbprint: &str, secondary_thumbprint: &str) -> Device {
+        let mut device = Device::new(device_id);
+        device.authentication = DeviceAuthentication{
+            kind: AuthenticationType::SelfSigned,
+            symmetric_key: None,
+            x509_thumbprint: Some(X509Thumbprint{
+                primary_thumbprint: Some(primary_thumbprint.to_string()),
+                secondary_thumbprint: Some(secondary_thumbprint.to_string())
+            })
+        };
+        device
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.status == DeviceStatus::Enabled
+    }
+
+    pub fn primary_key(&self) -> Option<&str> {
+        self.authentication.symmetric_key.as_ref()?.primary_key.as_deref()
+    }
+
+    pub fn secondary_key(&self) -> Option<&str> {
+        self.authentication.symmetric_key.as_ref()?.secondary_key.as_deref()
+    }
+
+    // Device connection string for the primary key, None for certificate identities.
+    pub fn connection_string(&self, host_name: &str) -> Option<String> {
+        Some(format!("HostName={};DeviceId={};SharedAccessKey={}", host_name, self.device_id, self.primary_key()?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind{
+    Primary,
+    Secondary,
+    Both,
+}
+
+#[derive(Clone)]
+pub struct RegistryClient{
+    host_name: String,
+    port: u16,
+    // None: plain HTTP
+    tls_config: Option<Arc<ClientConfig>>,
+    primary_key: String,
+    policy: String,
+    timeout: Duration,
+}
+
+impl RegistryClient{
+    pub fn new(host_name: &str, tls_config: Arc<ClientConfig>, primary_key: &str, policy: &str) -> RegistryClient {
+        RegistryClient{
+            host_name: host_name.to_string(),
+            port: HTTPS_PORT,
+            tls_config: Some(tls_config),
+            primary_key: primary_key.to_string(),
+            policy: policy.to_string(),
+            timeout: DEFAULT_REQUEST_TIMEOUT
+        }
+    }
+
+    // Registry client without TLS, for a local stand-in of the hub.
+    // The host name is still the audience of the service token.
+    pub fn plain(host_name: &str, port: u16, primary_key: &str, policy: &str) -> RegistryClient {
+        RegistryClient{
+            host_name: host_name.to_string(),
+            port,
+            tls_config: None,
+            primary_key: primary_key.to_string(),
+            policy: policy.to_string(),
+            timeout: DEFAULT_REQUEST_TIMEOUT
+        }
+    }
+
+    // From a shared access policy connection string (HostName=...;SharedAccessKeyName=...;SharedAccessKey=...).
+    pub async fn from_connection_string(connection_string: &str, cert_location: &str) -> Result<RegistryClient, IotHubError> {
+        let parsed = ConnectionString::parse(connection_string)?;
+        let policy = match parsed.shared_access_key_name.as_ref(){
+            None => {
+                return Err(ConnectionStringException::NotAServiceConnectionString.into());
+            }
+            Some(policy) => {
+                policy.clone()
+            }
+        };
+        let tls_config = load_tls_config(cert_location).await?;
+        Ok(RegistryClient::new(&parsed.host_name, Arc::new(tls_config), &parsed.shared_access_key, &policy))
+    }
+
+    pub fn host_name(&self) -> &str {
+        &self.host_name
+    }
+
+    // How long a single request may take (default 30 seconds).
+    pub fn set_timeout(&mut self, timeout: Duration){
+        self.timeout = timeout;
+    }
+
+    // Up to max identities (at most 1000) in no particular order.
+    pub async fn list_devices(&self, max: usize) -> Result<Vec<Device>, RegistryFailure> {
+        let response = self.request("GET", &list_path(max), None, None).await?;
+        serde_json::from_slice(&response.body).map_err(RegistryFailure::InvalidBody)
+    }
+
+    pub async fn get_device(&self, device_id: &str) -> Result<Device, RegistryFailure> {
+        let response = self.request("GET", &device_path(device_id), None, None).await?;
+        serde_json::from_slice(&response.body).map_err(RegistryFailure::InvalidBody)
+    }
+
+    // Create a new identity, fails with 409 when the device id is taken.
+    // Returns the identity as stored, including generated keys.
+    pub async fn create_device(&self, device: &Device) -> Result<Device, RegistryFailure> {
+        let body = serde_json::to_vec(device).map_err(RegistryFailure::InvalidBody)?;
+        let response = self.request("PUT", &device_path(&device.device_id), None, Some(&body)).await?;
+        serde_json::from_slice(&response.body).map_err(RegistryFailure::InvalidBody)
+    }
+
+    // Replace an existing identity. With an etag the update fails with 412
+    // when the identity changed since it was read, without one it always applies.
+    pub async fn update_device(&self, device: &Device) -> Result<Device, RegistryFailure> {
+        let body = serde_json::to_vec(device).map_err(RegistryFailure::InvalidBody)?;
+        let if_match = if_match(device.etag.as_deref());
+        let response = self.request("PUT", &device_path(&device.device_id), Some(&if_match), Some(&body)).await?;
+        serde_json::from_slice(&response.body).map_err(RegistryFailure::InvalidBody)
+    }
+
+    // Enable or disable a device, a disabled device can no longer connect.
+    pub async fn set_status(&self, device_id: &str, status: DeviceStatus, reason: Option<&str>) -> Result<Device, RegistryFailure> {
+        let mut device = self.get_device(device_id).await?;
+        device.status = status;
+        device.status_reason = reason.map(|reason| reason.to_string());
+        self.update_device(&device).await
+    }
+
+    pub async fn disable_device(&self, device_id: &str, reason: Option<&str>) -> Result<Device, RegistryFailure> {
+        self.set_status(device_id, DeviceStatus::Disabled, reason).await
+    }
+
+    pub async fn enable_device(&self, device_id: &str) -> Result<Device, RegistryFailure> {
+        self.set_status(device_id, DeviceStatus::Enabled, None).await
+    }
+
+    // Delete an identity, conditional on the etag when one is given.
+    pub async fn delete_device(&self, device_id: &str, etag: Option<&str>) -> Result<(), RegistryFailure> {
+        let if_match = if_match(etag);
+        self.request("DELETE", &device_path(device_id), Some(&if_match), None).await?;
+        Ok(())
+    }
+
+    // Replace one or both symmetric keys with new random keys.
+    // Devices using a replaced key have to be given the new one before they reconnect.
+    pub async fn regenerate_key(&self, device_id: &str, key: KeyKind) -> Result<Device, RegistryFailure> {
+        let mut device = self.get_device(device_id).await?;
+        let keys = match device.authentication.symmetric_key.as_mut(){
+            Some(keys) if device.authentication.kind == AuthenticationType::Sas => {
+                keys
+            }
+            _ => {
+                return Err(RegistryFailure::NotSymmetricKey);
+            }
+        };
+        if key != KeyKind::Secondary{
+            keys.primary_key = Some(generate_key());
+        }
+        if key != KeyKind::Primary{
+            keys.secondary_key = Some(generate_key());
+        }
+        self.update_device(&device).await
+    }
+
+    async fn request(&self, method: &str, path: &str, if_match: Option<&str>, body: Option<&[u8]>) -> Result<HttpResponse, RegistryFailure> {
+        let token = SasToken::service_token_for_host(
+            &self.primary_key,
+            chrono::Duration::hours(1),
+            &self.host_name,
+            &self.policy,
+            &SystemClock)
+            .map_err(RegistryFailure::Token)?;
+        let mut headers = vec![("Authorization", token.sas.as_str())];
+        if let Some(if_match) = if_match{
+            headers.push(("If-Match", if_match));
+        }
+        if body.is_some(){
+            headers.push(("Content-Type", "application/json; charset=utf-8"));
+        }
+        let request = encode_request(method, &self.host_name, path, &headers, body);
+        let response = match self.tls_config.as_ref(){
+            None => {
+                http_request(&self.host_name, self.port, request, self.timeout).await?
+            }
+            Some(tls_config) => {
+                https_request(tls_config.clone(), &self.host_name, self.port, request, self.timeout).await?
+            }
+        };
+        if !response.is_success(){
+            return Err(RegistryFailure::Rejected(response.status, String::from_utf8_lossy(&response.body).to_string()));
+        }
+        Ok(response)
+    }
+}
+
+// The hub expects the etag quoted, * matches any version.
+fn if_match(etag: Option<&str>) -> String {
+    match etag{
+        None => {
+            String::from("*")
+        }
+        Some(etag) => {
+            format!("\"{}\"", etag.trim_matches('"'))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryFailure{
+    Token(SasTokenCreateException),
+    Http(HttpFailure),
+    InvalidBody(serde_json::Error),
+    // HTTP status and body (404: unknown device, 409: device exists, 412: etag mismatch)
+    Rejected(u16, String),
+    NotSymmetricKey,
+}
+
+impl RegistryFailure{
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, RegistryFailure::Rejected(404, _))
+    }
+}
+
+impl Display for RegistryFailure{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self{
+            RegistryFailure::Token(err) => write!(f, "Failed to create the service token: {}", err),
+            RegistryFailure::Http(err) => write!(f, "{}", err),
+            RegistryFailure::InvalidBody(err) => write!(f, "Invalid device identity: {}", err),
+            RegistryFailure::Rejected(status, body) => write!(f, "Registry request failed ({}): {}", status, body),
+            RegistryFailure::NotSymmetricKey => write!(f, "The device does not use symmetric keys."),
+        }
+    }
+}
+impl std::error::Error for RegistryFailure{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            RegistryFailure::Token(err) => Some(err),
+            RegistryFailure::Http(err) => Some(err),
+            RegistryFailure::InvalidBody(err) => Some(err),
+            _ => None
+        }
+    }
+}
+impl From<HttpFailure> for RegistryFailure{
+    fn from(err: HttpFailure) -> Self {
+        RegistryFailure::Http(err)
+    }
+}
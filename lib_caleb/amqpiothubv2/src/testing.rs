@@ -0,0 +1,1450 @@
+pub mod broker{
+    // In-process AMQP 1.0 broker that imitates the IoT Hub endpoints.
+    // Only meant for tests: no TLS, every well formed SAS login is accepted.
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fmt::{Display, Formatter};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use ntex::server::Server;
+    use ntex::service::{fn_factory_with_config, fn_service};
+    use ntex::util::{Bytes, ByteString};
+    use ntex_amqp::codec::protocol::{DeliveryState, TransferBody};
+    use ntex_amqp::codec::types::{Symbol, Variant};
+    use ntex_amqp::codec::{Decode, Message};
+    use ntex_amqp::error::{AmqpError, LinkError};
+    use ntex_amqp::server::{self, ControlFrame, ControlFrameKind, Handshake, HandshakeAck};
+    use ntex_amqp::{SenderLink, types};
+    use ntex_amqp::codec::protocol::SaslCode;
+    use crate::amqp::cbs::CBS_ADDRESS;
+    use crate::amqp::config::{Endpoint, Transport};
+    use crate::amqp::transfer::{BATCH_MESSAGE_FORMAT, CREATION_TIME_ANNOTATION, empty_properties, string_annotation, string_app_property, string_variant};
+    use crate::amqp::methods::{METHOD_NAME_PROPERTY, response_status};
+    use crate::amqp::feedback::{ACK_PROPERTY, FEEDBACK_ADDRESS, FEEDBACK_CONTENT_TYPE, message_id_string};
+    use crate::amqp::eventhub::{DEVICE_ID_ANNOTATION, ENQUEUED_TIME_ANNOTATION, filter_position, MANAGEMENT_ADDRESS, OFFSET_ANNOTATION, partition_ids_value, SEQUENCE_NUMBER_ANNOTATION};
+    use crate::util::checkpoint::EventPosition;
+    use chrono::{DateTime, Utc};
+    use ntex_amqp::codec::protocol::MessageId;
+    use crate::amqp::twin::{DESIRED_NOTIFICATIONS_RESOURCE, OPERATION_ANNOTATION, REPORTED_RESOURCE, RESOURCE_ANNOTATION, STATUS_ANNOTATION, VERSION_ANNOTATION, response_body};
+    use serde_json::{json, Value};
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use futures::StreamExt;
+    use ntex_amqp::codec::protocol::{Error, Fields};
+
+    // One telemetry message received on /devices/{id}/messages/events
+    #[derive(Clone, Debug)]
+    pub struct MockTelemetry{
+        pub device_id: String,
+        pub body: Bytes,
+        pub enqueued_time: DateTime<Utc>,
+        // iothub-creation-time-utc of the message, if the device set it
+        pub creation_time: Option<String>,
+    }
+
+    // Partitions of the Event Hub-compatible endpoint, a device always lands on the same one.
+    pub const MOCK_PARTITIONS: [&str; 2] = ["0", "1"];
+
+    pub fn mock_partition(device_id: &str) -> &'static str {
+        let sum: usize = device_id.bytes().map(|byte| byte as usize).sum();
+        MOCK_PARTITIONS[sum % MOCK_PARTITIONS.len()]
+    }
+
+    // Everything the broker saw, shared between the worker thread and the test.
+    #[derive(Default)]
+    struct MockState{
+        logins: Vec<String>,
+        telemetry: Vec<MockTelemetry>,
+        devicebound: Vec<(String, Bytes)>,
+        settlements: Vec<(String, MockSettlement)>,
+        cbs_audiences: Vec<String>,
+        twins: HashMap<String, MockTwin>,
+        // Method requests waiting for the device to open its method links
+        pending_methods: Vec<MockMethodRequest>,
+        method_responses: Vec<MockMethodResponse>,
+        next_method_id: u64,
+        // Feedback records waiting for the service to open the feedback link
+        pending_feedback: Vec<Value>,
+        // Where event hub readers are sent with amqp:link:redirect
+        event_hub_redirect: Option<MockRedirect>,
+    }
+
+    #[derive(Clone)]
+    struct MockRedirect{
+        port: u16,
+        address: String,
+    }
+
+    impl MockRedirect{
+        // Info fields IoT Hub puts on the redirect error
+        fn fields(&self) -> Fields {
+            let mut info = Fields::default();
+            info.insert(Symbol::from("hostname"), string_variant("127.0.0.1"));
+            info.insert(Symbol::from("network-host"), string_variant("127.0.0.1"));
+            info.insert(Symbol::from("port"), Variant::Int(self.port as i32));
+            info.insert(Symbol::from("address"), string_variant(&self.address));
+            info
+        }
+
+        fn link_error(&self) -> LinkError {
+            LinkError::redirect()
+                .description("The event hub is served by another host")
+                .fields(self.fields())
+        }
+    }
+
+    struct MockMethodRequest{
+        device_id: String,
+        correlation_id: String,
+        name: String,
+        payload: Value,
+    }
+
+    // Answer of a device to a direct method
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct MockMethodResponse{
+        pub device_id: String,
+        pub correlation_id: String,
+        pub status: i64,
+        pub payload: Value,
+    }
+
+    // Twin of one device as the broker keeps it
+    #[derive(Clone, Debug)]
+    pub struct MockTwin{
+        pub desired: Value,
+        pub reported: Value,
+        pub subscribed: bool,
+        // Desired patches not yet pushed to the device
+        pending: Vec<Value>,
+    }
+
+    impl Default for MockTwin{
+        fn default() -> Self {
+            MockTwin{
+                desired: json!({"$version": 1}),
+                reported: json!({"$version": 1}),
+                subscribed: false,
+                pending: Vec::new(),
+            }
+        }
+    }
+
+    // How a device settled a cloud to device message
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum MockSettlement{
+        Completed,
+        Abandoned,
+        Rejected,
+        // Link closed or any other outcome
+        Other,
+    }
+
+    pub struct MockIotHub{
+        address: String,
+        port: u16,
+        hub_name: String,
+        state: Arc<Mutex<MockState>>,
+        server: Server,
+        // Reaches the worker, where the links live
+        commands: UnboundedSender<MockCommand>,
+    }
+
+    enum MockCommand{
+        Detach{ address: String, error: Option<Error> },
+    }
+
+    impl MockIotHub{
+        // Start the broker on a free local port.
+        pub async fn start(hub_name: &str) -> std::io::Result<MockIotHub> {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            let local_address = listener.local_addr()?;
+            let address = local_address.to_string();
+            let port = local_address.port();
+            let state = Arc::new(Mutex::new(MockState::default()));
+            let (server, commands) = MockIotHub::serve(listener, state.clone())?;
+            Ok(MockIotHub{
+                address,
+                port,
+                hub_name: hub_name.to_string(),
+                state,
+                server,
+                commands
+            })
+        }
+
+        // Drop every connection and listen on the same port again, like a hub that restarts.
+        // What the broker saw so far is kept.
+        pub async fn restart(&mut self) -> std::io::Result<()> {
+            self.server.stop(true).await;
+            let listener = std::net::TcpListener::bind(self.address.as_str())?;
+            let (server, commands) = MockIotHub::serve(listener, self.state.clone())?;
+            self.server = server;
+            self.commands = commands;
+            Ok(())
+        }
+
+        // Detach the hub side of the link a device receives on (e.g. its devicebound link),
+        // with the given error or a plain detach.
+        pub fn detach_link(&self, address: &str, error: Option<Error>){
+            self.commands.unbounded_send(MockCommand::Detach{ address: normalize_address(address), error }).ok();
+        }
+
+        // Send event hub readers (the $management link) to the other broker with
+        // amqp:link:redirect, like IoT Hub does with its Event Hubs namespace.
+        // entity is the event hub name on the other broker, e.g. "messages/events".
+        pub fn redirect_event_hub(&self, target: &MockIotHub, entity: &str){
+            self.state.lock().unwrap().event_hub_redirect = Some(MockRedirect{
+                port: target.port,
+                address: format!("amqp://127.0.0.1:{}/{}", target.port, entity)
+            });
+        }
+
+        fn serve(listener: std::net::TcpListener, factory_state: Arc<Mutex<MockState>>) -> std::io::Result<(Server, UnboundedSender<MockCommand>)> {
+            let (commands, command_receiver) = unbounded();
+            let command_receiver = Arc::new(Mutex::new(Some(command_receiver)));
+            let server = Server::build()
+                .listen("mock_iothub", listener, move || {
+                    // Links are !Send, so they live in the (single) worker.
+                    let receivers: Rc<RefCell<HashMap<String, SenderLink>>> = Rc::new(RefCell::new(HashMap::new()));
+                    if let Some(command_receiver) = command_receiver.lock().unwrap().take(){
+                        ntex::rt::spawn(run_commands(command_receiver, receivers.clone()));
+                    }
+                    let handshake_state = factory_state.clone();
+                    let events_state = factory_state.clone();
+                    let events_receivers = receivers.clone();
+                    let management_receivers = receivers.clone();
+                    let management_state = factory_state.clone();
+                    let devicebound_state = factory_state.clone();
+                    let control_receivers = receivers.clone();
+                    let devicebound_receivers = receivers.clone();
+                    let cbs_state = factory_state.clone();
+                    let cbs_receivers = receivers.clone();
+                    let twin_state = factory_state.clone();
+                    let twin_receivers = receivers.clone();
+                    let control_state = factory_state.clone();
+                    let methods_state = factory_state.clone();
+                    server::Server::new(move |handshake: Handshake| {
+                        let state = handshake_state.clone();
+                        async move {
+                            handle_handshake(handshake, state).await
+                        }
+                    })
+                    .control(move |frame: ControlFrame| {
+                        let receivers = control_receivers.clone();
+                        let state = control_state.clone();
+                        async move {
+                            handle_control_frame(frame, receivers, state);
+                            Ok::<_, ()>(())
+                        }
+                    })
+                    .finish(
+                        server::Router::<()>::new()
+                            .service("/devices/{device_id}/messages/events", fn_factory_with_config(move |link: types::Link<()>| {
+                                let state = events_state.clone();
+                                let receivers = events_receivers.clone();
+                                let device_id = device_from_address(link_address(&link).as_str());
+                                async move {
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let state = state.clone();
+                                        let receivers = receivers.clone();
+                                        let device_id = device_id.clone();
+                                        async move {
+                                            // A batch counts as one transfer, every message in it is stored on its own.
+                                            for (body, creation_time) in message_bodies(&transfer)?{
+                                                let telemetry = MockTelemetry{
+                                                    device_id: device_id.clone(),
+                                                    body,
+                                                    enqueued_time: Utc::now(),
+                                                    creation_time
+                                                };
+                                                let (offset, sequence_number) = {
+                                                    let mut state = state.lock().unwrap();
+                                                    state.telemetry.push(telemetry.clone());
+                                                    let partition = mock_partition(&telemetry.device_id);
+                                                    let sequence_number = state.telemetry.iter()
+                                                        .filter(|telemetry| mock_partition(&telemetry.device_id) == partition)
+                                                        .count() as i64 - 1;
+                                                    (state.telemetry.len() - 1, sequence_number)
+                                                };
+                                                // Readers of the partition get the event right away.
+                                                let partition_suffix = format!("/Partitions/{}", mock_partition(&telemetry.device_id));
+                                                let links: Vec<SenderLink> = receivers.borrow().iter()
+                                                    .filter(|(address, _)| address.starts_with("/messages/events/ConsumerGroups/") && address.ends_with(&partition_suffix))
+                                                    .map(|(_, link)| link.clone())
+                                                    .collect();
+                                                for link in links{
+                                                    send_events(vec![event_message(offset, sequence_number, &telemetry)], link);
+                                                }
+                                            }
+                                            Ok::<_, AmqpError>(types::Outcome::Accept)
+                                        }
+                                    }))
+                                }
+                            }))
+                            .service("/messages/devicebound", fn_factory_with_config(move |_link: types::Link<()>| {
+                                let state = devicebound_state.clone();
+                                let receivers = devicebound_receivers.clone();
+                                async move {
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let state = state.clone();
+                                        let receivers = receivers.clone();
+                                        async move {
+                                            forward_devicebound(transfer, state, receivers).await
+                                        }
+                                    }))
+                                }
+                            }))
+                            .service("/devices/{device_id}/twin", fn_factory_with_config(move |link: types::Link<()>| {
+                                let state = twin_state.clone();
+                                let receivers = twin_receivers.clone();
+                                let device_id = device_from_address(link_address(&link).as_str());
+                                async move {
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let state = state.clone();
+                                        let receivers = receivers.clone();
+                                        let device_id = device_id.clone();
+                                        async move {
+                                            answer_twin_request(transfer, device_id, state, receivers).await
+                                        }
+                                    }))
+                                }
+                            }))
+                            .service("/devices/{device_id}/methods/devicebound", fn_factory_with_config(move |link: types::Link<()>| {
+                                let state = methods_state.clone();
+                                let device_id = device_from_address(link_address(&link).as_str());
+                                async move {
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let state = state.clone();
+                                        let device_id = device_id.clone();
+                                        async move {
+                                            record_method_response(transfer, device_id, state)
+                                        }
+                                    }))
+                                }
+                            }))
+                            .service("$management", fn_factory_with_config(move |_link: types::Link<()>| {
+                                let receivers = management_receivers.clone();
+                                let redirect = management_state.lock().unwrap().event_hub_redirect.clone();
+                                async move {
+                                    if let Some(redirect) = redirect{
+                                        return Err(redirect.link_error());
+                                    }
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let receivers = receivers.clone();
+                                        async move {
+                                            answer_management_request(transfer, receivers).await
+                                        }
+                                    }))
+                                }
+                            }))
+                            .service("$cbs", fn_factory_with_config(move |_link: types::Link<()>| {
+                                let state = cbs_state.clone();
+                                let receivers = cbs_receivers.clone();
+                                async move {
+                                    Ok::<_, LinkError>(fn_service(move |transfer: types::Transfer| {
+                                        let state = state.clone();
+                                        let receivers = receivers.clone();
+                                        async move {
+                                            answer_put_token(transfer, state, receivers).await
+                                        }
+                                    }))
+                                }
+                            }))
+                            .finish()
+                    )
+                })?
+                .workers(1)
+                .disable_signals()
+                .run();
+            Ok((server, commands))
+        }
+
+        pub fn address(&self) -> &str {
+            &self.address
+        }
+
+        // Plain TCP endpoint to hand to Client::set_endpoint / ServiceClient::set_endpoint
+        pub fn endpoint(&self) -> Endpoint {
+            Endpoint::custom("127.0.0.1", self.port, Transport::Plain)
+        }
+
+        pub fn hub_name(&self) -> &str {
+            &self.hub_name
+        }
+
+        // SASL usernames of every accepted login
+        pub fn logins(&self) -> Vec<String> {
+            self.state.lock().unwrap().logins.clone()
+        }
+
+        // Telemetry received from the devices, in arrival order
+        pub fn telemetry(&self) -> Vec<MockTelemetry> {
+            self.state.lock().unwrap().telemetry.clone()
+        }
+
+        // Cloud to device messages accepted from a service client (target, body)
+        pub fn devicebound(&self) -> Vec<(String, Bytes)> {
+            self.state.lock().unwrap().devicebound.clone()
+        }
+
+        // Settlements of the cloud to device messages (device, outcome), in the order they arrived
+        pub fn settlements(&self) -> Vec<(String, MockSettlement)> {
+            self.state.lock().unwrap().settlements.clone()
+        }
+
+        // Twin of the device, None until it used or was given one
+        pub fn twin(&self, device_id: &str) -> Option<MockTwin> {
+            self.state.lock().unwrap().twins.get(device_id).cloned()
+        }
+
+        // Change desired properties like a back end would. A subscribed device gets the
+        // patch pushed after its next twin request (the broker only acts on requests).
+        pub fn update_desired(&self, device_id: &str, patch: Value){
+            let mut state = self.state.lock().unwrap();
+            let twin = state.twins.entry(device_id.to_string()).or_default();
+            let version = merge_patch(&mut twin.desired, &patch);
+            let mut notification = patch;
+            notification["$version"] = json!(version);
+            twin.pending.push(notification);
+        }
+
+        // Queue a direct method call, it is sent when the device opens its method links.
+        // Returns the correlation id of the request.
+        pub fn queue_method(&self, device_id: &str, name: &str, payload: Value) -> String {
+            let mut state = self.state.lock().unwrap();
+            state.next_method_id += 1;
+            let correlation_id = format!("method-{}", state.next_method_id);
+            state.pending_methods.push(MockMethodRequest{
+                device_id: device_id.to_string(),
+                correlation_id: correlation_id.clone(),
+                name: name.to_string(),
+                payload
+            });
+            correlation_id
+        }
+
+        // Method responses of the devices, in arrival order
+        pub fn method_responses(&self) -> Vec<MockMethodResponse> {
+            self.state.lock().unwrap().method_responses.clone()
+        }
+
+        // Audiences authorized through put-token on the $cbs node
+        pub fn cbs_audiences(&self) -> Vec<String> {
+            self.state.lock().unwrap().cbs_audiences.clone()
+        }
+
+        pub async fn stop(self){
+            self.server.stop(true).await;
+        }
+    }
+
+    async fn run_commands(mut commands: UnboundedReceiver<MockCommand>, receivers: Rc<RefCell<HashMap<String, SenderLink>>>){
+        while let Some(command) = commands.next().await{
+            match command{
+                MockCommand::Detach{ address, error } => {
+                    let link = receivers.borrow_mut().remove(&address);
+                    if let Some(link) = link{
+                        let detached = match error{
+                            Some(error) => link.close_with_error(error).await,
+                            None => link.close().await
+                        };
+                        detached.ok();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_handshake(handshake: Handshake, state: Arc<Mutex<MockState>>) -> Result<HandshakeAck<()>, MockBrokerFailure> {
+        match handshake{
+            Handshake::Amqp(_) => {
+                // IoT Hub does not accept unauthenticated connections
+                Err(MockBrokerFailure::SaslRequired)
+            }
+            Handshake::Sasl(sasl) => {
+                let init = sasl.mechanism("PLAIN").init().await
+                    .map_err(|_| MockBrokerFailure::SaslRequired)?;
+                let login = parse_plain_response(init.initial_response().unwrap_or(&[]));
+                let (username, password) = match login{
+                    None => {
+                        init.outcome(SaslCode::Auth).await.ok();
+                        return Err(MockBrokerFailure::InvalidLogin);
+                    }
+                    Some(login) => {
+                        login
+                    }
+                };
+                if !is_valid_login(&username, &password){
+                    init.outcome(SaslCode::Auth).await.ok();
+                    return Err(MockBrokerFailure::InvalidLogin);
+                }
+                state.lock().unwrap().logins.push(username);
+                let success = init.outcome(SaslCode::Ok).await
+                    .map_err(|_| MockBrokerFailure::InvalidLogin)?;
+                let opened = success.open().await
+                    .map_err(|_| MockBrokerFailure::InvalidLogin)?;
+                Ok(opened.ack(()))
+            }
+        }
+    }
+
+    // Remember the sender side of every receiver link a client attaches,
+    // so cloud to device messages can be pushed to it.
+    // Queued method calls are pushed as soon as the device attaches its method receiver,
+    // queued feedback once the service attaches the feedback receiver and the stored
+    // telemetry (from the requested position on) once a partition receiver attaches.
+    fn handle_control_frame(frame: ControlFrame, receivers: Rc<RefCell<HashMap<String, SenderLink>>>, state: Arc<Mutex<MockState>>){
+        match frame.kind(){
+            ControlFrameKind::AttachSender(attach, link) => {
+                let address = attach.source.as_ref()
+                    .and_then(|source| source.address.as_ref())
+                    .map(|address| normalize_address(address))
+                    .unwrap_or_default();
+                receivers.borrow_mut().insert(address.clone(), link.clone());
+                if address.ends_with("/methods/devicebound"){
+                    send_pending_methods(&device_from_address(&address), link.clone(), state);
+                }
+                else if address == FEEDBACK_ADDRESS{
+                    let records = std::mem::take(&mut state.lock().unwrap().pending_feedback);
+                    send_feedback(records, link.clone());
+                }
+                else if address.starts_with("/messages/events/ConsumerGroups/"){
+                    let position = attach.source.as_ref()
+                        .and_then(|source| source.filter.as_ref())
+                        .and_then(filter_position)
+                        .unwrap_or(EventPosition::Start);
+                    let partition = address.rsplit('/').next().unwrap_or_default().to_string();
+                    send_events(stored_events(&state, &partition, &position), link.clone());
+                }
+            }
+            ControlFrameKind::DetachSender(_, link) => {
+                receivers.borrow_mut().retain(|_, attached| attached.handle() != link.handle());
+            }
+            _ => {}
+        }
+    }
+
+    async fn forward_devicebound(transfer: types::Transfer,
+                                 state: Arc<Mutex<MockState>>,
+                                 receivers: Rc<RefCell<HashMap<String, SenderLink>>>) -> Result<types::Outcome, AmqpError> {
+        let message: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        let target = message.properties.as_ref()
+            .and_then(|props| props.to.as_ref())
+            .map(|to| normalize_address(to));
+        let target = match target{
+            None => {
+                // Service messages must carry the device address in the To property
+                return Err(AmqpError::invalid_field().description("Missing To property"));
+            }
+            Some(target) => {
+                target
+            }
+        };
+        let body = message.body.data().cloned().unwrap_or_default();
+        state.lock().unwrap().devicebound.push((target.clone(), body));
+        let ack = string_app_property(&message, ACK_PROPERTY).unwrap_or_else(|| "none".to_string());
+        let message_id = message.properties.as_ref()
+            .and_then(|props| props.message_id.as_ref())
+            .and_then(message_id_string)
+            .unwrap_or_default();
+        let link = receivers.borrow().get(&target).cloned();
+        if let Some(link) = link{
+            // Like IoT Hub the message is accepted once queued, the device settles it later.
+            let device = device_from_address(&target);
+            ntex::rt::spawn(async move {
+                let settlement = match link.send(TransferBody::Message(Box::new(message))).await{
+                    Ok(disposition) => {
+                        match disposition.state{
+                            Some(DeliveryState::Accepted(_)) => MockSettlement::Completed,
+                            Some(DeliveryState::Released(_)) => MockSettlement::Abandoned,
+                            Some(DeliveryState::Rejected(_)) => MockSettlement::Rejected,
+                            _ => MockSettlement::Other
+                        }
+                    }
+                    Err(_) => {
+                        MockSettlement::Other
+                    }
+                };
+                let status = match settlement{
+                    MockSettlement::Completed => Some("Success"),
+                    MockSettlement::Rejected => Some("Rejected"),
+                    // Abandoned messages are delivered again, there is no outcome yet.
+                    _ => None
+                };
+                let reported = match (ack.as_str(), status){
+                    (_, None) => false,
+                    ("full", _) => true,
+                    ("positive", Some(status)) => status == "Success",
+                    ("negative", Some(status)) => status != "Success",
+                    _ => false
+                };
+                if reported{
+                    let record = json!({
+                        "originalMessageId": message_id,
+                        "deviceId": device,
+                        "deviceGenerationId": "",
+                        "statusCode": status,
+                        "description": status,
+                        "enqueuedTimeUtc": chrono::Utc::now().to_rfc3339()
+                    });
+                    let feedback_link = receivers.borrow().get(FEEDBACK_ADDRESS).cloned();
+                    match feedback_link{
+                        None => {
+                            state.lock().unwrap().pending_feedback.push(record);
+                        }
+                        Some(feedback_link) => {
+                            send_feedback(vec![record], feedback_link);
+                        }
+                    }
+                }
+                state.lock().unwrap().settlements.push((device, settlement));
+            });
+        }
+        Ok(types::Outcome::Accept)
+    }
+
+    // Telemetry of a partition after the position, as events. The offset of an event is its
+    // index in the telemetry of all devices.
+    fn stored_events(state: &Arc<Mutex<MockState>>, partition: &str, position: &EventPosition) -> Vec<Message> {
+        let state = state.lock().unwrap();
+        state.telemetry.iter()
+            .enumerate()
+            .filter(|(_, telemetry)| mock_partition(&telemetry.device_id) == partition)
+            .enumerate()
+            .filter(|(_, (offset, telemetry))| match position{
+                EventPosition::Start => true,
+                EventPosition::End => false,
+                EventPosition::Offset(after) => after.parse::<usize>().map_or(true, |after| *offset > after),
+                EventPosition::EnqueuedAfter(time) => telemetry.enqueued_time > *time,
+            })
+            .map(|(sequence_number, (offset, telemetry))| event_message(offset, sequence_number as i64, telemetry))
+            .collect()
+    }
+
+    fn event_message(offset: usize, sequence_number: i64, telemetry: &MockTelemetry) -> Message {
+        let mut event = Message::with_body(telemetry.body.clone());
+        event.add_message_annotation(OFFSET_ANNOTATION, string_variant(&offset.to_string()));
+        event.add_message_annotation(SEQUENCE_NUMBER_ANNOTATION, Variant::Long(sequence_number));
+        event.add_message_annotation(ENQUEUED_TIME_ANNOTATION, Variant::Timestamp(telemetry.enqueued_time));
+        event.add_message_annotation(DEVICE_ID_ANNOTATION, string_variant(&telemetry.device_id));
+        event
+    }
+
+    fn send_events(events: Vec<Message>, link: SenderLink){
+        ntex::rt::spawn(async move {
+            for event in events{
+                link.send(TransferBody::Message(Box::new(event))).await.ok();
+            }
+        });
+    }
+
+    // Answer READ requests on $management with the partition ids.
+    async fn answer_management_request(transfer: types::Transfer,
+                                       receivers: Rc<RefCell<HashMap<String, SenderLink>>>) -> Result<types::Outcome, AmqpError> {
+        let request: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        let mut response = Message::default();
+        let mut props = empty_properties();
+        props.correlation_id = request.properties.as_ref().and_then(|props| props.message_id.clone());
+        response.properties = Some(props);
+        if string_app_property(&request, "operation").as_deref() == Some("READ"){
+            let partition_ids: Vec<String> = MOCK_PARTITIONS.iter().map(|id| id.to_string()).collect();
+            response.body.value = Some(partition_ids_value(&partition_ids));
+            response.set_app_property(ByteString::from_static("status-code"), Variant::Int(200));
+        }
+        else {
+            response.set_app_property(ByteString::from_static("status-code"), Variant::Int(400));
+        }
+        let link = receivers.borrow().get(&normalize_address(MANAGEMENT_ADDRESS)).cloned();
+        if let Some(link) = link{
+            link.send(TransferBody::Message(Box::new(response))).await.ok();
+        }
+        Ok(types::Outcome::Accept)
+    }
+
+    // One feedback message carrying the records as a JSON array.
+    fn send_feedback(records: Vec<Value>, link: SenderLink){
+        if records.is_empty(){
+            return;
+        }
+        let mut feedback = Message::with_body(Bytes::from(Value::Array(records).to_string()));
+        let mut props = empty_properties();
+        props.content_type = Some(FEEDBACK_CONTENT_TYPE.into());
+        feedback.properties = Some(props);
+        ntex::rt::spawn(async move {
+            link.send(TransferBody::Message(Box::new(feedback))).await.ok();
+        });
+    }
+
+    // Accept every put-token request and reply with status 200 on the $cbs receiver.
+    async fn answer_put_token(transfer: types::Transfer,
+                              state: Arc<Mutex<MockState>>,
+                              receivers: Rc<RefCell<HashMap<String, SenderLink>>>) -> Result<types::Outcome, AmqpError> {
+        let request: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        let audience = string_app_property(&request, "name")
+            .ok_or_else(|| AmqpError::invalid_field().description("Missing name property"))?;
+        state.lock().unwrap().cbs_audiences.push(audience);
+        let mut response = Message::default();
+        let mut props = empty_properties();
+        props.correlation_id = request.properties.as_ref().and_then(|props| props.message_id.clone());
+        response.properties = Some(props);
+        response.set_app_property(ByteString::from_static("status-code"), Variant::Int(200));
+        response.set_app_property(ByteString::from_static("status-description"), string_variant("OK"));
+        let link = receivers.borrow().get(&normalize_address(CBS_ADDRESS)).cloned();
+        if let Some(link) = link{
+            link.send(TransferBody::Message(Box::new(response))).await.ok();
+        }
+        Ok(types::Outcome::Accept)
+    }
+
+    // GET, PATCH of reported properties and (un)subscribing to desired updates.
+    async fn answer_twin_request(transfer: types::Transfer,
+                                 device_id: String,
+                                 state: Arc<Mutex<MockState>>,
+                                 receivers: Rc<RefCell<HashMap<String, SenderLink>>>) -> Result<types::Outcome, AmqpError> {
+        let request: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        let operation = string_annotation(&request, OPERATION_ANNOTATION).unwrap_or_default();
+        let resource = string_annotation(&request, RESOURCE_ANNOTATION).unwrap_or_default();
+        let (status, version, body, notifications) = {
+            let mut state = state.lock().unwrap();
+            let twin = state.twins.entry(device_id.clone()).or_default();
+            let (status, version, body) = match (operation.as_str(), resource.as_str()){
+                ("GET", _) => {
+                    let document = json!({"desired": twin.desired, "reported": twin.reported});
+                    (200, None, Some(document.to_string()))
+                }
+                ("PATCH", REPORTED_RESOURCE) => {
+                    let body = response_body(&request).unwrap_or_default();
+                    match serde_json::from_slice::<Value>(&body){
+                        Ok(patch) if patch.is_object() => {
+                            (204, Some(merge_patch(&mut twin.reported, &patch)), None)
+                        }
+                        _ => (400, None, None)
+                    }
+                }
+                ("PUT", DESIRED_NOTIFICATIONS_RESOURCE) => {
+                    twin.subscribed = true;
+                    (200, None, None)
+                }
+                ("DELETE", DESIRED_NOTIFICATIONS_RESOURCE) => {
+                    twin.subscribed = false;
+                    (200, None, None)
+                }
+                _ => (400, None, None)
+            };
+            let notifications = if twin.subscribed{
+                std::mem::take(&mut twin.pending)
+            }
+            else {
+                Vec::new()
+            };
+            (status, version, body, notifications)
+        };
+        let mut response = match body{
+            None => Message::default(),
+            Some(body) => Message::with_body(Bytes::from(body))
+        };
+        let mut props = empty_properties();
+        props.correlation_id = request.properties.as_ref().and_then(|props| props.correlation_id.clone());
+        response.properties = Some(props);
+        response.add_message_annotation(STATUS_ANNOTATION, Variant::Int(status));
+        if let Some(version) = version{
+            response.add_message_annotation(VERSION_ANNOTATION, Variant::Long(version));
+        }
+        let link = receivers.borrow().get(&normalize_address(&format!("/devices/{}/twin", device_id))).cloned();
+        if let Some(link) = link{
+            link.send(TransferBody::Message(Box::new(response))).await.ok();
+            for notification in notifications{
+                let update = Message::with_body(Bytes::from(notification.to_string()));
+                link.send(TransferBody::Message(Box::new(update))).await.ok();
+            }
+        }
+        Ok(types::Outcome::Accept)
+    }
+
+    fn send_pending_methods(device_id: &str, link: SenderLink, state: Arc<Mutex<MockState>>){
+        let requests: Vec<MockMethodRequest> = {
+            let mut state = state.lock().unwrap();
+            let (requests, others) = std::mem::take(&mut state.pending_methods)
+                .into_iter()
+                .partition(|request| request.device_id == device_id);
+            state.pending_methods = others;
+            requests
+        };
+        ntex::rt::spawn(async move {
+            for method in requests{
+                let mut request = Message::with_body(Bytes::from(method.payload.to_string()));
+                let mut props = empty_properties();
+                props.correlation_id = Some(MessageId::String(ByteString::from(method.correlation_id)));
+                request.properties = Some(props);
+                request.set_app_property(ByteString::from_static(METHOD_NAME_PROPERTY), string_variant(&method.name));
+                link.send(TransferBody::Message(Box::new(request))).await.ok();
+            }
+        });
+    }
+
+    fn record_method_response(transfer: types::Transfer, device_id: String, state: Arc<Mutex<MockState>>) -> Result<types::Outcome, AmqpError> {
+        let response: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        let correlation_id = match response.properties.as_ref().and_then(|props| props.correlation_id.as_ref()){
+            Some(MessageId::String(id)) => id.to_string(),
+            _ => return Err(AmqpError::invalid_field().description("Missing correlation id"))
+        };
+        let status = response_status(&response)
+            .ok_or_else(|| AmqpError::invalid_field().description("Missing IoThub-status"))?;
+        let body = response_body(&response).unwrap_or_default();
+        state.lock().unwrap().method_responses.push(MockMethodResponse{
+            device_id,
+            correlation_id,
+            status,
+            payload: serde_json::from_slice(&body).unwrap_or(Value::Null)
+        });
+        Ok(types::Outcome::Accept)
+    }
+
+    // Merge a JSON patch (null removes) into the properties and bump their $version.
+    fn merge_patch(properties: &mut Value, patch: &Value) -> i64 {
+        if let (Some(target), Some(changes)) = (properties.as_object_mut(), patch.as_object()){
+            for (key, value) in changes{
+                if key.starts_with('$'){
+                    continue;
+                }
+                if value.is_null(){
+                    target.remove(key);
+                }
+                else {
+                    target.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        let version = properties.get("$version").and_then(|version| version.as_i64()).unwrap_or(0) + 1;
+        properties["$version"] = json!(version);
+        version
+    }
+
+    // Body and creation time of the message
+    fn message_body(transfer: &types::Transfer) -> Result<(Bytes, Option<String>), AmqpError> {
+        match transfer.load_message::<Message>(){
+            Ok(message) => {
+                Ok(body_and_creation_time(&message))
+            }
+            Err(_) => {
+                // Not an encoded message, keep the raw payload.
+                Ok((transfer.body().cloned().unwrap_or_default(), None))
+            }
+        }
+    }
+
+    fn body_and_creation_time(message: &Message) -> (Bytes, Option<String>) {
+        (message.body.data().cloned().unwrap_or_default(), string_annotation(message, CREATION_TIME_ANNOTATION))
+    }
+
+    // Body of the message, or of every message in a batch (one encoded message per data section).
+    fn message_bodies(transfer: &types::Transfer) -> Result<Vec<(Bytes, Option<String>)>, AmqpError> {
+        if transfer.frame().message_format != Some(BATCH_MESSAGE_FORMAT){
+            return Ok(vec![message_body(transfer)?]);
+        }
+        let batch: Message = transfer.load_message()
+            .map_err(|_| AmqpError::decode_error().description("Not an AMQP message"))?;
+        batch.body.data.iter()
+            .map(|section| match Message::decode(section){
+                Ok((_, message)) => Ok(body_and_creation_time(&message)),
+                Err(_) => Err(AmqpError::decode_error().description("Batch section is not an AMQP message"))
+            })
+            .collect()
+    }
+
+    fn link_address(link: &types::Link<()>) -> String {
+        link.frame().target.as_ref()
+            .and_then(|target| target.address.as_ref())
+            .map(|address| address.to_string())
+            .unwrap_or_default()
+    }
+
+    // Splits the SASL PLAIN initial response: authzid NUL authcid NUL password
+    pub fn parse_plain_response(response: &[u8]) -> Option<(String, String)> {
+        let parts: Vec<&[u8]> = response.split(|byte| *byte == 0).collect();
+        if parts.len() != 3{
+            return None;
+        }
+        let username = String::from_utf8(parts[1].to_vec()).ok()?;
+        let password = String::from_utf8(parts[2].to_vec()).ok()?;
+        Some((username, password))
+    }
+
+    // Device logins are "{device}@sas.{hub}", policy logins "{policy}@sas.root.{hub}".
+    pub fn is_valid_login(username: &str, password: &str) -> bool {
+        username.contains("@sas.") && password.starts_with("SharedAccessSignature ")
+    }
+
+    pub fn normalize_address(address: &str) -> String {
+        format!("/{}", address.trim_start_matches('/'))
+    }
+
+    pub fn device_from_address(address: &str) -> String {
+        // /devices/{device_id}/messages/events
+        normalize_address(address)
+            .split('/')
+            .nth(2)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    pub enum MockBrokerFailure{
+        SaslRequired,
+        InvalidLogin,
+    }
+
+    impl Display for MockBrokerFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match *self{
+                MockBrokerFailure::SaslRequired => write!(f, "SASL PLAIN is required."),
+                MockBrokerFailure::InvalidLogin => write!(f, "Invalid SAS login."),
+            }
+        }
+    }
+
+    impl From<MockBrokerFailure> for AmqpError {
+        fn from(failure: MockBrokerFailure) -> Self {
+            AmqpError::unauthorized_access().description(failure.to_string())
+        }
+    }
+}
+pub mod http{
+    // Plain HTTP/1.1 server for stand-ins of the REST endpoints, one request per connection.
+    // Requests are handled one after the other on a worker thread.
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use serde_json::{json, Value};
+
+    pub struct MockRequest{
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl MockRequest{
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        // Path without the query and the query (empty when there is none)
+        pub fn path_and_query(&self) -> (&str, &str) {
+            self.path.split_once('?').unwrap_or((self.path.as_str(), ""))
+        }
+    }
+
+    pub struct MockResponse{
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Option<Value>,
+    }
+
+    impl MockResponse{
+        pub fn json(status: u16, body: Value) -> MockResponse {
+            MockResponse{
+                status,
+                headers: Vec::new(),
+                body: Some(body)
+            }
+        }
+
+        pub fn empty(status: u16) -> MockResponse {
+            MockResponse{
+                status,
+                headers: Vec::new(),
+                body: None
+            }
+        }
+
+        // Error body in the format of the Azure REST endpoints
+        pub fn error(status: u16, message: &str) -> MockResponse {
+            MockResponse::json(status, json!({"Message": message}))
+        }
+
+        pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    pub struct MockHttpServer{
+        port: u16,
+        stopped: Arc<AtomicBool>,
+        worker: Option<thread::JoinHandle<()>>,
+    }
+
+    impl MockHttpServer{
+        // Start on a free local port, every request is answered by the handler.
+        pub fn start<H>(name: &str, mut handler: H) -> std::io::Result<MockHttpServer>
+            where H: FnMut(&MockRequest) -> MockResponse + Send + 'static {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let port = listener.local_addr()?.port();
+            let stopped = Arc::new(AtomicBool::new(false));
+            let worker_stopped = stopped.clone();
+            let worker_name = name.to_string();
+            let worker = thread::Builder::new().name(name.to_string()).spawn(move || {
+                for stream in listener.incoming(){
+                    if worker_stopped.load(Ordering::SeqCst){
+                        break;
+                    }
+                    match stream{
+                        Ok(stream) => {
+                            if let Err(err) = serve(stream, &mut handler){
+                                println!("{}: {}", worker_name, err);
+                            }
+                        }
+                        Err(err) => {
+                            println!("{} accept failed: {}", worker_name, err);
+                        }
+                    }
+                }
+            })?;
+            Ok(MockHttpServer{
+                port,
+                stopped,
+                worker: Some(worker)
+            })
+        }
+
+        pub fn port(&self) -> u16 {
+            self.port
+        }
+
+        pub fn stop(mut self){
+            self.shutdown();
+        }
+
+        fn shutdown(&mut self){
+            self.stopped.store(true, Ordering::SeqCst);
+            // Wake up the blocking accept
+            TcpStream::connect(("127.0.0.1", self.port)).ok();
+            if let Some(worker) = self.worker.take(){
+                worker.join().ok();
+            }
+        }
+    }
+
+    impl Drop for MockHttpServer{
+        fn drop(&mut self){
+            self.shutdown();
+        }
+    }
+
+    fn serve<H>(mut stream: TcpStream, handler: &mut H) -> std::io::Result<()>
+        where H: FnMut(&MockRequest) -> MockResponse {
+        let request = match read_request(&mut stream)?{
+            None => {
+                // Connection without a request (the wake up of shutdown)
+                return Ok(());
+            }
+            Some(request) => {
+                request
+            }
+        };
+        let response = handler(&request);
+        let body = response.body.map(|body| body.to_string()).unwrap_or_default();
+        let mut head = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+                               response.status, reason(response.status), body.len());
+        for (name, value) in response.headers{
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        stream.write_all(format!("{}\r\n{}", head, body).as_bytes())?;
+        stream.flush()
+    }
+
+    fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<MockRequest>> {
+        let mut raw = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let head_end = loop{
+            if let Some(position) = raw.windows(4).position(|window| window == b"\r\n\r\n"){
+                break position;
+            }
+            let read = stream.read(&mut buffer)?;
+            if read == 0{
+                return Ok(None);
+            }
+            raw.extend_from_slice(&buffer[..read]);
+        };
+        let head = String::from_utf8_lossy(&raw[..head_end]).to_string();
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default().to_string();
+        let path = request_line.next().unwrap_or_default().to_string();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let mut request = MockRequest{
+            method,
+            path,
+            headers,
+            body: raw[head_end + 4..].to_vec()
+        };
+        let length = request.header("Content-Length")
+            .and_then(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+        while request.body.len() < length{
+            let read = stream.read(&mut buffer)?;
+            if read == 0{
+                break;
+            }
+            request.body.extend_from_slice(&buffer[..read]);
+        }
+        Ok(Some(request))
+    }
+
+    fn reason(status: u16) -> &'static str {
+        match status{
+            200 => "OK",
+            202 => "Accepted",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            412 => "Precondition Failed",
+            _ => "Error"
+        }
+    }
+}
+pub mod registry{
+    // Local stand-in for the registry REST endpoint of the hub, plain HTTP on a free port.
+    // Identities are kept as JSON, etags count the versions of an identity.
+    // Requests need a service token, it is not verified.
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::registry::{generate_key, RegistryClient};
+    use crate::testing::http::{MockHttpServer, MockRequest, MockResponse};
+
+    #[derive(Default)]
+    struct RegistryState{
+        devices: BTreeMap<String, Value>,
+        next_version: u64,
+        // Method and path of every request, in arrival order
+        requests: Vec<String>,
+    }
+
+    pub struct MockRegistry{
+        server: MockHttpServer,
+        state: Arc<Mutex<RegistryState>>,
+    }
+
+    impl MockRegistry{
+        pub fn start() -> std::io::Result<MockRegistry> {
+            let state = Arc::new(Mutex::new(RegistryState::default()));
+            let server_state = state.clone();
+            let server = MockHttpServer::start("mock-registry", move |request| {
+                answer(request, &mut server_state.lock().unwrap())
+            })?;
+            Ok(MockRegistry{
+                server,
+                state
+            })
+        }
+
+        pub fn port(&self) -> u16 {
+            self.server.port()
+        }
+
+        // Registry client pointed at the stand-in.
+        pub fn client(&self, primary_key: &str, policy: &str) -> RegistryClient {
+            RegistryClient::plain("127.0.0.1", self.server.port(), primary_key, policy)
+        }
+
+        // Identity as stored, None when the device does not exist
+        pub fn device(&self, device_id: &str) -> Option<Value> {
+            self.state.lock().unwrap().devices.get(device_id).cloned()
+        }
+
+        // "METHOD path" of every request received
+        pub fn requests(&self) -> Vec<String> {
+            self.state.lock().unwrap().requests.clone()
+        }
+
+        pub fn stop(self){
+            self.server.stop();
+        }
+    }
+
+    fn answer(request: &MockRequest, state: &mut RegistryState) -> MockResponse {
+        state.requests.push(format!("{} {}", request.method, request.path));
+        if !request.header("Authorization").unwrap_or_default().starts_with("SharedAccessSignature "){
+            return MockResponse::error(401, "Unauthorized");
+        }
+        let (path, query) = request.path_and_query();
+        if !query.contains("api-version="){
+            return MockResponse::error(400, "api-version is missing");
+        }
+        let device_id = match path.strip_prefix("/devices/"){
+            None if path == "/devices" && request.method == "GET" => {
+                let top = query.split('&')
+                    .find_map(|pair| pair.strip_prefix("top="))
+                    .and_then(|top| top.parse::<usize>().ok())
+                    .unwrap_or(usize::MAX);
+                let devices: Vec<Value> = state.devices.values().take(top).cloned().collect();
+                return MockResponse::json(200, Value::Array(devices));
+            }
+            None => {
+                return MockResponse::error(404, "Unknown resource");
+            }
+            Some(device_id) => {
+                urlencoding::decode(device_id).map(|device_id| device_id.to_string()).unwrap_or_default()
+            }
+        };
+        let if_match = request.header("If-Match").map(|etag| etag.trim_matches('"').to_string());
+        let etag_matches = |device: &Value| match if_match.as_deref(){
+            None | Some("*") => true,
+            Some(etag) => device["etag"].as_str() == Some(etag)
+        };
+        match request.method.as_str(){
+            "GET" => {
+                match state.devices.get(&device_id){
+                    None => MockResponse::error(404, "DeviceNotFound"),
+                    Some(device) => MockResponse::json(200, device.clone())
+                }
+            }
+            "PUT" => {
+                let mut device: Value = match serde_json::from_slice(&request.body){
+                    Ok(device) => device,
+                    Err(_) => return MockResponse::error(400, "Invalid identity")
+                };
+                if device["deviceId"].as_str() != Some(device_id.as_str()){
+                    return MockResponse::error(400, "The device id does not match the path");
+                }
+                let generation_id = match (state.devices.get(&device_id), if_match.is_some()){
+                    // Creating needs a free id, updating an existing one with a matching etag
+                    (Some(_), false) => return MockResponse::error(409, "DeviceAlreadyExists"),
+                    (None, true) => return MockResponse::error(404, "DeviceNotFound"),
+                    (Some(existing), true) => {
+                        if !etag_matches(existing){
+                            return MockResponse::error(412, "PreconditionFailed");
+                        }
+                        existing["generationId"].clone()
+                    }
+                    (None, false) => {
+                        json!(format!("{:016x}", rand::random::<u64>()))
+                    }
+                };
+                state.next_version += 1;
+                device["etag"] = json!(base64::encode(state.next_version.to_string()));
+                device["generationId"] = generation_id;
+                device["connectionState"] = json!("Disconnected");
+                if device["status"].is_null(){
+                    device["status"] = json!("enabled");
+                }
+                if device["authentication"].is_null(){
+                    device["authentication"] = json!({"type": "sas", "symmetricKey": {}});
+                }
+                if device["authentication"]["type"] == "sas"{
+                    for key in ["primaryKey", "secondaryKey"]{
+                        if device["authentication"]["symmetricKey"][key].is_null(){
+                            device["authentication"]["symmetricKey"][key] = json!(generate_key());
+                        }
+                    }
+                }
+                state.devices.insert(device_id, device.clone());
+                MockResponse::json(200, device)
+            }
+            "DELETE" => {
+                match state.devices.get(&device_id){
+                    None => MockResponse::error(404, "DeviceNotFound"),
+                    Some(device) if !etag_matches(device) => MockResponse::error(412, "PreconditionFailed"),
+                    Some(_) => {
+                        state.devices.remove(&device_id);
+                        MockResponse::empty(204)
+                    }
+                }
+            }
+            _ => {
+                MockResponse::error(405, "Method not allowed")
+            }
+        }
+    }
+}
+pub mod provisioning{
+    // Local stand-in for the Device Provisioning Service with one symmetric key group enrollment.
+    // Registration tokens are verified with the key derived from the group key (or the key of an
+    // individual enrollment), registrations are assigned after a number of polls.
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use chrono::Utc;
+    use serde_json::json;
+    use crate::provisioning::{ProvisioningClient, REGISTRATION_KEY_NAME};
+    use crate::testing::http::{MockHttpServer, MockRequest, MockResponse};
+    use crate::util::token::{SasToken, SystemClock};
+
+    struct ProvisioningState{
+        id_scope: String,
+        assigned_hub: String,
+        group_key: String,
+        // Registration id -> key of the individual enrollment
+        individual: HashMap<String, String>,
+        disabled: Vec<String>,
+        // Operation id -> registration id and the polls left until it is assigned
+        operations: HashMap<String, (String, u32)>,
+        polls_before_assignment: u32,
+        next_operation: u64,
+        assigned: Vec<String>,
+    }
+
+    pub struct MockProvisioning{
+        server: MockHttpServer,
+        state: Arc<Mutex<ProvisioningState>>,
+    }
+
+    impl MockProvisioning{
+        // Devices registering in id_scope are assigned to assigned_hub (a host name).
+        pub fn start(id_scope: &str, assigned_hub: &str, group_key: &str) -> std::io::Result<MockProvisioning> {
+            let state = Arc::new(Mutex::new(ProvisioningState{
+                id_scope: id_scope.to_string(),
+                assigned_hub: assigned_hub.to_string(),
+                group_key: group_key.to_string(),
+                individual: HashMap::new(),
+                disabled: Vec::new(),
+                operations: HashMap::new(),
+                polls_before_assignment: 1,
+                next_operation: 0,
+                assigned: Vec::new()
+            }));
+            let server_state = state.clone();
+            let server = MockHttpServer::start("mock-provisioning", move |request| {
+                answer(request, &mut server_state.lock().unwrap())
+            })?;
+            Ok(MockProvisioning{
+                server,
+                state
+            })
+        }
+
+        pub fn port(&self) -> u16 {
+            self.server.port()
+        }
+
+        // Provisioning client pointed at the stand-in.
+        pub fn client(&self, registration_id: &str, device_key: &str) -> ProvisioningClient {
+            let id_scope = self.state.lock().unwrap().id_scope.clone();
+            ProvisioningClient::plain("127.0.0.1", self.server.port(), &id_scope, registration_id, device_key)
+        }
+
+        // Polls answered with "assigning" before a registration is assigned (default 1)
+        pub fn set_polls_before_assignment(&self, polls: u32){
+            self.state.lock().unwrap().polls_before_assignment = polls;
+        }
+
+        pub fn add_individual_enrollment(&self, registration_id: &str, key: &str){
+            self.state.lock().unwrap().individual.insert(registration_id.to_string(), key.to_string());
+        }
+
+        pub fn disable_enrollment(&self, registration_id: &str){
+            self.state.lock().unwrap().disabled.push(registration_id.to_string());
+        }
+
+        // Registration ids assigned so far, in order
+        pub fn assigned(&self) -> Vec<String> {
+            self.state.lock().unwrap().assigned.clone()
+        }
+
+        pub fn stop(self){
+            self.server.stop();
+        }
+    }
+
+    fn answer(request: &MockRequest, state: &mut ProvisioningState) -> MockResponse {
+        let (path, query) = request.path_and_query();
+        if !query.contains("api-version="){
+            return MockResponse::error(400, "api-version is missing");
+        }
+        // /{id scope}/registrations/{registration id}/register
+        // /{id scope}/registrations/{registration id}/operations/{operation id}
+        let parts: Vec<String> = path.trim_start_matches('/').split('/')
+            .map(|part| urlencoding::decode(part).map(|part| part.to_string()).unwrap_or_default())
+            .collect();
+        if parts.len() < 4 || parts[0] != state.id_scope || parts[1] != "registrations"{
+            return MockResponse::error(404, "Unknown resource");
+        }
+        let registration_id = parts[2].clone();
+        if !authorized(request, state, &registration_id){
+            return MockResponse::error(401, "Unauthorized");
+        }
+        match (request.method.as_str(), parts[3].as_str(), parts.get(4)){
+            ("PUT", "register", None) => {
+                if state.disabled.contains(&registration_id){
+                    return MockResponse::json(200, json!({
+                        "operationId": "disabled",
+                        "status": "disabled",
+                        "registrationState": {"registrationId": registration_id, "status": "disabled"}
+                    }));
+                }
+                state.next_operation += 1;
+                let operation_id = format!("4.{:016x}.{}", rand::random::<u64>(), state.next_operation);
+                state.operations.insert(operation_id.clone(), (registration_id, state.polls_before_assignment));
+                MockResponse::json(202, json!({"operationId": operation_id, "status": "assigning"}))
+            }
+            ("GET", "operations", Some(operation_id)) => {
+                let (operation_registration, polls_left) = match state.operations.get_mut(operation_id){
+                    None => return MockResponse::error(404, "Unknown operation"),
+                    Some(operation) => operation
+                };
+                if *operation_registration != registration_id{
+                    return MockResponse::error(404, "Unknown operation");
+                }
+                if *polls_left > 0{
+                    *polls_left -= 1;
+                    return MockResponse::json(202, json!({"operationId": operation_id, "status": "assigning"}));
+                }
+                if !state.assigned.contains(&registration_id){
+                    state.assigned.push(registration_id.clone());
+                }
+                let now = Utc::now();
+                MockResponse::json(200, json!({
+                    "operationId": operation_id,
+                    "status": "assigned",
+                    "registrationState": {
+                        "registrationId": registration_id,
+                        "createdDateTimeUtc": now,
+                        "assignedHub": state.assigned_hub,
+                        "deviceId": registration_id,
+                        "status": "assigned",
+                        "substatus": "initialAssignment",
+                        "lastUpdatedDateTimeUtc": now,
+                        "etag": "\"00000000-0000-0000-0000-000000000000\""
+                    }
+                }))
+            }
+            _ => {
+                MockResponse::error(405, "Method not allowed")
+            }
+        }
+    }
+
+    // Token for {id scope}/registrations/{registration id}, signed with the key of the enrollment.
+    fn authorized(request: &MockRequest, state: &ProvisioningState, registration_id: &str) -> bool {
+        let token = match request.header("Authorization").map(SasToken::parse){
+            Some(Ok(token)) => token,
+            _ => return false
+        };
+        let key = match state.individual.get(registration_id){
+            Some(key) => key.clone(),
+            None => match SasToken::derive_device_key(&state.group_key, registration_id){
+                Ok(key) => key,
+                Err(_) => return false
+            }
+        };
+        token.resource_uri() == format!("{}/registrations/{}", state.id_scope, registration_id)
+            && token.key_name.as_deref() == Some(REGISTRATION_KEY_NAME)
+            && !token.is_expired(&SystemClock)
+            && token.verify(&key)
+    }
+}
@@ -0,0 +1,577 @@
+pub mod websocket{
+    // AMQP over WebSockets: IoT Hub accepts the AMQP byte stream inside binary
+    // WebSocket frames on wss://{host}:443/$iothub/websocket (subprotocol AMQPWSB10).
+    // Useful on networks that block outbound port 5671.
+    use std::future::Future;
+    use std::io;
+    use std::marker::PhantomData;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use futures::{Sink, Stream};
+    use ntex::connect::{Address, Connect, ConnectError};
+    use ntex::service::Service;
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    pub const WEBSOCKET_PATH: &str = "/$iothub/websocket";
+    pub const WEBSOCKET_SUBPROTOCOL: &str = "AMQPWSB10";
+    pub const WEBSOCKET_PORT: u16 = 443;
+
+    // Byte stream over binary WebSocket frames, so the AMQP codec can run on top of it.
+    pub struct WsStream<S>{
+        inner: WebSocketStream<S>,
+        read_buffer: Vec<u8>,
+        read_position: usize,
+    }
+
+    impl<S> WsStream<S>{
+        pub fn new(inner: WebSocketStream<S>) -> WsStream<S> {
+            WsStream{
+                inner,
+                read_buffer: Vec::new(),
+                read_position: 0
+            }
+        }
+    }
+
+    fn to_io_error(error: tokio_tungstenite::tungstenite::Error) -> io::Error {
+        io::Error::other(error)
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S>{
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            loop{
+                if self.read_position < self.read_buffer.len(){
+                    // Hand out what is left of the last frame first.
+                    let available = &self.read_buffer[self.read_position..];
+                    let amount = available.len().min(buf.remaining());
+                    buf.put_slice(&available[..amount]);
+                    self.read_position += amount;
+                    return Poll::Ready(Ok(()));
+                }
+                match Pin::new(&mut self.inner).poll_next(cx){
+                    Poll::Pending => {
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(None) | Poll::Ready(Some(Ok(Message::Close(_)))) => {
+                        // End of stream
+                        return Poll::Ready(Ok(()));
+                    }
+                    Poll::Ready(Some(Err(error))) => {
+                        return Poll::Ready(Err(to_io_error(error)));
+                    }
+                    Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                        self.read_buffer = data;
+                        self.read_position = 0;
+                    }
+                    Poll::Ready(Some(Ok(_))) => {
+                        // Ping/pong are answered by tungstenite, text frames are not AMQP.
+                        continue;
+                    }
+                }
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S>{
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            match Pin::new(&mut self.inner).poll_ready(cx){
+                Poll::Pending => {
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(error)) => {
+                    return Poll::Ready(Err(to_io_error(error)));
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+            match Pin::new(&mut self.inner).start_send(Message::Binary(buf.to_vec())){
+                Ok(()) => Poll::Ready(Ok(buf.len())),
+                Err(error) => Poll::Ready(Err(to_io_error(error)))
+            }
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx).map_err(to_io_error)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_close(cx).map_err(to_io_error)
+        }
+    }
+
+    // Upgrade an open stream to a WebSocket speaking the AMQP subprotocol.
+    pub async fn websocket_handshake<S>(stream: S, url: &str) -> io::Result<WsStream<S>>
+        where S: AsyncRead + AsyncWrite + Unpin {
+        let mut request = url.into_client_request().map_err(to_io_error)?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(WEBSOCKET_SUBPROTOCOL)
+        );
+        let (socket, response) = tokio_tungstenite::client_async(request, stream).await
+            .map_err(to_io_error)?;
+        let protocol = response.headers().get("Sec-WebSocket-Protocol");
+        if protocol.map(|value| value.as_bytes()) != Some(WEBSOCKET_SUBPROTOCOL.as_bytes()){
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Server did not accept the AMQPWSB10 subprotocol"));
+        }
+        Ok(WsStream::new(socket))
+    }
+
+    pub fn websocket_url(host: &str, port: u16) -> String {
+        if port == WEBSOCKET_PORT{
+            format!("wss://{}{}", host, WEBSOCKET_PATH)
+        }
+        else {
+            format!("wss://{}:{}{}", host, port, WEBSOCKET_PATH)
+        }
+    }
+
+    // TCP -> TLS (same rustls config as the AMQPS transport) -> WebSocket
+    pub async fn connect_websocket(tls_config: Arc<ClientConfig>, host: &str, port: u16) -> io::Result<WsStream<TlsStream<TcpStream>>> {
+        let tcp = TcpStream::connect((host, port)).await?;
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(host)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid host name"))?;
+        let tls = TlsConnector::from(tls_config).connect(dns_name, tcp).await?;
+        websocket_handshake(tls, &websocket_url(host, port)).await
+    }
+
+    // ntex connector service, plugs the WebSocket stream into ntex_amqp::client::Connector.
+    pub struct WebSocketConnector<T>{
+        tls_config: Arc<ClientConfig>,
+        _address: PhantomData<T>,
+    }
+
+    impl<T> WebSocketConnector<T>{
+        pub fn new(tls_config: Arc<ClientConfig>) -> WebSocketConnector<T> {
+            WebSocketConnector{
+                tls_config,
+                _address: PhantomData
+            }
+        }
+    }
+
+    impl<T> Clone for WebSocketConnector<T>{
+        fn clone(&self) -> Self {
+            WebSocketConnector::new(self.tls_config.clone())
+        }
+    }
+
+    impl<T: Address> Service for WebSocketConnector<T>{
+        type Request = Connect<T>;
+        type Response = WsStream<TlsStream<TcpStream>>;
+        type Error = ConnectError;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+        fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&self, request: Connect<T>) -> Self::Future {
+            let tls_config = self.tls_config.clone();
+            let host = request.host().to_string();
+            let port = request.port();
+            Box::pin(async move {
+                connect_websocket(tls_config, &host, port).await.map_err(ConnectError::Io)
+            })
+        }
+    }
+}
+
+pub mod sasl{
+    // SASL ANONYMOUS for X.509 devices: the TLS client certificate is the identity,
+    // the SASL layer only has to be negotiated. ntex-amqp only speaks SASL PLAIN,
+    // so the exchange is done here before the stream is handed to ntex-amqp.
+    use std::fmt::{Display, Formatter};
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use ntex::connect::{Address, Connect, ConnectError};
+    use ntex::service::Service;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    pub const SASL_PROTOCOL_HEADER: [u8; 8] = *b"AMQP\x03\x01\x00\x00";
+    pub const ANONYMOUS: &str = "ANONYMOUS";
+    // Frame type of SASL frames
+    const SASL_FRAME_TYPE: u8 = 0x01;
+    // Descriptors of the SASL performatives
+    const SASL_MECHANISMS: u8 = 0x40;
+    const SASL_INIT: u8 = 0x41;
+    const SASL_OUTCOME: u8 = 0x44;
+    // Upper bound for SASL frames, they only carry a few symbols.
+    const MAX_SASL_FRAME_SIZE: usize = 512;
+
+    #[derive(Debug, PartialEq)]
+    pub enum SaslFailure
+    {
+        InvalidProtocolHeader,
+        InvalidFrame,
+        MechanismNotOffered,
+        // sasl-outcome code: 1 auth, 2 sys, 3 sys-perm, 4 sys-temp
+        Rejected(u8),
+    }
+    impl Display for SaslFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                SaslFailure::InvalidProtocolHeader => write!(f, "The server did not answer with the SASL protocol header."),
+                SaslFailure::InvalidFrame => write!(f, "Received an invalid SASL frame."),
+                SaslFailure::MechanismNotOffered => write!(f, "The server does not offer SASL ANONYMOUS."),
+                SaslFailure::Rejected(code) => write!(f, "SASL authentication rejected with code {}.", code),
+            }
+        }
+    }
+    impl std::error::Error for SaslFailure {}
+
+    fn to_io_error(failure: SaslFailure) -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, failure)
+    }
+
+    // sasl-init with only the mechanism set
+    pub fn encode_sasl_init(mechanism: &str) -> Vec<u8> {
+        let mut body = vec![0x00, 0x53, SASL_INIT];
+        // list8: size, count, sym8 mechanism
+        body.push(0xc0);
+        body.push((1 + 2 + mechanism.len()) as u8);
+        body.push(1);
+        body.push(0xa3);
+        body.push(mechanism.len() as u8);
+        body.extend_from_slice(mechanism.as_bytes());
+        let mut frame = Vec::with_capacity(8 + body.len());
+        frame.extend_from_slice(&((8 + body.len()) as u32).to_be_bytes());
+        // doff 2 (8 byte header), SASL frame, channel 0
+        frame.extend_from_slice(&[0x02, SASL_FRAME_TYPE, 0x00, 0x00]);
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    // Returns the descriptor and the encoded fields of a SASL frame.
+    pub fn decode_sasl_frame(frame: &[u8]) -> Result<(u8, &[u8]), SaslFailure> {
+        if frame.len() < 8 || frame[5] != SASL_FRAME_TYPE{
+            return Err(SaslFailure::InvalidFrame);
+        }
+        let body_offset = frame[4] as usize * 4;
+        if body_offset < 8 || frame.len() < body_offset + 3{
+            return Err(SaslFailure::InvalidFrame);
+        }
+        let body = &frame[body_offset..];
+        // Descriptor as smallulong
+        if body[0] != 0x00 || body[1] != 0x53{
+            return Err(SaslFailure::InvalidFrame);
+        }
+        Ok((body[2], &body[3..]))
+    }
+
+    // First field of the sasl-outcome list is the ubyte code.
+    pub fn decode_sasl_outcome(fields: &[u8]) -> Result<u8, SaslFailure> {
+        let first = match fields.first(){
+            Some(0xc0) => 3,
+            Some(0xd0) => 9,
+            _ => {
+                return Err(SaslFailure::InvalidFrame);
+            }
+        };
+        match (fields.get(first), fields.get(first + 1)){
+            (Some(0x50), Some(code)) => Ok(*code),
+            _ => Err(SaslFailure::InvalidFrame)
+        }
+    }
+
+    async fn read_sasl_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).await?;
+        let size = u32::from_be_bytes(size) as usize;
+        if !(8..=MAX_SASL_FRAME_SIZE).contains(&size){
+            return Err(to_io_error(SaslFailure::InvalidFrame));
+        }
+        let mut frame = vec![0u8; size];
+        frame[..4].copy_from_slice(&(size as u32).to_be_bytes());
+        stream.read_exact(&mut frame[4..]).await?;
+        Ok(frame)
+    }
+
+    // Header exchange, mechanisms, init and outcome.
+    pub async fn sasl_anonymous_handshake<S>(stream: &mut S) -> io::Result<()>
+        where S: AsyncRead + AsyncWrite + Unpin {
+        stream.write_all(&SASL_PROTOCOL_HEADER).await?;
+        stream.flush().await?;
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+        if header != SASL_PROTOCOL_HEADER{
+            return Err(to_io_error(SaslFailure::InvalidProtocolHeader));
+        }
+        let mechanisms = read_sasl_frame(stream).await?;
+        let (descriptor, fields) = decode_sasl_frame(&mechanisms).map_err(to_io_error)?;
+        if descriptor != SASL_MECHANISMS{
+            return Err(to_io_error(SaslFailure::InvalidFrame));
+        }
+        if !fields.windows(ANONYMOUS.len()).any(|symbol| symbol == ANONYMOUS.as_bytes()){
+            return Err(to_io_error(SaslFailure::MechanismNotOffered));
+        }
+        stream.write_all(&encode_sasl_init(ANONYMOUS)).await?;
+        stream.flush().await?;
+        let outcome = read_sasl_frame(stream).await?;
+        let (descriptor, fields) = decode_sasl_frame(&outcome).map_err(to_io_error)?;
+        if descriptor != SASL_OUTCOME{
+            return Err(to_io_error(SaslFailure::InvalidFrame));
+        }
+        match decode_sasl_outcome(fields).map_err(to_io_error)?{
+            0 => Ok(()),
+            code => Err(to_io_error(SaslFailure::Rejected(code)))
+        }
+    }
+
+    // Wraps a connector (TLS or WebSocket) and runs SASL ANONYMOUS on the new stream.
+    // Use it with ntex_amqp::client::Connector::connect, not connect_sasl.
+    #[derive(Clone)]
+    pub struct SaslAnonymousConnector<C>{
+        inner: C,
+    }
+
+    impl<C> SaslAnonymousConnector<C>{
+        pub fn new(inner: C) -> SaslAnonymousConnector<C> {
+            SaslAnonymousConnector{
+                inner
+            }
+        }
+    }
+
+    impl<C, T> Service for SaslAnonymousConnector<C>
+        where T: Address,
+              C: Service<Request = Connect<T>, Error = ConnectError>,
+              C::Future: 'static,
+              C::Response: AsyncRead + AsyncWrite + Unpin + 'static {
+        type Request = Connect<T>;
+        type Response = C::Response;
+        type Error = ConnectError;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+        fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&self, request: Connect<T>) -> Self::Future {
+            let connecting = self.inner.call(request);
+            Box::pin(async move {
+                let mut stream = connecting.await?;
+                sasl_anonymous_handshake(&mut stream).await.map_err(ConnectError::Io)?;
+                Ok(stream)
+            })
+        }
+    }
+}
+pub mod https{
+    // Minimal HTTP/1.1 over TLS (plain HTTP for local stand-ins) for the IoT Hub REST
+    // endpoints: direct method invocation, the device registry (crate::registry) and the
+    // Device Provisioning Service (crate::provisioning).
+    // One request per connection (Connection: close), the response is read to the end.
+    use std::fmt::{Display, Formatter};
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    pub const HTTPS_PORT: u16 = 443;
+    // Responses of the REST endpoints are small, anything bigger is refused.
+    const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct HttpResponse{
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl HttpResponse{
+        // Header value, names compared case-insensitively.
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn is_success(&self) -> bool {
+            (200..300).contains(&self.status)
+        }
+    }
+
+    pub fn encode_request(method: &str, host: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Vec<u8> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, host);
+        for (name, value) in headers{
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let body = body.unwrap_or_default();
+        request.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        let mut encoded = request.into_bytes();
+        encoded.extend_from_slice(body);
+        encoded
+    }
+
+    // Parse a complete response: status line, headers and a body that is either
+    // chunked, sized by Content-Length or everything up to the end of the stream.
+    pub fn parse_response(raw: &[u8]) -> Result<HttpResponse, HttpFailure> {
+        let head_end = find(raw, b"\r\n\r\n").ok_or(HttpFailure::InvalidResponse)?;
+        let head = std::str::from_utf8(&raw[..head_end]).map_err(|_| HttpFailure::InvalidResponse)?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().ok_or(HttpFailure::InvalidResponse)?;
+        // HTTP/1.1 200 OK
+        let mut parts = status_line.splitn(3, ' ');
+        if !parts.next().unwrap_or_default().starts_with("HTTP/"){
+            return Err(HttpFailure::InvalidResponse);
+        }
+        let status = parts.next()
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or(HttpFailure::InvalidResponse)?;
+        let mut headers = Vec::new();
+        for line in lines{
+            let (name, value) = line.split_once(':').ok_or(HttpFailure::InvalidResponse)?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let mut response = HttpResponse{
+            status,
+            headers,
+            body: Vec::new()
+        };
+        let rest = &raw[head_end + 4..];
+        let chunked = response.header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+        response.body = if chunked{
+            decode_chunked(rest)?
+        }
+        else if let Some(length) = response.header("Content-Length"){
+            let length = length.parse::<usize>().map_err(|_| HttpFailure::InvalidResponse)?;
+            if rest.len() < length{
+                return Err(HttpFailure::InvalidResponse);
+            }
+            rest[..length].to_vec()
+        }
+        else {
+            rest.to_vec()
+        };
+        Ok(response)
+    }
+
+    fn decode_chunked(mut raw: &[u8]) -> Result<Vec<u8>, HttpFailure> {
+        let mut body = Vec::new();
+        loop{
+            let line_end = find(raw, b"\r\n").ok_or(HttpFailure::InvalidResponse)?;
+            let size_line = std::str::from_utf8(&raw[..line_end]).map_err(|_| HttpFailure::InvalidResponse)?;
+            // Chunk extensions after ';' are ignored
+            let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size_hex, 16).map_err(|_| HttpFailure::InvalidResponse)?;
+            raw = &raw[line_end + 2..];
+            if size == 0{
+                return Ok(body);
+            }
+            if raw.len() < size + 2{
+                return Err(HttpFailure::InvalidResponse);
+            }
+            body.extend_from_slice(&raw[..size]);
+            raw = &raw[size + 2..];
+        }
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|window| window == needle)
+    }
+
+    // Write the request and read until the server closes the connection.
+    pub async fn exchange<S>(stream: &mut S, request: &[u8]) -> Result<HttpResponse, HttpFailure>
+        where S: AsyncRead + AsyncWrite + Unpin {
+        stream.write_all(request).await?;
+        stream.flush().await?;
+        let mut raw = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop{
+            let read = match stream.read(&mut buffer).await{
+                Ok(read) => read,
+                // Servers often skip the TLS close_notify, the data is complete anyway.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => 0,
+                Err(err) => return Err(err.into())
+            };
+            if read == 0{
+                break;
+            }
+            raw.extend_from_slice(&buffer[..read]);
+            if raw.len() > MAX_RESPONSE_SIZE{
+                return Err(HttpFailure::ResponseTooLarge);
+            }
+        }
+        parse_response(&raw)
+    }
+
+    // Send a request made with encode_request to host:port, bounded by the timeout.
+    pub async fn https_request(tls_config: Arc<ClientConfig>, host: &str, port: u16, request: Vec<u8>, timeout: Duration) -> Result<HttpResponse, HttpFailure> {
+        let exchange_task = tokio::time::timeout(timeout, async{
+            let tcp = TcpStream::connect((host, port)).await?;
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(host)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid host name"))?;
+            let mut tls = TlsConnector::from(tls_config).connect(dns_name, tcp).await?;
+            exchange(&mut tls, &request).await
+        }).await;
+        match exchange_task{
+            Err(_) => {
+                Err(HttpFailure::Timeout)
+            }
+            Ok(result) => {
+                result
+            }
+        }
+    }
+
+    // Same as https_request without TLS, only meant for local stand-ins of the REST endpoints.
+    pub async fn http_request(host: &str, port: u16, request: Vec<u8>, timeout: Duration) -> Result<HttpResponse, HttpFailure> {
+        let exchange_task = tokio::time::timeout(timeout, async{
+            let mut tcp = TcpStream::connect((host, port)).await?;
+            exchange(&mut tcp, &request).await
+        }).await;
+        match exchange_task{
+            Err(_) => {
+                Err(HttpFailure::Timeout)
+            }
+            Ok(result) => {
+                result
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum HttpFailure{
+        Io(io::Error),
+        Timeout,
+        InvalidResponse,
+        ResponseTooLarge,
+    }
+    impl Display for HttpFailure{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                HttpFailure::Io(err) => write!(f, "HTTPS connection failed: {}", err),
+                HttpFailure::Timeout => write!(f, "HTTPS request timed out."),
+                HttpFailure::InvalidResponse => write!(f, "Received an invalid HTTP response."),
+                HttpFailure::ResponseTooLarge => write!(f, "The HTTP response is too large."),
+            }
+        }
+    }
+    impl std::error::Error for HttpFailure{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                HttpFailure::Io(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+    impl From<io::Error> for HttpFailure{
+        fn from(err: io::Error) -> Self {
+            HttpFailure::Io(err)
+        }
+    }
+}
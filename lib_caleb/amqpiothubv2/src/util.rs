@@ -6,10 +6,37 @@ pub mod token{
     use hmac::{Hmac, Mac, NewMac};
     use sha2::Sha256;
 
+    // Public Azure cloud, sovereign clouds use e.g. azure-devices.cn or azure-devices.us
+    pub const DEFAULT_HOST_SUFFIX: &str = "azure-devices.net";
+
+    // Source of the current time, replaceable to make token generation deterministic.
+    pub trait Clock{
+        fn now(&self) -> DateTime<Utc>;
+    }
+
+    // The real wall clock.
+    pub struct SystemClock;
+
+    impl Clock for SystemClock{
+        fn now(&self) -> DateTime<Utc> {
+            chrono::offset::Utc::now()
+        }
+    }
+
+    // Always returns the same instant (tests, replaying known tokens).
+    pub struct FixedClock(pub DateTime<Utc>);
+
+    impl Clock for FixedClock{
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
     // SasToken struct.
     pub struct SasToken{
         pub sig: String,
         pub sas: String,
+        pub expiry: DateTime<Utc>,
     }
 
     // SasToken Display Implementation
@@ -21,86 +48,144 @@ pub mod token{
 
     impl SasToken{
         pub fn new(primary_key: &str, days: i64, hub_name: &str, device_id: &str) -> Result<SasToken, SasTokenCreateException> {
-            // Check code encode
-            let check_token_decode = SasToken::is_key_decode_good(&primary_key);
-            if check_token_decode == false{
-                return Err(SasTokenCreateException::InvalidPrimaryTokenEncoding);
-            }
-            // Token encode correct --> Continue
-            // Check token length
-            let check_token_length = SasToken::is_key_length_good(&primary_key);
-            if check_token_length == false{
-                return Err(SasTokenCreateException::InvalidPrimaryTokenLength);
-            }
-            // Token length correct --> Continue
-            // Create timestamps (Days is consumed!)
-            let future_time = SasToken::create_future_date(days);
+            SasToken::with_validity(primary_key, Duration::days(days), hub_name, device_id, &SystemClock)
+        }
+
+        // Device token valid for `validity` starting at the time given by the clock.
+        pub fn with_validity(primary_key: &str,
+                             validity: Duration,
+                             hub_name: &str,
+                             device_id: &str,
+                             clock: &dyn Clock) -> Result<SasToken, SasTokenCreateException> {
+            let host_name = SasToken::hostname_from_iothub_name(hub_name.to_string());
+            SasToken::for_host(primary_key, validity, &host_name, device_id, clock)
+        }
+
+        // Device token for a full host name (other clouds, custom DNS).
+        pub fn for_host(primary_key: &str,
+                        validity: Duration,
+                        host_name: &str,
+                        device_id: &str,
+                        clock: &dyn Clock) -> Result<SasToken, SasTokenCreateException> {
+            SasToken::check_key(primary_key)?;
+            // Create timestamps
+            let future_time = SasToken::create_expiry(clock, validity)?;
             let timestamp = future_time.timestamp();
 
             // Create hub url
-            let hub_url = SasToken::create_hub_url(hub_name, device_id);
+            let hub_url = SasToken::create_resource_url(host_name, device_id);
 
             // Create signature
-            let to_sign = SasToken::create_to_sign(hub_url, timestamp);
-
-            // Every check done --> Generating a key should be safe now.
-            let unwrapped_key = base64::decode(&primary_key).unwrap();
-            // Start generation
-            let mut mac  = Hmac::<Sha256>::new_from_slice(&unwrapped_key).unwrap();
-            // SIGN GEN
-            mac.update(to_sign.as_bytes());
-            let mac_result = mac.finalize();
-            let signature = base64::encode(mac_result.into_bytes());
-            let pairs = &vec![("sig", signature)];
-            let token_result = serde_urlencoded::to_string(pairs).unwrap();
+            let to_sign = SasToken::create_to_sign(hub_url.clone(), timestamp);
+            let token_result = SasToken::sign(primary_key, &to_sign)?;
             // Build up token signature
-            let sas = SasToken::format_password_token(hub_name, &token_result, timestamp, device_id);
+            let sas = format!("SharedAccessSignature sr={}&{}&se={}&skn={}", hub_url, token_result, timestamp, device_id);
             Ok(SasToken{
-                sig: token_result.to_owned(),
-                sas
+                sig: token_result,
+                sas,
+                expiry: future_time
             })
         }
 
         pub fn service_token(service_key: &str, days: i64, hub_name: &str, service: &str) -> Result<SasToken, SasTokenCreateException> {
-            // Check code encode
-            let check_token_decode = SasToken::is_key_decode_good(&service_key);
-            if check_token_decode == false{
-                return Err(SasTokenCreateException::InvalidPrimaryTokenEncoding);
-            }
-            // Token encode correct --> Continue
-            // Check token length
-            let check_token_length = SasToken::is_key_length_good(&service_key);
-            if check_token_length == false{
-                return Err(SasTokenCreateException::InvalidPrimaryTokenLength);
-            }
-            let hostname = format!("{}.azure-devices.net", hub_name);
+            SasToken::service_token_with_validity(service_key, Duration::days(days), hub_name, service, &SystemClock)
+        }
 
-            let future_time = SasToken::create_future_date(days);
-            let timestamp = future_time.timestamp();
+        // Service (shared access policy) token valid for `validity` starting at the time given by the clock.
+        pub fn service_token_with_validity(service_key: &str,
+                                           validity: Duration,
+                                           hub_name: &str,
+                                           service: &str,
+                                           clock: &dyn Clock) -> Result<SasToken, SasTokenCreateException> {
+            let hostname = SasToken::hostname_from_iothub_name(hub_name.to_string());
+            SasToken::service_token_for_host(service_key, validity, &hostname, service, clock)
+        }
 
-            let to_sign =  format!("{}.azure-devices.net\n{}", hub_name, timestamp);
-            println!("Sign: {}", to_sign);
+        // Service token for a full host name (other clouds, custom DNS).
+        pub fn service_token_for_host(service_key: &str,
+                                      validity: Duration,
+                                      hostname: &str,
+                                      service: &str,
+                                      clock: &dyn Clock) -> Result<SasToken, SasTokenCreateException> {
+            SasToken::check_key(service_key)?;
+            let future_time = SasToken::create_expiry(clock, validity)?;
+            let timestamp = future_time.timestamp();
 
-            let unwrapped_key = base64::decode(&service_key).unwrap();
-            // Start generation
-            let mut mac  = Hmac::<Sha256>::new_from_slice(&unwrapped_key).unwrap();
-            // SIGN GEN
-            mac.update(to_sign.as_bytes());
-            let mac_result = mac.finalize();
-            let signature = base64::encode(mac_result.into_bytes());
-            let pairs = &vec![("sig", signature)];
-            let token_result = serde_urlencoded::to_string(pairs).unwrap();
+            let to_sign = SasToken::create_to_sign(hostname.to_string(), timestamp);
+            let token_result = SasToken::sign(service_key, &to_sign)?;
             // Build up token signature
             let sas = SasToken::format_password_token_service(
-                &token_result.clone(),
+                &token_result,
                 timestamp,
                 service,
-                &hostname);
+                hostname);
             Ok(SasToken{
-                sig: token_result.to_owned(),
-                sas
+                sig: token_result,
+                sas,
+                expiry: future_time
             })
+        }
+
+        // Token for the Device Provisioning Service, resource {id scope}/registrations/{registration id}.
+        // Signed with the device key, key_name is "registration" for group enrollments.
+        pub fn provisioning_token(key: &str,
+                                  validity: Duration,
+                                  id_scope: &str,
+                                  registration_id: &str,
+                                  key_name: Option<&str>,
+                                  clock: &dyn Clock) -> Result<SasToken, SasTokenCreateException> {
+            SasToken::check_key(key)?;
+            let future_time = SasToken::create_expiry(clock, validity)?;
+            let timestamp = future_time.timestamp();
+            let resource = format!("{}%2Fregistrations%2F{}", id_scope, urlencoding::encode(registration_id));
+            let to_sign = SasToken::create_to_sign(resource.clone(), timestamp);
+            let token_result = SasToken::sign(key, &to_sign)?;
+            let mut sas = format!("SharedAccessSignature sr={}&{}&se={}", resource, token_result, timestamp);
+            if let Some(key_name) = key_name{
+                sas.push_str(&format!("&skn={}", key_name));
+            }
+            Ok(SasToken{
+                sig: token_result,
+                sas,
+                expiry: future_time
+            })
+        }
+
+        // Key of a device in a symmetric key group enrollment:
+        // HMAC-SHA256 of the registration id with the group key, base64 encoded.
+        pub fn derive_device_key(group_key: &str, registration_id: &str) -> Result<String, SasTokenCreateException> {
+            let mut mac = SasToken::keyed_mac(group_key)?;
+            mac.update(registration_id.as_bytes());
+            Ok(base64::encode(mac.finalize().into_bytes()))
+        }
+
+        // Validate the base64 key before signing with it.
+        fn check_key(key: &str) -> Result<(), SasTokenCreateException> {
+            SasToken::keyed_mac(key).map(|_| ())
+        }
+
+        // HMAC-SHA256 keyed with the decoded key.
+        fn keyed_mac(key: &str) -> Result<Hmac<Sha256>, SasTokenCreateException> {
+            let unwrapped_key = match base64::decode(key){
+                Ok(unwrapped_key) => {
+                    unwrapped_key
+                }
+                Err(_) => {
+                    return Err(SasTokenCreateException::InvalidPrimaryTokenEncoding);
+                }
+            };
+            Hmac::<Sha256>::new_from_slice(&unwrapped_key)
+                .map_err(|_| SasTokenCreateException::InvalidPrimaryTokenLength)
+        }
 
+        // HMAC-SHA256 of to_sign with the decoded key, returned as the url encoded "sig=..." pair.
+        pub fn sign(key: &str, to_sign: &str) -> Result<String, SasTokenCreateException> {
+            let mut mac = SasToken::keyed_mac(key)?;
+            mac.update(to_sign.as_bytes());
+            let mac_result = mac.finalize();
+            let signature = base64::encode(mac_result.into_bytes());
+            let pairs = &vec![("sig", signature)];
+            serde_urlencoded::to_string(pairs).map_err(|_| SasTokenCreateException::Failed)
         }
 
         // Static functions for SasToken
@@ -121,16 +206,15 @@ pub mod token{
         }
 
         pub fn hostname_from_iothub_name(name: String) -> String{
-            format!("{}.azure-devices.net", name)
+            format!("{}.{}", name, DEFAULT_HOST_SUFFIX)
         }
         pub fn format_password_token(hub_name: &str,
                                      token_result: &String,
                                      expiry_timestamp: i64,
                                      device_id: &str) -> String{
             format!(
-                "SharedAccessSignature sr={}.azure-devices.net%2Fdevices%2F{}&{}&se={}&skn={}",
-                hub_name,
-                device_id,
+                "SharedAccessSignature sr={}&{}&se={}&skn={}",
+                SasToken::create_hub_url(hub_name, device_id),
                 token_result,
                 expiry_timestamp,
                 device_id
@@ -160,24 +244,180 @@ pub mod token{
             )
         }
         pub fn create_hub_url(hub_name: &str, device_id: &str) -> String{
-            format!("{}.azure-devices.net%2Fdevices%2F{}", hub_name, device_id)
+            SasToken::create_resource_url(&SasToken::hostname_from_iothub_name(hub_name.to_string()), device_id)
+        }
+        pub fn create_resource_url(host_name: &str, device_id: &str) -> String{
+            format!("{}%2Fdevices%2F{}", host_name, device_id)
         }
         pub fn create_future_date(days_in_future: i64) -> DateTime<Utc> {
-            let mut time_now = chrono::offset::Utc::now();
-            let mut add_days = Duration::days(days_in_future);
-            time_now.add(add_days);
-            time_now
+            SystemClock.now().add(Duration::days(days_in_future))
+        }
+        pub fn create_expiry(clock: &dyn Clock, validity: Duration) -> Result<DateTime<Utc>, SasTokenCreateException> {
+            if validity <= Duration::zero(){
+                return Err(SasTokenCreateException::InvalidValidity);
+            }
+            Ok(clock.now().add(validity))
+        }
+        // Read the expiry (se) back from a formatted SharedAccessSignature string.
+        pub fn expiry_from_sas(sas: &str) -> Option<DateTime<Utc>> {
+            SasToken::parse(sas).ok().map(|token| token.expiry)
+        }
+
+        // Decode a "SharedAccessSignature sr=..&sig=..&se=..&skn=.." string.
+        // Accepts both the device and the service format, fields may come in any order.
+        pub fn parse(sas: &str) -> Result<ParsedSasToken, SasTokenParseException> {
+            let fields = match sas.trim().strip_prefix("SharedAccessSignature "){
+                None => {
+                    return Err(SasTokenParseException::MissingPrefix);
+                }
+                Some(fields) => {
+                    fields
+                }
+            };
+            let mut resource = None;
+            let mut signature = None;
+            let mut expiry = None;
+            let mut key_name = None;
+            for field in fields.trim().split('&'){
+                let (key, value) = match field.split_once('='){
+                    None => {
+                        return Err(SasTokenParseException::InvalidField(field.to_string()));
+                    }
+                    Some(pair) => {
+                        pair
+                    }
+                };
+                match key{
+                    "sr" => resource = Some(value.to_string()),
+                    "sig" => signature = Some(value.to_string()),
+                    "se" => expiry = Some(value.to_string()),
+                    "skn" => key_name = Some(value.to_string()),
+                    _ => return Err(SasTokenParseException::InvalidField(field.to_string()))
+                }
+            }
+            let resource = resource.ok_or(SasTokenParseException::MissingField("sr"))?;
+            let signature = signature.ok_or(SasTokenParseException::MissingField("sig"))?;
+            let expiry = expiry.ok_or(SasTokenParseException::MissingField("se"))?;
+
+            let signature = urlencoding::decode(&signature)
+                .map_err(|_| SasTokenParseException::InvalidEncoding)?;
+            let signature = base64::decode(&signature)
+                .map_err(|_| SasTokenParseException::InvalidEncoding)?;
+            let timestamp = expiry.parse::<i64>()
+                .map_err(|_| SasTokenParseException::InvalidExpiry)?;
+            let expiry = chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)
+                .ok_or(SasTokenParseException::InvalidExpiry)?;
+            let key_name = match key_name{
+                None => {
+                    None
+                }
+                Some(key_name) => {
+                    Some(urlencoding::decode(&key_name).map_err(|_| SasTokenParseException::InvalidEncoding)?)
+                }
+            };
+            Ok(ParsedSasToken{
+                resource,
+                signature,
+                expiry: DateTime::<Utc>::from_utc(expiry, Utc),
+                key_name
+            })
         }
         pub fn create_to_sign(hub_url: String, expiry_timestamp: i64) -> String{
             format!("{}\n{}", hub_url, expiry_timestamp)
         }
     }
 
+    // The fields of a parsed SharedAccessSignature.
+    pub struct ParsedSasToken{
+        // sr exactly as it appears in the token, this is the signed text.
+        pub resource: String,
+        // Raw HMAC-SHA256 bytes of sig.
+        pub signature: Vec<u8>,
+        pub expiry: DateTime<Utc>,
+        // skn: policy name for service tokens (device tokens created here carry the device id).
+        pub key_name: Option<String>,
+    }
+
+    impl ParsedSasToken{
+        // sr with the url encoding removed, e.g. "hub.azure-devices.net/devices/airquality"
+        pub fn resource_uri(&self) -> String {
+            match urlencoding::decode(&self.resource){
+                Ok(resource) => resource,
+                Err(_) => self.resource.clone()
+            }
+        }
+
+        // Device id for device scoped tokens
+        pub fn device_id(&self) -> Option<String> {
+            let resource = self.resource_uri();
+            let mut parts = resource.split('/');
+            parts.next()?;
+            if parts.next()? != "devices"{
+                return None;
+            }
+            parts.next().map(|device_id| device_id.to_string())
+        }
+
+        pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+            clock.now() >= self.expiry
+        }
+
+        // Check the signature with a base64 key (constant time compare).
+        pub fn verify(&self, key: &str) -> bool {
+            let unwrapped_key = match base64::decode(key){
+                Ok(key) => key,
+                Err(_) => return false
+            };
+            let mut mac = match Hmac::<Sha256>::new_from_slice(&unwrapped_key){
+                Ok(mac) => mac,
+                Err(_) => return false
+            };
+            let to_sign = SasToken::create_to_sign(self.resource.clone(), self.expiry.timestamp());
+            mac.update(to_sign.as_bytes());
+            mac.verify(&self.signature).is_ok()
+        }
+    }
+
+    impl Display for ParsedSasToken{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Resource: {}\nKey name: {}\nExpiry: {}",
+                   self.resource_uri(),
+                   self.key_name.as_deref().unwrap_or("-"),
+                   self.expiry.to_rfc3339())
+        }
+    }
+
+    // SasToken Exceptions on parsing.
+    #[derive(Debug)]
+    pub enum SasTokenParseException{
+        MissingPrefix,
+        MissingField(&'static str),
+        InvalidField(String),
+        InvalidEncoding,
+        InvalidExpiry,
+    }
+
+    impl Display for SasTokenParseException{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                SasTokenParseException::MissingPrefix => write!(f, "MissingPrefix"),
+                SasTokenParseException::MissingField(field) => write!(f, "MissingField: {}", field),
+                SasTokenParseException::InvalidField(field) => write!(f, "InvalidField: {}", field),
+                SasTokenParseException::InvalidEncoding => write!(f, "InvalidEncoding"),
+                SasTokenParseException::InvalidExpiry => write!(f, "InvalidExpiry"),
+            }
+        }
+    }
+
+    impl std::error::Error for SasTokenParseException {}
+
     // SasToken Exceptions on creation.
+    #[derive(Debug)]
     pub enum SasTokenCreateException{
         Failed,
         InvalidPrimaryTokenEncoding,
         InvalidPrimaryTokenLength,
+        InvalidValidity,
     }
 
     // SasToken Exceptions Display implementation
@@ -187,8 +427,951 @@ pub mod token{
                 SasTokenCreateException::Failed => write!(f, "{}", "Failed"),
                 SasTokenCreateException::InvalidPrimaryTokenEncoding => write!(f, "{}", "InvalidPrimaryTokenEncoding"),
                 SasTokenCreateException::InvalidPrimaryTokenLength => write!(f, "{}", "InvalidPrimaryTokenLength"),
+                SasTokenCreateException::InvalidValidity => write!(f, "{}", "InvalidValidity"),
             }
         }
     }
 
-}
\ No newline at end of file
+    impl std::error::Error for SasTokenCreateException {}
+
+}
+pub mod connection_string{
+    // IoT Hub connection strings as shown in the Azure portal:
+    // device: HostName={hub}.azure-devices.net;DeviceId={device};SharedAccessKey={key}
+    // policy: HostName={hub}.azure-devices.net;SharedAccessKeyName={policy};SharedAccessKey={key}
+    use std::fmt::{Display, Formatter};
+    use crate::util::token::SasToken;
+
+    pub struct ConnectionString{
+        pub host_name: String,
+        pub device_id: Option<String>,
+        pub shared_access_key_name: Option<String>,
+        pub shared_access_key: String,
+    }
+
+    impl ConnectionString{
+        pub fn parse(connection_string: &str) -> Result<ConnectionString, ConnectionStringException> {
+            let mut host_name = None;
+            let mut device_id = None;
+            let mut shared_access_key_name = None;
+            let mut shared_access_key = None;
+            for field in connection_string.trim().split(';'){
+                if field.trim().is_empty(){
+                    // Trailing separator
+                    continue;
+                }
+                // Keys end with '=' padding, only split on the first one.
+                let (key, value) = match field.split_once('='){
+                    None => {
+                        return Err(ConnectionStringException::InvalidField(field.to_string()));
+                    }
+                    Some(pair) => {
+                        pair
+                    }
+                };
+                let value = Some(value.trim().to_string());
+                match key.trim(){
+                    "HostName" => host_name = value,
+                    "DeviceId" => device_id = value,
+                    "SharedAccessKeyName" => shared_access_key_name = value,
+                    "SharedAccessKey" => shared_access_key = value,
+                    // Module identities would need module scoped tokens, refuse them instead
+                    // of signing a device token with the module key.
+                    "ModuleId" => return Err(ConnectionStringException::Unsupported("ModuleId")),
+                    // Not used by this library, but valid in IoT Hub connection strings.
+                    "GatewayHostName" => {}
+                    _ => return Err(ConnectionStringException::InvalidField(field.to_string()))
+                }
+            }
+            let host_name = host_name
+                .filter(|host_name| !host_name.is_empty())
+                .ok_or(ConnectionStringException::MissingField("HostName"))?;
+            let shared_access_key = shared_access_key
+                .filter(|key| !key.is_empty())
+                .ok_or(ConnectionStringException::MissingField("SharedAccessKey"))?;
+            if !SasToken::is_key_decode_good(&shared_access_key){
+                return Err(ConnectionStringException::InvalidKey);
+            }
+            if device_id.is_some() == shared_access_key_name.is_some(){
+                // Either a device or a policy, never both.
+                return Err(ConnectionStringException::AmbiguousIdentity);
+            }
+            Ok(ConnectionString{
+                host_name,
+                device_id,
+                shared_access_key_name,
+                shared_access_key
+            })
+        }
+
+        // "researchprojecthub" for "researchprojecthub.azure-devices.net"
+        pub fn hub_name(&self) -> String {
+            self.host_name.split('.').next().unwrap_or_default().to_string()
+        }
+
+        pub fn is_device(&self) -> bool {
+            self.device_id.is_some()
+        }
+    }
+
+    impl Display for ConnectionString{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            // Never print the key itself.
+            match (&self.device_id, &self.shared_access_key_name){
+                (Some(device_id), _) => write!(f, "HostName={};DeviceId={};SharedAccessKey=***", self.host_name, device_id),
+                (_, Some(policy)) => write!(f, "HostName={};SharedAccessKeyName={};SharedAccessKey=***", self.host_name, policy),
+                _ => write!(f, "HostName={}", self.host_name),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum ConnectionStringException{
+        MissingField(&'static str),
+        InvalidField(String),
+        InvalidKey,
+        AmbiguousIdentity,
+        NotADeviceConnectionString,
+        NotAServiceConnectionString,
+        // Valid in IoT Hub connection strings, but not supported by this library
+        Unsupported(&'static str),
+    }
+
+    impl Display for ConnectionStringException{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                ConnectionStringException::MissingField(field) => write!(f, "Missing field: {}", field),
+                ConnectionStringException::InvalidField(field) => write!(f, "Invalid field: {}", field),
+                ConnectionStringException::InvalidKey => write!(f, "SharedAccessKey is not valid base64."),
+                ConnectionStringException::AmbiguousIdentity => write!(f, "Expected exactly one of DeviceId or SharedAccessKeyName."),
+                ConnectionStringException::NotADeviceConnectionString => write!(f, "Not a device connection string."),
+                ConnectionStringException::NotAServiceConnectionString => write!(f, "Not a service (policy) connection string."),
+                ConnectionStringException::Unsupported(field) => write!(f, "{} is not supported.", field),
+            }
+        }
+    }
+
+    impl std::error::Error for ConnectionStringException {}
+}
+pub mod reconnect{
+    // When and how often a dropped client tries to get its session back.
+    // Exponential backoff with jitter, bounded attempts and a circuit breaker that
+    // stops hammering the hub after repeated failures.
+    use std::time::{Duration, Instant};
+    use rand::Rng;
+
+    #[derive(Clone, Debug)]
+    pub struct ReconnectPolicy{
+        // Reconnect on send/receive failures without the caller asking for it.
+        pub automatic: bool,
+        pub initial_delay: Duration,
+        pub max_delay: Duration,
+        pub multiplier: f64,
+        // Fraction of the delay that is randomized, 0.0 (none) to 1.0.
+        pub jitter: f64,
+        // None: keep trying until the circuit breaker opens.
+        pub max_attempts: Option<u32>,
+        // Consecutive failed attempts that open the circuit.
+        pub failure_threshold: u32,
+        // How long the circuit stays open before one trial attempt is let through.
+        pub cooldown: Duration,
+    }
+
+    impl Default for ReconnectPolicy{
+        fn default() -> Self {
+            ReconnectPolicy{
+                automatic: false,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+                multiplier: 2.0,
+                jitter: 0.2,
+                max_attempts: Some(5),
+                failure_threshold: 10,
+                cooldown: Duration::from_secs(300),
+            }
+        }
+    }
+
+    impl ReconnectPolicy{
+        // Reconnect on send/receive failures. A failing send_message or receive then
+        // blocks through all attempts (up to 5, with delays of up to 60 s) before it returns.
+        pub fn automatic() -> ReconnectPolicy {
+            ReconnectPolicy{
+                automatic: true,
+                ..ReconnectPolicy::default()
+            }
+        }
+
+        // Delay before retry number `retry` (1 = the first retry), without jitter.
+        pub fn base_delay(&self, retry: u32) -> Duration {
+            let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1) as i32);
+            let delay = self.initial_delay.as_secs_f64() * factor;
+            Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+        }
+
+        // `sample` in [0, 1) picks where in the jitter window the delay lands.
+        pub fn delay_with_sample(&self, retry: u32, sample: f64) -> Duration {
+            let base = self.base_delay(retry).as_secs_f64();
+            let jitter = self.jitter.clamp(0.0, 1.0);
+            // Spread over [base * (1 - jitter), base * (1 + jitter)]
+            let delay = base * (1.0 - jitter + 2.0 * jitter * sample.clamp(0.0, 1.0));
+            Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+        }
+
+        pub fn next_delay(&self, retry: u32) -> Duration {
+            let sample = rand::thread_rng().gen_range(0.0..1.0);
+            self.delay_with_sample(retry, sample)
+        }
+
+        pub fn attempts_exhausted(&self, attempts: u32) -> bool {
+            match self.max_attempts{
+                None => false,
+                Some(max_attempts) => attempts >= max_attempts
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum CircuitState{
+        // Normal operation
+        Closed,
+        // Too many failures, no attempts until the cooldown has passed.
+        Open,
+        // Cooldown passed, the next attempt decides.
+        HalfOpen,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct CircuitBreaker{
+        state: CircuitState,
+        consecutive_failures: u32,
+        opened_at: Option<Instant>,
+    }
+
+    impl Default for CircuitBreaker{
+        fn default() -> Self {
+            CircuitBreaker{
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+            }
+        }
+    }
+
+    impl CircuitBreaker{
+        pub fn state(&self) -> CircuitState {
+            self.state
+        }
+
+        pub fn consecutive_failures(&self) -> u32 {
+            self.consecutive_failures
+        }
+
+        // Whether an attempt may be made now, moves Open to HalfOpen after the cooldown.
+        pub fn allow(&mut self, policy: &ReconnectPolicy, now: Instant) -> bool {
+            match self.state{
+                CircuitState::Closed | CircuitState::HalfOpen => true,
+                CircuitState::Open => {
+                    let cooled_down = match self.opened_at{
+                        None => true,
+                        Some(opened_at) => now.duration_since(opened_at) >= policy.cooldown
+                    };
+                    if cooled_down{
+                        self.state = CircuitState::HalfOpen;
+                    }
+                    cooled_down
+                }
+            }
+        }
+
+        // Returns the new state when it changed.
+        pub fn record_success(&mut self) -> Option<CircuitState> {
+            self.consecutive_failures = 0;
+            self.opened_at = None;
+            self.transition(CircuitState::Closed)
+        }
+
+        // Returns the new state when it changed.
+        pub fn record_failure(&mut self, policy: &ReconnectPolicy, now: Instant) -> Option<CircuitState> {
+            self.consecutive_failures += 1;
+            if self.state == CircuitState::HalfOpen || self.consecutive_failures >= policy.failure_threshold{
+                // The trial failed or the threshold is reached: (re)open.
+                self.opened_at = Some(now);
+                return self.transition(CircuitState::Open);
+            }
+            None
+        }
+
+        fn transition(&mut self, state: CircuitState) -> Option<CircuitState> {
+            if self.state == state{
+                return None;
+            }
+            self.state = state;
+            Some(state)
+        }
+    }
+
+    // Reported to the reconnect listener of a client.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum ReconnectEvent{
+        // Attempt number `attempt` starts after waiting `delay`.
+        Reconnecting{ attempt: u32, delay: Duration },
+        AttemptFailed{ attempt: u32, error: String },
+        // Session and recorded links are restored.
+        Reconnected{ attempts: u32 },
+        // max_attempts reached, the caller gets the last error.
+        GaveUp{ attempts: u32 },
+        CircuitChanged(CircuitState),
+    }
+}
+pub mod events{
+    // Lifecycle events of a client, pushed to every subscriber as they happen.
+    use std::sync::{Arc, Mutex};
+    use chrono::{DateTime, Utc};
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum ClientEvent{
+        Connected{ host_name: String },
+        LinkAttached{ name: String, address: String },
+        // error: the AMQP error that detached the link, if the hub sent one.
+        LinkDetached{ name: String, error: Option<String> },
+        // error: None when the client closed the connection itself.
+        Disconnected{ error: Option<String> },
+        // The hub asked to continue on another host (amqp:link:redirect / amqp:connection:redirect).
+        Redirect{ name: String, error: String, target: RedirectTarget },
+        TokenRefreshed{ expiry: DateTime<Utc> },
+    }
+
+    // Fan-out of events, cheap to clone into the dispatcher task.
+    #[derive(Clone, Default)]
+    pub struct EventBus{
+        subscribers: Arc<Mutex<Vec<UnboundedSender<ClientEvent>>>>,
+    }
+
+    impl EventBus{
+        pub fn new() -> EventBus {
+            EventBus::default()
+        }
+
+        // New receiver for all events from now on, drop it to unsubscribe.
+        pub fn subscribe(&self) -> UnboundedReceiver<ClientEvent> {
+            let (sender, receiver) = unbounded();
+            self.subscribers.lock().unwrap().push(sender);
+            receiver
+        }
+
+        pub fn emit(&self, event: ClientEvent){
+            let mut subscribers = self.subscribers.lock().unwrap();
+            // Dropped receivers are removed on the way.
+            subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        }
+
+        pub fn subscriber_count(&self) -> usize {
+            self.subscribers.lock().unwrap().len()
+        }
+    }
+
+    // Where a redirect points to, read from the info map of the AMQP error.
+    // Fields the hub left out stay None.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct RedirectTarget{
+        pub host_name: Option<String>,
+        pub network_host: Option<String>,
+        pub port: Option<u16>,
+        // Node to attach to on the new host
+        pub address: Option<String>,
+    }
+}
+pub mod queue{
+    // Store-and-forward for telemetry: messages that could not be sent are appended to a
+    // JSON lines file and replayed in order once the client is connected again.
+    // Messages that left the queue are recorded as a sequence watermark in a small ack
+    // file; the JSON lines file is only rewritten once enough of them piled up.
+    use std::collections::VecDeque;
+    use std::fmt::{Display, Formatter};
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufRead, BufReader, Write};
+    use std::path::{Path, PathBuf};
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum EvictionPolicy{
+        // Make room by dropping the oldest queued messages.
+        DropOldest,
+        // Keep what is queued, the new message is dropped.
+        DropNewest,
+        // Keep what is queued and report QueueFull to the caller.
+        Reject,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct QueueConfig{
+        pub max_messages: usize,
+        // Sum of the payload sizes
+        pub max_bytes: usize,
+        pub eviction: EvictionPolicy,
+        // Messages DropOldest evicts at once (at least the ones needed to make room)
+        pub eviction_batch: usize,
+        // Delivered messages left in the file before it is rewritten without them
+        pub compact_after: usize,
+    }
+
+    impl Default for QueueConfig{
+        fn default() -> Self {
+            QueueConfig{
+                max_messages: 10_000,
+                max_bytes: 16 * 1024 * 1024,
+                eviction: EvictionPolicy::DropOldest,
+                eviction_batch: 100,
+                compact_after: 1000,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct QueuedMessage{
+        pub sequence: u64,
+        pub enqueued_at: DateTime<Utc>,
+        // Sender link the message was meant for
+        pub link: String,
+        // Encoded AMQP message, base64 in the file
+        #[serde(with = "base64_payload")]
+        pub payload: Vec<u8>,
+    }
+
+    mod base64_payload{
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&base64::encode(payload))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+            let encoded = String::deserialize(deserializer)?;
+            base64::decode(encoded).map_err(serde::de::Error::custom)
+        }
+    }
+
+    pub struct OfflineQueue{
+        path: PathBuf,
+        // Holds the sequence of the last message that left the queue (delivered or evicted).
+        ack_path: PathBuf,
+        // Lines of the file at or below the acked sequence, dropped on the next compaction
+        stale: usize,
+        config: QueueConfig,
+        messages: VecDeque<QueuedMessage>,
+        bytes: usize,
+        next_sequence: u64,
+    }
+
+    impl OfflineQueue{
+        // Opens the queue file, everything still in it from an earlier run is kept.
+        // A line that does not parse (e.g. cut off by a power loss) is skipped, so are
+        // messages at or below the acked sequence.
+        pub fn open<P: AsRef<Path>>(path: P, config: QueueConfig) -> Result<OfflineQueue, QueueException> {
+            let path = path.as_ref().to_path_buf();
+            let ack_path = sibling(&path, ".ack");
+            let acked = read_acked(&ack_path)?;
+            let mut messages = VecDeque::new();
+            let mut stale = 0;
+            // Sequences keep growing past acked ones, even when the file was emptied.
+            let mut next_sequence = acked.map_or(0, |acked| acked + 1);
+            if path.exists(){
+                let reader = BufReader::new(File::open(&path)?);
+                for line in reader.lines(){
+                    let line = line?;
+                    if line.trim().is_empty(){
+                        continue;
+                    }
+                    match serde_json::from_str::<QueuedMessage>(&line){
+                        Ok(message) => {
+                            next_sequence = next_sequence.max(message.sequence + 1);
+                            if acked.map_or(false, |acked| message.sequence <= acked){
+                                stale += 1;
+                            }
+                            else {
+                                messages.push_back(message);
+                            }
+                        }
+                        Err(err) => {
+                            println!("Skipping corrupt queue entry: {}", err);
+                        }
+                    }
+                }
+            }
+            let bytes = messages.iter().map(|message| message.payload.len()).sum();
+            Ok(OfflineQueue{
+                path,
+                ack_path,
+                stale,
+                config,
+                messages,
+                bytes,
+                next_sequence,
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn len(&self) -> usize {
+            self.messages.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.messages.is_empty()
+        }
+
+        pub fn bytes(&self) -> usize {
+            self.bytes
+        }
+
+        pub fn peek(&self) -> Option<&QueuedMessage> {
+            self.messages.front()
+        }
+
+        // Appends a message, evicting according to the policy when the caps are reached.
+        // DropOldest frees eviction_batch messages at once, so a full queue is not trimmed
+        // on every push. Returns the number of messages dropped (or 1 for DropNewest).
+        pub fn push(&mut self, link: &str, payload: Vec<u8>, enqueued_at: DateTime<Utc>) -> Result<usize, QueueException> {
+            if payload.len() > self.config.max_bytes || self.config.max_messages == 0{
+                return Err(QueueException::MessageTooLarge);
+            }
+            let mut evicted = 0;
+            if self.is_full(self.messages.len(), self.bytes, payload.len()){
+                match self.config.eviction{
+                    EvictionPolicy::DropOldest => {
+                        let mut freed = 0;
+                        while evicted < self.messages.len() && (evicted < self.config.eviction_batch || self.is_full(self.messages.len() - evicted, self.bytes - freed, payload.len())){
+                            freed += self.messages[evicted].payload.len();
+                            evicted += 1;
+                        }
+                        self.remove_front(evicted)?;
+                    }
+                    EvictionPolicy::DropNewest => {
+                        return Ok(1);
+                    }
+                    EvictionPolicy::Reject => {
+                        return Err(QueueException::QueueFull);
+                    }
+                }
+            }
+            let message = QueuedMessage{
+                sequence: self.next_sequence,
+                enqueued_at,
+                link: link.to_string(),
+                payload,
+            };
+            // A failed write may still have reached the file, its sequence is not reused.
+            self.next_sequence += 1;
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            writeln!(file, "{}", serde_json::to_string(&message)?)?;
+            file.sync_data()?;
+            // Only counted once it is on disk.
+            self.bytes += message.payload.len();
+            self.messages.push_back(message);
+            Ok(evicted)
+        }
+
+        // Removes the oldest message once it has been delivered.
+        // Only the acked sequence is written, the file itself is compacted now and then.
+        pub fn pop(&mut self) -> Result<Option<QueuedMessage>, QueueException> {
+            if self.messages.is_empty(){
+                return Ok(None);
+            }
+            Ok(self.remove_front(1)?.pop())
+        }
+
+        fn is_full(&self, messages: usize, bytes: usize, incoming: usize) -> bool {
+            messages + 1 > self.config.max_messages || bytes + incoming > self.config.max_bytes
+        }
+
+        // Removes count messages from the front of the queue, after their sequence was
+        // persisted as acked: a failed write leaves the queue as it was.
+        // The file is rewritten once the acked prefix reaches compact_after, or right away
+        // when nothing is left (an empty file is cheap to write).
+        fn remove_front(&mut self, count: usize) -> Result<Vec<QueuedMessage>, QueueException> {
+            if count == 0{
+                return Ok(Vec::new());
+            }
+            let acked = self.messages[count - 1].sequence;
+            self.write_acked(acked)?;
+            let removed: Vec<QueuedMessage> = self.messages.drain(..count).collect();
+            self.bytes -= removed.iter().map(|message| message.payload.len()).sum::<usize>();
+            self.stale += count;
+            if self.messages.is_empty() || self.stale >= self.config.compact_after{
+                // The ack file already covers these messages, compaction can wait for the next try.
+                match self.rewrite(){
+                    Ok(()) => {
+                        self.stale = 0;
+                    }
+                    Err(err) => {
+                        println!("Compacting the offline queue failed: {}", err);
+                    }
+                }
+            }
+            Ok(removed)
+        }
+
+        fn write_acked(&self, acked: u64) -> Result<(), QueueException> {
+            let temporary = sibling(&self.ack_path, ".tmp");
+            {
+                let mut file = File::create(&temporary)?;
+                write!(file, "{}", acked)?;
+                file.sync_data()?;
+            }
+            std::fs::rename(&temporary, &self.ack_path)?;
+            Ok(())
+        }
+
+        // Write the remaining messages to a temporary file and swap it in.
+        fn rewrite(&self) -> Result<(), QueueException> {
+            let temporary = sibling(&self.path, ".tmp");
+            {
+                let mut file = File::create(&temporary)?;
+                for message in self.messages.iter(){
+                    writeln!(file, "{}", serde_json::to_string(message)?)?;
+                }
+                file.sync_data()?;
+            }
+            std::fs::rename(&temporary, &self.path)?;
+            Ok(())
+        }
+    }
+
+    // queue.jsonl -> queue.jsonl.ack
+    fn sibling(path: &Path, suffix: &str) -> PathBuf {
+        let mut sibling = path.to_path_buf().into_os_string();
+        sibling.push(suffix);
+        PathBuf::from(sibling)
+    }
+
+    // A missing or unreadable ack file means nothing was acked: messages may be sent twice,
+    // never lost.
+    fn read_acked(path: &Path) -> Result<Option<u64>, QueueException> {
+        if !path.exists(){
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)?;
+        match content.trim().parse::<u64>(){
+            Ok(acked) => {
+                Ok(Some(acked))
+            }
+            Err(err) => {
+                println!("Ignoring corrupt queue ack file: {}", err);
+                Ok(None)
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum QueueException{
+        Io(std::io::Error),
+        Serialization(serde_json::Error),
+        QueueFull,
+        MessageTooLarge,
+    }
+
+    impl Display for QueueException{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                QueueException::Io(err) => write!(f, "Queue file: {}", err),
+                QueueException::Serialization(err) => write!(f, "Queue entry: {}", err),
+                QueueException::QueueFull => write!(f, "The offline queue is full."),
+                QueueException::MessageTooLarge => write!(f, "The message does not fit in the offline queue."),
+            }
+        }
+    }
+
+    impl std::error::Error for QueueException{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                QueueException::Io(err) => Some(err),
+                QueueException::Serialization(err) => Some(err),
+                _ => None
+            }
+        }
+    }
+
+    impl From<std::io::Error> for QueueException{
+        fn from(err: std::io::Error) -> Self {
+            QueueException::Io(err)
+        }
+    }
+
+    impl From<serde_json::Error> for QueueException{
+        fn from(err: serde_json::Error) -> Self {
+            QueueException::Serialization(err)
+        }
+    }
+}
+pub mod batch{
+    // Collects encoded telemetry messages until the batch is big enough or old enough
+    // to go out as one batched transfer.
+    use std::time::{Duration, Instant};
+
+    // IoT Hub refuses messages (a batch counts as one) above 256 KB.
+    pub const MAX_BATCH_BYTES: usize = 256 * 1024;
+    // Room for the data section header wrapped around each message in the batch.
+    pub const SECTION_OVERHEAD: usize = 8;
+    // Room for the properties of the batch message itself.
+    pub const BATCH_ENVELOPE: usize = 1024;
+
+    #[derive(Clone, Debug)]
+    pub struct BatchConfig{
+        // Encoded size of the batch, section headers included
+        pub max_bytes: usize,
+        pub max_messages: usize,
+        // How long the first message of a batch may wait for company
+        pub window: Duration,
+    }
+
+    impl Default for BatchConfig{
+        fn default() -> Self {
+            BatchConfig{
+                max_bytes: MAX_BATCH_BYTES - BATCH_ENVELOPE,
+                max_messages: 100,
+                window: Duration::from_secs(5),
+            }
+        }
+    }
+
+    pub struct MessageBatch<T>{
+        config: BatchConfig,
+        items: Vec<T>,
+        bytes: usize,
+        opened_at: Option<Instant>,
+    }
+
+    impl<T> MessageBatch<T>{
+        pub fn new(config: BatchConfig) -> MessageBatch<T> {
+            MessageBatch{
+                config,
+                items: Vec::new(),
+                bytes: 0,
+                opened_at: None,
+            }
+        }
+
+        pub fn config(&self) -> &BatchConfig {
+            &self.config
+        }
+
+        pub fn len(&self) -> usize {
+            self.items.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.items.is_empty()
+        }
+
+        pub fn bytes(&self) -> usize {
+            self.bytes
+        }
+
+        // Whether a message of the given encoded size can still join this batch.
+        pub fn fits(&self, size: usize) -> bool {
+            self.items.len() < self.config.max_messages && self.bytes + size + SECTION_OVERHEAD <= self.config.max_bytes
+        }
+
+        // Adds the message, the caller checks fits() first and sends the batch if it does not.
+        pub fn push(&mut self, item: T, size: usize, now: Instant){
+            if self.opened_at.is_none(){
+                self.opened_at = Some(now);
+            }
+            self.bytes += size + SECTION_OVERHEAD;
+            self.items.push(item);
+        }
+
+        // Full on count, or the oldest message has waited the whole window.
+        pub fn is_due(&self, now: Instant) -> bool {
+            if self.items.len() >= self.config.max_messages{
+                return true;
+            }
+            match self.opened_at{
+                None => false,
+                Some(opened_at) => now.saturating_duration_since(opened_at) >= self.config.window
+            }
+        }
+
+        // Empties the batch and hands over the messages in the order they were added.
+        pub fn take(&mut self) -> Vec<T> {
+            self.bytes = 0;
+            self.opened_at = None;
+            std::mem::take(&mut self.items)
+        }
+    }
+}
+pub mod checkpoint{
+    // Where reading the Event Hub-compatible endpoint starts, and the last processed event
+    // per consumer group and partition. The checkpoints are kept in one JSON file that is
+    // rewritten (via a temporary file) on every update.
+    use std::collections::BTreeMap;
+    use std::fmt::{Display, Formatter};
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum EventPosition{
+        // Oldest event still retained
+        Start,
+        // Only events that arrive from now on
+        End,
+        // Events after the one with this offset
+        Offset(String),
+        // Events enqueued after this time
+        EnqueuedAfter(DateTime<Utc>),
+    }
+
+    impl EventPosition{
+        // Selector filter expression for the receiver link.
+        pub fn selector(&self) -> String {
+            match self{
+                EventPosition::Start => {
+                    "amqp.annotation.x-opt-offset > '-1'".to_string()
+                }
+                EventPosition::End => {
+                    "amqp.annotation.x-opt-offset > '@latest'".to_string()
+                }
+                EventPosition::Offset(offset) => {
+                    format!("amqp.annotation.x-opt-offset > '{}'", offset)
+                }
+                EventPosition::EnqueuedAfter(time) => {
+                    format!("amqp.annotation.x-opt-enqueued-time > '{}'", time.timestamp_millis())
+                }
+            }
+        }
+
+        // Reads back a selector expression (for the mock hub and tests).
+        pub fn from_selector(selector: &str) -> Option<EventPosition> {
+            let (annotation, value) = selector.split_once(" > ")?;
+            let value = value.trim().strip_prefix('\'')?.strip_suffix('\'')?;
+            match (annotation.trim(), value){
+                ("amqp.annotation.x-opt-offset", "-1") => Some(EventPosition::Start),
+                ("amqp.annotation.x-opt-offset", "@latest") => Some(EventPosition::End),
+                ("amqp.annotation.x-opt-offset", offset) => Some(EventPosition::Offset(offset.to_string())),
+                ("amqp.annotation.x-opt-enqueued-time", millis) => {
+                    let millis = millis.parse::<i64>().ok()?;
+                    Some(EventPosition::EnqueuedAfter(Utc.timestamp_millis(millis)))
+                }
+                _ => None
+            }
+        }
+    }
+
+    // The last processed event of a partition.
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct Checkpoint{
+        pub offset: String,
+        pub sequence_number: i64,
+        pub enqueued_time: Option<DateTime<Utc>>,
+    }
+
+    pub struct CheckpointStore{
+        path: PathBuf,
+        // "{consumer group}/{partition id}" -> checkpoint
+        checkpoints: BTreeMap<String, Checkpoint>,
+    }
+
+    impl CheckpointStore{
+        // Opens the checkpoint file, a missing file is an empty store.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<CheckpointStore, CheckpointException> {
+            let path = path.as_ref().to_path_buf();
+            let checkpoints = if path.exists(){
+                serde_json::from_reader(File::open(&path)?)?
+            }
+            else {
+                BTreeMap::new()
+            };
+            Ok(CheckpointStore{
+                path,
+                checkpoints
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn get(&self, consumer_group: &str, partition_id: &str) -> Option<&Checkpoint> {
+            self.checkpoints.get(&checkpoint_key(consumer_group, partition_id))
+        }
+
+        // Where to continue on a partition: right after its checkpoint, or the given
+        // position when there is none yet.
+        pub fn position(&self, consumer_group: &str, partition_id: &str, initial: &EventPosition) -> EventPosition {
+            match self.get(consumer_group, partition_id){
+                None => {
+                    initial.clone()
+                }
+                Some(checkpoint) => {
+                    EventPosition::Offset(checkpoint.offset.clone())
+                }
+            }
+        }
+
+        pub fn update(&mut self, consumer_group: &str, partition_id: &str, checkpoint: Checkpoint) -> Result<(), CheckpointException> {
+            self.checkpoints.insert(checkpoint_key(consumer_group, partition_id), checkpoint);
+            self.save()
+        }
+
+        fn save(&self) -> Result<(), CheckpointException> {
+            let mut temporary = self.path.clone().into_os_string();
+            temporary.push(".tmp");
+            let temporary = PathBuf::from(temporary);
+            {
+                let mut file = File::create(&temporary)?;
+                file.write_all(&serde_json::to_vec_pretty(&self.checkpoints)?)?;
+                file.sync_data()?;
+            }
+            std::fs::rename(&temporary, &self.path)?;
+            Ok(())
+        }
+    }
+
+    fn checkpoint_key(consumer_group: &str, partition_id: &str) -> String {
+        format!("{}/{}", consumer_group, partition_id)
+    }
+
+    #[derive(Debug)]
+    pub enum CheckpointException{
+        Io(std::io::Error),
+        Serialization(serde_json::Error),
+    }
+
+    impl Display for CheckpointException{
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self{
+                CheckpointException::Io(err) => write!(f, "Checkpoint file: {}", err),
+                CheckpointException::Serialization(err) => write!(f, "Checkpoint file contents: {}", err),
+            }
+        }
+    }
+
+    impl std::error::Error for CheckpointException{
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self{
+                CheckpointException::Io(err) => Some(err),
+                CheckpointException::Serialization(err) => Some(err),
+            }
+        }
+    }
+
+    impl From<std::io::Error> for CheckpointException{
+        fn from(err: std::io::Error) -> Self {
+            CheckpointException::Io(err)
+        }
+    }
+
+    impl From<serde_json::Error> for CheckpointException{
+        fn from(err: serde_json::Error) -> Self {
+            CheckpointException::Serialization(err)
+        }
+    }
+}
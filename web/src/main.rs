@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::{Arc, mpsc};
 use std::sync::mpsc::Sender;
@@ -8,11 +8,14 @@ use std::time::Duration;
 use amqpiothubv2;
 use amqpiothubv2::{amqp, ntex_amqp};
 use amqpiothubv2::amqp::transfer::{create_directed_message, create_message_from_str};
+use amqpiothubv2::amqp::client::ServiceClientHandle;
+use amqpiothubv2::registry::RegistryClient;
+use amqpiothubv2::amqp::eventhub::{DEFAULT_CONSUMER_GROUP, EventHubFailure};
+use amqpiothubv2::util::checkpoint::{CheckpointStore, EventPosition};
 use amqpiothubv2::amqp::util;
 use amqpiothubv2::async_std;
 use amqpiothubv2::ntex;
 use amqpiothubv2::ntex_amqp::types::Transfer;
-use amqpiothubv2::util::token::SasToken;
 use azureblobmanager;
 use azureblobmanager::Blob;
 use azureblobmanager::storage::{BlobPlotData, StorageEntry, StorageEntryFields, StorageReadClient};
@@ -41,36 +44,103 @@ struct DataEntry{
     value: f64
 }
 
-#[derive(Serialize, Deserialize)]
-struct Action{
-    action: String
+// Devices the actions can be sent to, anything else goes to the test device.
+fn target_device(device_id: &str) -> String {
+    match device_id{
+        "airquality" | "temperature" => {
+            String::from(device_id)
+        }
+        _ => {
+            String::from("rusttestingdevice")
+        }
+    }
 }
 
 #[get("/device_actions/<device_id>")]
-async fn devices(device_id: &str) -> String{
-    println!("Sender triggered... Preparing payloads...");
-    let target_path = match device_id{
-        "airquality" =>  {
-            String::from("/devices/airquality/messages/devicebound")
+async fn devices(device_id: &str, service: &State<Option<ServiceClientHandle>>) -> String{
+    println!("Action triggered... Invoking the direct method...");
+    let service = match service.inner(){
+        None => {
+            return String::from("Not configured");
         }
-        "temperature" => {
-            String::from("/devices/temperature/messages/devicebound")
+        Some(service) => {
+            service
         }
-        _ => {
-            String::from("/devices/rusttestingdevice/messages/devicebound")
+    };
+    match service.invoke_method(&target_device(device_id), "test", serde_json::json!({}), 30).await{
+        Ok(response) => {
+            format!("Executed ({}): {}", response.status, response.payload)
+        }
+        Err(err) => {
+            println!("Direct method failed: {}", err);
+            format!("Failed: {}", err)
+        }
+    }
+}
+
+#[get("/device_messages/<device_id>")]
+async fn device_messages(device_id: &str, service: &State<Option<ServiceClientHandle>>) -> String{
+    println!("Action triggered... Sending a cloud to device message...");
+    let service = match service.inner(){
+        None => {
+            return String::from("Not configured");
+        }
+        Some(service) => {
+            service
         }
     };
-    let action = Action{
-        action: "test".to_string()
+    let device = target_device(device_id);
+    let message = create_directed_message(
+        String::from("{\"action\":\"test\"}"),
+        format!("/devices/{}/messages/devicebound", device));
+    let message_id = match service.send_message(message, &device, 10).await{
+        Ok(message_id) => {
+            message_id
+        }
+        Err(err) => {
+            println!("Failed to send the message: {}", err);
+            return format!("Failed: {}", err);
+        }
     };
-    let mut device = String::from(device_id);
-    let json = serde_json::to_string(&action);
-    let mut message = create_directed_message(json.unwrap(), target_path);
-
-    tokio::task::spawn_blocking(|| {
-        ntex_main(message, device);
-    }).await.expect("Task panicked");
-    return String::from("Executed");
+    // The hub batches feedback, give the device and the hub some time.
+    match service.wait_for_feedback(&message_id, 90).await{
+        Ok(record) => {
+            format!("{:?}: {}", record.status, record.description)
+        }
+        Err(err) => {
+            println!("No feedback for {}: {}", message_id, err);
+            format!("Unknown: {}", err)
+        }
+    }
+}
+
+// Device identities in the hub registry: id, status and connection state.
+#[get("/registry/devices")]
+async fn registry_devices(registry: &State<Option<RegistryClient>>) -> String {
+    let registry = match registry.inner(){
+        None => {
+            return String::from("Not configured");
+        }
+        Some(registry) => {
+            registry
+        }
+    };
+    match registry.list_devices(100).await{
+        Ok(devices) => {
+            let devices: Vec<Value> = devices.iter()
+                .map(|device| serde_json::json!({
+                    "deviceId": device.device_id,
+                    "status": device.status,
+                    "connectionState": device.connection_state
+                }))
+                .collect();
+            serde_json::to_string(&devices).unwrap()
+        }
+        Err(err) => {
+            println!("Failed to list the devices: {}", err);
+            format!("Failed: {}", err)
+        }
+    }
 }
 
 #[get("/device_data/<device_id>/<count>")]
@@ -80,6 +150,19 @@ async fn device_data(device_id: &str, count: u64) -> String
     return String::new();
 }
 
+// Telemetry read live from the Event Hub-compatible endpoint of the hub, oldest first.
+type LiveTelemetry = Arc<std::sync::Mutex<VecDeque<BlobPlotData>>>;
+const LIVE_TELEMETRY_ENTRIES: usize = 1000;
+
+#[get("/device_data_live/<sensor_id>")]
+async fn device_data_live(sensor_id: &str, live: &State<LiveTelemetry>) -> String {
+    let live = live.lock().unwrap();
+    let plot_data: Vec<&BlobPlotData> = live.iter()
+        .filter(|entry| entry.sensor == sensor_id)
+        .collect();
+    serde_json::to_string(&plot_data).unwrap()
+}
+
 #[get("/device_data_vars/date")]
 async fn get_available_dates() -> String {
     let get_blobs = get_blob_urls().await;
@@ -271,14 +354,137 @@ pub fn get_all_status() -> HashMap<&'static str, i32> {
 }
 
 
+// Reads the telemetry of all devices on its own thread, continuing after the last
+// checkpoint (or the last hour on the first run).
+fn start_live_telemetry(live: LiveTelemetry){
+    let connection_string = match std::env::var("IOTHUB_SERVICE_CONNECTION_STRING"){
+        Ok(connection_string) => {
+            connection_string
+        }
+        Err(_) => {
+            return;
+        }
+    };
+    thread::spawn(move || {
+        ntex::rt::System::new("live-telemetry").block_on(read_live_telemetry(connection_string, live));
+    });
+}
+
+async fn read_live_telemetry(connection_string: String, live: LiveTelemetry){
+    let mut client = match amqp::client::ServiceClient::from_connection_string(&connection_string, "src/root.pem").await{
+        Ok(client) => {
+            client
+        }
+        Err(err) => {
+            println!("Live telemetry: failed to create the service client: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = client.connect().await{
+        println!("Live telemetry: failed to connect: {}", err);
+        return;
+    }
+    let checkpoints = match CheckpointStore::open("live-checkpoints.json"){
+        Ok(checkpoints) => {
+            Some(checkpoints)
+        }
+        Err(err) => {
+            println!("Live telemetry: checkpoints unavailable: {}", err);
+            None
+        }
+    };
+    let start = EventPosition::EnqueuedAfter(Utc::now() - chrono::Duration::hours(1));
+    let mut consumer = match client.event_consumer(DEFAULT_CONSUMER_GROUP, start, checkpoints, 10).await{
+        Ok(consumer) => {
+            consumer
+        }
+        Err(err) => {
+            println!("Live telemetry: failed to open the partitions: {}", err);
+            return;
+        }
+    };
+    loop{
+        let event = match consumer.next_event(60).await{
+            Ok(event) => {
+                event
+            }
+            Err(EventHubFailure::Timeout) => {
+                continue;
+            }
+            Err(err) => {
+                println!("Live telemetry stopped: {}", err);
+                return;
+            }
+        };
+        if let Ok(entry) = serde_json::from_slice::<DataEntry>(&event.body){
+            let mut live = live.lock().unwrap();
+            live.push_back(BlobPlotData{
+                device: event.device_id.clone().unwrap_or_default(),
+                sensor: entry.sensor,
+                date_time: event.enqueued_time.map(|time| time.to_rfc3339()).unwrap_or_default(),
+                value: entry.value
+            });
+            while live.len() > LIVE_TELEMETRY_ENTRIES{
+                live.pop_front();
+            }
+        }
+        if consumer.checkpoint(&event).is_err(){
+            println!("Live telemetry: failed to store the checkpoint of partition {}", event.partition_id);
+        }
+    }
+}
+
+// One service client for all requests, None when no connection string is configured.
+async fn start_service_client() -> Option<ServiceClientHandle> {
+    // Service Params: HostName=...;SharedAccessKeyName=iothubowner;SharedAccessKey=...
+    let connection_string = match std::env::var("IOTHUB_SERVICE_CONNECTION_STRING"){
+        Ok(connection_string) => {
+            connection_string
+        }
+        Err(_) => {
+            println!("IOTHUB_SERVICE_CONNECTION_STRING is not set, device actions are disabled");
+            return None;
+        }
+    };
+    match ServiceClientHandle::start(&connection_string, "src/root.pem").await{
+        Ok(service) => {
+            Some(service)
+        }
+        Err(err) => {
+            println!("Failed to start the service client: {}", err);
+            None
+        }
+    }
+}
+
+// Registry client with the service credentials, None when no connection string is configured.
+async fn start_registry_client() -> Option<RegistryClient> {
+    let connection_string = std::env::var("IOTHUB_SERVICE_CONNECTION_STRING").ok()?;
+    match RegistryClient::from_connection_string(&connection_string, "src/root.pem").await{
+        Ok(registry) => {
+            Some(registry)
+        }
+        Err(err) => {
+            println!("Failed to create the registry client: {}", err);
+            None
+        }
+    }
+}
+
 #[rocket::main]
 async fn main(){
     println!("Rocket is launching...");
+    let service = start_service_client().await;
+    let registry = start_registry_client().await;
+    let live: LiveTelemetry = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    start_live_telemetry(live.clone());
 
     let rocket = rocket::build()
         .mount("/",
                routes![
                    devices,
+                   device_messages,
+                   registry_devices,
                    actions,
                    raspberrypi_airquality,
                    raspberrypi_temperature,
@@ -289,61 +495,16 @@ async fn main(){
                    ping_all,
                    device_data,
                    device_data_period,
+                   device_data_live,
                    get_available_dates
                ]
         )
+        .manage(service)
+        .manage(registry)
+        .manage(live)
         .attach(Template::fairing());
 
     let launch = rocket.launch().await;
     println!("Rocket end/crash: {:?}", launch);
 
 }
-
-#[ntex::main]
-async fn ntex_main(message: TransferBody, target_device: String){
-    println!("Main is launching...");
-    // Device Params
-    let mut service_key  = "";
-    let mut hub_name = "";
-
-    // TEST SER
-    let mut sas_token = SasToken::service_token(
-        service_key, 1, "researchprojecthub", "iothubowner"
-    );
-
-    let mut sas_token_opt = match sas_token{
-        Ok(ok) => {
-            Some(ok)
-        }
-        Err(fail) => {
-            panic!("Failed SAS: {}",fail);
-            None
-        }
-    };
-    let token = sas_token_opt.unwrap();
-
-    let mut client = amqp::client::ServiceClient::new(
-        "", "src/root.pem",
-        service_key, &token.sas, "iothubowner"
-    ).await;
-
-    let connector = client.connect().await;
-    if connector.is_err(){
-        // Failure
-        return;
-    }
-    let action = Action{
-        action: String::from("action")
-    };
-    let json = serde_json::to_string(&action);
-    if json.is_err(){
-        println!("Failed to send message");
-        return;
-    }
-    else{
-        client.send_simple_message(message, &target_device, 6 ).await;
-    }
-}
-
-
-